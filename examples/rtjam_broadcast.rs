@@ -1,17 +1,109 @@
-use rtjam_rust::{common::box_error::BoxError, server::broadcast_server, utils::get_git_hash};
-use std::{env, process::exit};
+use chrono::Local;
+use clap::{Parser, ValueEnum};
+use rtjam_rust::{
+    common::box_error::BoxError,
+    server::broadcast_server::{self, ServerOptions},
+    utils::get_git_hash,
+};
+use serde_json::json;
+use std::{io::Write, process::exit};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// one json object per line
+    Json,
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None, disable_version_flag = true)]
+struct Args {
+    /// settings file
+    #[arg(short, long, default_value = "settings.json")]
+    config: String,
+
+    /// udp port for the room (overrides the settings file)
+    #[arg(short, long)]
+    port: Option<u32>,
+
+    /// address players use to reach the room (overrides the settings file)
+    #[arg(short, long)]
+    wan_ip: Option<String>,
+
+    /// mix the room on the server or forward each player (overrides the settings file)
+    #[arg(short = 'm', long, value_parser = ["mix", "separate"])]
+    room_mode: Option<String>,
+
+    /// where saved recordings go (overrides the settings file)
+    #[arg(short, long)]
+    recording_dir: Option<String>,
+
+    /// run without the rtjam-nation (use the local websocket to run the room)
+    #[arg(long, default_value_t = false)]
+    offline: bool,
+
+    /// how log lines look (RUST_LOG sets the level)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// check the settings, print what the server would run with, and exit
+    #[arg(long, default_value_t = false)]
+    check_config: bool,
+
+    /// print the git hash and exit
+    #[arg(short, long, default_value_t = false)]
+    version: bool,
+}
+
+fn init_logger(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                json!({
+                    "time": Local::now().to_rfc3339(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+            )
+        });
+    }
+    builder.init();
+}
 
 fn main() -> Result<(), BoxError> {
+    let args = Args::parse();
     // Turn on the logger
-    env_logger::init();
+    init_logger(args.log_format);
 
-    // note: add error checking yourself.
     let git_hash = get_git_hash();
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
+    if args.version {
         println!("{}", git_hash);
         exit(0);
     }
-    broadcast_server::run(git_hash.as_str())?;
+    let options = ServerOptions {
+        config_file: args.config,
+        port: args.port,
+        wan_ip: args.wan_ip,
+        room_mode: args.room_mode,
+        recording_dir: args.recording_dir,
+        offline: args.offline,
+    };
+    if args.check_config {
+        match broadcast_server::check_config(&options) {
+            Ok(summary) => {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+                exit(0);
+            }
+            Err(e) => {
+                eprintln!("config problem: {}", e);
+                exit(1);
+            }
+        }
+    }
+    broadcast_server::run(git_hash.as_str(), options)?;
     Ok(())
 }
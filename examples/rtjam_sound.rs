@@ -1,5 +1,9 @@
 use clap::{Parser, command};
-use rtjam_rust::{common::box_error::BoxError, sound::client, utils::get_git_hash};
+use rtjam_rust::{
+    common::box_error::BoxError,
+    sound::client::{self, ClientOptions, SETTINGS_FILE},
+    utils::get_git_hash,
+};
 use std::process::exit;
 
 #[derive(Parser)]
@@ -18,6 +22,26 @@ struct Args {
 
     #[arg(short, long, default_value_t = false)]
     alsa: bool,
+
+    /// settings file
+    #[arg(short, long, default_value = SETTINGS_FILE)]
+    config: String,
+
+    /// rtjam-nation api url (overrides the settings file)
+    #[arg(long)]
+    api_url: Option<String>,
+
+    /// port for the local websocket, 0 turns it off (overrides the settings file)
+    #[arg(long)]
+    local_ws_port: Option<u32>,
+
+    /// udp port for OSC control surfaces, 0 turns it off (overrides the settings file)
+    #[arg(long)]
+    osc_port: Option<u32>,
+
+    /// turn off the local loopback
+    #[arg(long, default_value_t = false)]
+    no_loopback: bool,
 }
 
 
@@ -33,6 +57,13 @@ fn main() -> Result<(), BoxError> {
         println!("{}", git_hash);
         exit(0);
     }
-    client::run(git_hash, args.alsa, args.in_dev, args.out_dev)?;
+    let options = ClientOptions {
+        config_file: args.config,
+        api_url: args.api_url,
+        local_ws_port: args.local_ws_port,
+        osc_port: args.osc_port,
+        no_loopback: args.no_loopback,
+    };
+    client::run(git_hash, args.alsa, args.in_dev, args.out_dev, options)?;
     Ok(())
 }
@@ -0,0 +1,84 @@
+use chrono::{DateTime, Local, NaiveDate};
+use clap::Parser;
+use rtjam_rust::common::box_error::BoxError;
+use rtjam_rust::common::session_report::{summarize, SessionLog, SessionReport};
+
+/// Query the session history the broadcast component writes (one json report per line)
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Session log written by the broadcast component
+    #[arg(short, long, default_value = "sessions.jsonl")]
+    file: String,
+
+    /// Only sessions for this client id
+    #[arg(short, long)]
+    client: Option<u32>,
+
+    /// Only sessions that started on or after this date (YYYY-MM-DD)
+    #[arg(short, long)]
+    since: Option<NaiveDate>,
+
+    /// Only the most recent n sessions
+    #[arg(short, long)]
+    last: Option<usize>,
+
+    /// Print totals instead of the sessions
+    #[arg(long)]
+    summary: bool,
+
+    /// Print the full json reports
+    #[arg(long)]
+    json: bool,
+}
+
+fn start_time(report: &SessionReport) -> DateTime<Local> {
+    DateTime::from_timestamp_micros(report.start as i64)
+        .unwrap_or_default()
+        .with_timezone(&Local)
+}
+
+fn main() -> Result<(), BoxError> {
+    let args = Args::parse();
+    let mut reports: Vec<SessionReport> = SessionLog::new(&args.file)
+        .read()?
+        .into_iter()
+        .filter(|r| args.client.is_none_or(|id| r.client_id == id))
+        .filter(|r| args.since.is_none_or(|d| start_time(r).date_naive() >= d))
+        .collect();
+    if let Some(n) = args.last {
+        let skip = reports.len().saturating_sub(n);
+        reports.drain(0..skip);
+    }
+    if args.summary {
+        println!("{}", serde_json::to_string_pretty(&summarize(&reports))?);
+        return Ok(());
+    }
+    if args.json {
+        for r in &reports {
+            println!("{}", serde_json::to_string(r)?);
+        }
+        return Ok(());
+    }
+    println!(
+        "{:<19} {:>8} {:>8} {:>8} {:>6} {:>6} {:>8} {:>8} {:>8} {:>8}",
+        "start", "client", "minutes", "packets", "loss%", "late%", "gap99ms", "loop50ms", "loop99ms", "gapms"
+    );
+    for r in &reports {
+        println!(
+            "{:<19} {:>8} {:>8.1} {:>8} {:>6.2} {:>6.2} {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+            start_time(r).format("%Y-%m-%d %H:%M:%S"),
+            r.client_id,
+            r.duration / 60.0,
+            r.packets,
+            r.loss_rate * 100.0,
+            r.late_rate * 100.0,
+            r.interarrival.p99 / 1000.0,
+            r.loop_time.p50 / 1000.0,
+            r.loop_time.p99 / 1000.0,
+            r.longest_gap as f64 / 1000.0,
+        );
+    }
+    Ok(())
+}
@@ -9,14 +9,22 @@ pub fn get_micro_time() -> u128 {
         .as_micros()
 }
 
+pub mod auth;
 pub mod box_error;
+pub mod command_reply;
 pub mod config;
 pub mod jam_nation_api;
+pub mod json_file;
 pub mod jam_packet;
+pub mod latency_matrix;
+pub mod layered_config;
+pub mod local_websocket;
 pub mod packet_stream;
 pub mod player;
 pub mod recording;
 pub mod room;
+pub mod seq_tracker;
+pub mod session_report;
 pub mod sock_with_tos;
 pub mod stream_time_stat;
 pub mod websock_message;
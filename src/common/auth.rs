@@ -0,0 +1,242 @@
+//! Signed control messages from the u/x
+//!
+//! Anything posted in a unit's chat room with `context == "user"` used to be treated as a command.
+//! When a component has a `command_secret` in settings.json, commands carry an `auth` block
+//! with an HMAC-SHA256 signature made with that secret.  Only the owner's session is given the
+//! secret so only it can sign:
+//!
+//! ```json
+//! {
+//!   "cmd": "setChannelGain", "channel": 4, "gain": -6.0,
+//!   "auth": { "ts": 1700000000, "nonce": "8f14e45f", "sig": "<hex hmac>" }
+//! }
+//! ```
+//!
+//! The signature covers `"<ts>\n<nonce>\n<body>"` where body is the message without the auth block
+//! as compact json with the keys sorted (what `JSON.stringify` gives after sorting the keys).
+//! Messages more than [`AUTH_WINDOW`] seconds from our clock are rejected, as is any nonce already
+//! seen inside the window, so a captured command can't be replayed.
+//!
+//! Once a component has a secret every command has to be signed, whether it came in through the
+//! room or the local websocket.  Without one unsigned commands are accepted so existing front ends
+//! keep working, but anything carrying a signature is rejected since it can't be checked.
+//!
+//! The local websocket tags messages with the connection they came in on (`wsClient`).  That tag
+//! isn't part of the signed body.
+use std::collections::HashMap;
+
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use simple_error::bail;
+
+use crate::common::{box_error::BoxError, command_reply::command_reply};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// how far (seconds) a message timestamp can be from our clock
+pub const AUTH_WINDOW: u64 = 30;
+/// longest nonce we will keep track of
+const MAX_NONCE_LEN: usize = 64;
+
+/// Checks the signatures on incoming commands
+pub struct CommandAuth {
+    secret: Vec<u8>,
+    seen: HashMap<String, u64>,
+}
+
+impl CommandAuth {
+    /// commands have to be signed with secret.  An empty secret accepts them unsigned
+    pub fn new(secret: &str) -> CommandAuth {
+        CommandAuth {
+            secret: secret.as_bytes().to_vec(),
+            seen: HashMap::new(),
+        }
+    }
+    /// check the message.  now is unix time in seconds
+    pub fn verify(&mut self, msg: &Value, now: u64) -> Result<(), BoxError> {
+        let auth = &msg["auth"];
+        if self.secret.is_empty() {
+            if !auth.is_null() {
+                bail!("signed command but no command_secret configured");
+            }
+            return Ok(());
+        }
+        if auth.is_null() {
+            bail!("command is not signed");
+        }
+        let (ts, nonce, sig) = match (auth["ts"].as_u64(), auth["nonce"].as_str(), auth["sig"].as_str()) {
+            (Some(ts), Some(nonce), Some(sig)) => (ts, nonce, sig),
+            _ => bail!("auth needs ts, nonce and sig"),
+        };
+        if ts.abs_diff(now) > AUTH_WINDOW {
+            bail!("command timestamp out of window");
+        }
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            bail!("bad nonce");
+        }
+        let sig = hex::decode(sig)?;
+        let mac = self.mac(ts, nonce, msg)?;
+        if mac.verify_slice(&sig).is_err() {
+            bail!("bad command signature");
+        }
+        // forget the nonces that are too old to pass the time check anyway
+        self.seen.retain(|_, t| t.abs_diff(now) <= AUTH_WINDOW * 2);
+        if self.seen.insert(String::from(nonce), ts).is_some() {
+            bail!("command replayed");
+        }
+        Ok(())
+    }
+    /// sign a message.  Used by tools and tests, the u/x does the same in javascript
+    pub fn sign(&self, msg: &Value, ts: u64, nonce: &str) -> Result<Value, BoxError> {
+        let mac = self.mac(ts, nonce, msg)?;
+        let mut signed = msg.clone();
+        signed["auth"] = json!({
+            "ts": ts,
+            "nonce": nonce,
+            "sig": hex::encode(mac.finalize().into_bytes()),
+        });
+        Ok(signed)
+    }
+    fn mac(&self, ts: u64, nonce: &str, msg: &Value) -> Result<HmacSha256, BoxError> {
+        let mut body = msg.clone();
+        if let Some(obj) = body.as_object_mut() {
+            obj.remove("auth");
+            obj.remove("wsClient");
+        }
+        let mut text = format!("{}\n{}\n", ts, nonce);
+        canonical(&body, &mut text);
+        let mut mac = HmacSha256::new_from_slice(&self.secret)?;
+        mac.update(text.as_bytes());
+        Ok(mac)
+    }
+}
+
+// compact json with the object keys sorted here, not left to however serde_json orders a map
+fn canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(obj) => {
+            let mut keys: Vec<&String> = obj.keys().collect();
+            keys.sort();
+            out.push('{');
+            for (i, key) in keys.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::from(key.as_str()).to_string());
+                out.push(':');
+                canonical(&obj[key], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// The proof of a room password a sound component sends in its JOIN packet, answering the
+/// challenge nonce the room sent it.  The password itself never goes on the wire.  See
+/// [`crate::server::admission`]
+pub fn password_token(password: &str, client_id: u32, challenge: u64) -> u64 {
+    let mut mac = HmacSha256::new_from_slice(password.as_bytes()).expect("hmac takes any key");
+    mac.update(&client_id.to_be_bytes());
+    mac.update(&challenge.to_be_bytes());
+    let bytes = mac.finalize().into_bytes();
+    let mut token = [0; 8];
+    token.copy_from_slice(&bytes[0..8]);
+    u64::from_be_bytes(token)
+}
+
+/// The event that tells the u/x a command was refused.  A u/x waiting on a request id gets a
+/// failed command reply instead.  It goes back to the local connection the command came in on.
+pub fn rejection(speaker: &str, msg: &Value, reason: BoxError) -> Value {
+    let mut event = match msg["requestId"].as_str() {
+        Some(id) => command_reply(speaker, id, Err(reason)),
+        None => json!({
+            "speaker": speaker,
+            "commandRejected": { "reason": reason.to_string() }
+        }),
+    };
+    if !msg["wsClient"].is_null() {
+        event["wsClient"] = msg["wsClient"].clone();
+    }
+    event
+}
+
+#[cfg(test)]
+mod test_auth {
+    use super::*;
+
+    #[test]
+    fn signed_round_trip() {
+        let mut auth = CommandAuth::new("sekrit");
+        let msg = json!({"cmd": "setChannelGain", "channel": 4, "gain": -6.0});
+        let signed = auth.sign(&msg, 1000, "abc").unwrap();
+        assert!(auth.verify(&signed, 1010).is_ok());
+        // same nonce again is a replay
+        assert_eq!(auth.verify(&signed, 1010).unwrap_err().to_string(), "command replayed");
+        // too old
+        let signed = auth.sign(&msg, 1000, "def").unwrap();
+        assert!(auth.verify(&signed, 1000 + AUTH_WINDOW + 1).is_err());
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let mut auth = CommandAuth::new("sekrit");
+        let mut signed = auth.sign(&json!({"cmd": "disconnect"}), 1000, "abc").unwrap();
+        signed["cmd"] = json!("shutdown");
+        assert_eq!(auth.verify(&signed, 1000).unwrap_err().to_string(), "bad command signature");
+        // signed with some other secret
+        let other = CommandAuth::new("guess");
+        let signed = other.sign(&json!({"cmd": "disconnect"}), 1000, "abc").unwrap();
+        assert!(auth.verify(&signed, 1000).is_err());
+    }
+
+    #[test]
+    fn unsigned_only_without_a_secret() {
+        let msg = json!({"param": 14, "fValue": -3.0});
+        assert!(CommandAuth::new("").verify(&msg, 0).is_ok());
+        assert!(CommandAuth::new("sekrit").verify(&msg, 0).is_err());
+        let signed = CommandAuth::new("sekrit").sign(&msg, 5, "n").unwrap();
+        assert!(CommandAuth::new("").verify(&signed, 5).is_err());
+        // key order doesn't matter to the signature
+        let mut auth = CommandAuth::new("sekrit");
+        let signed = auth.sign(&json!({"b": 1, "a": 2}), 5, "n").unwrap();
+        let reordered: Value = serde_json::from_str(
+            &format!(r#"{{"a": 2, "auth": {}, "b": 1}}"#, signed["auth"]),
+        )
+        .unwrap();
+        assert!(auth.verify(&reordered, 5).is_ok());
+        // the local websocket's connection tag isn't signed
+        let mut tagged = auth.sign(&json!({"a": 2}), 5, "m").unwrap();
+        tagged["wsClient"] = json!(3);
+        assert!(auth.verify(&tagged, 5).is_ok());
+    }
+
+    #[test]
+    fn canonical_body() {
+        let mut text = String::new();
+        canonical(&json!({"b": [{"d": 1, "c": "x\"y"}], "a": -6.5, "e": null}), &mut text);
+        assert_eq!(text, r#"{"a":-6.5,"b":[{"c":"x\"y","d":1}],"e":null}"#);
+    }
+
+    #[test]
+    fn rejection_events() {
+        let reply = rejection("UnitChatRobot", &json!({"requestId": "x"}), "command is not signed".into());
+        assert_eq!(reply["commandReply"]["success"], false);
+        let event = rejection("RoomChatRobot", &json!({}), "command is not signed".into());
+        assert_eq!(event["commandRejected"]["reason"], "command is not signed");
+        assert!(event["wsClient"].is_null());
+        let event = rejection("UnitChatRobot", &json!({"wsClient": 2}), "command is not signed".into());
+        assert_eq!(event["wsClient"], 2);
+    }
+}
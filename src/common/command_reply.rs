@@ -0,0 +1,54 @@
+//! Reply event sent back to the u/x for commands that carry a request id
+//!
+//! Both the sound component and the broadcast component answer commands the same way so a u/x
+//! can match the reply to its request:
+//!
+//! ```json
+//! {
+//!   "speaker": "UnitChatRobot",
+//!   "commandReply": {
+//!     "requestId": "abc123",
+//!     "success": true,
+//!     "error": null,
+//!     "state": { "channel": 4, "gain": -6.0, "mute": false, "fade": 0.0 }
+//!   }
+//! }
+//! ```
+use serde_json::{json, Value};
+
+use crate::common::box_error::BoxError;
+
+/// build the reply for a command.  state is whatever the command changed (or Null)
+pub fn command_reply(speaker: &str, request_id: &str, result: Result<Value, BoxError>) -> Value {
+    let (success, error, state) = match result {
+        Ok(state) => (true, Value::Null, state),
+        Err(e) => (false, json!(e.to_string()), Value::Null),
+    };
+    json!({
+        "speaker": speaker,
+        "commandReply": {
+            "requestId": request_id,
+            "success": success,
+            "error": error,
+            "state": state,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test_command_reply {
+    use super::*;
+
+    #[test]
+    fn success_and_failure() {
+        let ok = command_reply("UnitChatRobot", "1", Ok(json!({"gain": -3.0})));
+        assert_eq!(ok["commandReply"]["success"], true);
+        assert_eq!(ok["commandReply"]["state"]["gain"], -3.0);
+        assert!(ok["commandReply"]["error"].is_null());
+        let bad = command_reply("RoomChatRobot", "2", Err("channel 99 out of range".into()));
+        assert_eq!(bad["speaker"], "RoomChatRobot");
+        assert_eq!(bad["commandReply"]["requestId"], "2");
+        assert_eq!(bad["commandReply"]["success"], false);
+        assert_eq!(bad["commandReply"]["error"], "channel 99 out of range");
+    }
+}
@@ -11,6 +11,54 @@ use std::fmt;
 use super::box_error::BoxError;
 
 pub const JAM_BUF_SIZE: usize = 1024;
+
+/// packet types (byte 1 of the header).  Audio is 0 which is what older components always send
+pub const PACKET_AUDIO: u8 = 0;
+/// sound to broadcast: asking to join a room (password token in the server time field, session
+/// nonce in the client timestamp)
+pub const PACKET_JOIN: u8 = 1;
+/// broadcast to sound: you are in the room
+pub const PACKET_ADMIT: u8 = 2;
+/// broadcast to sound: you are not in the room (reason code in the chunk count byte)
+pub const PACKET_REJECT: u8 = 3;
+/// broadcast to sound: something the player should know about (code in the chunk count byte)
+pub const PACKET_NOTICE: u8 = 4;
+/// sound to broadcast: audio device latency and jitter buffer depths (u32 words after the header)
+pub const PACKET_REPORT: u8 = 5;
+/// broadcast to sound: the room's latency matrix (u32 words after the header)
+pub const PACKET_LATENCY: u8 = 6;
+/// broadcast to sound: answer this nonce (in the server time field) with the room password
+pub const PACKET_CHALLENGE: u8 = 7;
+
+/// client id is on the ban list
+pub const REJECT_BANNED: u8 = 1;
+/// allow-list in effect and client id is not on it
+pub const REJECT_NOT_INVITED: u8 = 2;
+/// room has a password and the client has not sent the right one
+pub const REJECT_PASSWORD: u8 = 3;
+/// room has max players already
+pub const REJECT_FULL: u8 = 4;
+/// somebody else is using the same client id
+pub const REJECT_DUPLICATE_ID: u8 = 5;
+/// packets from a new address that has not proven it owns the client id (send a JOIN)
+pub const REJECT_UNVERIFIED: u8 = 6;
+/// the room server is shutting down (NOTICE)
+pub const NOTICE_GOING_AWAY: u8 = 7;
+
+/// text for a rejection code
+pub fn reject_reason(code: u8) -> &'static str {
+    match code {
+        REJECT_BANNED => "banned from the room",
+        REJECT_NOT_INVITED => "not on the room guest list",
+        REJECT_PASSWORD => "room password required",
+        REJECT_FULL => "room is full",
+        REJECT_DUPLICATE_ID => "another player is using this client id",
+        REJECT_UNVERIFIED => "address changed, rejoining",
+        NOTICE_GOING_AWAY => "room server is shutting down",
+        _ => "not admitted",
+    }
+}
+
 /// the message that gets read/write on the udp socket
 ///
 /// super simple by design.  just has getters/setters to make sure everything
@@ -35,6 +83,8 @@ pub struct JamMessage {
 // };
 // Header size is all the stuff up to the buffer...
 pub const JAM_HEADER_SIZE: usize = 1 + 1 + 1 + 1 + 8 + 8 + 4 + 4;
+/// most u32 words a control packet can carry
+pub const MAX_WORDS: usize = (JAM_BUF_SIZE - JAM_HEADER_SIZE) / 4;
 
 impl JamMessage {
     /// build a message
@@ -53,6 +103,39 @@ impl JamMessage {
     pub fn set_channel(&mut self, chan: u8) -> () {
         self.buffer[0] = chan;
     }
+    /// What kind of packet this is ([`PACKET_AUDIO`], [`PACKET_JOIN`], etc).  This is the old sample
+    /// rate byte which components always set to 0.
+    pub fn get_packet_type(&self) -> u8 {
+        self.buffer[1]
+    }
+    /// Turn this into a header only control packet.  code goes in the chunk count byte and value
+    /// in the server time field.  Older components see an audio packet with no audio.
+    pub fn set_control(&mut self, packet_type: u8, code: u8, value: u64) {
+        self.buffer[1] = packet_type;
+        self.buffer[2] = code;
+        self.set_server_time(value);
+        self.nbytes = JAM_HEADER_SIZE;
+    }
+    /// Turn this into a control packet with a payload of u32 words after the header
+    pub fn set_words(&mut self, packet_type: u8, words: &[u32]) -> Result<(), BoxError> {
+        if words.len() > MAX_WORDS {
+            bail!("{} words won't fit in a packet", words.len());
+        }
+        self.set_control(packet_type, 0, 0);
+        for (n, w) in words.iter().enumerate() {
+            let idx = JAM_HEADER_SIZE + n * 4;
+            NetworkEndian::write_u32(&mut self.buffer[idx..idx + 4], *w);
+        }
+        self.nbytes = JAM_HEADER_SIZE + words.len() * 4;
+        Ok(())
+    }
+    /// the u32 words after the header (see [`JamMessage::set_words`])
+    pub fn get_words(&self) -> Vec<u32> {
+        self.buffer[JAM_HEADER_SIZE..self.nbytes]
+            .chunks_exact(4)
+            .map(NetworkEndian::read_u32)
+            .collect()
+    }
     /// Not used
     pub fn get_sample_rate(&self) -> u8 {
         self.buffer[1]
@@ -239,6 +322,27 @@ mod test {
         assert_eq!(msg.get_beat(), 4);
     }
     #[test]
+    fn control_packets() {
+        let mut msg = JamMessage::new();
+        assert_eq!(msg.get_packet_type(), PACKET_AUDIO);
+        msg.set_control(PACKET_REJECT, 3, 77);
+        assert_eq!(msg.get_packet_type(), PACKET_REJECT);
+        assert_eq!(msg.get_num_audio_chunks(), 3);
+        assert_eq!(msg.get_server_time(), 77);
+        assert_eq!(msg.get_send_buffer().len(), JAM_HEADER_SIZE);
+        // no audio in it
+        assert_eq!(msg.decode_audio().0.len(), 0);
+    }
+    #[test]
+    fn words() {
+        let mut msg = JamMessage::new();
+        msg.set_words(PACKET_REPORT, &[1, 2, u32::MAX]).unwrap();
+        assert_eq!(msg.get_packet_type(), PACKET_REPORT);
+        assert_eq!(msg.get_send_buffer().len(), JAM_HEADER_SIZE + 12);
+        assert_eq!(msg.get_words(), vec![1, 2, u32::MAX]);
+        assert!(msg.set_words(PACKET_REPORT, &[0; MAX_WORDS + 1]).is_err());
+    }
+    #[test]
     fn client_id() {
         // You should get the client id from the packet
         let mut msg = JamMessage::new();
@@ -0,0 +1,41 @@
+//! A value kept in a json file between restarts
+//!
+//! The sound unit keeps its state ([`UnitStateFile`](crate::sound::unit_state::UnitStateFile)) and
+//! its mixer scenes ([`SceneFile`](crate::sound::mixer_scenes::SceneFile)) this way.  Loading never
+//! fails: a missing file is nothing saved and a damaged one is logged and ignored.
+use std::{fs, marker::PhantomData};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::common::box_error::BoxError;
+
+/// Where a value is kept
+pub struct JsonFile<T> {
+    path: String,
+    kind: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> JsonFile<T> {
+    pub fn new(path: &str) -> JsonFile<T> {
+        JsonFile { path: path.to_string(), kind: PhantomData }
+    }
+    /// None when nothing was saved (or it can't be read)
+    pub fn load(&self) -> Option<T> {
+        let raw = fs::read_to_string(&self.path).ok()?;
+        match serde_json::from_str(&raw) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("ignoring what was saved in {}: {}", self.path, e);
+                None
+            }
+        }
+    }
+    /// written to the side and renamed so a crash can't leave half a file
+    pub fn save(&self, value: &T) -> Result<(), BoxError> {
+        let tmp = format!("{}.tmp", self.path);
+        fs::write(&tmp, serde_json::to_string_pretty(value)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
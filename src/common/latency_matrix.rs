@@ -0,0 +1,216 @@
+//! Who hears whom how late
+//!
+//! The mouth-to-ear latency from a speaker A to a listener B is estimated as
+//! ```text
+//!   capture(A) + loop(A) / 2 + buffered + loop(B) / 2 + playback(B)
+//! ```
+//! - capture and playback are the audio device latencies each sound component reports
+//! - loop is the round trip between the broadcast server and the player (half of it each way)
+//! - buffered is how much of A's audio sits in B's jitter buffer.  In "mix" room mode it is A's
+//!   buffer on the server plus the mix's buffer on B.
+//!
+//! Sound components send a [`DeviceReport`] ([`PACKET_REPORT`](crate::common::jam_packet::PACKET_REPORT))
+//! about once a second.  The broadcast component keeps them in a [`RoomLatency`] and publishes the
+//! [`LatencyMatrix`] to the websocket room and in-band ([`PACKET_LATENCY`](crate::common::jam_packet::PACKET_LATENCY))
+//! to the players that report.  Older components would take the matrix for audio.
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+use simple_error::bail;
+
+use crate::common::box_error::BoxError;
+
+/// most players the in-band matrix has room for (1 + n + n * n words)
+pub const MAX_MATRIX_PLAYERS: usize = 15;
+/// reports older than this (usec) are ignored
+pub const REPORT_STALE: u128 = 5_000_000;
+/// latency that could not be estimated (on the wire)
+const UNKNOWN: u32 = u32::MAX;
+
+/// What a sound component knows about its end (all usec)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DeviceReport {
+    pub capture: u32,
+    pub playback: u32,
+    /// (client id, jitter buffer depth) for each peer being heard
+    pub depths: Vec<(u32, u32)>,
+}
+
+impl DeviceReport {
+    /// [capture, playback, id, depth, id, depth, ...]
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut words = vec![self.capture, self.playback];
+        for (id, depth) in &self.depths {
+            words.push(*id);
+            words.push(*depth);
+        }
+        words
+    }
+    pub fn from_words(words: &[u32]) -> Result<DeviceReport, BoxError> {
+        if words.len() < 2 || !words.len().is_multiple_of(2) {
+            bail!("bad device report: {} words", words.len());
+        }
+        Ok(DeviceReport {
+            capture: words[0],
+            playback: words[1],
+            depths: words[2..].chunks_exact(2).map(|p| (p[0], p[1])).collect(),
+        })
+    }
+    /// jitter buffer depth for a peer
+    pub fn depth(&self, id: u32) -> Option<u32> {
+        self.depths.iter().find(|(i, _)| *i == id).map(|(_, d)| *d)
+    }
+}
+
+/// Estimated latency (usec) from every player (rows) to every player (columns).  The diagonal
+/// is hearing yourself through local monitoring
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LatencyMatrix {
+    pub ids: Vec<u32>,
+    /// row major, speaker by listener
+    pub usec: Vec<Option<u32>>,
+}
+
+impl LatencyMatrix {
+    pub fn get(&self, speaker: u32, listener: u32) -> Option<u32> {
+        let row = self.ids.iter().position(|id| *id == speaker)?;
+        let col = self.ids.iter().position(|id| *id == listener)?;
+        self.usec[row * self.ids.len() + col]
+    }
+    /// [n, ids..., n * n latencies]
+    pub fn to_words(&self) -> Vec<u32> {
+        let mut words = vec![self.ids.len() as u32];
+        words.extend(&self.ids);
+        words.extend(self.usec.iter().map(|v| v.unwrap_or(UNKNOWN)));
+        words
+    }
+    pub fn from_words(words: &[u32]) -> Result<LatencyMatrix, BoxError> {
+        let n = words.first().copied().unwrap_or(0) as usize;
+        if n > MAX_MATRIX_PLAYERS || words.len() != 1 + n + n * n {
+            bail!("bad latency matrix: {} players in {} words", n, words.len());
+        }
+        Ok(LatencyMatrix {
+            ids: words[1..=n].to_vec(),
+            usec: words[1 + n..]
+                .iter()
+                .map(|v| if *v == UNKNOWN { None } else { Some(*v) })
+                .collect(),
+        })
+    }
+    /// {"ids": [...], "msec": [[...], ...]}.  null where there is no estimate
+    pub fn as_json(&self) -> Value {
+        let n = self.ids.len().max(1);
+        let rows: Vec<Vec<Option<f64>>> = self
+            .usec
+            .chunks(n)
+            .map(|row| row.iter().map(|v| v.map(|u| (u as f64 / 100.0).round() / 10.0)).collect())
+            .collect();
+        json!({ "ids": self.ids, "msec": rows })
+    }
+}
+
+/// In "mix" mode the server buffers each source and listeners buffer the mix
+pub struct MixDepths {
+    /// client id the mix goes out under
+    pub mix_id: u32,
+    /// source id -> depth (usec) on the server
+    pub sources: HashMap<u32, u32>,
+}
+
+/// The reports from the players in a room
+#[derive(Default)]
+pub struct RoomLatency {
+    reports: HashMap<u32, (u128, DeviceReport)>,
+}
+
+impl RoomLatency {
+    pub fn new() -> RoomLatency {
+        Self::default()
+    }
+    pub fn report(&mut self, client_id: u32, report: DeviceReport, now_time: u128) {
+        self.reports.insert(client_id, (now_time, report));
+    }
+    /// this player sends reports, so can take the matrix in-band
+    pub fn has_reported(&self, client_id: u32) -> bool {
+        self.reports.contains_key(&client_id)
+    }
+    /// forget players that stopped reporting
+    pub fn prune(&mut self, now_time: u128) {
+        self.reports.retain(|_, (time, _)| now_time < *time + REPORT_STALE);
+    }
+    /// estimate the matrix.  loops is (client id, loop time usec) for the players in the room
+    pub fn matrix(&self, loops: &[(u32, f64)], mix: Option<&MixDepths>) -> LatencyMatrix {
+        let players = &loops[..loops.len().min(MAX_MATRIX_PLAYERS)];
+        let mut usec = vec![];
+        for speaker in players {
+            for listener in players {
+                usec.push(self.estimate(*speaker, *listener, mix));
+            }
+        }
+        LatencyMatrix {
+            ids: players.iter().map(|(id, _)| *id).collect(),
+            usec,
+        }
+    }
+    fn estimate(&self, speaker: (u32, f64), listener: (u32, f64), mix: Option<&MixDepths>) -> Option<u32> {
+        let (_, from) = self.reports.get(&speaker.0)?;
+        let (_, to) = self.reports.get(&listener.0)?;
+        if speaker.0 == listener.0 {
+            return Some(from.capture + from.playback);
+        }
+        let buffered = match mix {
+            Some(m) => *m.sources.get(&speaker.0)? as f64 + to.depth(m.mix_id)? as f64,
+            None => to.depth(speaker.0)? as f64,
+        };
+        let total = from.capture as f64 + speaker.1 / 2.0 + buffered + listener.1 / 2.0 + to.playback as f64;
+        Some(total.round() as u32)
+    }
+}
+
+#[cfg(test)]
+mod test_latency_matrix {
+    use super::*;
+
+    fn room() -> RoomLatency {
+        let mut room = RoomLatency::new();
+        let a = DeviceReport { capture: 3000, playback: 5000, depths: vec![(2, 8000), (40002, 9000)] };
+        let b = DeviceReport { capture: 2000, playback: 6000, depths: vec![(1, 10_000), (40002, 11_000)] };
+        room.report(1, DeviceReport::from_words(&a.to_words()).unwrap(), 0);
+        room.report(2, b, 0);
+        room
+    }
+
+    #[test]
+    fn estimates() {
+        let room = room();
+        // 3 has not reported
+        let loops = [(1, 20_000.0), (2, 40_000.0), (3, 10_000.0)];
+        let m = room.matrix(&loops, None);
+        // 3000 + 10000 + 10000 + 20000 + 6000
+        assert_eq!(m.get(1, 2), Some(49_000));
+        // 2000 + 20000 + 8000 + 10000 + 5000
+        assert_eq!(m.get(2, 1), Some(45_000));
+        assert_eq!(m.get(1, 1), Some(8000));
+        assert_eq!(m.get(1, 3), None);
+        assert_eq!(m.as_json()["msec"][0][1], 49.0);
+        assert!(m.as_json()["msec"][2][0].is_null());
+        // round trip on the wire
+        assert_eq!(LatencyMatrix::from_words(&m.to_words()).unwrap(), m);
+        assert!(LatencyMatrix::from_words(&[2, 1]).is_err());
+        // the server buffers in mix mode
+        let mix = MixDepths { mix_id: 40002, sources: HashMap::from([(1, 4000), (2, 5000)]) };
+        let m = room.matrix(&loops, Some(&mix));
+        // 3000 + 10000 + 4000 + 11000 + 20000 + 6000
+        assert_eq!(m.get(1, 2), Some(54_000));
+    }
+
+    #[test]
+    fn stale_reports() {
+        let mut room = room();
+        room.report(1, DeviceReport::default(), REPORT_STALE);
+        room.prune(REPORT_STALE + 1);
+        assert!(room.has_reported(1));
+        assert!(!room.has_reported(2));
+        assert!(DeviceReport::from_words(&[1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Typed settings for a component, built up in layers (later ones win)
+//!
+//! 1. the defaults (the settings struct's `Default`)
+//! 2. the settings file (json)
+//! 3. environment variables.  The key upper cased behind the component's prefix, so `port` is
+//!    `RTJAM_PORT`.  Values are read as json except for string settings which are taken as is.
+//! 4. the command line
+//!
+//! Every value is checked against the type of its default as it goes on, so a mistake says which
+//! key, where it came from and what it should have been.  [`LayeredConfig::validate`] then checks
+//! the values make sense together.  Keys the file has that the component does not know about are
+//! logged and ignored.
+//!
+//! Some keys ([`LayeredConfig::HOT_KEYS`]) can change while the component runs.  A [`Reloader`]
+//! loads the settings again when the file changes (or on SIGHUP) and hands back the new values for
+//! the component to apply.  Changes to any other key wait for a restart.
+use std::{
+    env,
+    fs,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
+
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use simple_error::bail;
+
+use crate::common::{box_error::BoxError, config::check_filename, stream_time_stat::MicroTimer};
+
+/// how often (usec) a [`ConfigWatcher`] looks at the file
+pub const WATCH_INTERVAL: u128 = 1_000_000;
+
+/// A component's settings
+pub trait LayeredConfig: Serialize + DeserializeOwned + Default + Clone {
+    /// in front of the upper cased key for environment variables
+    const ENV_PREFIX: &'static str;
+    /// keys that take effect without a restart
+    const HOT_KEYS: &'static [&'static str];
+    /// check the values make sense.  Called after all the layers are on
+    fn validate(&self) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+/// load the settings for a component.  cli has the command line values (by key)
+pub fn load<T: LayeredConfig>(file: &str, cli: &Map<String, Value>) -> Result<T, BoxError> {
+    check_filename(file)?;
+    let defaults = match serde_json::to_value(T::default())? {
+        Value::Object(m) => m,
+        _ => bail!("settings have to be a json object"),
+    };
+    let mut merged = defaults.clone();
+    for (key, value) in read_file(file)? {
+        if !defaults.contains_key(&key) {
+            warn!("{}: unknown setting '{}' ignored", file, key);
+            continue;
+        }
+        layer(&mut merged, &key, value, file)?;
+    }
+    for (key, default) in &defaults {
+        let var = format!("{}{}", T::ENV_PREFIX, key.to_uppercase());
+        if let Ok(raw) = env::var(&var) {
+            let value = match default {
+                Value::String(_) => Value::String(raw),
+                _ => serde_json::from_str(&raw).unwrap_or(Value::String(raw)),
+            };
+            layer(&mut merged, key, value, &var)?;
+        }
+    }
+    for (key, value) in cli {
+        layer(&mut merged, key, value.clone(), "command line")?;
+    }
+    let config: T = serde_json::from_value(Value::Object(merged)).map_err(|e| format!("settings: {}", e))?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// the settings file as a json object.  No file is no settings
+fn read_file(file: &str) -> Result<Map<String, Value>, BoxError> {
+    let raw = match fs::read_to_string(file) {
+        Ok(raw) => raw,
+        Err(e) => {
+            info!("no settings from {} ({}), using defaults", file, e);
+            return Ok(Map::new());
+        }
+    };
+    match serde_json::from_str(&raw) {
+        Ok(Value::Object(m)) => Ok(m),
+        Ok(_) => bail!("{}: settings have to be a json object", file),
+        Err(e) => bail!("{}: {}", file, e),
+    }
+}
+
+/// put one value on, checking it is the same kind of thing as what it replaces
+fn layer(merged: &mut Map<String, Value>, key: &str, value: Value, source: &str) -> Result<(), BoxError> {
+    let current = match merged.get(key) {
+        Some(v) => v,
+        None => bail!("{}: unknown setting '{}'", source, key),
+    };
+    let fits = match current {
+        // no default (optional), anything goes
+        Value::Null => true,
+        Value::Bool(_) => value.is_boolean(),
+        Value::Number(n) if n.is_f64() => value.is_number(),
+        Value::Number(_) => value.is_u64(),
+        Value::String(_) => value.is_string(),
+        Value::Array(_) => value.is_array(),
+        Value::Object(_) => value.is_object(),
+    };
+    if !fits {
+        bail!("{}: setting '{}' should be {}, not {}", source, key, kind(current), value);
+    }
+    merged.insert(key.to_string(), value);
+    Ok(())
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "anything",
+        Value::Bool(_) => "true or false",
+        Value::Number(n) if n.is_f64() => "a number",
+        Value::Number(_) => "a whole number (0 or more)",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// keys whose values differ between two settings
+pub fn changed_keys<T: LayeredConfig>(old: &T, new: &T) -> Vec<String> {
+    match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(Value::Object(a)), Ok(Value::Object(b))) => {
+            a.iter().filter(|(k, v)| b.get(*k) != Some(*v)).map(|(k, _)| k.clone()).collect()
+        }
+        _ => vec![],
+    }
+}
+
+static HUNG_UP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_sig: libc::c_int) {
+    HUNG_UP.store(true, Ordering::SeqCst);
+}
+
+/// reload the settings on SIGHUP rather than dying
+pub fn catch_hangup() -> Result<(), BoxError> {
+    let handler = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        bail!("can't catch SIGHUP");
+    }
+    Ok(())
+}
+
+/// Notices the settings file changing
+pub struct ConfigWatcher {
+    file: String,
+    modified: Option<SystemTime>,
+    timer: MicroTimer,
+}
+
+impl ConfigWatcher {
+    pub fn new(file: &str, now_time: u128) -> ConfigWatcher {
+        ConfigWatcher {
+            file: file.to_string(),
+            modified: modified(Path::new(file)),
+            timer: MicroTimer::new(now_time, WATCH_INTERVAL),
+        }
+    }
+    /// true when the file changed (or a SIGHUP came in) since the last time it said so.  The file
+    /// is only looked at every [`WATCH_INTERVAL`]
+    pub fn poll(&mut self, now_time: u128) -> bool {
+        let hung_up = HUNG_UP.swap(false, Ordering::SeqCst);
+        if !hung_up && !self.timer.expired(now_time) {
+            return false;
+        }
+        self.timer.reset(now_time);
+        let modified = modified(Path::new(&self.file));
+        let changed = modified != self.modified;
+        self.modified = modified;
+        hung_up || changed
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Keeps the settings a component is running with up to date
+pub struct Reloader<T: LayeredConfig> {
+    file: String,
+    cli: Map<String, Value>,
+    watcher: ConfigWatcher,
+    current: T,
+}
+
+impl<T: LayeredConfig> Reloader<T> {
+    /// current is what was loaded at startup from file and cli
+    pub fn new(file: &str, cli: Map<String, Value>, current: T, now_time: u128) -> Reloader<T> {
+        Reloader {
+            file: file.to_string(),
+            cli,
+            watcher: ConfigWatcher::new(file, now_time),
+            current,
+        }
+    }
+    /// call this regularly.  When hot keys change it returns the new settings and which keys
+    /// changed.  Settings that don't load (or validate) are logged and the old ones kept.
+    pub fn poll(&mut self, now_time: u128) -> Option<(T, Vec<String>)> {
+        if !self.watcher.poll(now_time) {
+            return None;
+        }
+        let new: T = match load(&self.file, &self.cli) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("settings not reloaded: {}", e);
+                return None;
+            }
+        };
+        let (hot, cold): (Vec<String>, Vec<String>) =
+            changed_keys(&self.current, &new).into_iter().partition(|k| T::HOT_KEYS.contains(&k.as_str()));
+        for key in &cold {
+            warn!("setting '{}' changed, it takes a restart to use it", key);
+        }
+        if hot.is_empty() {
+            return None;
+        }
+        // only the hot keys take effect
+        let mut running = serde_json::to_value(&self.current).ok()?;
+        let reloaded = serde_json::to_value(&new).ok()?;
+        for key in &hot {
+            running[key] = reloaded[key].clone();
+        }
+        self.current = serde_json::from_value(running).ok()?;
+        info!("settings reloaded: {}", hot.join(", "));
+        Some((self.current.clone(), hot))
+    }
+}
+
+#[cfg(test)]
+mod test_layered_config {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct TestConfig {
+        name: String,
+        port: u32,
+        gain: f64,
+        mix: bool,
+        extra: Option<Value>,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            TestConfig { name: "room".to_string(), port: 7891, gain: 0.0, mix: false, extra: None }
+        }
+    }
+
+    impl LayeredConfig for TestConfig {
+        const ENV_PREFIX: &'static str = "RTJAM_LAYER_TEST_";
+        const HOT_KEYS: &'static [&'static str] = &["gain"];
+        fn validate(&self) -> Result<(), BoxError> {
+            if self.port == 0 {
+                bail!("port can't be 0");
+            }
+            Ok(())
+        }
+    }
+
+    fn temp_file(tag: &str, contents: &str) -> String {
+        let file = env::temp_dir().join(format!("layered_{}_{}.json", tag, std::process::id()));
+        fs::write(&file, contents).unwrap();
+        file.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn layers() {
+        let file = temp_file("layers", r#"{ "port": 9000, "name": "jam", "extra": [1, 2], "colour": "red" }"#);
+        env::set_var("RTJAM_LAYER_TEST_NAME", "1234");
+        env::set_var("RTJAM_LAYER_TEST_GAIN", "-6");
+        let cli = Map::from_iter([("mix".to_string(), json!(true))]);
+        let config: TestConfig = load(&file, &cli).unwrap();
+        assert_eq!(config.port, 9000);
+        // string settings from the environment stay strings
+        assert_eq!(config.name, "1234");
+        assert_eq!(config.gain, -6.0);
+        assert!(config.mix);
+        assert_eq!(config.extra, Some(json!([1, 2])));
+        env::remove_var("RTJAM_LAYER_TEST_NAME");
+        env::remove_var("RTJAM_LAYER_TEST_GAIN");
+        // the errors say where the problem is
+        let err = load::<TestConfig>(&file, &Map::from_iter([("port".to_string(), json!("loud"))])).unwrap_err();
+        assert_eq!(err.to_string(), r#"command line: setting 'port' should be a whole number (0 or more), not "loud""#);
+        fs::write(&file, r#"{ "port": 0 }"#).unwrap();
+        assert_eq!(load::<TestConfig>(&file, &Map::new()).unwrap_err().to_string(), "port can't be 0");
+        fs::write(&file, r#"{ "port": 10, }"#).unwrap();
+        assert!(load::<TestConfig>(&file, &Map::new()).unwrap_err().to_string().starts_with(&file));
+        fs::remove_file(&file).unwrap();
+        // no file is just the defaults
+        assert_eq!(load::<TestConfig>("no_such_settings.json", &Map::new()).unwrap(), TestConfig::default());
+        assert!(load::<TestConfig>("Illegal*File$Name", &Map::new()).is_err());
+    }
+
+    #[test]
+    fn reloads_hot_keys() {
+        let file = temp_file("reload", r#"{ "port": 9000 }"#);
+        let current: TestConfig = load(&file, &Map::new()).unwrap();
+        let mut reloader = Reloader::new(&file, Map::new(), current, 0);
+        assert!(reloader.poll(WATCH_INTERVAL).is_none());
+        // a cold key change is not applied
+        fs::write(&file, r#"{ "port": 9001, "gain": -3.5 }"#).unwrap();
+        // what SIGHUP does, without sending a signal to every test running
+        on_hangup(libc::SIGHUP);
+        let (config, keys) = reloader.poll(WATCH_INTERVAL + 1).unwrap();
+        assert_eq!(keys, vec!["gain".to_string()]);
+        assert_eq!(config.gain, -3.5);
+        assert_eq!(config.port, 9000);
+        // bad settings leave things as they were
+        fs::write(&file, r#"{ "gain": "loud" }"#).unwrap();
+        on_hangup(libc::SIGHUP);
+        assert!(reloader.poll(WATCH_INTERVAL + 2).is_none());
+        fs::remove_file(&file).unwrap();
+        let before = TestConfig { port: 9000, ..Default::default() };
+        assert_eq!(changed_keys(&before, &config), vec!["gain".to_string()]);
+    }
+}
@@ -0,0 +1,393 @@
+//! Websocket server for u/x clients on the same network as the component
+//!
+//! The rtjam-nation chat room is a "meet me in the middle" relay.  When the browser is on the
+//! same LAN as the component that round trip through the nation is wasted.  This thread listens on
+//! a local TCP port and speaks the same json dialect as the chat room.  Messages from local clients
+//! are forwarded to the main thread exactly as if they came from the room, and chat messages from
+//! the main thread are written to every connected local client.
+//!
+//! A plain http request (no websocket upgrade) is answered with a small static mixing page so
+//! a browser pointed at the unit gets something useful without the nation.
+//!
+//! Each connection gets a number and every message it sends is tagged with it as `wsClient`.  A chat
+//! message carrying a `wsClient` tag (a reply or a subscription update) is only written to that
+//! connection.  When a connection goes away `{"wsClient": N, "wsClosed": true}` is sent so anything
+//! kept for it can be dropped.
+use log::{debug, error, info, trace, warn};
+use serde_json::{json, Value};
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc,
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+use tungstenite::{Message, WebSocket};
+
+use crate::common::{box_error::BoxError, websock_message::WebsockMessage};
+
+/// static page served to browsers that hit the port without a websocket upgrade
+const LOCAL_PAGE: &str = include_str!("local_ux.html");
+/// how long a new connection gets to send its request before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// a connection that has not sent its whole request yet
+struct Pending {
+    stream: TcpStream,
+    addr: SocketAddr,
+    since: Instant,
+}
+
+/// the local connection a message came from (or is for).  0 when it has no tag
+pub fn client_tag(msg: &Value) -> u32 {
+    msg["wsClient"].as_u64().and_then(|c| u32::try_from(c).ok()).unwrap_or(0)
+}
+
+/// Sends u/x messages to the nation chat room and any local listeners (websocket clients, OSC, etc).
+///
+/// Only Chat messages go to local listeners.  API messages are meant for the nation.  Binary messages
+/// and chat addressed to a local connection (tagged `wsClient`) only go to the local listeners.
+#[derive(Clone)]
+pub struct UxSender {
+    room_tx: mpsc::Sender<WebsockMessage>,
+    listeners: Vec<mpsc::Sender<WebsockMessage>>,
+}
+
+impl UxSender {
+    pub fn new(
+        room_tx: mpsc::Sender<WebsockMessage>,
+        local_tx: Option<mpsc::Sender<WebsockMessage>>,
+    ) -> UxSender {
+        UxSender {
+            room_tx,
+            listeners: local_tx.into_iter().collect(),
+        }
+    }
+    /// add another local listener for chat messages
+    pub fn add_listener(&mut self, tx: mpsc::Sender<WebsockMessage>) {
+        self.listeners.push(tx);
+    }
+    /// send a message to the room and a copy of any chat to the local listeners
+    pub fn send(&self, msg: WebsockMessage) -> Result<(), BoxError> {
+        match &msg {
+            WebsockMessage::Chat(v) => {
+                for tx in &self.listeners {
+                    // local listeners are best effort.  Don't let them take down the room traffic
+                    let _res = tx.send(WebsockMessage::Chat(v.clone()));
+                }
+                if !v["wsClient"].is_null() {
+                    return Ok(());
+                }
+            }
+            WebsockMessage::Binary(client, data) => {
+                for tx in &self.listeners {
+                    let _res = tx.send(WebsockMessage::Binary(*client, data.clone()));
+                }
+                return Ok(());
+            }
+            WebsockMessage::API(_, _) => {}
+        }
+        self.room_tx.send(msg)?;
+        Ok(())
+    }
+}
+
+/// Open the listening socket for the local websocket server.
+///
+/// This is split from the thread so the caller finds out right away if the port is taken.
+pub fn local_websocket_listener(port: u32) -> Result<TcpListener, BoxError> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    listener.set_nonblocking(true)?;
+    info!("local websocket listening on port {}", port);
+    Ok(listener)
+}
+
+/// start a thread with this function.  Pass it the listener from [`local_websocket_listener`] and
+/// two channels.  The first forwards json messages from local clients to the thread that called us.
+/// The second is read for messages that get written to every local client.
+///
+/// The function loops forever.
+pub fn local_websocket_thread(
+    listener: TcpListener,                 // socket from local_websocket_listener
+    ws_tx: mpsc::Sender<Value>,            // channel to main thread
+    ws_rx: mpsc::Receiver<WebsockMessage>, // channel from main thread
+) -> Result<(), BoxError> {
+    let mut clients: Vec<(u32, WebSocket<TcpStream>)> = vec![];
+    let mut pending: Vec<Pending> = vec![];
+    let mut next_id: u32 = 1;
+    loop {
+        // Pick up any new connections
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    debug!("local connection from {}", addr);
+                    pending.push(Pending { stream, addr, since: Instant::now() });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("local websocket accept error: {}", e);
+                    break;
+                }
+            }
+        }
+        // Connections that have sent their whole request get answered.  A slow one just waits for
+        // the next pass so it can't hold up everybody else
+        let mut waiting = vec![];
+        for conn in pending.drain(..) {
+            match request_complete(&conn.stream) {
+                Some(true) => {
+                    if let Some(client) = accept_connection(conn.stream) {
+                        info!("local websocket client {} connected: {}", next_id, conn.addr);
+                        clients.push((next_id, client));
+                        next_id = next_id.wrapping_add(1).max(1);
+                    }
+                }
+                Some(false) if conn.since.elapsed() < REQUEST_TIMEOUT => waiting.push(conn),
+                _ => debug!("dropping local connection from {}", conn.addr),
+            }
+        }
+        pending = waiting;
+        // Read whatever the clients sent us
+        clients.retain_mut(|(id, client)| {
+            let open = read_client(*id, client, &ws_tx);
+            if !open {
+                let _res = ws_tx.send(json!({ "wsClient": id, "wsClosed": true }));
+            }
+            open
+        });
+
+        // Relay chat and binary messages from the main thread to everyone (or the one it is for)
+        for msg in ws_rx.try_iter() {
+            let (to, frame) = match msg {
+                WebsockMessage::Chat(v) => (client_tag(&v), Message::Text(v.to_string())),
+                WebsockMessage::Binary(to, data) => (to, Message::Binary(data)),
+                WebsockMessage::API(_, _) => continue,
+            };
+            clients.retain_mut(|(id, client)| {
+                let open = (to != 0 && to != *id) || write_client(client, frame.clone());
+                if !open {
+                    let _res = ws_tx.send(json!({ "wsClient": id, "wsClosed": true }));
+                }
+                open
+            });
+        }
+        for (_id, client) in &mut clients {
+            // flush anything the socket would not take on the last write
+            let _res = client.write_pending();
+        }
+        sleep(Duration::new(0, 2_000_000));
+    }
+}
+
+/// Convenience to open the port and spawn the server thread.
+///
+/// Messages from local clients are sent on ws_tx.  Returns the channel used to send to the local
+/// clients or None if the port is 0 or can't be opened.  A failure here is not fatal since the u/x
+/// can still get to us through rtjam-nation.
+pub fn start_local_websocket(
+    port: u32,
+    ws_tx: mpsc::Sender<Value>,
+) -> Option<mpsc::Sender<WebsockMessage>> {
+    if port == 0 {
+        info!("local websocket disabled");
+        return None;
+    }
+    let listener = match local_websocket_listener(port) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("local websocket can't listen on port {}: {}", port, e);
+            return None;
+        }
+    };
+    let (to_local_tx, to_local_rx) = mpsc::channel();
+    let _local_handle = thread::spawn(move || {
+        if let Err(e) = local_websocket_thread(listener, ws_tx, to_local_rx) {
+            error!("local websocket thread exited with error: {}", e);
+        }
+    });
+    Some(to_local_tx)
+}
+
+/// Some(true) when the request headers are all in, Some(false) while waiting, None if the
+/// connection is gone.  Never blocks.
+fn request_complete(stream: &TcpStream) -> Option<bool> {
+    let _res = stream.set_nonblocking(true);
+    let mut peek_buf = [0; 2048];
+    match stream.peek(&mut peek_buf) {
+        Ok(0) => None,
+        // a request too big for the buffer is as complete as we are going to look at
+        Ok(n) => Some(n == peek_buf.len() || peek_buf[..n].windows(4).any(|w| w == b"\r\n\r\n")),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Some(false),
+        Err(e) => {
+            debug!("local connection peek error: {}", e);
+            None
+        }
+    }
+}
+
+/// Decide if this is a websocket upgrade or a browser looking for the page.  Only called once the
+/// whole request is waiting so the reads here don't stall the thread.
+fn accept_connection(stream: TcpStream) -> Option<WebSocket<TcpStream>> {
+    let _res = stream.set_nonblocking(false);
+    let _res = stream.set_read_timeout(Some(Duration::from_millis(10)));
+    let _res = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let mut peek_buf = [0; 2048];
+    let n = match stream.peek(&mut peek_buf) {
+        Ok(n) => n,
+        Err(e) => {
+            debug!("local connection peek error: {}", e);
+            return None;
+        }
+    };
+    let request = String::from_utf8_lossy(&peek_buf[..n]).to_lowercase();
+    if !request.contains("upgrade: websocket") {
+        serve_page(stream);
+        return None;
+    }
+    match tungstenite::accept(stream) {
+        Ok(client) => {
+            if let Err(e) = client.get_ref().set_nonblocking(true) {
+                warn!("local websocket nonblocking error: {}", e);
+                return None;
+            }
+            Some(client)
+        }
+        Err(e) => {
+            warn!("local websocket handshake failed: {}", e);
+            None
+        }
+    }
+}
+
+/// answer a plain http request with the static mixing page
+fn serve_page(mut stream: TcpStream) {
+    let mut buf = [0; 2048];
+    // consume the request.  we serve the same page no matter what was asked
+    let _res = stream.read(&mut buf);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        LOCAL_PAGE.len(),
+        LOCAL_PAGE
+    );
+    let _res = stream.write_all(response.as_bytes());
+}
+
+/// read all pending messages from a client and tag them with its id.  returns false if the
+/// client should be dropped
+fn read_client(id: u32, client: &mut WebSocket<TcpStream>, ws_tx: &mpsc::Sender<Value>) -> bool {
+    loop {
+        match client.read_message() {
+            Ok(Message::Text(data)) => match serde_json::from_str::<Value>(&data) {
+                Ok(mut msg) => {
+                    if msg.is_object() {
+                        msg["wsClient"] = json!(id);
+                    }
+                    let _res = ws_tx.send(msg);
+                }
+                Err(e) => {
+                    debug!("bad json from local client: {} {}", data, e);
+                }
+            },
+            Ok(Message::Close(_)) => {
+                info!("local websocket client closed");
+                return false;
+            }
+            Ok(_) => {
+                // ping/pong/binary are not part of the protocol
+            }
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                return true;
+            }
+            Err(e) => {
+                debug!("local websocket read error: {}", e);
+                return false;
+            }
+        }
+    }
+}
+
+/// write a message to a client.  returns false if the client should be dropped
+fn write_client(client: &mut WebSocket<TcpStream>, frame: Message) -> bool {
+    match client.write_message(frame) {
+        Ok(()) => true,
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+            // tungstenite keeps the frame and it gets flushed by write_pending
+            trace!("local websocket client backed up");
+            true
+        }
+        Err(e) => {
+            debug!("local websocket write error: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_local_websocket {
+    use super::*;
+
+    #[test]
+    fn ux_sender_copies_chat() {
+        let (room_tx, room_rx) = mpsc::channel();
+        let (local_tx, local_rx) = mpsc::channel();
+        let sender = UxSender::new(room_tx, Some(local_tx));
+        sender.send(WebsockMessage::Chat(json!({"speaker": "UnitChatRobot"}))).unwrap();
+        sender.send(WebsockMessage::API("saveStats".to_string(), json!({}))).unwrap();
+        sender.send(WebsockMessage::Binary(0, vec![1, 2, 3])).unwrap();
+        sender.send(WebsockMessage::Chat(json!({"speaker": "UnitChatRobot", "wsClient": 1}))).unwrap();
+        // room gets chat and api, local gets chat, binary and the one addressed to it
+        assert_eq!(room_rx.try_iter().count(), 2);
+        assert_eq!(local_rx.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn slow_connection_does_not_stall() {
+        let port = 18933;
+        let listener = local_websocket_listener(port).unwrap();
+        let (from_local_tx, from_local_rx) = mpsc::channel();
+        let (_to_local_tx, to_local_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _res = local_websocket_thread(listener, from_local_tx, to_local_rx);
+        });
+        // connects and never says anything
+        let _idle = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let (mut client, _resp) =
+            tungstenite::connect(format!("ws://127.0.0.1:{}/", port)).unwrap();
+        client.write_message(Message::Text(json!({"param": 14}).to_string())).unwrap();
+        assert!(from_local_rx.recv_timeout(Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn relays_between_client_and_main() {
+        let port = 18931;
+        let listener = local_websocket_listener(port).unwrap();
+        let (from_local_tx, from_local_rx) = mpsc::channel();
+        let (to_local_tx, to_local_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _res = local_websocket_thread(listener, from_local_tx, to_local_rx);
+        });
+        let (mut client, _resp) =
+            tungstenite::connect(format!("ws://127.0.0.1:{}/", port)).unwrap();
+        // client to main thread
+        client
+            .write_message(Message::Text(json!({"param": 14, "fValue": -3.0}).to_string()))
+            .unwrap();
+        let msg = from_local_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg["param"], 14);
+        assert_eq!(msg["wsClient"], 1);
+        // main thread to client.  Messages for some other connection are not written
+        thread::sleep(Duration::from_millis(50));
+        to_local_tx.send(WebsockMessage::Chat(json!({"speaker": "someone", "wsClient": 2}))).unwrap();
+        to_local_tx.send(WebsockMessage::Chat(json!({"speaker": "UnitChatRobot"}))).unwrap();
+        match client.read_message().unwrap() {
+            Message::Text(t) => assert!(t.contains("UnitChatRobot")),
+            other => panic!("unexpected message {:?}", other),
+        }
+        // closing is passed along
+        client.close(None).unwrap();
+        let _res = client.write_pending();
+        let msg = from_local_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(msg, json!({"wsClient": 1, "wsClosed": true}));
+    }
+}
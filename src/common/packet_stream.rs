@@ -32,6 +32,12 @@ impl PacketWriter {
         }
         Ok(())
     }
+    /// make sure everything written is on the disk
+    pub fn finish(&mut self) -> Result<(), BoxError> {
+        self.file.flush()?;
+        self.file.sync_data()?;
+        Ok(())
+    }
     pub fn get_status(&self) -> Value {
         let mut state = "idle";
         if self.is_writing {
@@ -7,7 +7,11 @@ use serde::Serialize;
 use std::fmt;
 use std::net::SocketAddr;
 
-use super::stream_time_stat::StreamTimeStat;
+use super::{
+    seq_tracker::{SeqStats, SeqTracker},
+    session_report::{Percentiles, SessionReport, REPORT_QUANTILES},
+    stream_time_stat::StreamTimeStat,
+};
 
 // This is how long a player lasts until we boot them (if they go silent)
 pub const EXPIRATION_IN_MICROSECONDS: u128 = 1_000_000;
@@ -21,17 +25,18 @@ const HISTOGRAM_BUCKETS: usize = 15;
 /// Structure that represents a person in a room.  Used by both sound and broadcast components
 ///
 /// It has an ID, (assigned by rtjam-nation when they join a room)
-/// It also has a SocketAddr used by the broadcast server to do the multicast
+/// It also has a SocketAddr used by the broadcast server to do the multicast, and the session nonce
+/// the player proved it owns the id with (see [`crate::server::player_list`])
 /// The keep_alive is used to time them out if we have not heard from them for over a second (no packets)
-/// It has the sequence number assigned by the packet originator and uses that to track dropped packets
+/// It tracks the sequence numbers assigned by the packet originator to count lost, reordered, late and
+/// duplicated packets (see [`SeqTracker`])
 /// lastly it has some stat objects to characterize the packet stream (histogram, loop stats and packet arrival stats)
 #[derive(Serialize)]
 pub struct Player {
     pub address: SocketAddr,          // key used by broadcast
     pub client_id: u32,               // key used by sound
     keep_alive: u128,                 // last time we saw this player
-    seq: u32,                         // packet sequence number
-    drops: usize,                     // dropped packet counter
+    seq: SeqTracker,                  // packet sequence accounting
     hist: [usize; HISTOGRAM_BUCKETS], // histogram of packet arrivals
     #[serde(skip)]
     loop_stat: SmoothingFilter,       // statistics about packet loop time
@@ -39,42 +44,71 @@ pub struct Player {
     pack_stats: StreamTimeStat,       // interarrival stats
     packet_count: usize,              // count number of packets
     latency_hist: Vec<f64>,           // latency values per minute
+    #[serde(skip)]
+    pub session: Option<u64>,         // nonce from the player's JOIN
+    start_time: u128,                 // when we first heard from them
+    longest_gap: u128,                // longest time between packets
+    #[serde(skip)]
+    loop_times: StreamTimeStat,       // loop time percentiles
 }
 
 const PACKETS_PER_SIX_SECS: usize = 6 * 48_000 / 128; // 128 samples per packet
 
+fn percentiles(stat: &StreamTimeStat) -> Percentiles {
+    Percentiles {
+        p50: stat.get_quantile(0.5).unwrap_or(0.0),
+        p95: stat.get_quantile(0.95).unwrap_or(0.0),
+        p99: stat.get_quantile(0.99).unwrap_or(0.0),
+    }
+}
+
 impl Player {
     pub fn new(now_time: u128, id: u32, addr: SocketAddr) -> Player {
         debug!("New player: {} {}", id, addr);
         Player {
             client_id: id,
             keep_alive: now_time,
-            seq: 0,
-            drops: 0,
+            seq: SeqTracker::new(),
             hist: [0; HISTOGRAM_BUCKETS],
             address: addr,
             loop_stat: SmoothingFilter::build(0.5, 2666.6),
-            pack_stats: StreamTimeStat::new(100),
+            pack_stats: StreamTimeStat::new(100).with_quantiles(&REPORT_QUANTILES),
             packet_count: 0,
             latency_hist: Vec::new(),
+            session: None,
+            start_time: now_time,
+            longest_gap: 0,
+            loop_times: StreamTimeStat::new(100).with_quantiles(&REPORT_QUANTILES),
         }
     }
+    /// packets that never showed up
     pub fn get_drops(&self) -> usize {
-        self.drops
+        self.seq.stats().lost as usize
+    }
+    pub fn get_seq_stats(&self) -> &SeqStats {
+        self.seq.stats()
     }
     pub fn get_last_loop(&self) -> f64 {
         self.loop_stat.get_last_output()
     }
+    pub fn get_packet_count(&self) -> usize {
+        self.packet_count
+    }
+    /// recent loop time percentiles (usec)
+    pub fn get_loop_percentiles(&self) -> Percentiles {
+        percentiles(&self.loop_times)
+    }
     pub fn clear(&mut self) -> () {
         debug!("Clearing player: {}", self.client_id);
         self.hist = [0; HISTOGRAM_BUCKETS];
         self.client_id = EMPTY_SLOT;
         self.keep_alive = 0;
-        self.seq = 0;
-        self.drops = 0;
+        self.seq = SeqTracker::new();
         self.packet_count = 0;
         self.latency_hist.clear();
         self.pack_stats.clear();
+        self.loop_times.clear();
+        self.longest_gap = 0;
     }
     pub fn update(&mut self, now: u128, id: u32, loop_time: u128, seq: u32) -> () {
         self.packet_count += 1;
@@ -88,21 +122,46 @@ impl Player {
         }
         if self.keep_alive <= now {
             self.pack_stats.add_sample((now - self.keep_alive) as f64);
+            self.longest_gap = self.longest_gap.max(now - self.keep_alive);
             let idx: usize = ((1333 + now - self.keep_alive) / 2667) as usize; // 2667 microsec per 128 sample frame
             self.hist[idx.clamp(0, HISTOGRAM_BUCKETS - 1)] += 1;
         }
         if loop_time < MAX_LOOP_TIME {
             // Only count loop times less than 100msec
             self.loop_stat.get(loop_time as f64);
+            self.loop_times.add_sample(loop_time as f64);
         }
         self.keep_alive = now;
         self.client_id = id;
         // Check sequence number
-        if self.seq + 1 != seq {
-            // We have a dropped packet
-            self.drops += 1;
+        self.seq.update(seq, now);
+    }
+    /// how the session went.  Call when they leave
+    pub fn report(&self) -> SessionReport {
+        let seq = self.seq.stats();
+        SessionReport {
+            client_id: self.client_id,
+            address: self.address.to_string(),
+            start: self.start_time as u64,
+            end: self.keep_alive as u64,
+            duration: self.keep_alive.saturating_sub(self.start_time) as f64 / 1_000_000.0,
+            packets: self.packet_count,
+            lost: seq.lost as usize,
+            reordered: seq.reordered as usize,
+            late: seq.late as usize,
+            duplicates: seq.duplicated as usize,
+            loss_rate: SessionReport::rate(seq.lost as usize, seq.expected as usize),
+            late_rate: SessionReport::rate(seq.late as usize, self.packet_count),
+            duplicate_rate: SessionReport::rate(seq.duplicated as usize, self.packet_count),
+            transit_jitter: seq.jitter,
+            interarrival: percentiles(&self.pack_stats),
+            loop_time: percentiles(&self.loop_times),
+            longest_gap: self.longest_gap as u64,
         }
-        self.seq = seq;
+    }
+    /// last time we got a packet from this player
+    pub fn get_keep_alive(&self) -> u128 {
+        self.keep_alive
     }
     pub fn is_old(&self, now: u128) -> bool {
         self.keep_alive + EXPIRATION_IN_MICROSECONDS < now
@@ -133,4 +192,38 @@ mod test_player {
         println!("player: {}", serde_json::to_string(&player).unwrap());
         assert_eq!(player.address, socket);
     }
+    #[test]
+    fn session_report() {
+        let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut player = Player::new(0, 44, socket);
+        let mut now = 0;
+        for seq in 1..=1000 {
+            now += if seq == 500 { 50_000 } else { 2667 };
+            player.update(now, 44, 10_000 + (seq as u128 % 10) * 1000, seq);
+        }
+        // a duplicate, a reordered and a late one
+        player.update(now + 10, 44, 10_000, 1000);
+        player.update(now + 15, 44, 10_000, 1002);
+        player.update(now + 20, 44, 10_000, 1001);
+        player.update(now + 20, 44, 10_000, 980);
+        let report = player.report();
+        assert_eq!(report.packets, 1004);
+        assert_eq!(report.duplicates, 2);
+        assert_eq!(report.reordered, 1);
+        assert_eq!(report.late, 0);
+        assert_eq!(report.lost, 0);
+        player.update(now + 20, 44, 10_000, 1020);
+        player.update(now + 20, 44, 10_000, 1005);
+        assert_eq!(player.report().late, 1);
+        assert_eq!(player.get_drops(), 16);
+        assert_eq!(report.longest_gap, 50_000);
+        assert!((report.interarrival.p50 - 2667.0).abs() < 1.0);
+        assert!(report.loop_time.p99 > 18_000.0);
+        assert!((report.duration - (now + 20) as f64 / 1_000_000.0).abs() < 0.0001);
+        // a restarted stream is not a pile of late packets
+        player.update(now + 30, 44, 10_000, 0);
+        player.update(now + 40, 44, 10_000, 1);
+        assert_eq!(player.report().late, 1);
+        assert_eq!(player.get_seq_stats().restarts, 1);
+    }
 }
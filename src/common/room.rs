@@ -130,6 +130,10 @@ impl Room {
                 jmsg["params"] = params.clone();
                 jmsg["params"]["action"] = action.as_str().into();
             }
+            WebsockMessage::Binary(..) => {
+                // The chat room only does text
+                return;
+            }
         }
         self.msg_id += 1;
         // println!("sending this message: {}", jmsg.to_string());
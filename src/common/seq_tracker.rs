@@ -0,0 +1,196 @@
+//! Packet sequence accounting (RFC 3550 style)
+//!
+//! Every packet from a sound component carries a u32 sequence number that goes up by one per
+//! frame.  The [`SeqTracker`] extends it past wraparound and keeps:
+//! - lost: packets expected (highest - first + 1) that never showed up
+//! - reordered: packets that came after a later one but within [`LATE_AGE`] packets of it
+//! - late: packets that came more than [`LATE_AGE`] packets behind (too late to play, most likely)
+//! - duplicated: packets we already had
+//! - jitter: the RFC 3550 interarrival jitter estimate (usec).  The "media timestamp" is the
+//!   sequence number times the frame time since a packet goes out every frame.
+//!
+//! A big jump (more than [`MAX_DROPOUT`] ahead or [`MAX_MISORDER`] behind) is taken as the
+//! sender restarting its sequence (a reconnect resets it to 0) once two packets in a row agree.
+//! The counts carry over, nothing is counted lost for the jump.
+use serde::Serialize;
+
+/// usec per 128 sample frame at 48k
+const FRAME_TIME: f64 = 128.0 * 1_000_000.0 / 48_000.0;
+/// packets ahead of the highest that are still the same stream
+pub const MAX_DROPOUT: i64 = 3000;
+/// packets behind the highest that are still the same stream
+pub const MAX_MISORDER: i64 = 100;
+/// out of order packets further behind than this are counted late rather than reordered
+pub const LATE_AGE: i64 = 8;
+
+/// The counters
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeqStats {
+    pub received: u64,
+    pub expected: u64,
+    pub lost: u64,
+    pub reordered: u64,
+    pub late: u64,
+    pub duplicated: u64,
+    pub restarts: u64,
+    pub jitter: f64,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SeqTracker {
+    #[serde(flatten)]
+    stats: SeqStats,
+    #[serde(skip)]
+    started: bool,
+    #[serde(skip)]
+    base: i64,
+    #[serde(skip)]
+    highest: i64,
+    // bit n set means we have highest - n
+    #[serde(skip)]
+    seen: u128,
+    // the sequence that would confirm a restart
+    #[serde(skip)]
+    bad_seq: Option<u32>,
+    // unique packets and expected count from before the last restart
+    #[serde(skip)]
+    prior_received: u64,
+    #[serde(skip)]
+    prior_expected: u64,
+    #[serde(skip)]
+    unique: u64,
+    #[serde(skip)]
+    transit: Option<f64>,
+}
+
+impl SeqTracker {
+    pub fn new() -> SeqTracker {
+        Self::default()
+    }
+    pub fn stats(&self) -> &SeqStats {
+        &self.stats
+    }
+    /// the last sequence number in order
+    pub fn last_seq(&self) -> u32 {
+        self.highest as u32
+    }
+    /// a packet came in.  now is the arrival time (usec)
+    pub fn update(&mut self, seq: u32, now: u128) {
+        self.stats.received += 1;
+        if !self.started {
+            self.begin(seq);
+            self.started = true;
+        }
+        // signed distance from the highest, good across the wrap
+        let mut delta = seq.wrapping_sub(self.highest as u32) as i32 as i64;
+        if !(-MAX_MISORDER..=MAX_DROPOUT).contains(&delta) {
+            if self.bad_seq != Some(seq) {
+                // wait for the next one to see if the sender started over
+                self.bad_seq = Some(seq.wrapping_add(1));
+                return;
+            }
+            self.begin(seq);
+            self.stats.restarts += 1;
+            delta = 1;
+        }
+        self.bad_seq = None;
+        let ext = self.highest + delta;
+        if delta > 0 {
+            self.seen = if delta >= 128 { 0 } else { self.seen << delta };
+            self.seen |= 1;
+            self.highest = ext;
+        } else {
+            let age = -delta;
+            if age >= 128 || self.seen & (1 << age) != 0 {
+                self.stats.duplicated += 1;
+                return;
+            }
+            self.seen |= 1 << age;
+            if age > LATE_AGE {
+                self.stats.late += 1;
+            } else {
+                self.stats.reordered += 1;
+            }
+        }
+        self.unique += 1;
+        self.count(now, ext);
+    }
+    /// start a new stream at seq.  The counts from the old one carry over
+    fn begin(&mut self, seq: u32) {
+        if self.started {
+            self.prior_received += self.unique;
+            self.prior_expected += (self.highest - self.base + 1).max(0) as u64;
+        }
+        self.unique = 0;
+        // extended numbers for this stream count from seq
+        self.base = seq as i64;
+        self.highest = self.base - 1;
+        self.seen = 0;
+        self.bad_seq = None;
+        self.transit = None;
+    }
+    fn count(&mut self, now: u128, ext: i64) {
+        // RFC 3550 A.8
+        let transit = now as f64 - ext as f64 * FRAME_TIME;
+        if let Some(last) = self.transit {
+            let d = (transit - last).abs();
+            self.stats.jitter += (d - self.stats.jitter) / 16.0;
+        }
+        self.transit = Some(transit);
+        self.stats.expected = self.prior_expected + (self.highest - self.base + 1) as u64;
+        let unique = self.prior_received + self.unique;
+        self.stats.lost = self.stats.expected.saturating_sub(unique);
+    }
+}
+
+#[cfg(test)]
+mod test_seq_tracker {
+    use super::*;
+
+    fn feed(tracker: &mut SeqTracker, seqs: &[u32]) {
+        for seq in seqs {
+            tracker.update(*seq, *seq as u128 * 2667);
+        }
+    }
+
+    #[test]
+    fn loss_and_reorder() {
+        let mut t = SeqTracker::new();
+        feed(&mut t, &[1, 2, 3, 5, 4, 6, 9, 10]);
+        let s = t.stats();
+        // 4 came late but close.  7 and 8 never came
+        assert_eq!(s.reordered, 1);
+        assert_eq!(s.lost, 2);
+        assert_eq!(s.expected, 10);
+        // one that is way late, and a duplicate
+        feed(&mut t, &[11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 7, 20]);
+        let s = t.stats();
+        assert_eq!(s.late, 1);
+        assert_eq!(s.duplicated, 1);
+        assert_eq!(s.lost, 1);
+        assert_eq!(s.received, 20);
+        assert!(s.jitter < 5.0);
+    }
+
+    #[test]
+    fn wraps_and_restarts() {
+        let mut t = SeqTracker::new();
+        feed(&mut t, &[u32::MAX - 1, u32::MAX, 0, 1]);
+        assert_eq!(t.stats().lost, 0);
+        assert_eq!(t.stats().expected, 4);
+        // reconnect starts the sequence over.  one odd packet is ignored, two is a restart
+        feed(&mut t, &[50_000]);
+        assert_eq!(t.stats().restarts, 0);
+        feed(&mut t, &[2, 3, 4]);
+        feed(&mut t, &[0]);
+        assert_eq!(t.stats().restarts, 0);
+        assert_eq!(t.stats().duplicated, 1);
+        feed(&mut t, &[1_000_000, 1_000_001, 1_000_002]);
+        let s = t.stats();
+        assert_eq!(s.restarts, 1);
+        assert_eq!(s.lost, 0);
+        assert_eq!(s.expected, 9);
+        assert_eq!(t.last_seq(), 1_000_002);
+    }
+}
@@ -0,0 +1,202 @@
+//! Summary of one player's session in a room
+//!
+//! When a player leaves (times out of the [`PlayerList`](crate::server::player_list::PlayerList))
+//! the broadcast component makes a [`SessionReport`] from their [`Player`](crate::common::player::Player)
+//! stats.  It goes to rtjam-nation with `packetStatCreate` and is appended to the local
+//! [`SessionLog`], one json object per line, by the thread from [`start_session_writer`] so the
+//! audio thread never waits on the disk.  The `session_report` example reads the log back.
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    sync::mpsc,
+    thread,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::common::box_error::BoxError;
+
+/// the quantiles the reports show
+pub const REPORT_QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+/// P50/P95/P99 of a stat (usec)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+/// How a player's session went
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SessionReport {
+    pub client_id: u32,
+    pub address: String,
+    /// unix time (usec) of the first and last packets
+    pub start: u64,
+    pub end: u64,
+    /// seconds
+    pub duration: f64,
+    pub packets: usize,
+    pub lost: usize,
+    pub reordered: usize,
+    pub late: usize,
+    pub duplicates: usize,
+    /// lost over expected.  The others are over packets received
+    pub loss_rate: f64,
+    pub late_rate: f64,
+    pub duplicate_rate: f64,
+    /// RFC 3550 interarrival jitter (usec)
+    pub transit_jitter: f64,
+    /// time between packets (usec).  Older logs called it jitter
+    #[serde(alias = "jitter")]
+    pub interarrival: Percentiles,
+    /// round trip through the server (usec)
+    pub loop_time: Percentiles,
+    /// longest time (usec) between two packets
+    pub longest_gap: u64,
+}
+
+impl SessionReport {
+    /// count over packets, 0 if there weren't any
+    pub fn rate(count: usize, packets: usize) -> f64 {
+        if packets == 0 {
+            0.0
+        } else {
+            count as f64 / packets as f64
+        }
+    }
+}
+
+/// Append only history of session reports.  An empty filename turns it off
+pub struct SessionLog {
+    filename: String,
+}
+
+impl SessionLog {
+    pub fn new(filename: &str) -> SessionLog {
+        SessionLog {
+            filename: filename.to_string(),
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        !self.filename.is_empty()
+    }
+    pub fn append(&self, report: &SessionReport) -> Result<(), BoxError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.filename)?;
+        writeln!(file, "{}", serde_json::to_string(report)?)?;
+        Ok(())
+    }
+    /// all the reports in the log.  Lines that don't parse are skipped
+    pub fn read(&self) -> Result<Vec<SessionReport>, BoxError> {
+        let file = std::fs::File::open(&self.filename)?;
+        let mut reports = vec![];
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(report) => reports.push(report),
+                Err(e) => warn!("{} line {}: {}", self.filename, n + 1, e),
+            }
+        }
+        Ok(reports)
+    }
+}
+
+/// Start the thread that appends reports to the log.  Send it the reports
+pub fn start_session_writer(log: SessionLog) -> mpsc::Sender<SessionReport> {
+    let (report_tx, report_rx) = mpsc::channel::<SessionReport>();
+    let _writer_handle = thread::spawn(move || {
+        for report in report_rx {
+            if let Err(e) = log.append(&report) {
+                warn!("can't save session report: {}", e);
+            }
+        }
+    });
+    report_tx
+}
+
+/// totals and averages over a set of reports
+pub fn summarize(reports: &[SessionReport]) -> Value {
+    let sessions = reports.len();
+    let packets: usize = reports.iter().map(|r| r.packets).sum();
+    let lost: usize = reports.iter().map(|r| r.lost).sum();
+    let late: usize = reports.iter().map(|r| r.late).sum();
+    let mean = |f: fn(&SessionReport) -> f64| {
+        if sessions == 0 {
+            0.0
+        } else {
+            reports.iter().map(f).sum::<f64>() / sessions as f64
+        }
+    };
+    json!({
+        "sessions": sessions,
+        "hours": reports.iter().map(|r| r.duration).sum::<f64>() / 3600.0,
+        "packets": packets,
+        "lossRate": SessionReport::rate(lost, packets),
+        "lateRate": SessionReport::rate(late, packets),
+        "meanInterarrivalP99": mean(|r| r.interarrival.p99),
+        "meanLoopTimeP50": mean(|r| r.loop_time.p50),
+        "worstLoopTimeP99": reports.iter().map(|r| r.loop_time.p99).fold(0.0, f64::max),
+        "longestGap": reports.iter().map(|r| r.longest_gap).max().unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod test_session_report {
+    use super::*;
+
+    #[test]
+    fn log_round_trip() {
+        let filename = std::env::temp_dir().join(format!("sessions_{}.jsonl", std::process::id()));
+        let _res = std::fs::remove_file(&filename);
+        let log = SessionLog::new(filename.to_str().unwrap());
+        let mut report = SessionReport {
+            client_id: 7,
+            duration: 1800.0,
+            packets: 1000,
+            lost: 10,
+            ..Default::default()
+        };
+        log.append(&report).unwrap();
+        report.client_id = 8;
+        report.loop_time.p99 = 30_000.0;
+        log.append(&report).unwrap();
+        // junk gets skipped
+        OpenOptions::new().append(true).open(&filename).unwrap().write_all(b"{oops\n").unwrap();
+        let reports = log.read().unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1], report);
+        let summary = summarize(&reports);
+        assert_eq!(summary["sessions"], 2);
+        assert_eq!(summary["hours"], 1.0);
+        assert_eq!(summary["lossRate"], 0.01);
+        assert_eq!(summary["worstLoopTimeP99"], 30_000.0);
+        std::fs::remove_file(&filename).unwrap();
+        // turned off
+        assert!(SessionLog::new("").append(&report).is_ok());
+        // through the writer thread.  It's done when the channel closes
+        let writer = start_session_writer(SessionLog::new(filename.to_str().unwrap()));
+        writer.send(report.clone()).unwrap();
+        drop(writer);
+        for _ in 0..100 {
+            if log.read().is_ok_and(|r| !r.is_empty()) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(log.read().unwrap(), vec![report]);
+        std::fs::remove_file(&filename).unwrap();
+        // an old log line still reads
+        let old: SessionReport = serde_json::from_str(r#"{"clientId": 9, "jitter": {"p50": 1.0, "p95": 2.0, "p99": 3.0}}"#).unwrap();
+        assert_eq!(old.interarrival.p99, 3.0);
+    }
+}
@@ -3,6 +3,9 @@
 //! The [`JitterBuffer`](crate::sound::jitter_buffer::JitterBuffer) uses StreamTimeStat
 //! to get mean and sigma values on the buffer depth to adapt
 //!
+//! A StreamTimeStat can also track quantiles (P50, P99, etc) with [`P2Quantile`] estimators.  They
+//! run in constant memory so they cover the whole stream, not just the moving average window.
+//!
 //! The MicroTimer is used to trigger periodic events (when to send latency updates)
 //! by the broadcast component, or when to update u/x elements in the sound component
 use std::f64;
@@ -13,12 +16,109 @@ use serde::Serialize;
 
 use pedal_board::dsp::moving_avg::MovingAverage;
 
+/// Streaming quantile estimate using the P² algorithm (Jain and Chlamtac).
+///
+/// Keeps five markers whose heights follow the min, p/2, p, (1+p)/2 and max of everything added
+/// so far.  No samples are stored.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct P2Quantile {
+    p: f64,
+    count: u64,
+    heights: [f64; 5],
+    positions: [f64; 5],
+    desired: [f64; 5],
+}
+
+impl P2Quantile {
+    /// estimate the p quantile (0.0 to 1.0)
+    pub fn new(p: f64) -> P2Quantile {
+        let p = p.clamp(0.0, 1.0);
+        P2Quantile {
+            p,
+            count: 0,
+            heights: [0.0; 5],
+            positions: [0.0, 1.0, 2.0, 3.0, 4.0],
+            desired: [0.0, 2.0 * p, 4.0 * p, 2.0 + 2.0 * p, 4.0],
+        }
+    }
+    pub fn get_p(&self) -> f64 {
+        self.p
+    }
+    pub fn get_count(&self) -> u64 {
+        self.count
+    }
+    pub fn add_sample(&mut self, sample: f64) {
+        if self.count < 5 {
+            self.heights[self.count as usize] = sample;
+            self.count += 1;
+            if self.count == 5 {
+                self.heights.sort_by(|a, b| a.total_cmp(b));
+            }
+            return;
+        }
+        self.count += 1;
+        // find the cell the sample lands in, stretching the ends if needed
+        let q = &mut self.heights;
+        let k = if sample < q[0] {
+            q[0] = sample;
+            0
+        } else if sample >= q[4] {
+            q[4] = sample;
+            3
+        } else {
+            (0..4).rev().find(|i| q[*i] <= sample).unwrap_or(0)
+        };
+        for n in self.positions.iter_mut().skip(k + 1) {
+            *n += 1.0;
+        }
+        let increments = [0.0, self.p / 2.0, self.p, (1.0 + self.p) / 2.0, 1.0];
+        for (d, inc) in self.desired.iter_mut().zip(increments) {
+            *d += inc;
+        }
+        // move the middle markers toward where they should be
+        for i in 1..4 {
+            let n = &mut self.positions;
+            let off = self.desired[i] - n[i];
+            if (off >= 1.0 && n[i + 1] - n[i] > 1.0) || (off <= -1.0 && n[i - 1] - n[i] < -1.0) {
+                let d = off.signum();
+                let parabolic = q[i]
+                    + d / (n[i + 1] - n[i - 1])
+                        * ((n[i] - n[i - 1] + d) * (q[i + 1] - q[i]) / (n[i + 1] - n[i])
+                            + (n[i + 1] - n[i] - d) * (q[i] - q[i - 1]) / (n[i] - n[i - 1]));
+                q[i] = if q[i - 1] < parabolic && parabolic < q[i + 1] {
+                    parabolic
+                } else {
+                    // parabola overshot.  go linear
+                    let j = if d > 0.0 { i + 1 } else { i - 1 };
+                    q[i] + d * (q[j] - q[i]) / (n[j] - n[i])
+                };
+                n[i] += d;
+            }
+        }
+    }
+    /// the current estimate.  0.0 until there are samples
+    pub fn get(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            c if c < 5 => {
+                // not enough for markers yet.  just look it up
+                let mut first: Vec<f64> = self.heights[..c as usize].to_vec();
+                first.sort_by(|a, b| a.total_cmp(b));
+                first[(self.p * (c - 1) as f64).round() as usize]
+            }
+            _ => self.heights[2],
+        }
+    }
+}
+
 /// moving average filter that collect peak, mean, and sigma values for sequences
 #[derive(Debug, Deserialize, Serialize)]
 pub struct StreamTimeStat {
     window: u64,
     avg: MovingAverage,
     dev: MovingAverage,
+    #[serde(default)]
+    quantiles: Vec<P2Quantile>,
 }
 
 impl StreamTimeStat {
@@ -28,11 +128,24 @@ impl StreamTimeStat {
             window: window_size,
             avg: MovingAverage::new(window_size as usize),
             dev: MovingAverage::new(window_size as usize),
+            quantiles: vec![],
         }
     }
+    /// also track these quantiles (0.5 for the median, etc) over the whole stream
+    pub fn with_quantiles(mut self, probs: &[f64]) -> StreamTimeStat {
+        self.quantiles = probs.iter().map(|p| P2Quantile::new(*p)).collect();
+        self
+    }
     pub fn clear(&mut self) -> () {
         self.avg = MovingAverage::new(self.window as usize);
         self.dev = MovingAverage::new(self.window as usize);
+        for q in self.quantiles.iter_mut() {
+            *q = P2Quantile::new(q.get_p());
+        }
+    }
+    /// estimate for a quantile passed to [`StreamTimeStat::with_quantiles`].  None if it's not tracked
+    pub fn get_quantile(&self, p: f64) -> Option<f64> {
+        self.quantiles.iter().find(|q| q.get_p() == p).map(|q| q.get())
     }
     pub fn get_mean(&self) -> f64 {
         self.avg.get_mean()
@@ -49,6 +162,9 @@ impl StreamTimeStat {
         self.avg.add_sample(sample);
         let delta = sample - self.get_mean();
         self.dev.add_sample(delta * delta);
+        for q in self.quantiles.iter_mut() {
+            q.add_sample(sample);
+        }
     }
 }
 
@@ -82,6 +198,26 @@ mod test_stream_time_stat {
         assert!(stat.get_mean() > 0.999);
         assert!(stat.get_sigma() < 0.01);
     }
+    #[test]
+    fn quantiles() {
+        let mut stat = StreamTimeStat::new(100).with_quantiles(&[0.5, 0.95, 0.99]);
+        assert_eq!(stat.get_quantile(0.5), Some(0.0));
+        assert_eq!(stat.get_quantile(0.9), None);
+        stat.add_sample(30.0);
+        stat.add_sample(10.0);
+        stat.add_sample(20.0);
+        assert_eq!(stat.get_quantile(0.5), Some(20.0));
+        // every number from 0 to 9999 in a scrambled order
+        for i in 0..10_000u64 {
+            stat.add_sample(((i * 7919) % 10_000) as f64);
+        }
+        let near = |p: f64, want: f64| (stat.get_quantile(p).unwrap() - want).abs() < 100.0;
+        assert!(near(0.5, 5000.0));
+        assert!(near(0.95, 9500.0));
+        assert!(near(0.99, 9900.0));
+        stat.clear();
+        assert_eq!(stat.get_quantile(0.99), Some(0.0));
+    }
 }
 
 /// Timer with microsecond accuracy to let things know when a certain time (or more) passed
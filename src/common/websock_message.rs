@@ -10,6 +10,7 @@ use serde::Serialize;
 pub enum WebsockMessage {
     Chat(Value),
     API(String, Value),
+    Binary(u32, Vec<u8>), // compact data for a local client (0 for all of them).  The nation room is text
 }
 
 impl fmt::Display for WebsockMessage {
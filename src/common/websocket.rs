@@ -56,7 +56,7 @@ pub fn websocket_thread(
                                                 }
                                             }
                                         }
-                                        WebsockMessage::API(_a, _s) => {
+                                        WebsockMessage::API(_, _) | WebsockMessage::Binary(..) => {
                                             // No need to do anything
                                         }
                                     }
@@ -1,10 +1,18 @@
 //! things used to make the broadcast server  (UDP Multicast server)
+pub mod admission;
 pub mod audio_thread;
 pub mod broadcast_server;
 pub mod cmd_message;
+pub mod frame_clock;
 pub mod player_list;
 pub mod playback_thread;
 pub mod room_mixer;
 pub mod ping_thread;
 pub mod metronome;
-pub mod playback_mixer;
\ No newline at end of file
+pub mod packet_guard;
+pub mod playback_mixer;
+pub mod reactor;
+pub mod fan_out;
+pub mod metrics;
+pub mod maintenance;
+pub mod shutdown;
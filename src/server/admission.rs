@@ -0,0 +1,227 @@
+//! Who is allowed into a room
+//!
+//! Without a policy anybody who sends a packet to the broadcast port is in the room.  The
+//! [`AdmissionPolicy`] held by the [`PlayerList`](crate::server::player_list::PlayerList) can
+//! limit that with:
+//! - an allow-list of client ids (loaded from a file or pushed by the owner with a room command)
+//! - a ban list
+//! - a room password
+//! - a maximum number of players
+//!
+//! The password handshake and the rejection notice are header only control packets (see
+//! [`JamMessage::get_packet_type`](crate::common::jam_packet::JamMessage::get_packet_type)) so
+//! older components just see an empty audio packet.  A sound component joining a room with a
+//! password sends a JOIN and gets a CHALLENGE back with a random nonce in the server time field.
+//! Its next JOIN carries [`password_token`] for that nonce.  The nonce is only good once, from the
+//! address it was sent to, for [`CHALLENGE_TTL`], so a JOIN picked up off the wire is no use to
+//! anybody else.  The broadcast answers with ADMIT, or REJECT with one of the `REJECT_*` codes (see
+//! [`reject_reason`](crate::common::jam_packet::reject_reason)) in the chunk count byte.
+//!
+//! Getting the password right admits the client id from that address only.  Packets for the id
+//! from anywhere else need their own challenge.
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use serde_json::{json, Value};
+
+use crate::common::{
+    auth::password_token,
+    box_error::BoxError,
+    jam_packet::{REJECT_BANNED, REJECT_FULL, REJECT_NOT_INVITED, REJECT_PASSWORD},
+};
+
+/// how long (usec) a password challenge can be answered
+pub const CHALLENGE_TTL: u128 = 5_000_000;
+/// most challenges waiting for an answer
+const MAX_CHALLENGES: usize = 256;
+
+/// The rules for getting in the room
+pub struct AdmissionPolicy {
+    allow_list: Option<HashSet<u32>>,
+    banned: HashSet<u32>,
+    password: String,
+    max_players: usize,
+    // clients that have sent the right password, and the address they sent it from
+    joined: HashMap<u32, SocketAddr>,
+    // challenges sent out and not answered yet: nonce and when it went out
+    challenges: HashMap<SocketAddr, (u64, u128)>,
+}
+
+impl AdmissionPolicy {
+    /// a policy that lets everybody in
+    pub fn new() -> AdmissionPolicy {
+        AdmissionPolicy {
+            allow_list: None,
+            banned: HashSet::new(),
+            password: String::new(),
+            max_players: 0,
+            joined: HashMap::new(),
+            challenges: HashMap::new(),
+        }
+    }
+    /// Only let these ids in.  None lets anybody in.
+    pub fn set_allow_list(&mut self, ids: Option<Vec<u32>>) {
+        self.allow_list = ids.map(|ids| ids.into_iter().collect());
+    }
+    /// load the allow-list from a file with a json array of client ids
+    pub fn load_allow_list(&mut self, filename: &str) -> Result<(), BoxError> {
+        let ids: Vec<u32> = serde_json::from_str(&std::fs::read_to_string(filename)?)?;
+        self.set_allow_list(Some(ids));
+        Ok(())
+    }
+    pub fn ban(&mut self, id: u32) {
+        self.banned.insert(id);
+        self.joined.remove(&id);
+    }
+    pub fn unban(&mut self, id: u32) {
+        self.banned.remove(&id);
+    }
+    /// change the room password.  Everybody has to send the new one
+    pub fn set_password(&mut self, password: &str) {
+        self.password = String::from(password);
+        self.joined.clear();
+        self.challenges.clear();
+    }
+    /// 0 is no limit
+    pub fn set_max_players(&mut self, max: usize) {
+        self.max_players = max;
+    }
+    /// check a client sending from addr.  present is if they are already in the room, count is how
+    /// many are in the room
+    pub fn check(&self, id: u32, addr: SocketAddr, present: bool, count: usize) -> Result<(), u8> {
+        if self.banned.contains(&id) {
+            return Err(REJECT_BANNED);
+        }
+        if let Some(allowed) = &self.allow_list {
+            if !allowed.contains(&id) {
+                return Err(REJECT_NOT_INVITED);
+            }
+        }
+        if !self.password.is_empty() && self.joined.get(&id) != Some(&addr) {
+            return Err(REJECT_PASSWORD);
+        }
+        if self.max_players > 0 && !present && count >= self.max_players {
+            return Err(REJECT_FULL);
+        }
+        Ok(())
+    }
+    /// the nonce to send back for a JOIN from addr, if it has to prove the password first.  None
+    /// when there is no password, the id already proved it from there, or a challenge is waiting
+    /// for its answer (this JOIN may be it)
+    pub fn challenge(&mut self, id: u32, addr: SocketAddr, now_time: u128) -> Option<u64> {
+        if self.password.is_empty() || self.joined.get(&id) == Some(&addr) {
+            return None;
+        }
+        self.challenges.retain(|_, (_, sent)| now_time < *sent + CHALLENGE_TTL);
+        if self.challenges.contains_key(&addr) || self.challenges.len() >= MAX_CHALLENGES {
+            return None;
+        }
+        let nonce = rand::random::<u64>().max(1);
+        self.challenges.insert(addr, (nonce, now_time));
+        Some(nonce)
+    }
+    /// handle a JOIN from addr.  token is the client's [`password_token`] for the challenge sent there
+    pub fn join(&mut self, id: u32, addr: SocketAddr, token: u64, present: bool, count: usize, now_time: u128) -> Result<(), u8> {
+        if !self.password.is_empty() && self.joined.get(&id) != Some(&addr) {
+            // the challenge is used up whatever the answer
+            match self.challenges.remove(&addr) {
+                Some((nonce, sent)) if now_time < sent + CHALLENGE_TTL && token == password_token(&self.password, id, nonce) => {
+                    self.joined.insert(id, addr);
+                }
+                _ => return Err(REJECT_PASSWORD),
+            }
+        }
+        self.check(id, addr, present, count)
+    }
+    /// client left the room.  They need to send the password again to get back in
+    pub fn forget(&mut self, id: u32) {
+        self.joined.remove(&id);
+    }
+    pub fn as_json(&self) -> Value {
+        json!({
+            "allowList": self.allow_list.as_ref().map(|ids| ids.iter().collect::<Vec<_>>()),
+            "banned": self.banned.iter().collect::<Vec<_>>(),
+            "hasPassword": !self.password.is_empty(),
+            "maxPlayers": self.max_players,
+        })
+    }
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test_admission {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "182.1.1.1:33345".parse().unwrap()
+    }
+
+    #[test]
+    fn open_by_default() {
+        assert_eq!(AdmissionPolicy::new().check(1, addr(), false, 100), Ok(()));
+        assert_eq!(AdmissionPolicy::new().challenge(1, addr(), 0), None);
+    }
+
+    #[test]
+    fn allow_and_ban_lists() {
+        let mut policy = AdmissionPolicy::new();
+        policy.set_allow_list(Some(vec![1, 2]));
+        assert_eq!(policy.check(1, addr(), false, 0), Ok(()));
+        assert_eq!(policy.check(3, addr(), false, 0), Err(REJECT_NOT_INVITED));
+        policy.ban(2);
+        assert_eq!(policy.check(2, addr(), false, 0), Err(REJECT_BANNED));
+        policy.unban(2);
+        assert_eq!(policy.check(2, addr(), false, 0), Ok(()));
+    }
+
+    #[test]
+    fn password_handshake() {
+        let mut policy = AdmissionPolicy::new();
+        policy.set_password("letmein");
+        assert_eq!(policy.check(7, addr(), false, 0), Err(REJECT_PASSWORD));
+        // no answer without a challenge
+        let nonce = 99;
+        assert_eq!(policy.join(7, addr(), password_token("letmein", 7, nonce), false, 0, 0), Err(REJECT_PASSWORD));
+        let nonce = policy.challenge(7, addr(), 0).unwrap();
+        // one challenge at a time per address
+        assert_eq!(policy.challenge(7, addr(), 10), None);
+        assert_eq!(policy.join(7, addr(), password_token("guess", 7, nonce), false, 0, 10), Err(REJECT_PASSWORD));
+        // a wrong answer uses it up
+        assert_eq!(policy.join(7, addr(), password_token("letmein", 7, nonce), false, 0, 20), Err(REJECT_PASSWORD));
+        // a token for another id doesn't work either
+        let nonce = policy.challenge(7, addr(), 30).unwrap();
+        assert_eq!(policy.join(7, addr(), password_token("letmein", 8, nonce), false, 0, 40), Err(REJECT_PASSWORD));
+        // nor does a late one
+        let nonce = policy.challenge(7, addr(), 50).unwrap();
+        assert_eq!(policy.join(7, addr(), password_token("letmein", 7, nonce), false, 0, 50 + CHALLENGE_TTL), Err(REJECT_PASSWORD));
+        let nonce = policy.challenge(7, addr(), 60).unwrap();
+        let token = password_token("letmein", 7, nonce);
+        assert_eq!(policy.join(7, addr(), token, false, 0, 70), Ok(()));
+        assert_eq!(policy.check(7, addr(), false, 0), Ok(()));
+        // joining again from there doesn't need a challenge
+        assert_eq!(policy.challenge(7, addr(), 80), None);
+        // the same JOIN replayed from another address gets nowhere
+        let other: SocketAddr = "10.0.0.9:4000".parse().unwrap();
+        assert_eq!(policy.check(7, other, false, 0), Err(REJECT_PASSWORD));
+        assert_eq!(policy.join(7, other, token, false, 0, 90), Err(REJECT_PASSWORD));
+        assert!(policy.challenge(7, other, 100).is_some());
+        assert_eq!(policy.join(7, other, token, false, 0, 110), Err(REJECT_PASSWORD));
+        policy.forget(7);
+        assert_eq!(policy.check(7, addr(), false, 0), Err(REJECT_PASSWORD));
+    }
+
+    #[test]
+    fn max_players() {
+        let mut policy = AdmissionPolicy::new();
+        policy.set_max_players(2);
+        assert_eq!(policy.check(1, addr(), false, 1), Ok(()));
+        assert_eq!(policy.check(3, addr(), false, 2), Err(REJECT_FULL));
+        // somebody already in the room stays in
+        assert_eq!(policy.check(1, addr(), true, 2), Ok(()));
+    }
+}
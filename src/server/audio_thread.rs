@@ -1,77 +1,353 @@
 //! listen for packets from sound components and multicast them to people in the room
 //!
-//! The socket read is non-blocking.
+//! The thread sleeps in a [`Reactor`] until packets come in, a room command is sent (on the
+//! channel from [`command_channel`]) or the next frame is due.  The socket is non-blocking and
+//! gets read dry every time it wakes up.  Forwarding a packet to the room goes out in one
+//! batch ([`send_all`]).
+//!
+//! Packets are only forwarded for clients the room's [`AdmissionPolicy`] lets in.  Anybody else
+//! gets a REJECT control packet (at most once a second) telling them why.  A JOIN control packet
+//! carries the client's password token and session nonce and is answered with ADMIT or REJECT, or
+//! a CHALLENGE when the password has to be proven from that address first.
+//! A player whose id is claimed from another address gets a NOTICE.
+//!
+//! The room mix ("mix" mode) and playback stream are clocked out by a [`FrameClock`] so they
+//! go out at a steady frame rate whether or not anybody's packets are coming in.  The playback
+//! thread keeps a couple of frames queued and each tick takes one.
+//!
+//! Before any of that the [`PacketGuard`] drops floods and malformed packets.  Socket errors are
+//! counted (and show up in the latency message) rather than taking the room down.
+//!
+//! Players that send a REPORT (their audio device latency and jitter buffer depths) go into the
+//! [`RoomLatency`].  Every couple of seconds the room's [`LatencyMatrix`](crate::common::latency_matrix::LatencyMatrix)
+//! goes out with the latency message and in-band (PACKET_LATENCY) to the players that report.
+//!
+//! Every second the thread publishes a [`RoomSnapshot`] for the metrics endpoint.  When a player
+//! leaves, their [`SessionReport`] goes to the nation and the
+//! [`SessionLog`](crate::common::session_report::SessionLog).
+//!
+//! When a [`Shutdown`] is requested everybody gets a NOTICE that the room is going away, their
+//! session reports are saved, and the thread returns.
 use crate::{
     common::{
         box_error::BoxError,
         get_micro_time,
-        jam_packet::{JamMessage, JAM_HEADER_SIZE},
+        jam_packet::{
+            JamMessage, JAM_HEADER_SIZE, NOTICE_GOING_AWAY, PACKET_ADMIT, PACKET_CHALLENGE, PACKET_JOIN,
+            PACKET_LATENCY, PACKET_NOTICE, PACKET_REJECT, PACKET_REPORT, REJECT_DUPLICATE_ID,
+        },
+        latency_matrix::{DeviceReport, MixDepths, RoomLatency},
         player::MAX_LOOP_TIME,
+        session_report::SessionReport,
         sock_with_tos,
         stream_time_stat::MicroTimer,
         websock_message::WebsockMessage,
     },
-    server::player_list::PlayerList,
+    server::{
+        admission::AdmissionPolicy,
+        fan_out::{send_all, Datagram},
+        frame_clock::{FrameClock, FRAME_TIME},
+        metrics::{RoomSnapshot, ServerMetrics, Traffic},
+        packet_guard::PacketGuard,
+        player_list::{PlayerList, Refusal},
+        reactor::{Reactor, WakingSender},
+        shutdown::Shutdown,
+    },
+    sound::jitter_buffer::check_depth_limits,
 };
-use log::{debug, error};
+use log::{debug, error, warn};
 use serde_json::json;
-use std::{io::ErrorKind, sync::mpsc, time::Duration};
+use std::{io::ErrorKind, net::SocketAddr, net::UdpSocket, sync::mpsc};
+
+use super::{
+    cmd_message::{RoomCommandMessage, RoomParam},
+    metronome::Metronome,
+    room_mixer::{RoomMixer, MIX_CLIENT_ID},
+};
+
+/// How the room starts out
+pub struct RoomOptions {
+    /// mix the room on the server (true) or forward each player's packets (false)
+    pub mix_mode: bool,
+    pub admission: AdmissionPolicy,
+    /// where the room's numbers get published
+    pub metrics: ServerMetrics,
+    /// where session reports get saved (see [`start_session_writer`](crate::common::session_report::start_session_writer))
+    pub session_log: mpsc::Sender<SessionReport>,
+    /// the thread returns when this is requested
+    pub shutdown: Shutdown,
+}
+
+/// most packets read before checking on the frame clock again
+const READ_BATCH: usize = 32;
+/// fastest and slowest (msec) the latency message can be set to go out
+pub const MIN_UPDATE_MSEC: i64 = 250;
+pub const MAX_UPDATE_MSEC: i64 = 10_000;
+
+/// The audio thread's end of the room command channel
+pub struct RoomCommands {
+    reactor: Reactor,
+    rx: mpsc::Receiver<RoomCommandMessage>,
+}
+
+/// channel for room commands.  Sending a command wakes up the audio thread
+pub fn command_channel() -> Result<(WakingSender<RoomCommandMessage>, RoomCommands), BoxError> {
+    let reactor = Reactor::new()?;
+    let (tx, rx) = reactor.channel();
+    Ok((tx, RoomCommands { reactor, rx }))
+}
+
+/// send a header only control packet to a client.  value goes in the server time field
+fn send_control(sock: &UdpSocket, addr: SocketAddr, client_id: u32, packet_type: u8, code: u8, value: u64) {
+    let mut reply = JamMessage::new();
+    reply.set_client_id(client_id);
+    reply.set_control(packet_type, code, value);
+    if let Err(e) = sock.send_to(reply.get_send_buffer(), addr) {
+        debug!("control packet to {} failed: {}", addr, e);
+    }
+}
+
+/// send packets to players.  Failed sends are counted, they don't stop the room
+fn forward(sock: &UdpSocket, packets: &[Datagram], guard: &mut PacketGuard, traffic: &mut Traffic) {
+    let failed = send_all(sock, packets);
+    for _ in 0..failed {
+        guard.count_socket_error();
+    }
+    traffic.sent(packets, failed);
+}
+
+/// tell the sender (and anybody else involved) why their packet was refused.  Rate limited
+fn refuse(sock: &UdpSocket, players: &mut PlayerList, src: SocketAddr, client_id: u32, refusal: Refusal, now_time: u128) {
+    if players.should_notify(src, now_time) {
+        debug!("client {} refused: {}", client_id, refusal.code);
+        send_control(sock, src, client_id, PACKET_REJECT, refusal.code, 0);
+    }
+    if let Some(addr) = refusal.warn {
+        if players.should_notify(addr, now_time) {
+            send_control(sock, addr, client_id, PACKET_NOTICE, REJECT_DUPLICATE_ID, 0);
+        }
+    }
+}
+
+/// send the reports from sessions that ended to the nation and the session log
+fn flush_reports(
+    players: &mut PlayerList,
+    session_log: &mpsc::Sender<SessionReport>,
+    audio_tx: &WakingSender<WebsockMessage>,
+    token: &str,
+) -> Result<(), BoxError> {
+    while let Some(stats) = players.stat_queue.pop() {
+        if session_log.send(stats.clone()).is_err() {
+            warn!("session log writer is gone");
+        }
+        audio_tx.send(WebsockMessage::API(
+            "packetStatCreate".to_string(),
+            json!({ "roomToken": token, "stats": stats }),
+        ))?;
+    }
+    Ok(())
+}
+
+/// room commands that change the admission policy
+fn admission_command(players: &mut PlayerList, m: &RoomCommandMessage) -> Result<serde_json::Value, BoxError> {
+    let policy = &mut players.admission;
+    match m.param {
+        RoomParam::SetAllowList => {
+            if m.svalue.is_empty() {
+                policy.set_allow_list(None);
+            } else {
+                policy.set_allow_list(Some(serde_json::from_str(&m.svalue)?));
+            }
+        }
+        RoomParam::BanPlayer => policy.ban(u32::try_from(m.ivalue_1)?),
+        RoomParam::UnbanPlayer => policy.unban(u32::try_from(m.ivalue_1)?),
+        RoomParam::SetMaxPlayers => policy.set_max_players(usize::try_from(m.ivalue_1)?),
+        RoomParam::SetRoomPassword => policy.set_password(&m.svalue),
+        _ => (),
+    }
+    Ok(json!({ "admission": policy.as_json() }))
+}
+
+/// room commands for a listener's (ivalue_1) personal mix.  Setting a source takes svalue
+/// {"source", "gain", "pan"}
+fn personal_mix_command(room_mixer: &mut RoomMixer, m: &RoomCommandMessage) -> Result<serde_json::Value, BoxError> {
+    let listener = u32::try_from(m.ivalue_1)?;
+    match m.param {
+        RoomParam::SetPersonalMix => {
+            let setting: serde_json::Value = serde_json::from_str(&m.svalue)?;
+            let source = match setting["source"].as_u64() {
+                Some(s) => u32::try_from(s)?,
+                None => return Err("personal mix needs a source".into()),
+            };
+            let gain = setting["gain"].as_f64().unwrap_or(0.0);
+            let pan = setting["pan"].as_f64().unwrap_or(0.0) as f32;
+            room_mixer.set_source_mix(listener, source, gain, pan);
+        }
+        RoomParam::ClearPersonalMix => room_mixer.clear_personal_mix(listener),
+        _ => (),
+    }
+    Ok(room_mixer.personal_mix_json(listener))
+}
 
-use super::{cmd_message::{RoomCommandMessage, RoomParam}, metronome::Metronome, room_mixer::RoomMixer};
+/// how shallow and deep (samples) the room's jitter buffers adapt.  svalue is {"min", "max"}
+fn jitter_command(room_mixer: &mut RoomMixer, m: &RoomCommandMessage) -> Result<serde_json::Value, BoxError> {
+    let setting: serde_json::Value = serde_json::from_str(&m.svalue)?;
+    let (min, max) = match (setting["min"].as_u64(), setting["max"].as_u64()) {
+        (Some(min), Some(max)) => (min as usize, max as usize),
+        _ => return Err("jitter depth needs a min and a max".into()),
+    };
+    check_depth_limits(min, max)?;
+    room_mixer.set_jitter_limits(min, max);
+    Ok(json!({ "jitterDepth": { "min": min, "max": max } }))
+}
 
-const FRAME_TIME: u128 = 2_667;
 
 pub fn run(
     port: u32,
-    cmd_rx: mpsc::Receiver<RoomCommandMessage>,
-    audio_tx: mpsc::Sender<WebsockMessage>,
+    commands: RoomCommands,
+    audio_tx: WakingSender<WebsockMessage>,
     token: &str,
-    record_tx: mpsc::Sender<JamMessage>,
+    record_tx: WakingSender<JamMessage>,
     playback_rx: mpsc::Receiver<JamMessage>,
-    mode: bool
+    options: RoomOptions,
 ) -> Result<(), BoxError> {
     // So let's create a UDP socket and listen for shit
     let sock = sock_with_tos::new(port);
-    sock.set_read_timeout(Some(Duration::new(0, 6_000_000)))?;
+    let RoomCommands { mut reactor, rx: cmd_rx } = commands;
+    reactor.register(&sock)?;
     let mut players = PlayerList::new();
+    players.admission = options.admission;
+    let metrics = options.metrics;
+    let session_log = options.session_log;
+    let shutdown = options.shutdown;
     let mut msg = JamMessage::new();
+    let mut guard = PacketGuard::new();
     let mut latency_update_timer = MicroTimer::new(get_micro_time(), 2_000_000);
+    let mut metrics_timer = MicroTimer::new(get_micro_time(), 1_000_000);
+    let mut traffic = Traffic::default();
     let mut room_mixer = RoomMixer::new();
-    let mut pback_timer = MicroTimer::new(get_micro_time(), FRAME_TIME);
-    let mut room_mode = mode;
+    let mut out_clock = FrameClock::new(get_micro_time(), FRAME_TIME);
+    let mut room_mode = options.mix_mode;
     let mut met = Metronome::new();
+    let mut latency = RoomLatency::new();
     loop {
         // get a timestamp to use
         let now_time = get_micro_time();
+        if shutdown.is_requested() {
+            for player in players.get_players() {
+                send_control(&sock, player.address, player.client_id, PACKET_NOTICE, NOTICE_GOING_AWAY, 0);
+            }
+            players.close();
+            return flush_reports(&mut players, &session_log, &audio_tx, token);
+        }
 
         // Check for any commands
-        match cmd_rx.try_recv() {
-            Ok(m) => {
-                debug!("Audio Command message: {}", m);
-                match m.param {
-                    RoomParam::SwitchRoomMode => {
-                        room_mode = !room_mode;
-                        pback_timer.reset(now_time);
-                    }
-                    RoomParam::SetTempo => {
-                        met.set_tempo(m.ivalue_1 as u128);
-                    }
-                    _ => {
-                        error!("Unknown audio command: {}", m);
-                        // No commands to process
-                    }
+        for m in cmd_rx.try_iter() {
+            debug!("Audio Command message: {}", m);
+            let result = match m.param {
+                RoomParam::SwitchRoomMode => {
+                    room_mode = !room_mode;
+                    Ok(json!({ "roomMode": if room_mode { "mix" } else { "separate" } }))
+                }
+                RoomParam::SetTempo => {
+                    met.set_tempo(m.ivalue_1 as u128);
+                    Ok(json!({ "tempo": met.get_tempo() }))
+                }
+                RoomParam::GetTempo => Ok(json!({ "tempo": met.get_tempo() })),
+                RoomParam::SetAllowList
+                | RoomParam::BanPlayer
+                | RoomParam::UnbanPlayer
+                | RoomParam::SetMaxPlayers
+                | RoomParam::SetRoomPassword
+                | RoomParam::GetAdmission => admission_command(&mut players, &m),
+                RoomParam::SetPersonalMix | RoomParam::ClearPersonalMix | RoomParam::GetPersonalMix => {
+                    personal_mix_command(&mut room_mixer, &m)
                 }
+                RoomParam::SetJitterDepth => jitter_command(&mut room_mixer, &m),
+                RoomParam::SetUpdateInterval => {
+                    let msec = m.ivalue_1.clamp(MIN_UPDATE_MSEC, MAX_UPDATE_MSEC);
+                    latency_update_timer.set_interval(msec as u128 * 1000);
+                    Ok(json!({ "updateInterval": msec }))
+                }
+                _ => {
+                    error!("Unknown audio command: {}", m);
+                    Err(format!("unsupported room command {}", m).into())
+                }
+            };
+            if let Some(reply) = m.reply(result) {
+                audio_tx.send(WebsockMessage::Chat(reply))?;
+            }
+        }
+        // Clock out the room mix and any playback
+        while out_clock.tick(now_time) {
+            let beat = met.get_beat(now_time);
+            if room_mode {
+                room_mixer.mix_frame(now_time);
+                // everybody gets their own mix
+                let mixes: Vec<(JamMessage, SocketAddr)> = players
+                    .get_players()
+                    .iter()
+                    .map(|player| {
+                        let mut p = room_mixer.get_a_packet(player.client_id, now_time);
+                        p.set_beat(beat);
+                        (p, player.address)
+                    })
+                    .collect();
+                let packets: Vec<Datagram> = mixes.iter().map(|(p, addr)| (p.get_send_buffer(), *addr)).collect();
+                forward(&sock, &packets, &mut guard, &mut traffic);
             }
-            Err(_e) => {
-                // No commands to process
+            // one frame of playback per tick
+            if let Ok(mut m) = playback_rx.try_recv() {
+                m.set_beat(beat);
+                // need to broadcast message
+                let packets: Vec<Datagram> = players
+                    .get_players()
+                    .iter()
+                    .map(|player| (m.get_send_buffer(), player.address))
+                    .collect();
+                forward(&sock, &packets, &mut guard, &mut traffic);
             }
         }
-        // Read the network
-        let res = sock.recv_from(msg.get_buffer());
-        // update the player list
-        players.prune(now_time);
+        // update the player list.  Personal mixes go with the players that left
+        for id in players.prune(now_time) {
+            room_mixer.clear_personal_mix(id);
+        }
+        if metrics_timer.expired(now_time) {
+            metrics_timer.reset(now_time);
+            metrics.heartbeat("audio", now_time);
+            metrics.publish_room(RoomSnapshot {
+                mix_mode: room_mode,
+                players: players.get_players().iter().map(Into::into).collect(),
+                traffic,
+                guard: guard.stats.clone(),
+                blocked_sources: guard.blocked_sources(now_time),
+            });
+        }
         if latency_update_timer.expired(now_time) {
             latency_update_timer.reset(now_time);
+            guard.prune(now_time);
+            latency.prune(now_time);
+            let loops: Vec<(u32, f64)> = players
+                .get_players()
+                .iter()
+                .map(|p| (p.client_id, p.get_last_loop()))
+                .collect();
+            let mix = room_mode.then(|| MixDepths {
+                mix_id: MIX_CLIENT_ID,
+                sources: room_mixer.get_depths(),
+            });
+            let matrix = latency.matrix(&loops, mix.as_ref());
+            // in-band to the players that know what it is
+            let mut matrix_msg = JamMessage::new();
+            if matrix_msg.set_words(PACKET_LATENCY, &matrix.to_words()).is_ok() {
+                let packets: Vec<Datagram> = players
+                    .get_players()
+                    .iter()
+                    .filter(|p| latency.has_reported(p.client_id))
+                    .map(|p| (matrix_msg.get_send_buffer(), p.address))
+                    .collect();
+                forward(&sock, &packets, &mut guard, &mut traffic);
+            }
             audio_tx.send(WebsockMessage::Chat(
                 serde_json::json!({
                     "speaker": "RoomChatRobot",
@@ -79,104 +355,127 @@ pub fn run(
                     "latency": players.get_latency(),
                     "update_count": players.get_update_cnt(),
                     "tempo": met.get_tempo(),
+                    "guard": guard.as_json(now_time),
+                    "clock": out_clock.as_json(),
+                    "latencyMatrix": matrix.as_json(),
                 })
             ))?;
             // This code flushes any stats from sessions that terminated
-            while players.stat_queue.len() > 0 {
-                if let Some(stats) = players.stat_queue.pop() {
-                    audio_tx.send(WebsockMessage::API(
-                        "packetStatCreate".to_string(),
-                        json!({ "roomToken": token, "stats": stats }),
-                    ))?;
-                }
-            }
+            flush_reports(&mut players, &session_log, &audio_tx, token)?;
         }
-        match res {
-            Ok((amt, src)) => {
-                // check if the packet was good
-                if amt <= 0 || !msg.is_valid(amt) || !players.is_allowed(msg.get_client_id()) {
-                    continue;
-                }
-                let _res = msg.set_nbytes(amt);
-                // Do this here in case client encode audio did not
-                // Update this player with the current time
-                let mut time_diff: u128 = MAX_LOOP_TIME;
-                let packet_time = msg.get_server_time() as u128;
-                if now_time > packet_time {
-                    time_diff = now_time - packet_time;
-                }
-                players.update_player(
-                    now_time,
-                    time_diff,
-                    msg.get_client_id(),
-                    src,
-                    msg.get_sequence_num(),
-                );
-
-                // set the server timestamp
-                msg.set_server_time(now_time as u64);
-                let beat = met.get_beat(now_time);
-                msg.set_beat(beat);
-
-                if room_mode {
-                    room_mixer.add_a_packet(now_time, &msg);
-
-                    // Clock out the room mix
-                    while pback_timer.expired(now_time) {
-                        pback_timer.advance(FRAME_TIME);
-                        let mut p = room_mixer.get_a_packet(now_time);
-                        p.set_beat(beat);
-                        for player in players.get_players() {
-                            sock.send_to(p.get_send_buffer(), player.address)?;
-                        }
+        // Read everything that's waiting (up to a batch so the frame clock doesn't starve)
+        let mut drained = false;
+        for _ in 0..READ_BATCH {
+            match sock.recv_from(msg.get_buffer()) {
+                Ok((amt, src)) => {
+                    traffic.received(amt);
+                    // check if the packet was good
+                    if !guard.admit(src, &msg, amt, now_time) {
+                        continue;
                     }
-                } else {
-                // Broadcast
-                    for player in players.get_players() {
-                        if player.address != src {
-                            // don't send echo back
-                            // send the packet
-                            sock.send_to(&msg.get_buffer()[0..amt], player.address)?;
-                        } else {
-                            // Send just a header to keep the timer looping around
-                            sock.send_to(&msg.get_buffer()[0..JAM_HEADER_SIZE], player.address)?;
+                    let client_id = msg.get_client_id();
+                    if msg.get_packet_type() == PACKET_JOIN {
+                        if let Some(challenge) = players.admission.challenge(client_id, src, now_time) {
+                            // they have to prove the password from this address first
+                            send_control(&sock, src, client_id, PACKET_CHALLENGE, 0, challenge);
+                            continue;
+                        }
+                        let nonce = msg.get_client_timestamp();
+                        let result = players
+                            .join(client_id, src, msg.get_server_time(), now_time)
+                            .map_err(Refusal::from)
+                            .and_then(|_| players.identify(now_time, client_id, nonce, src));
+                        match result {
+                            Ok(()) => send_control(&sock, src, client_id, PACKET_ADMIT, 0, 0),
+                            Err(refusal) => refuse(&sock, &mut players, src, client_id, refusal, now_time),
                         }
+                        continue;
                     }
-                }
-                // send this packet to the recorder
-                // Used for read/write packet stream to disk
-                msg.set_num_audio_chunks((amt/32) as u8);
-                let _res = record_tx.send(msg.clone());
-                // See if there are playback packets
-                let mut playing = true;
-                while playing {
-                    match playback_rx.try_recv() {
-                        Ok(mut m) => {
-                            m.set_beat(beat);
-                            // need to broadcast message
-                            for player in players.get_players() {
-                                sock.send_to(m.get_send_buffer(), player.address)?;
+                    if let Err(code) = players.check_admission(client_id, src) {
+                        guard.count_unknown();
+                        refuse(&sock, &mut players, src, client_id, code.into(), now_time);
+                        continue;
+                    }
+                    let _res = msg.set_nbytes(amt);
+                    if msg.get_packet_type() == PACKET_REPORT {
+                        // only from the address that is playing as this client
+                        if players.get_players().iter().any(|p| p.client_id == client_id && p.address == src) {
+                            match DeviceReport::from_words(&msg.get_words()) {
+                                Ok(report) => latency.report(client_id, report, now_time),
+                                Err(e) => debug!("client {}: {}", client_id, e),
                             }
                         }
-                        Err(_e) => {
-                            playing = false;
-                        }
+                        continue;
+                    }
+                    // Do this here in case client encode audio did not
+                    // Update this player with the current time
+                    let mut time_diff: u128 = MAX_LOOP_TIME;
+                    let packet_time = msg.get_server_time() as u128;
+                    if now_time > packet_time {
+                        time_diff = now_time - packet_time;
+                    }
+                    if let Err(refusal) = players.update_player(
+                        now_time,
+                        time_diff,
+                        client_id,
+                        src,
+                        msg.get_sequence_num(),
+                    ) {
+                        guard.count_unknown();
+                        refuse(&sock, &mut players, src, client_id, refusal, now_time);
+                        continue;
                     }
+
+                    // set the server timestamp
+                    msg.set_server_time(now_time as u64);
+                    let beat = met.get_beat(now_time);
+                    msg.set_beat(beat);
+
+                    if room_mode {
+                        // the frame clock sends it out
+                        room_mixer.add_a_packet(now_time, &msg);
+                    } else {
+                        // Broadcast
+                        let buf = &msg.get_buffer()[0..amt];
+                        let packets: Vec<Datagram> = players
+                            .get_players()
+                            .iter()
+                            .map(|player| {
+                                if player.address != src {
+                                    // don't send echo back
+                                    (buf, player.address)
+                                } else {
+                                    // Send just a header to keep the timer looping around
+                                    (&buf[0..JAM_HEADER_SIZE], player.address)
+                                }
+                            })
+                            .collect();
+                        forward(&sock, &packets, &mut guard, &mut traffic);
+                    }
+                    // send this packet to the recorder
+                    // Used for read/write packet stream to disk
+                    msg.set_num_audio_chunks((amt/32) as u8);
+                    let _res = record_tx.send(msg.clone());
                 }
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => {
+                        // Read it all
+                        drained = true;
+                        break;
+                    }
+                    other_error => {
+                        // An ICMP port unreachable from a player that left shows up here.  Keep going
+                        guard.count_socket_error();
+                        if guard.stats.socket_errors % 1000 == 1 {
+                            warn!("socket error ({} so far): {}", guard.stats.socket_errors, other_error);
+                        }
+                    }
+                },
             }
-            Err(e) => match e.kind() {
-                ErrorKind::WouldBlock => {
-                    // Socket timed out. advance room playback timer
-                    pback_timer.reset(now_time);
-                }
-                ErrorKind::TimedOut => {
-                    // Socket timed out. advance room playback timer
-                    pback_timer.reset(now_time);
-                }
-                other_error => {
-                    panic!("my socket went nuts! {}", other_error);
-                }
-            },
+        }
+        if drained {
+            // Sleep until there's more to do.  Don't wait past the next frame
+            reactor.wait(out_clock.wait_time(get_micro_time()))?;
         }
     }
 }
@@ -4,138 +4,400 @@
 //! - listen for audio packets [`crate::common::jam_packet::JamMessage`] and forward them to others
 //! - listen for messages from the chatRoom for the audio room being hosted
 //! - let the rtjam-nation know this component is registered and alive
+//!
+//! It restarts itself (exits so the service manager starts it again) once a night when the room
+//! is idle (see [`MaintenanceWindow`]) and shuts down cleanly on SIGTERM or SIGINT (see
+//! [`crate::server::shutdown`]).
+//!
+//! The tempo, jitter buffer depths and latency update interval in the settings are applied again
+//! when the settings file changes (or on SIGHUP) without restarting the room.
 use crate::{
     common::{
+        auth::{rejection, CommandAuth},
         box_error::BoxError, 
-        config::Config, 
+        command_reply::command_reply,
         get_micro_time, 
         jam_nation_api::JamNationApi, 
         jam_packet::JamMessage, 
+        layered_config::{self, catch_hangup, LayeredConfig, Reloader},
+        local_websocket::{start_local_websocket, UxSender},
         packet_stream::PacketWriter, 
         recording::RecordingCatalog,
+        session_report::{start_session_writer, SessionLog},
         stream_time_stat::MicroTimer, 
         websock_message::WebsockMessage, 
         websocket
     },
     server::{
-        audio_thread,
-        cmd_message::{RoomCommandMessage, RoomParam},
+        admission::AdmissionPolicy,
+        audio_thread::{self, RoomOptions, MAX_UPDATE_MSEC, MIN_UPDATE_MSEC},
+        cmd_message::{RoomCommand, RoomCommandMessage, RoomParam},
+        maintenance::MaintenanceWindow,
+        metrics::{start_metrics_server, ServerMetrics},
         ping_thread::broadcast_ping_thread, 
-        playback_thread
+        playback_thread,
+        reactor::Reactor,
+        shutdown::{catch_signals, signal, Shutdown},
     },
+    sound::jitter_buffer::{check_depth_limits, MAX_DEPTH, MIN_DEPTH},
     utils,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
 use std::{
     sync::mpsc,
     thread::{self, sleep},
     time::Duration,
 };
-use chrono::{Local, NaiveTime, SubsecRound};
+use chrono::Local;
+use simple_error::bail;
 use log::{debug, error, info, trace, warn};
 
+/// how long a shutdown waits for the websocket thread to send the last messages
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+/// room token when running without the rtjam-nation
+pub const OFFLINE_TOKEN: &str = "offline";
+
+/// What the command line can change.  None leaves it to the settings file (or the default)
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub config_file: String,
+    pub port: Option<u32>,
+    pub wan_ip: Option<String>,
+    /// "mix" or "separate"
+    pub room_mode: Option<String>,
+    /// where saved recordings go
+    pub recording_dir: Option<String>,
+    /// don't talk to the rtjam-nation.  The room is only reachable on the local websocket
+    pub offline: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            config_file: "settings.json".to_string(),
+            port: None,
+            wan_ip: None,
+            room_mode: None,
+            recording_dir: None,
+            offline: false,
+        }
+    }
+}
+
+/// The broadcast component's settings, layered from the defaults, the settings file, RTJAM_
+/// environment variables and the command line (see [`layered_config`])
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub api_url: String,
+    pub ws_url: String,
+    /// "mix" or "separate"
+    pub room_mode: String,
+    pub wan_ip: String,
+    pub port: u32,
+    /// 0 turns off the local websocket
+    pub local_ws_port: u32,
+    /// when set every command has to be signed (see [`CommandAuth`])
+    pub command_secret: String,
+    pub allow_list_file: String,
+    /// client ids kept out of the room
+    pub ban_list: Vec<u32>,
+    pub room_password: String,
+    /// 0 is no limit
+    pub max_players: u32,
+    /// 0 turns off the metrics endpoint
+    pub metrics_port: u32,
+    pub session_log: String,
+    /// "HH:MM-HH:MM" local time for the nightly restart (see [`MaintenanceWindow`]).  Empty never restarts
+    pub restart_window: String,
+    pub restart_idle_secs: u32,
+    pub recording_dir: String,
+    pub dump_file: String,
+    /// metronome tempo (bpm)
+    pub tempo: u32,
+    /// how shallow the jitter buffers can get in "mix" room mode (samples)
+    pub jitter_min_depth: u32,
+    /// how deep the jitter buffers can get in "mix" room mode (samples)
+    pub jitter_max_depth: u32,
+    /// how often the room latency message goes out (msec)
+    pub latency_update_msec: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            api_url: "http://rtjam-nation.com/api/1/".to_string(),
+            ws_url: "ws://rtjam-nation.com/primus".to_string(),
+            room_mode: "separate".to_string(),
+            wan_ip: String::new(),
+            port: 7891,
+            local_ws_port: 0,
+            command_secret: String::new(),
+            allow_list_file: String::new(),
+            ban_list: vec![],
+            room_password: String::new(),
+            max_players: 0,
+            metrics_port: 0,
+            session_log: "sessions.jsonl".to_string(),
+            restart_window: "02:00-05:00".to_string(),
+            restart_idle_secs: 600,
+            recording_dir: "recs".to_string(),
+            dump_file: "audio.dmp".to_string(),
+            tempo: 120,
+            jitter_min_depth: MIN_DEPTH as u32,
+            jitter_max_depth: MAX_DEPTH as u32,
+            latency_update_msec: 2000,
+        }
+    }
+}
+
+impl LayeredConfig for ServerConfig {
+    const ENV_PREFIX: &'static str = "RTJAM_";
+    const HOT_KEYS: &'static [&'static str] = &["tempo", "jitter_min_depth", "jitter_max_depth", "latency_update_msec"];
+    fn validate(&self) -> Result<(), BoxError> {
+        if self.room_mode != "mix" && self.room_mode != "separate" {
+            bail!("room_mode {} should be mix or separate", self.room_mode);
+        }
+        if self.tempo == 0 {
+            bail!("tempo has to be at least 1 bpm");
+        }
+        let msec = self.latency_update_msec as i64;
+        if !(MIN_UPDATE_MSEC..=MAX_UPDATE_MSEC).contains(&msec) {
+            bail!("latency_update_msec {} should be {} to {}", msec, MIN_UPDATE_MSEC, MAX_UPDATE_MSEC);
+        }
+        check_depth_limits(self.jitter_min_depth as usize, self.jitter_max_depth as usize)?;
+        MaintenanceWindow::parse(&self.restart_window, self.restart_idle_secs, Local::now().naive_local())?;
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /// what the health check shows.  No secrets
+    fn summary(&self, offline: bool) -> serde_json::Value {
+        serde_json::json!({
+            "apiUrl": self.api_url,
+            "wsUrl": self.ws_url,
+            "wanIp": self.wan_ip,
+            "roomMode": self.room_mode,
+            "port": self.port,
+            "localWsPort": self.local_ws_port,
+            "signedCommands": !self.command_secret.is_empty(),
+            "maxPlayers": self.max_players,
+            "allowListFile": self.allow_list_file,
+            "passwordSet": !self.room_password.is_empty(),
+            "sessionLog": self.session_log,
+            "metricsPort": self.metrics_port,
+            "restartWindow": self.restart_window,
+            "recordingDir": self.recording_dir,
+            "dumpFile": self.dump_file,
+            "tempo": self.tempo,
+            "jitterDepth": { "min": self.jitter_min_depth, "max": self.jitter_max_depth },
+            "latencyUpdateMsec": self.latency_update_msec,
+            "offline": offline,
+        })
+    }
+    fn mix_mode(&self) -> bool {
+        self.room_mode == "mix"
+    }
+}
+
+/// The commands that put the hot settings (keys) into effect in the audio thread
+fn room_commands(config: &ServerConfig, keys: &[String]) -> Vec<RoomCommandMessage> {
+    let mut commands = vec![];
+    if keys.iter().any(|k| k == "tempo") {
+        commands.push(RoomCommand::SetTempo { bpm: config.tempo as i64 }.to_message());
+    }
+    if keys.iter().any(|k| k.starts_with("jitter_")) {
+        commands.push(
+            RoomCommand::SetJitterDepth { min: config.jitter_min_depth, max: config.jitter_max_depth }.to_message(),
+        );
+    }
+    if keys.iter().any(|k| k == "latency_update_msec") {
+        commands.push(RoomCommand::SetUpdateInterval { msec: config.latency_update_msec as i64 }.to_message());
+    }
+    commands
+}
+
+/// Everything the server runs with, checked over
+struct Settings {
+    config: ServerConfig,
+    admission: AdmissionPolicy,
+    maintenance: Option<MaintenanceWindow>,
+    offline: bool,
+}
+
+/// the command line values that go on top of the settings file
+fn command_line(options: &ServerOptions) -> Map<String, serde_json::Value> {
+    let mut cli = Map::new();
+    if let Some(port) = options.port {
+        cli.insert("port".to_string(), port.into());
+    }
+    if let Some(wan_ip) = &options.wan_ip {
+        cli.insert("wan_ip".to_string(), wan_ip.as_str().into());
+    }
+    if let Some(mode) = &options.room_mode {
+        cli.insert("room_mode".to_string(), mode.as_str().into());
+    }
+    if let Some(dir) = &options.recording_dir {
+        cli.insert("recording_dir".to_string(), dir.as_str().into());
+    }
+    cli
+}
+
+/// read the settings file and apply the environment and command line on top
+fn load_settings(options: &ServerOptions) -> Result<Settings, BoxError> {
+    let config: ServerConfig = layered_config::load(&options.config_file, &command_line(options))?;
+    Ok(Settings {
+        admission: build_admission(&config)?,
+        maintenance: MaintenanceWindow::parse(
+            &config.restart_window,
+            config.restart_idle_secs,
+            Local::now().naive_local(),
+        )?,
+        config,
+        offline: options.offline,
+    })
+}
+
+/// Check the settings (and the recording directory) without starting anything.  Returns what the
+/// server would run with.  Unlike a normal start, a missing settings file is an error
+pub fn check_config(options: &ServerOptions) -> Result<serde_json::Value, BoxError> {
+    if let Err(e) = std::fs::metadata(&options.config_file) {
+        bail!("{}: {}", options.config_file, e);
+    }
+    let settings = load_settings(options)?;
+    RecordingCatalog::new(&settings.config.recording_dir)?;
+    Ok(settings.config.summary(settings.offline))
+}
+
 /// To start a broadcast component, call this function
 ///
-/// pass in the git_hash associated with the build so the nation can know what we are running.
+/// pass in the git_hash associated with the build so the nation can know what we are running, and
+/// the [`ServerOptions`] from the command line.
 ///
 /// This function will start additional threads.  
 /// - websocket thread - creates a websocket connection to rtjam-nation and creates a chatRoom
 /// - audio thread - listens for UDP datagrams and forwards to others in the audio room
 /// - broadcast ping thread - periodically updates rtjam-nation with keepalives so it knows the room is up
+/// - local websocket thread - (optional) serves the room chat to browsers on the same network
+/// - metrics thread - (optional) serves Prometheus metrics and a health check (see [`crate::server::metrics`])
+///
+/// Offline there is no websocket or ping thread.  The room is run from the local websocket.
 ///
-/// the original thread that calls run then loops checking mpsc::channels for messages between the websocket
-/// and the audio_thread.  The broadcast ping thread just runs by itself (fire and forget)
-pub fn run(git_hash: &str) -> Result<(), BoxError> {
+/// the original thread that calls run then sleeps in a [`Reactor`] until a command comes in, another thread
+/// has a message to relay or recording audio to write, or the next transport update is due.  The broadcast
+/// ping thread just runs by itself (fire and forget)
+pub fn run(git_hash: &str, options: ServerOptions) -> Result<(), BoxError> {
     info!("Starting run function");
 
-    // load up the config to get required info
-    // TODO: keep bubbling up the config file name to remove hard coding, and make configurable for testing fun and games
-    // NOTE: Is this the best place for these defaults, or should the be either encapsulated in the config object, or passed in as arguments?
-    let defaults = json::object! {
-        "api_url": "http://rtjam-nation.com/api/1/",
-        "ws_url": "ws://rtjam-nation.com/primus",
-        "room_mode": "separate",
-        "wan_ip": "",
-        "port": 7891,
-    };
-    let config = Config::build(String::from("settings.json"), defaults);
-    let config = match config {
-        Ok(c) => c,
+    let Settings { config, admission, mut maintenance, offline } = match load_settings(&options) {
+        Ok(s) => s,
         Err(e) => {
             error!("Issue with config file or parameter: {}", e);
-            return Err(Box::new(e));
+            return Err(e);
         }
     };
-
-    let api_url = String::from(config.get_str_value("api_url", None)?);
-    let ws_url = String::from(config.get_str_value("ws_url", None)?);
-    let wan_ip = String::from(config.get_str_value("wan_ip", None)?);
-    let room_mode = config.get_str_value("room_mode", None)? == "mix";
-    let port: u32 = config.get_u32_value("port", None)?;
+    let port = config.port;
+    let mut auth = CommandAuth::new(&config.command_secret);
+    let session_log = start_session_writer(SessionLog::new(&config.session_log));
+    let metrics = ServerMetrics::new(port, get_micro_time());
+    metrics.set_config(config.summary(offline));
+    start_metrics_server(config.metrics_port, metrics.clone());
     let room_port = port.clone();
     let mac_address = utils::get_my_mac_address()?;
-    let mut room_token = "".to_string();
     // Create an api endpoint and register this server
     // TODO: figure out way to get lan ip and mac address
-    let mut api = JamNationApi::new(&api_url, &mac_address, &String::from(git_hash));
-    while room_token == "" {
-        let _register = api.broadcast_unit_register();
-        // Activate the room
-        match api.activate_room(port, &wan_ip) {
-            Ok(res) => {
-                if let Some(tok) = res["room"]["token"].as_str() {
-                    room_token = tok.to_string();
-                }
-            }
-            Err(e) => {
-                warn!("{}", e);
-            }
-        }
-        if room_token == "" {
-            // can't connect to rtjam-nation.  sleep and then keep trying
-            sleep(Duration::new(2, 0));
-        }
-    }
+    let mut api = JamNationApi::new(&config.api_url, &mac_address, &String::from(git_hash));
+    let room_token = if offline {
+        OFFLINE_TOKEN.to_string()
+    } else {
+        register_room(&mut api, port, &config.wan_ip)
+    };
     let at_room_token = room_token.clone();
+    let shutdown = Shutdown::new();
+    catch_signals()?;
+    catch_hangup()?;
+    let mut reloader = Reloader::new(&options.config_file, command_line(&options), config.clone(), get_micro_time());
 
+    // The main loop sleeps here until one of its channels has something
+    let mut reactor = Reactor::new()?;
     // Let's create a mpsc channel to send messages to the websocket
-    let (to_ws_tx, to_ws_rx): (mpsc::Sender<WebsockMessage>, mpsc::Receiver<WebsockMessage>) =
+    let (to_room_tx, to_ws_rx): (mpsc::Sender<WebsockMessage>, mpsc::Receiver<WebsockMessage>) =
         mpsc::channel();
+    // All the threads send to this channel.  The main loop relays to the room and any local clients
+    let (to_ws_tx, to_ux_rx) = reactor.channel::<WebsockMessage>();
         
     // Let's create a mpsc stream for capturing room output
-    let (record_tx, record_rx): (mpsc::Sender<JamMessage>, mpsc::Receiver<JamMessage>) =
-        mpsc::channel();
-    // Let's create a mpsc stream for playback of room recordings
-    let (playback_tx, playback_rx): (mpsc::Sender<JamMessage>, mpsc::Receiver<JamMessage>) =
-        mpsc::channel();
+    let (record_tx, record_rx) = reactor.channel::<JamMessage>();
+    // Let's create a mpsc stream for playback of room recordings.  The audio thread paces it
+    let (playback_tx, playback_rx): (mpsc::SyncSender<JamMessage>, mpsc::Receiver<JamMessage>) =
+        mpsc::sync_channel(playback_thread::PLAYBACK_QUEUE);
     // Let's create a mpsc stream for playback thread commands
     let (playback_cmd_tx, playback_cmd_rx): (mpsc::Sender<RoomCommandMessage>, mpsc::Receiver<RoomCommandMessage>) =
     mpsc::channel();
     // Clone the ws_tx channel so the playback thread can send playback status messages
     let pback_ws_tx = to_ws_tx.clone();
+    let pback_metrics = metrics.clone();
     // Create playback thread
     let _playback_handle = thread::spawn(move || {
         let _res = playback_thread::run(
             pback_ws_tx,
             playback_cmd_rx, 
-            playback_tx);
+            playback_tx,
+            pback_metrics);
     });
 
     // Now we have the token, we can pass it to the websocket thread along with the websocket url
-    let (from_ws_tx, from_ws_rx): (
+    let (from_ws_tx, ws_commands): (
         mpsc::Sender<serde_json::Value>,
         mpsc::Receiver<serde_json::Value>,
     ) = mpsc::channel();
-    let _websocket_handle = thread::spawn(move || {
-        let _res = websocket::websocket_thread(&room_token, &ws_url, from_ws_tx, to_ws_rx);
+    // the websocket threads (shared with the sound component) send on a plain channel.  Pass their
+    // commands on to one that wakes the main loop
+    let (command_tx, from_ws_rx) = reactor.channel::<serde_json::Value>();
+    thread::spawn(move || {
+        for m in ws_commands {
+            if command_tx.send(m).is_err() {
+                break;
+            }
+        }
     });
+    // local clients get to send the same commands as the room
+    let to_local_tx = start_local_websocket(config.local_ws_port, from_ws_tx.clone());
+    let to_ux_tx = UxSender::new(to_room_tx, to_local_tx);
+    if offline {
+        // nobody out there to send to.  Chat still goes to the local websocket
+        thread::spawn(move || {
+            for msg in to_ws_rx {
+                trace!("offline, not sent: {:?}", msg);
+            }
+        });
+    } else {
+        let ws_url = config.ws_url.clone();
+        let _websocket_handle = thread::spawn(move || {
+            let _res = websocket::websocket_thread(&room_token, &ws_url, from_ws_tx, to_ws_rx);
+        });
+        let wan_ip = config.wan_ip.clone();
+        let _ping_handle = thread::spawn(move || {
+            let _res = broadcast_ping_thread(api, port, wan_ip);
+        });
+    }
 
-    // create a command channel to the audio thread 
-    let (audio_cmd_tx, audio_cmd_rx): (mpsc::Sender<RoomCommandMessage>, mpsc::Receiver<RoomCommandMessage>) =
-    mpsc::channel();
+    // create a command channel to the audio thread.  Sending on it wakes the thread up
+    let (audio_cmd_tx, audio_cmd_rx) = audio_thread::command_channel()?;
+    // the hot settings go to the audio thread as room commands, now and when they change
+    let all_keys: Vec<String> = ServerConfig::HOT_KEYS.iter().map(|k| k.to_string()).collect();
+    for cmd in room_commands(&config, &all_keys) {
+        audio_cmd_tx.send(cmd)?;
+    }
 
     // Clone the websocket channel tx so the audio thread can send to it too.
     let audio_tx = to_ws_tx.clone();
-    let _room_handle = thread::spawn(move || {
+    let room_metrics = metrics.clone();
+    let room_shutdown = shutdown.clone();
+    let mix_mode = config.mix_mode();
+    let room_handle = thread::spawn(move || {
         let _res = audio_thread::run(
             room_port, 
             audio_cmd_rx,
@@ -143,72 +405,108 @@ pub fn run(git_hash: &str) -> Result<(), BoxError> {
             &at_room_token, 
             record_tx, 
             playback_rx, 
-            room_mode);
-    });
-
-    let _ping_handle = thread::spawn(move || {
-        let _res = broadcast_ping_thread(api, port, wan_ip);
+            RoomOptions {
+                mix_mode,
+                admission,
+                metrics: room_metrics,
+                session_log,
+                shutdown: room_shutdown,
+            });
     });
 
-    let mut catalog = RecordingCatalog::new("recs")?;
-    let mut dmpfile = PacketWriter::new("audio.dmp")?;
+    let recording_dir = config.recording_dir.clone();
+    let dump_file = config.dump_file.clone();
+    let mut catalog = RecordingCatalog::new(&recording_dir)?;
+    let mut dmpfile = PacketWriter::new(&dump_file)?;
     let mut transport_update_timer = MicroTimer::new(get_micro_time(), 333_000);
-    // Now this main thread will listen on the mpsc channels
-    loop {
+    // Now this main thread will listen on the mpsc channels until it's time to stop
+    let restart = loop {
+        // sleep until there is something to do or the transport update is due
+        let now_time = get_micro_time();
+        let idle = transport_update_timer.get_interval().saturating_sub(transport_update_timer.since(now_time));
+        reactor.wait(Duration::from_micros(idle as u64 + 1))?;
         let now_time = get_micro_time();
-        let res = from_ws_rx.try_recv();
-        match res {
-            Ok(m) => {
-                // This is where we listen for commands from the room to do stuff.
-                info!("websocket message: {}", m.to_string());
-                transport_update_timer.reset(0);
-                match RoomCommandMessage::from_json(&m) {
-                    Ok(mut cmd) => match cmd.param {
+        for m in from_ws_rx.try_iter() {
+            // This is where we listen for commands from the room to do stuff.
+            info!("websocket message: {}", m.to_string());
+            transport_update_timer.reset(0);
+            let checked = auth
+                .verify(&m, (now_time / 1_000_000) as u64)
+                .map(|_| RoomCommandMessage::from_json(&m));
+            match checked {
+                Err(e) => {
+                    warn!("rejected command: {}", e);
+                    to_ws_tx.send(WebsockMessage::Chat(rejection("RoomChatRobot", &m, e)))?;
+                }
+                Ok(Ok(mut cmd)) => {
+                    let request_id = cmd.request_id.clone();
+                    // None means another thread handles (and replies to) the command
+                    let result: Option<Result<serde_json::Value, BoxError>> = match cmd.param {
                         RoomParam::Record => {
-                            dmpfile = PacketWriter::new("audio.dmp")?;
+                            dmpfile = PacketWriter::new(&dump_file)?;
                             dmpfile.is_writing = true;
+                            Some(Ok(dmpfile.get_status()))
                         }
                         RoomParam::Stop => {
                             dmpfile.is_writing = false;
                             catalog.load_recordings()?;
                             playback_cmd_tx.send(cmd)?;
+                            Some(Ok(dmpfile.get_status()))
                         }
                         RoomParam::ListFiles => {
                             catalog.load_recordings()?;
+                            Some(Ok(catalog.as_json()))
                         }
                         RoomParam::SaveRecording => {
-                            // Copy audio.dmp into the catalog
-                            catalog.add_file("audio.dmp", &cmd.svalue);
-                            dmpfile = PacketWriter::new("audio.dmp")?;
+                            // Copy the last recording into the catalog
+                            catalog.add_file(&dump_file, &cmd.svalue);
+                            dmpfile = PacketWriter::new(&dump_file)?;
+                            Some(Ok(catalog.as_json()))
                         }
                         RoomParam::DeleteRecording => {
                             if cmd.svalue == "" {
-                                dmpfile = PacketWriter::new("audio.dmp")?;
+                                dmpfile = PacketWriter::new(&dump_file)?;
                             } else {
                                 catalog.delete_file(&cmd.svalue);
                             }
+                            Some(Ok(catalog.as_json()))
                         }
                         RoomParam::Play => {
-                            if cmd.svalue == "" {
-                                cmd.svalue = "../audio.dmp".to_string();
-                            }
+                            // the playback thread gets the path
+                            cmd.svalue = if cmd.svalue.is_empty() {
+                                dump_file.clone()
+                            } else {
+                                format!("{}/{}", recording_dir, cmd.svalue)
+                            };
                             playback_cmd_tx.send(cmd)?;
+                            None
                         }
                         RoomParam::Seek => {
                             playback_cmd_tx.send(cmd)?;
+                            None
                         }
                         _ => {
                             audio_cmd_tx.send(cmd)?;
+                            None
                         }
-                    },
-                    Err(e) => {
+                    };
+                    if let (Some(id), Some(result)) = (request_id, result) {
+                        to_ws_tx.send(WebsockMessage::Chat(command_reply("RoomChatRobot", &id, result)))?;
+                    }
+                }
+                Ok(Err(e)) => {
+                    // Let a u/x waiting for a reply know it's not coming
+                    if let Some(id) = m["requestId"].as_str() {
+                        to_ws_tx.send(WebsockMessage::Chat(command_reply("RoomChatRobot", id, Err(e))))?;
+                    } else {
                         dbg!(e);
                     }
                 }
             }
-            Err(_e) => {
-                // dbg!(e);
-            }
+        }
+        // relay u/x messages from all the threads
+        for msg in to_ux_rx.try_iter() {
+            to_ux_tx.send(msg)?;
         }
         // drain out any recording audio
         for msg in record_rx.try_iter() {
@@ -222,6 +520,8 @@ pub fn run(git_hash: &str) -> Result<(), BoxError> {
         }
         if transport_update_timer.expired(now_time) {
             transport_update_timer.reset(now_time);
+            metrics.heartbeat("main", now_time);
+            metrics.set_recording(dmpfile.is_writing);
             // send transport update
             trace!("transport status {}", dmpfile.get_status());
             to_ws_tx.send(WebsockMessage::Chat(serde_json::json!({
@@ -236,24 +536,121 @@ pub fn run(git_hash: &str) -> Result<(), BoxError> {
                 })))?;
             }
         }
-        // This is the timer between channel polling
-        if restart_time() {
-            std::process::exit(-1);
+        if let Some((config, keys)) = reloader.poll(now_time) {
+            for cmd in room_commands(&config, &keys) {
+                audio_cmd_tx.send(cmd)?;
+            }
+            metrics.set_config(config.summary(offline));
+        }
+        let busy = metrics.player_count() > 0 || dmpfile.is_writing;
+        if let Some(window) = &mut maintenance {
+            if window.should_restart(now_time, Local::now().naive_local(), busy) {
+                info!("maintenance restart");
+                break true;
+            }
+        }
+        if let Some(sig) = signal() {
+            info!("caught signal {}, shutting down", sig);
+            break false;
         }
-        sleep(Duration::new(0, 500_000));
+    };
+
+    // let everybody know and wait for the audio thread to say goodbye to the players
+    shutdown.request();
+    to_ws_tx.send(WebsockMessage::Chat(serde_json::json!({
+        "speaker": "RoomChatRobot",
+        "serverGoingAway": { "restart": restart },
+    })))?;
+    if room_handle.join().is_err() {
+        warn!("audio thread panicked");
+    }
+    // finish off the recording
+    for msg in record_rx.try_iter() {
+        if let Err(e) = dmpfile.write_message(&msg) {
+            warn!("recording: {}", e);
+        }
+    }
+    dmpfile.finish()?;
+    for msg in to_ux_rx.try_iter() {
+        to_ux_tx.send(msg)?;
     }
+    sleep(SHUTDOWN_GRACE);
+    if restart {
+        // the service manager starts us back up
+        std::process::exit(-1);
+    }
+    Ok(())
+}
 
-    // Code won't ever get here
-    // let _res = room_handle.join();
-    // let _res = websocket_handle.join();
-    // Ok(())
+/// register with the rtjam-nation and activate the room.  Keeps trying until it works.  Returns the room token
+fn register_room(api: &mut JamNationApi, port: u32, wan_ip: &str) -> String {
+    loop {
+        let _register = api.broadcast_unit_register();
+        // Activate the room
+        match api.activate_room(port, wan_ip) {
+            Ok(res) => {
+                if let Some(tok) = res["room"]["token"].as_str() {
+                    return tok.to_string();
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+            }
+        }
+        // can't connect to rtjam-nation.  sleep and then keep trying
+        sleep(Duration::new(2, 0));
+    }
 }
 
-fn restart_time() -> bool {
-    let now = Local::now();
-    let current_time = now.time().round_subsecs(0);
-    if let Some(wee_hours) = NaiveTime::from_hms_opt(2, 07, 08) {
-        return current_time == wee_hours;
+/// the room admission rules from the settings
+fn build_admission(config: &ServerConfig) -> Result<AdmissionPolicy, BoxError> {
+    let mut admission = AdmissionPolicy::new();
+    if !config.allow_list_file.is_empty() {
+        admission.load_allow_list(&config.allow_list_file)?;
+    }
+    for id in &config.ban_list {
+        admission.ban(*id);
+    }
+    admission.set_password(&config.room_password);
+    admission.set_max_players(config.max_players as usize);
+    Ok(admission)
+}
+
+
+#[cfg(test)]
+mod test_broadcast_server {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_settings() {
+        let dir = std::env::temp_dir();
+        let file = dir.join(format!("broadcast_{}.json", std::process::id()));
+        std::fs::write(&file, r#"{ "port": 9000, "room_mode": "mix", "wan_ip": "1.2.3.4" }"#).unwrap();
+        let mut options = ServerOptions {
+            config_file: file.to_str().unwrap().to_string(),
+            port: Some(9100),
+            recording_dir: Some(dir.to_str().unwrap().to_string()),
+            offline: true,
+            ..Default::default()
+        };
+        let summary = check_config(&options).unwrap();
+        assert_eq!(summary["port"], 9100);
+        assert_eq!(summary["roomMode"], "mix");
+        assert_eq!(summary["wanIp"], "1.2.3.4");
+        assert_eq!(summary["offline"], true);
+        // bad values are caught before anything starts
+        options.room_mode = Some("loud".to_string());
+        assert!(check_config(&options).is_err());
+        options.room_mode = None;
+        options.recording_dir = Some("/no/such/dir".to_string());
+        assert!(check_config(&options).is_err());
+        options.recording_dir = Some(dir.to_str().unwrap().to_string());
+        // so is a settings file that is broken, has the wrong types or isn't there
+        std::fs::write(&file, r#"{ "port": 9000, "#).unwrap();
+        assert!(check_config(&options).is_err());
+        std::fs::write(&file, r#"{ "port": "9000" }"#).unwrap();
+        assert!(check_config(&options).is_err());
+        std::fs::remove_file(&file).unwrap();
+        assert!(check_config(&options).is_err());
     }
-    false
 }
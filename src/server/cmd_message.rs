@@ -1,9 +1,10 @@
 use num::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use simple_error::bail;
 use std::fmt;
 
-use crate::common::box_error::BoxError;
+use crate::common::{box_error::BoxError, command_reply::command_reply};
 
 #[derive(FromPrimitive, ToPrimitive, PartialEq, Debug)]
 pub enum RoomParam {
@@ -19,6 +20,17 @@ pub enum RoomParam {
     Loop,
     SwitchRoomMode,
     Seek,
+    SetAllowList,
+    BanPlayer,
+    UnbanPlayer,
+    SetMaxPlayers,
+    SetRoomPassword,
+    GetAdmission,
+    SetPersonalMix,
+    ClearPersonalMix,
+    GetPersonalMix,
+    SetJitterDepth,
+    SetUpdateInterval,
 }
 /// The RoomCommandMessage is used to define the API to the room component from the outside
 /// world.
@@ -28,15 +40,145 @@ pub enum RoomParam {
 ///
 /// other values are ivalue_1: integer, fvalue: float, and svalue: string.
 ///
-/// ### TODO
-/// This encoding needs to get normalized.  But it will require coordination between the u/x and the
-/// sound unit.
+/// The u/x can also send a typed [`RoomCommand`] which is converted into this encoding by
+/// [`RoomCommandMessage::from_json`].  Either form can carry a request_id and the thread that
+/// handles the command will answer with a commandReply when it is present.
 #[derive(Debug)]
 pub struct RoomCommandMessage {
     pub param: RoomParam,
     pub ivalue_1: i64,
     pub fvalue: f64,
     pub svalue: String,
+    pub request_id: Option<String>,
+}
+
+/// Typed commands for the room.  Sent by the u/x tagged by "cmd" with an optional "requestId":
+///
+/// ```json
+/// { "cmd": "setTempo", "requestId": "abc123", "bpm": 120 }
+/// { "cmd": "play", "file": "my_jam.dmp", "position": 50 }
+/// ```
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "camelCase")]
+pub enum RoomCommand {
+    GetTempo,
+    SetTempo { bpm: i64 },
+    Record,
+    Pause,
+    Stop,
+    /// play a file from the catalog (or the last recording if no file) starting at position (0-100%)
+    Play {
+        #[serde(default)]
+        file: String,
+        #[serde(default)]
+        position: i64,
+    },
+    ListFiles,
+    /// save the last recording into the catalog
+    SaveRecording { name: String },
+    /// delete a file from the catalog (or the last recording if no name)
+    DeleteRecording {
+        #[serde(default)]
+        name: String,
+    },
+    Loop,
+    SwitchRoomMode,
+    /// move playback to position (0-100%)
+    Seek { position: i64 },
+    /// only let these client ids in the room.  None lets anybody in
+    SetAllowList { ids: Option<Vec<u32>> },
+    BanPlayer { id: u32 },
+    UnbanPlayer { id: u32 },
+    /// 0 is no limit
+    SetMaxPlayers { max: i64 },
+    /// empty password opens the room
+    SetRoomPassword {
+        #[serde(default)]
+        password: String,
+    },
+    GetAdmission,
+    /// how a listener hears one source in their personal mix ("mix" room mode).
+    /// gain is dB and pan -1.0 (left) to 1.0 (right)
+    SetPersonalMix {
+        listener: u32,
+        source: u32,
+        #[serde(default)]
+        gain: f64,
+        #[serde(default)]
+        pan: f32,
+    },
+    /// back to the default mix for a listener
+    ClearPersonalMix { listener: u32 },
+    GetPersonalMix { listener: u32 },
+    /// how shallow and deep (samples) the room's jitter buffers adapt ("mix" room mode)
+    SetJitterDepth { min: u32, max: u32 },
+    /// how often (msec) the room latency message goes out
+    SetUpdateInterval { msec: i64 },
+}
+
+/// A typed room command plus the optional id used to match the reply
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoomCommandRequest {
+    #[serde(rename = "requestId")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub command: RoomCommand,
+}
+
+impl RoomCommand {
+    /// convert to the legacy encoding understood by the room threads
+    pub fn to_message(&self) -> RoomCommandMessage {
+        match self {
+            RoomCommand::GetTempo => RoomCommandMessage::new(RoomParam::GetTempo, 0, 0.0, ""),
+            RoomCommand::SetTempo { bpm } => RoomCommandMessage::new(RoomParam::SetTempo, *bpm, 0.0, ""),
+            RoomCommand::Record => RoomCommandMessage::new(RoomParam::Record, 0, 0.0, ""),
+            RoomCommand::Pause => RoomCommandMessage::new(RoomParam::Pause, 0, 0.0, ""),
+            RoomCommand::Stop => RoomCommandMessage::new(RoomParam::Stop, 0, 0.0, ""),
+            RoomCommand::Play { file, position } => {
+                RoomCommandMessage::new(RoomParam::Play, *position, 0.0, file)
+            }
+            RoomCommand::ListFiles => RoomCommandMessage::new(RoomParam::ListFiles, 0, 0.0, ""),
+            RoomCommand::SaveRecording { name } => {
+                RoomCommandMessage::new(RoomParam::SaveRecording, 0, 0.0, name)
+            }
+            RoomCommand::DeleteRecording { name } => {
+                RoomCommandMessage::new(RoomParam::DeleteRecording, 0, 0.0, name)
+            }
+            RoomCommand::Loop => RoomCommandMessage::new(RoomParam::Loop, 0, 0.0, ""),
+            RoomCommand::SwitchRoomMode => RoomCommandMessage::new(RoomParam::SwitchRoomMode, 0, 0.0, ""),
+            RoomCommand::Seek { position } => RoomCommandMessage::new(RoomParam::Seek, *position, 0.0, ""),
+            RoomCommand::SetAllowList { ids } => {
+                let ids = ids.as_ref().map(|ids| json!(ids).to_string()).unwrap_or_default();
+                RoomCommandMessage::new(RoomParam::SetAllowList, 0, 0.0, &ids)
+            }
+            RoomCommand::BanPlayer { id } => RoomCommandMessage::new(RoomParam::BanPlayer, *id as i64, 0.0, ""),
+            RoomCommand::UnbanPlayer { id } => {
+                RoomCommandMessage::new(RoomParam::UnbanPlayer, *id as i64, 0.0, "")
+            }
+            RoomCommand::SetMaxPlayers { max } => RoomCommandMessage::new(RoomParam::SetMaxPlayers, *max, 0.0, ""),
+            RoomCommand::SetRoomPassword { password } => {
+                RoomCommandMessage::new(RoomParam::SetRoomPassword, 0, 0.0, password)
+            }
+            RoomCommand::GetAdmission => RoomCommandMessage::new(RoomParam::GetAdmission, 0, 0.0, ""),
+            RoomCommand::SetPersonalMix { listener, source, gain, pan } => {
+                let setting = json!({ "source": source, "gain": gain, "pan": pan }).to_string();
+                RoomCommandMessage::new(RoomParam::SetPersonalMix, *listener as i64, 0.0, &setting)
+            }
+            RoomCommand::ClearPersonalMix { listener } => {
+                RoomCommandMessage::new(RoomParam::ClearPersonalMix, *listener as i64, 0.0, "")
+            }
+            RoomCommand::GetPersonalMix { listener } => {
+                RoomCommandMessage::new(RoomParam::GetPersonalMix, *listener as i64, 0.0, "")
+            }
+            RoomCommand::SetJitterDepth { min, max } => {
+                let setting = json!({ "min": min, "max": max }).to_string();
+                RoomCommandMessage::new(RoomParam::SetJitterDepth, 0, 0.0, &setting)
+            }
+            RoomCommand::SetUpdateInterval { msec } => {
+                RoomCommandMessage::new(RoomParam::SetUpdateInterval, *msec, 0.0, "")
+            }
+        }
+    }
 }
 impl RoomCommandMessage {
     pub fn new(param: RoomParam, ival1: i64, fval: f64, sval: &str) -> RoomCommandMessage {
@@ -45,21 +187,39 @@ impl RoomCommandMessage {
             ivalue_1: ival1,
             fvalue: fval,
             svalue: String::from(sval),
+            request_id: None,
         }
     }
     pub fn as_json(&self) -> serde_json::Value {
-        json!({
+        let mut rval = json!({
           "param": num::ToPrimitive::to_usize(&self.param),
           "iValue1": self.ivalue_1,
           "fValue": self.fvalue,
           "sValue": self.svalue,
-        })
+        });
+        if let Some(id) = &self.request_id {
+            rval["requestId"] = json!(id);
+        }
+        rval
+    }
+    /// build the commandReply for this message.  None if the sender did not ask for one
+    pub fn reply(&self, result: Result<serde_json::Value, BoxError>) -> Option<serde_json::Value> {
+        self.request_id
+            .as_ref()
+            .map(|id| command_reply("RoomChatRobot", id, result))
     }
     pub fn from_string(data: &str) -> Result<RoomCommandMessage, BoxError> {
         let raw = serde_json::from_str(data)?;
         Self::from_json(&raw)
     }
     pub fn from_json(raw: &serde_json::Value) -> Result<RoomCommandMessage, BoxError> {
+        if raw["cmd"].is_string() {
+            // This is a typed command
+            let req: RoomCommandRequest = serde_json::from_value(raw.clone())?;
+            let mut msg = req.command.to_message();
+            msg.request_id = req.request_id;
+            return Ok(msg);
+        }
         if !(raw["param"].is_i64() || raw["param"].is_string()) {
             bail!("no param in message");
         }
@@ -91,6 +251,9 @@ impl RoomCommandMessage {
                 if raw["sValue"].is_string() {
                     msg.svalue = String::from(raw["sValue"].as_str().unwrap());
                 }
+                if raw["requestId"].is_string() {
+                    msg.request_id = Some(String::from(raw["requestId"].as_str().unwrap()));
+                }
                 Ok(msg)
             }
             None => {
@@ -147,5 +310,34 @@ mod test_cmd_message {
         let raw: serde_json::Value = serde_json::from_str(data).unwrap();
         let msg = RoomCommandMessage::from_json(&raw).unwrap();
         assert_eq!(msg.param, RoomParam::Pause);
+        assert!(msg.request_id.is_none());
+    }
+    #[test]
+    fn from_typed_command() {
+        let raw = json!({"cmd": "setTempo", "requestId": "t1", "bpm": 120});
+        let msg = RoomCommandMessage::from_json(&raw).unwrap();
+        assert_eq!(msg.param, RoomParam::SetTempo);
+        assert_eq!(msg.ivalue_1, 120);
+        let reply = msg.reply(Ok(json!({"tempo": 120}))).unwrap();
+        assert_eq!(reply["speaker"], "RoomChatRobot");
+        assert_eq!(reply["commandReply"]["requestId"], "t1");
+        // optional fields default
+        let msg = RoomCommandMessage::from_json(&json!({"cmd": "play"})).unwrap();
+        assert_eq!(msg.param, RoomParam::Play);
+        assert_eq!(msg.svalue, "");
+        assert!(msg.reply(Ok(json!({}))).is_none());
+        // required fields are required
+        assert!(RoomCommandMessage::from_json(&json!({"cmd": "seek"})).is_err());
+    }
+    #[test]
+    fn admission_commands() {
+        let msg = RoomCommandMessage::from_json(&json!({"cmd": "setAllowList", "ids": [1, 2]})).unwrap();
+        assert_eq!(msg.param, RoomParam::SetAllowList);
+        assert_eq!(msg.svalue, "[1,2]");
+        let msg = RoomCommandMessage::from_json(&json!({"cmd": "setAllowList", "ids": null})).unwrap();
+        assert_eq!(msg.svalue, "");
+        let msg = RoomCommandMessage::from_json(&json!({"cmd": "banPlayer", "id": 44})).unwrap();
+        assert_eq!(msg.param, RoomParam::BanPlayer);
+        assert_eq!(msg.ivalue_1, 44);
     }
 }
@@ -0,0 +1,123 @@
+//! Send a batch of datagrams with one system call
+//!
+//! Forwarding one player's packet to the rest of the room (or sending everybody their mix) is
+//! one datagram per player.  On linux [`send_all`] hands the whole batch to `sendmmsg` so a
+//! full room costs one trip into the kernel instead of one per player.  Other platforms fall
+//! back to a `send_to` loop.
+//!
+//! A datagram that fails to send is skipped and counted.  The rest of the batch still goes out.
+use std::net::{SocketAddr, UdpSocket};
+
+use log::debug;
+
+/// most datagrams handed to the kernel in one call
+pub const MAX_BATCH: usize = 64;
+
+/// one datagram: what to send and where
+pub type Datagram<'a> = (&'a [u8], SocketAddr);
+
+/// send all the datagrams.  returns the number that could not be sent
+#[cfg(target_os = "linux")]
+pub fn send_all(sock: &UdpSocket, packets: &[Datagram]) -> u64 {
+    use std::io::ErrorKind;
+    let mut failed = 0;
+    let mut start = 0;
+    while start < packets.len() {
+        let end = packets.len().min(start + MAX_BATCH);
+        match send_mmsg(sock, &packets[start..end]) {
+            Ok(0) => {
+                // should not happen, but don't spin on it
+                failed += 1;
+                start += 1;
+            }
+            Ok(sent) => start += sent,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => {
+                // the first one in the batch failed.  skip it
+                debug!("send to {} failed: {}", packets[start].1, e);
+                failed += 1;
+                start += 1;
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(target_os = "linux")]
+fn send_mmsg(sock: &UdpSocket, packets: &[Datagram]) -> std::io::Result<usize> {
+    use socket2::SockAddr;
+    use std::os::fd::AsRawFd;
+    let addrs: Vec<SockAddr> = packets.iter().map(|(_, addr)| SockAddr::from(*addr)).collect();
+    let mut iovs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|(buf, _)| libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = iovs
+        .iter_mut()
+        .zip(addrs.iter())
+        .map(|(iov, addr)| {
+            // SAFETY: all zeros is a valid (empty) msghdr
+            let mut hdr: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = addr.len();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr
+        })
+        .collect();
+    // SAFETY: the headers point into addrs, iovs and the caller's buffers which all outlive the
+    // call.  The kernel only reads them (and writes msg_len)
+    let sent = unsafe {
+        libc::sendmmsg(sock.as_raw_fd(), hdrs.as_mut_ptr(), hdrs.len() as libc::c_uint, 0)
+    };
+    if sent < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+/// send all the datagrams.  returns the number that could not be sent
+#[cfg(not(target_os = "linux"))]
+pub fn send_all(sock: &UdpSocket, packets: &[Datagram]) -> u64 {
+    let mut failed = 0;
+    for (buf, addr) in packets {
+        if let Err(e) = sock.send_to(buf, addr) {
+            debug!("send to {} failed: {}", addr, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod test_fan_out {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn sends_everything() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rx: Vec<UdpSocket> = (0..3).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+        let payload = [7u8; 100];
+        // more than one batch worth
+        let packets: Vec<Datagram> = (0..MAX_BATCH + 6)
+            .map(|n| (&payload[0..10 + n % 3], rx[n % 3].local_addr().unwrap()))
+            .collect();
+        assert_eq!(send_all(&sock, &packets), 0);
+        let mut buf = [0u8; 200];
+        for r in &rx {
+            r.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            let (amt, src) = r.recv_from(&mut buf).unwrap();
+            assert_eq!(src, sock.local_addr().unwrap());
+            assert_eq!(&buf[0..amt], &payload[0..amt]);
+        }
+        let total: usize = rx.iter().map(|r| {
+            r.set_nonblocking(true).unwrap();
+            1 + std::iter::from_fn(|| r.recv_from(&mut buf).ok()).count()
+        }).sum();
+        assert_eq!(total, MAX_BATCH + 6);
+    }
+}
@@ -0,0 +1,122 @@
+//! Steady frame cadence for the broadcast output
+//!
+//! The room mix and the playback stream go out one packet per frame (128 samples at 48k) no matter
+//! when packets come in.  The audio thread asks the [`FrameClock`] if a frame is due every time
+//! around its loop and uses [`FrameClock::wait_time`] as the socket read timeout so it wakes up in
+//! time for the next one.
+//!
+//! If the thread falls more than [`MAX_CATCHUP`] frames behind (the machine stalled) the clock
+//! skips ahead rather than bursting out a pile of frames.  The pacing stats (interval between
+//! frames, how late they went out, skipped frames) go in the `RoomChatRobot` latency message.
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use crate::common::stream_time_stat::StreamTimeStat;
+
+/// usec per 128 sample frame at 48k
+pub const FRAME_TIME: u128 = 2_667;
+/// most frames we will send to catch up after a stall
+pub const MAX_CATCHUP: u128 = 4;
+/// shortest socket wait (usec).  A zero timeout is an error
+const MIN_WAIT: u128 = 100;
+
+pub struct FrameClock {
+    period: u128,
+    next_due: u128,
+    last_tick: u128,
+    frames: u64,
+    skipped: u64,
+    max_late: u128,
+    intervals: StreamTimeStat,
+    lateness: StreamTimeStat,
+}
+
+impl FrameClock {
+    pub fn new(now: u128, period: u128) -> FrameClock {
+        FrameClock {
+            period,
+            next_due: now + period,
+            last_tick: now,
+            frames: 0,
+            skipped: 0,
+            max_late: 0,
+            intervals: StreamTimeStat::new(100),
+            lateness: StreamTimeStat::new(100),
+        }
+    }
+    /// Is a frame due?  Call until it says no, sending a frame each time it says yes.
+    pub fn tick(&mut self, now: u128) -> bool {
+        if now < self.next_due {
+            return false;
+        }
+        let behind = (now - self.next_due) / self.period;
+        if behind > MAX_CATCHUP {
+            // stalled.  jump ahead to the current frame
+            self.skipped += (behind - MAX_CATCHUP) as u64;
+            self.next_due += (behind - MAX_CATCHUP) * self.period;
+        }
+        let late = now - self.next_due;
+        self.max_late = self.max_late.max(late);
+        self.lateness.add_sample(late as f64);
+        self.intervals.add_sample((now - self.last_tick) as f64);
+        self.last_tick = now;
+        self.next_due += self.period;
+        self.frames += 1;
+        true
+    }
+    /// how long to wait for packets before the next frame is due
+    pub fn wait_time(&self, now: u128) -> Duration {
+        let wait = self.next_due.saturating_sub(now).max(MIN_WAIT);
+        Duration::from_micros(wait as u64)
+    }
+    pub fn as_json(&self) -> Value {
+        json!({
+            "frames": self.frames,
+            "skipped": self.skipped,
+            "intervalMean": self.intervals.get_mean(),
+            "intervalSigma": self.intervals.get_sigma(),
+            "lateMean": self.lateness.get_mean(),
+            "lateMax": self.max_late,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_frame_clock {
+    use super::*;
+
+    #[test]
+    fn steady_ticks() {
+        let mut clock = FrameClock::new(0, FRAME_TIME);
+        assert!(!clock.tick(FRAME_TIME - 1));
+        assert_eq!(clock.wait_time(FRAME_TIME - 1000), Duration::from_micros(1000));
+        assert!(clock.tick(FRAME_TIME));
+        assert!(!clock.tick(FRAME_TIME + 1));
+        // a little late still gets all the frames out
+        let now = 4 * FRAME_TIME + 10;
+        let mut sent = 0;
+        while clock.tick(now) {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+        assert_eq!(clock.as_json()["skipped"], 0);
+        assert_eq!(clock.wait_time(now), Duration::from_micros((FRAME_TIME - 10) as u64));
+    }
+
+    #[test]
+    fn skips_after_stall() {
+        let mut clock = FrameClock::new(0, FRAME_TIME);
+        let now = 100 * FRAME_TIME;
+        let mut sent = 0;
+        while clock.tick(now) {
+            sent += 1;
+        }
+        assert_eq!(sent, MAX_CATCHUP + 1);
+        assert_eq!(clock.as_json()["skipped"], 100 - MAX_CATCHUP as u64 - 1);
+        assert_eq!(clock.as_json()["frames"], MAX_CATCHUP as u64 + 1);
+        // and is back on schedule
+        assert!(!clock.tick(now + 1));
+        assert!(clock.tick(now + FRAME_TIME));
+    }
+}
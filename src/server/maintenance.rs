@@ -0,0 +1,111 @@
+//! When the broadcast component restarts itself
+//!
+//! The server restarts once a night to pick up updates and start fresh.  Rather than at a fixed
+//! time, it waits until it is inside the [`MaintenanceWindow`] (local time) and the room has been
+//! idle (no players, not recording) for a while.  If the room stays busy through the whole window
+//! the restart waits for the next night.
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use simple_error::bail;
+
+use crate::common::box_error::BoxError;
+
+pub struct MaintenanceWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+    // usec the room has to be idle
+    idle_time: u128,
+    // when this process started (local time)
+    started: NaiveDateTime,
+    idle_since: Option<u128>,
+}
+
+impl MaintenanceWindow {
+    /// window is "HH:MM-HH:MM" local time and can wrap past midnight.  Empty means never restart
+    pub fn parse(window: &str, idle_secs: u32, started: NaiveDateTime) -> Result<Option<MaintenanceWindow>, BoxError> {
+        if window.trim().is_empty() {
+            return Ok(None);
+        }
+        let (start, end) = match window.split_once('-') {
+            Some((s, e)) => (
+                NaiveTime::parse_from_str(s.trim(), "%H:%M")?,
+                NaiveTime::parse_from_str(e.trim(), "%H:%M")?,
+            ),
+            None => bail!("maintenance window {} should look like 02:00-05:00", window),
+        };
+        Ok(Some(MaintenanceWindow {
+            start,
+            end,
+            idle_time: idle_secs as u128 * 1_000_000,
+            started,
+            idle_since: None,
+        }))
+    }
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+    // when the window we are in (or the last one) opened
+    fn opened(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let today = now.date().and_time(self.start);
+        if today <= now {
+            today
+        } else {
+            today - Duration::days(1)
+        }
+    }
+    /// call this regularly.  now_time is the micro time and now the local time.  busy means there
+    /// is somebody in the room.  Returns true when it's time to restart
+    pub fn should_restart(&mut self, now_time: u128, now: NaiveDateTime, busy: bool) -> bool {
+        if busy {
+            self.idle_since = None;
+            return false;
+        }
+        let idle_since = *self.idle_since.get_or_insert(now_time);
+        // once a window.  Not again if we started up inside it
+        self.contains(now.time()) && self.started < self.opened(now) && now_time >= idle_since + self.idle_time
+    }
+}
+
+#[cfg(test)]
+mod test_maintenance {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, h: u32, m: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, day).unwrap().and_hms_opt(h, m, 0).unwrap()
+    }
+    fn usec(t: NaiveDateTime) -> u128 {
+        t.and_utc().timestamp_micros() as u128
+    }
+
+    #[test]
+    fn waits_for_idle_room() {
+        let mut w = MaintenanceWindow::parse("02:00-05:00", 600, at(1, 12, 0)).unwrap().unwrap();
+        // idle, but not in the window
+        assert!(!w.should_restart(usec(at(1, 23, 0)), at(1, 23, 0), false));
+        // busy late night session
+        assert!(!w.should_restart(usec(at(2, 2, 30)), at(2, 2, 30), true));
+        // they left, still have to wait out the idle time
+        assert!(!w.should_restart(usec(at(2, 2, 35)), at(2, 2, 35), false));
+        assert!(!w.should_restart(usec(at(2, 2, 40)), at(2, 2, 40), false));
+        assert!(w.should_restart(usec(at(2, 2, 45)), at(2, 2, 45), false));
+        // started inside the window, wait for tomorrow
+        let mut w = MaintenanceWindow::parse("02:00-05:00", 0, at(2, 2, 45)).unwrap().unwrap();
+        assert!(!w.should_restart(usec(at(2, 3, 0)), at(2, 3, 0), false));
+        assert!(w.should_restart(usec(at(3, 2, 0)), at(3, 2, 0), false));
+    }
+
+    #[test]
+    fn parses_windows() {
+        assert!(MaintenanceWindow::parse("", 600, at(1, 0, 0)).unwrap().is_none());
+        assert!(MaintenanceWindow::parse("2am", 600, at(1, 0, 0)).is_err());
+        // wraps around midnight
+        let w = MaintenanceWindow::parse("23:30 - 01:00", 0, at(1, 0, 0)).unwrap().unwrap();
+        assert!(w.contains(NaiveTime::from_hms_opt(0, 15, 0).unwrap()));
+        assert!(!w.contains(NaiveTime::from_hms_opt(1, 15, 0).unwrap()));
+        assert_eq!(w.opened(at(2, 0, 15)), at(1, 23, 30));
+    }
+}
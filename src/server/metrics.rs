@@ -0,0 +1,411 @@
+//! Prometheus metrics and a json health check for the broadcast server
+//!
+//! Set `metrics_port` in settings.json to turn it on (0, the default, leaves it off).  The
+//! listener answers:
+//! - `GET /metrics` with Prometheus text format metrics
+//! - `GET /health` with json: the roster (client ids, not addresses), the config and how long
+//!   since each thread checked in.  The status is 503 when the room is degraded (a thread stopped
+//!   checking in or the audio thread has not published yet) so it can be alerted on.
+//!
+//! The threads publish into a shared [`ServerMetrics`].  The audio thread sends a
+//! [`RoomSnapshot`] every second and every thread calls [`ServerMetrics::heartbeat`].  A scrape only
+//! reads what was published so it never holds up the audio.
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    common::{box_error::BoxError, get_micro_time, player::Player, seq_tracker::SeqStats, session_report::Percentiles},
+    server::{fan_out::Datagram, packet_guard::GuardStats},
+};
+
+/// a thread that hasn't checked in for this long (usec) is considered dead
+pub const THREAD_STALE: u128 = 5_000_000;
+
+/// packet and byte counters for the room socket
+#[derive(Default, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Traffic {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+}
+
+impl Traffic {
+    pub fn received(&mut self, amt: usize) {
+        self.packets_in += 1;
+        self.bytes_in += amt as u64;
+    }
+    /// count a batch from [`send_all`](crate::server::fan_out::send_all).  The failed ones aren't counted
+    /// as packets but their bytes are (we don't know which ones they were)
+    pub fn sent(&mut self, packets: &[Datagram], failed: u64) {
+        self.packets_out += (packets.len() as u64).saturating_sub(failed);
+        self.bytes_out += packets.iter().map(|(buf, _)| buf.len() as u64).sum::<u64>();
+    }
+}
+
+/// What the metrics show about one player.  No address, the endpoint isn't authenticated
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerMetrics {
+    pub client_id: u32,
+    pub loop_time: f64,
+    pub drops: usize,
+    pub packets: usize,
+    pub seq: SeqStats,
+    #[serde(skip)]
+    pub loop_percentiles: Percentiles,
+}
+
+impl From<&Player> for PlayerMetrics {
+    fn from(player: &Player) -> Self {
+        PlayerMetrics {
+            client_id: player.client_id,
+            loop_time: player.get_last_loop(),
+            drops: player.get_drops(),
+            packets: player.get_packet_count(),
+            seq: player.get_seq_stats().clone(),
+            loop_percentiles: player.get_loop_percentiles(),
+        }
+    }
+}
+
+/// The audio thread's view of the room
+#[derive(Default, Clone)]
+pub struct RoomSnapshot {
+    pub mix_mode: bool,
+    pub players: Vec<PlayerMetrics>,
+    pub traffic: Traffic,
+    pub guard: GuardStats,
+    pub blocked_sources: usize,
+}
+
+struct MetricsState {
+    port: u32,
+    started: u128,
+    room: Option<RoomSnapshot>,
+    threads: BTreeMap<String, u128>,
+    recording: bool,
+    config: Value,
+}
+
+/// Shared between the threads that publish and the http thread that reads.  Clone it to hand it out
+#[derive(Clone)]
+pub struct ServerMetrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+impl ServerMetrics {
+    pub fn new(port: u32, now_time: u128) -> ServerMetrics {
+        ServerMetrics {
+            state: Arc::new(Mutex::new(MetricsState {
+                port,
+                started: now_time,
+                room: None,
+                threads: BTreeMap::new(),
+                recording: false,
+                config: json!({}),
+            })),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, MetricsState> {
+        // a panicked publisher doesn't make the numbers any less useful
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    /// a thread is still alive
+    pub fn heartbeat(&self, thread: &str, now_time: u128) {
+        self.lock().threads.insert(thread.to_string(), now_time);
+    }
+    pub fn publish_room(&self, room: RoomSnapshot) {
+        self.lock().room = Some(room);
+    }
+    /// players in the room as of the last snapshot
+    pub fn player_count(&self) -> usize {
+        self.lock().room.as_ref().map_or(0, |r| r.players.len())
+    }
+    pub fn set_recording(&self, recording: bool) {
+        self.lock().recording = recording;
+    }
+    /// settings shown on the health check.  Leave out the secrets
+    pub fn set_config(&self, config: Value) {
+        self.lock().config = config;
+    }
+    /// what's wrong with the room.  Empty means healthy
+    pub fn problems(&self, now_time: u128) -> Vec<String> {
+        let state = self.lock();
+        let mut problems = vec![];
+        if state.room.is_none() {
+            problems.push("audio thread has not published".to_string());
+        }
+        for (name, last) in &state.threads {
+            if now_time > last + THREAD_STALE {
+                problems.push(format!("{} thread stopped", name));
+            }
+        }
+        problems
+    }
+    /// the /health json
+    pub fn health_json(&self, now_time: u128) -> Value {
+        let problems = self.problems(now_time);
+        let state = self.lock();
+        let threads: BTreeMap<&String, f64> = state
+            .threads
+            .iter()
+            .map(|(name, last)| (name, now_time.saturating_sub(*last) as f64 / 1_000_000.0))
+            .collect();
+        let (mode, players) = match &state.room {
+            Some(room) => (if room.mix_mode { "mix" } else { "separate" }, json!(room.players)),
+            None => ("unknown", json!([])),
+        };
+        json!({
+            "status": if problems.is_empty() { "ok" } else { "degraded" },
+            "problems": problems,
+            "uptime": now_time.saturating_sub(state.started) / 1_000_000,
+            "port": state.port,
+            "roomMode": mode,
+            "players": players,
+            "recording": state.recording,
+            "threads": threads,
+            "config": state.config,
+        })
+    }
+    /// Prometheus text format
+    pub fn render(&self, now_time: u128) -> String {
+        let state = self.lock();
+        let port = format!("port=\"{}\"", state.port);
+        let mut out = String::new();
+        header(&mut out, "rtjam_thread_up", "1 if the thread checked in recently", "gauge");
+        for (name, last) in &state.threads {
+            let up = now_time <= last + THREAD_STALE;
+            out.push_str(&format!("rtjam_thread_up{{thread=\"{}\"}} {}\n", name, up as u8));
+        }
+        header(&mut out, "rtjam_recording", "1 while the room is being recorded", "gauge");
+        out.push_str(&format!("rtjam_recording{{{}}} {}\n", port, state.recording as u8));
+        let room = match &state.room {
+            Some(room) => room,
+            None => return out,
+        };
+        header(&mut out, "rtjam_players", "players in the room", "gauge");
+        out.push_str(&format!("rtjam_players{{{}}} {}\n", port, room.players.len()));
+        header(&mut out, "rtjam_room_mix_mode", "1 if the server mixes the room", "gauge");
+        out.push_str(&format!("rtjam_room_mix_mode{{{}}} {}\n", port, room.mix_mode as u8));
+        let counters = [
+            ("rtjam_packets_in_total", "packets read from the room socket", room.traffic.packets_in),
+            ("rtjam_bytes_in_total", "bytes read from the room socket", room.traffic.bytes_in),
+            ("rtjam_packets_out_total", "packets sent to players", room.traffic.packets_out),
+            ("rtjam_bytes_out_total", "bytes sent to players", room.traffic.bytes_out),
+            ("rtjam_socket_errors_total", "failed socket reads and sends", room.guard.socket_errors),
+        ];
+        for (name, help, value) in counters {
+            header(&mut out, name, help, "counter");
+            out.push_str(&format!("{}{{{}}} {}\n", name, port, value));
+        }
+        header(&mut out, "rtjam_dropped_packets_total", "packets dropped before reaching the room", "counter");
+        let drops = [
+            ("malformed", room.guard.malformed),
+            ("oversize", room.guard.oversize),
+            ("unknown_client", room.guard.unknown_client),
+            ("rate_limited", room.guard.rate_limited),
+            ("blocked", room.guard.blocked),
+            ("too_many_sources", room.guard.too_many_sources),
+        ];
+        for (reason, value) in drops {
+            out.push_str(&format!("rtjam_dropped_packets_total{{{},reason=\"{}\"}} {}\n", port, reason, value));
+        }
+        header(&mut out, "rtjam_blocked_sources", "source addresses currently blocked", "gauge");
+        out.push_str(&format!("rtjam_blocked_sources{{{}}} {}\n", port, room.blocked_sources));
+        let label = |p: &PlayerMetrics| format!("{},client_id=\"{}\"", port, p.client_id);
+        header(&mut out, "rtjam_player_packets_total", "packets from the player", "counter");
+        for p in &room.players {
+            out.push_str(&format!("rtjam_player_packets_total{{{}}} {}\n", label(p), p.packets));
+        }
+        header(&mut out, "rtjam_player_sequence_events_total", "lost, reordered, late and duplicated packets from the player", "counter");
+        for p in &room.players {
+            let kinds = [("lost", p.seq.lost), ("reordered", p.seq.reordered), ("late", p.seq.late), ("duplicated", p.seq.duplicated)];
+            for (kind, value) in kinds {
+                out.push_str(&format!("rtjam_player_sequence_events_total{{{},kind=\"{}\"}} {}\n", label(p), kind, value));
+            }
+        }
+        header(&mut out, "rtjam_player_jitter_microseconds", "RFC 3550 interarrival jitter", "gauge");
+        for p in &room.players {
+            out.push_str(&format!("rtjam_player_jitter_microseconds{{{}}} {}\n", label(p), p.seq.jitter));
+        }
+        header(&mut out, "rtjam_player_loop_time_microseconds", "recent round trip through the server by quantile", "gauge");
+        for p in &room.players {
+            let quantiles = [("0.5", p.loop_percentiles.p50), ("0.95", p.loop_percentiles.p95), ("0.99", p.loop_percentiles.p99)];
+            for (quantile, value) in quantiles {
+                out.push_str(&format!("rtjam_player_loop_time_microseconds{{{},quantile=\"{}\"}} {}\n", label(p), quantile, value));
+            }
+        }
+        out
+    }
+}
+
+/// HELP and TYPE lines that go before a metric's samples
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+}
+
+/// Open the metrics port.  Split from the thread so the caller finds out right away if it's taken
+pub fn metrics_listener(port: u32) -> Result<TcpListener, BoxError> {
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))?;
+    info!("metrics listening on port {}", port);
+    Ok(listener)
+}
+
+/// answer scrapes and health checks forever
+pub fn metrics_thread(listener: TcpListener, metrics: ServerMetrics) -> Result<(), BoxError> {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => serve(stream, &metrics),
+            Err(e) => debug!("metrics accept error: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// start the metrics thread unless the port is 0.  Failing to listen is logged, not fatal
+pub fn start_metrics_server(port: u32, metrics: ServerMetrics) {
+    if port == 0 {
+        info!("metrics disabled");
+        return;
+    }
+    match metrics_listener(port) {
+        Ok(listener) => {
+            let _metrics_handle = thread::spawn(move || {
+                if let Err(e) = metrics_thread(listener, metrics) {
+                    error!("metrics thread exited with error: {}", e);
+                }
+            });
+        }
+        Err(e) => error!("metrics can't listen on port {}: {}", port, e),
+    }
+}
+
+fn serve(mut stream: TcpStream, metrics: &ServerMetrics) {
+    let _res = stream.set_read_timeout(Some(Duration::new(1, 0)));
+    let mut buf = [0; 2048];
+    let n = match stream.read(&mut buf) {
+        Ok(n) => n,
+        Err(e) => {
+            debug!("metrics read error: {}", e);
+            return;
+        }
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let now_time = get_micro_time();
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.render(now_time)),
+        "/health" => {
+            let health = metrics.health_json(now_time);
+            let status = if health["status"] == "ok" { "200 OK" } else { "503 Service Unavailable" };
+            (status, "application/json", health.to_string())
+        }
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()) {
+        debug!("metrics write error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn room() -> RoomSnapshot {
+        let addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut traffic = Traffic::default();
+        traffic.received(100);
+        traffic.sent(&[(&[0u8; 60], addr), (&[0u8; 60], addr)], 1);
+        RoomSnapshot {
+            mix_mode: false,
+            players: vec![PlayerMetrics {
+                client_id: 7,
+                loop_time: 12_000.0,
+                drops: 3,
+                packets: 1000,
+                seq: SeqStats { lost: 3, late: 1, ..Default::default() },
+                loop_percentiles: Percentiles { p50: 12_000.0, p95: 40_000.0, p99: 60_000.0 },
+            }],
+            traffic,
+            guard: GuardStats { malformed: 2, ..Default::default() },
+            blocked_sources: 0,
+        }
+    }
+
+    #[test]
+    fn renders_prometheus() {
+        let metrics = ServerMetrics::new(7891, 0);
+        metrics.heartbeat("audio", 0);
+        metrics.publish_room(room());
+        let text = metrics.render(1_000_000);
+        assert!(text.contains("rtjam_players{port=\"7891\"} 1\n"));
+        assert!(text.contains("rtjam_packets_out_total{port=\"7891\"} 1\n"));
+        assert!(text.contains("rtjam_bytes_in_total{port=\"7891\"} 100\n"));
+        assert!(text.contains("rtjam_dropped_packets_total{port=\"7891\",reason=\"malformed\"} 2\n"));
+        assert!(text.contains("rtjam_player_loop_time_microseconds{port=\"7891\",client_id=\"7\",quantile=\"0.95\"} 40000\n"));
+        assert!(text.contains("# TYPE rtjam_player_loop_time_microseconds gauge\n"));
+        assert!(text.contains("rtjam_thread_up{thread=\"audio\"} 1\n"));
+        assert!(text.contains("rtjam_player_sequence_events_total{port=\"7891\",client_id=\"7\",kind=\"late\"} 1\n"));
+        assert_eq!(text.matches("# TYPE rtjam_dropped_packets_total ").count(), 1);
+    }
+
+    #[test]
+    fn health_check() {
+        let metrics = ServerMetrics::new(7891, 0);
+        assert_eq!(metrics.health_json(0)["status"], "degraded");
+        metrics.heartbeat("audio", 0);
+        metrics.publish_room(room());
+        metrics.set_config(json!({ "roomMode": "separate" }));
+        let health = metrics.health_json(1_000_000);
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["players"][0]["clientId"], 7);
+        assert!(health["players"][0]["address"].is_null());
+        assert_eq!(health["config"]["roomMode"], "separate");
+        // audio thread went quiet
+        let health = metrics.health_json(THREAD_STALE + 1);
+        assert_eq!(health["status"], "degraded");
+        assert_eq!(health["problems"][0], "audio thread stopped");
+    }
+
+    #[test]
+    fn serves_http() {
+        let metrics = ServerMetrics::new(7891, 0);
+        let listener = metrics_listener(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let m = metrics.clone();
+        thread::spawn(move || metrics_thread(listener, m));
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(get("/health").starts_with("HTTP/1.1 503"));
+        metrics.publish_room(room());
+        let response = get("/health");
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(get("/metrics").contains("rtjam_players{port=\"7891\"} 1"));
+        assert!(get("/nope").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,257 @@
+//! Protects the room from floods and junk packets
+//!
+//! Every datagram read by the [`audio_thread`](crate::server::audio_thread) goes through the
+//! [`PacketGuard`] before anything else looks at it.  The guard:
+//! - checks the size against the header (`num_audio_chunks` and the 2 channel sample layout)
+//! - rate limits each source address with a token bucket.  A sound component sends one packet
+//!   every 128 samples (375 a second) so the limit leaves plenty of room for that
+//! - blocks a source for [`BLOCK_TIME`] if it keeps sending packets that get dropped
+//! - keeps track of at most [`MAX_SOURCES`] addresses.  Packets from new ones past that are dropped
+//!   so a flood from spoofed addresses can't grow the table
+//!
+//! It also keeps the counters that show up in the `RoomChatRobot` latency message.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::common::jam_packet::{JamMessage, JAM_BUF_SIZE, JAM_HEADER_SIZE, PACKET_AUDIO, PACKET_JOIN, PACKET_REPORT};
+
+/// packets per second a source can send
+pub const RATE_LIMIT: f64 = 1000.0;
+/// how many packets a source can send in a burst
+pub const RATE_BURST: f64 = 500.0;
+/// dropped packets in a second that get a source blocked
+pub const BLOCK_STRIKES: u32 = 250;
+/// how long (usec) a source stays blocked
+pub const BLOCK_TIME: u128 = 10_000_000;
+/// forget sources we have not heard from in this long (usec)
+const SOURCE_IDLE: u128 = 5_000_000;
+const STRIKE_WINDOW: u128 = 1_000_000;
+/// most source addresses kept track of
+pub const MAX_SOURCES: usize = 1024;
+
+/// what we know about a source address
+struct Source {
+    tokens: f64,
+    last_seen: u128,
+    strikes: u32,
+    window_start: u128,
+    blocked_until: u128,
+}
+
+impl Source {
+    fn new(now_time: u128) -> Source {
+        Source {
+            tokens: RATE_BURST,
+            last_seen: now_time,
+            strikes: 0,
+            window_start: now_time,
+            blocked_until: 0,
+        }
+    }
+    // returns true if this strike got them blocked
+    fn strike(&mut self, now_time: u128) -> bool {
+        if now_time > self.window_start + STRIKE_WINDOW {
+            self.window_start = now_time;
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        if self.strikes >= BLOCK_STRIKES {
+            self.blocked_until = now_time + BLOCK_TIME;
+            self.strikes = 0;
+            return true;
+        }
+        false
+    }
+}
+
+/// Counters for packets that did not make it to the room
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct GuardStats {
+    pub malformed: u64,
+    pub oversize: u64,
+    pub unknown_client: u64,
+    pub rate_limited: u64,
+    pub blocked: u64,
+    pub too_many_sources: u64,
+    pub socket_errors: u64,
+}
+
+/// Rate limits and sanity checks incoming packets
+#[derive(Default)]
+pub struct PacketGuard {
+    sources: HashMap<SocketAddr, Source>,
+    pub stats: GuardStats,
+}
+
+impl PacketGuard {
+    pub fn new() -> PacketGuard {
+        Self::default()
+    }
+    /// check a packet that just came in.  amt is the number of bytes read.  false means drop it
+    pub fn admit(&mut self, src: SocketAddr, msg: &JamMessage, amt: usize, now_time: u128) -> bool {
+        if !self.sources.contains_key(&src) && self.sources.len() >= MAX_SOURCES {
+            self.stats.too_many_sources += 1;
+            return false;
+        }
+        let source = self.sources.entry(src).or_insert_with(|| Source::new(now_time));
+        if now_time < source.blocked_until {
+            self.stats.blocked += 1;
+            return false;
+        }
+        // refill the bucket
+        let elapsed = now_time.saturating_sub(source.last_seen) as f64 / 1_000_000.0;
+        source.tokens = (source.tokens + elapsed * RATE_LIMIT).min(RATE_BURST);
+        source.last_seen = now_time;
+        let problem = if amt >= JAM_BUF_SIZE {
+            // it didn't fit so it got cut off
+            Some(&mut self.stats.oversize)
+        } else if !size_ok(msg, amt) {
+            Some(&mut self.stats.malformed)
+        } else if source.tokens < 1.0 {
+            Some(&mut self.stats.rate_limited)
+        } else {
+            source.tokens -= 1.0;
+            None
+        };
+        match problem {
+            Some(counter) => {
+                *counter += 1;
+                if source.strike(now_time) {
+                    warn!("blocking {} for {} seconds", src, BLOCK_TIME / 1_000_000);
+                }
+                false
+            }
+            None => true,
+        }
+    }
+    /// packet from a client that is not in the room
+    pub fn count_unknown(&mut self) {
+        self.stats.unknown_client += 1;
+    }
+    pub fn count_socket_error(&mut self) {
+        self.stats.socket_errors += 1;
+    }
+    /// forget about sources that have gone quiet
+    pub fn prune(&mut self, now_time: u128) {
+        self.sources
+            .retain(|_, s| now_time < s.last_seen + SOURCE_IDLE || now_time < s.blocked_until);
+    }
+    /// number of sources currently blocked
+    pub fn blocked_sources(&self, now_time: u128) -> usize {
+        self.sources.values().filter(|s| now_time < s.blocked_until).count()
+    }
+    pub fn as_json(&self, now_time: u128) -> Value {
+        json!({
+            "malformed": self.stats.malformed,
+            "oversize": self.stats.oversize,
+            "unknownClient": self.stats.unknown_client,
+            "rateLimited": self.stats.rate_limited,
+            "blocked": self.stats.blocked,
+            "blockedSources": self.blocked_sources(now_time),
+            "tooManySources": self.stats.too_many_sources,
+            "socketErrors": self.stats.socket_errors,
+        })
+    }
+}
+
+/// does the size of the packet match what the header says
+fn size_ok(msg: &JamMessage, amt: usize) -> bool {
+    if !msg.is_valid(amt) {
+        return false;
+    }
+    match msg.get_packet_type() {
+        PACKET_AUDIO => {
+            // 2 channels of 2 byte samples
+            if !(amt - JAM_HEADER_SIZE).is_multiple_of(4) {
+                return false;
+            }
+            // older components leave the chunk count at 0
+            let chunks = msg.get_num_audio_chunks() as usize;
+            chunks == 0 || chunks == amt / 32
+        }
+        PACKET_JOIN => amt == JAM_HEADER_SIZE,
+        // capture and playback latency then (id, depth) pairs
+        PACKET_REPORT => amt >= JAM_HEADER_SIZE + 8 && (amt - JAM_HEADER_SIZE).is_multiple_of(8),
+        // nothing else should come from a sound component
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test_packet_guard {
+    use super::*;
+
+    fn audio_packet() -> (JamMessage, usize) {
+        let mut msg = JamMessage::new();
+        let amt = msg.encode_audio(&[0.0; 128], &[0.0; 128]);
+        (msg, amt)
+    }
+
+    #[test]
+    fn checks_sizes() {
+        let mut guard = PacketGuard::new();
+        let src: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let (mut msg, amt) = audio_packet();
+        assert!(guard.admit(src, &msg, amt, 0));
+        // claims more audio than it has
+        assert!(!guard.admit(src, &msg, amt - 32, 0));
+        // half a sample
+        assert!(!guard.admit(src, &msg, amt - 2, 0));
+        assert!(!guard.admit(src, &msg, JAM_BUF_SIZE, 0));
+        msg.set_control(PACKET_JOIN, 0, 0);
+        assert!(guard.admit(src, &msg, JAM_HEADER_SIZE, 0));
+        assert!(!guard.admit(src, &msg, amt, 0));
+        msg.set_words(PACKET_REPORT, &[1000, 2000, 7, 5000]).unwrap();
+        assert!(guard.admit(src, &msg, JAM_HEADER_SIZE + 16, 0));
+        assert!(!guard.admit(src, &msg, JAM_HEADER_SIZE + 12, 0));
+        assert_eq!(guard.stats.malformed, 4);
+        assert_eq!(guard.stats.oversize, 1);
+    }
+
+    #[test]
+    fn rate_limits_and_blocks() {
+        let mut guard = PacketGuard::new();
+        let src: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+        let (msg, amt) = audio_packet();
+        // a normal stream is fine
+        for n in 0..2000 {
+            assert!(guard.admit(src, &msg, amt, n * 2667));
+        }
+        // a burst all at once runs out the bucket
+        let now = 10_000_000;
+        let passed = (0..400).filter(|_| guard.admit(other, &msg, amt, now)).count();
+        assert_eq!(passed, 400);
+        let passed = (0..400).filter(|_| guard.admit(other, &msg, amt, now)).count();
+        assert_eq!(passed, 100);
+        // too many drops and they get blocked.  Not the other guy
+        assert_eq!(guard.stats.rate_limited, BLOCK_STRIKES as u64);
+        assert_eq!(guard.stats.blocked, 300 - BLOCK_STRIKES as u64);
+        assert_eq!(guard.blocked_sources(now), 1);
+        assert!(!guard.admit(other, &msg, amt, now + 1_000_000));
+        assert!(guard.admit(src, &msg, amt, now + 1_000_000));
+        assert!(guard.admit(other, &msg, amt, now + BLOCK_TIME + 1));
+        // quiet sources get forgotten
+        guard.prune(now + BLOCK_TIME + SOURCE_IDLE + 2);
+        assert_eq!(guard.sources.len(), 0);
+    }
+
+    #[test]
+    fn caps_the_sources() {
+        let mut guard = PacketGuard::new();
+        let (msg, amt) = audio_packet();
+        let spoofed = |n: usize| SocketAddr::from(([10, (n >> 16) as u8, (n >> 8) as u8, n as u8], 4000));
+        for n in 0..MAX_SOURCES * 2 {
+            guard.admit(spoofed(n), &msg, amt, 0);
+        }
+        assert_eq!(guard.sources.len(), MAX_SOURCES);
+        assert_eq!(guard.stats.too_many_sources, MAX_SOURCES as u64);
+        // the ones already known still get in
+        assert!(guard.admit(spoofed(1), &msg, amt, 0));
+        guard.prune(SOURCE_IDLE + 1);
+        assert!(guard.admit(spoofed(MAX_SOURCES + 1), &msg, amt, SOURCE_IDLE + 1));
+    }
+}
@@ -1,48 +1,58 @@
-use std::{sync::mpsc, thread::sleep, time::Duration};
+use std::{sync::mpsc, time::Duration};
 
 use log::{info, trace, warn};
 
 use crate::{
     common::{box_error::BoxError, get_micro_time, jam_packet::JamMessage, stream_time_stat::MicroTimer, websock_message::WebsockMessage},
-    server::{cmd_message::RoomParam, playback_mixer::PlaybackMixer},
+    server::{cmd_message::RoomParam, metrics::ServerMetrics, playback_mixer::PlaybackMixer, reactor::WakingSender},
 };
 
 use super::cmd_message::RoomCommandMessage;
 
-const FRAME_TIME: u128 = 2_667;
+/// frames of playback that can be waiting for the audio thread
+pub const PLAYBACK_QUEUE: usize = 2;
+/// how long to wait for a command when nothing is playing
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
 /// This thread will pump out playback packets to the audio_thread  (by writing to packet_tx)
 /// It will collect recorded packets from a file, push them into a mixer (all flat settings),
 /// then pull them out of the Mixer into a new packet that gets pumped to the audio_thread.
+///
+/// packet_tx only holds [`PLAYBACK_QUEUE`] frames and the audio thread takes one per frame off its
+/// [`FrameClock`](crate::server::frame_clock::FrameClock), so that's what paces playback.
 pub fn run(
-    to_ws_tx: mpsc::Sender<WebsockMessage>,
+    to_ws_tx: WakingSender<WebsockMessage>,
     cmd_rx: mpsc::Receiver<RoomCommandMessage>,
-    packet_tx: mpsc::Sender<JamMessage>
+    packet_tx: mpsc::SyncSender<JamMessage>,
+    metrics: ServerMetrics,
 ) -> Result<(), BoxError> {
     info!("playback thread");
     let mut mixer = PlaybackMixer::new();
     let mut now = get_micro_time();
-    let mut pback_timer = MicroTimer::new(now, FRAME_TIME);
     let mut transport_update_timer = MicroTimer::new(now, 333_000);
 
     loop {
-        let mut nanos = (FRAME_TIME - pback_timer.since(now)) * 1000;
-        nanos = nanos.clamp(0,100_000);
-        sleep(Duration::new(0, nanos as u32));
         now = get_micro_time();
-        while pback_timer.expired(now) {
-            pback_timer.advance(FRAME_TIME);
-            // Pull a packet out of the mixer and send it
-            match mixer.get_a_packet(now) {
-                Some(p) => {
-                    packet_tx.send(p)?;
-                }
-                None => {
-                    // Nothing to send
-                }
+        match mixer.load_up_till_now(now) {
+            Ok(()) => {}
+            Err(_e) => {
+                // dbg!(e);
+                // Probably was end of file.  stop playback
+                mixer.close_stream();
+                // let _err = mixer.seek_to(now, 0);
             }
         }
+        // Pull a packet out of the mixer and send it.  This waits until the audio thread has room
+        let playing = match mixer.get_a_packet(now) {
+            Some(p) => {
+                packet_tx.send(p)?;
+                true
+            }
+            None => false,
+        };
         if transport_update_timer.expired(now) {
             transport_update_timer.reset(now);
+            metrics.heartbeat("playback", now);
             // send transport update
             trace!("playback status {}", mixer.get_status());
             to_ws_tx.send(WebsockMessage::Chat(serde_json::json!({
@@ -50,42 +60,46 @@ pub fn run(
                 "playbackStatus": mixer.get_status(),
             })))?;
         }
-        match mixer.load_up_till_now(now) {
-            Ok(()) => {}
-            Err(_e) => {
-                // dbg!(e);
-                // Probably was end of file.  stop playback
-                mixer.close_stream();
-                // let _err = mixer.seek_to(now, 0);
-            }
-        }
-        // Check for a command before looping again.
-        match cmd_rx.try_recv() {
+        // Check for a command before looping again.  Nothing to play, so wait for one
+        let cmd = if playing {
+            cmd_rx.try_recv().map_err(|_| mpsc::RecvTimeoutError::Timeout)
+        } else {
+            cmd_rx.recv_timeout(IDLE_WAIT)
+        };
+        match cmd {
             Ok(m) => {
                 // Message from control
-                match m.param {
+                let result = match m.param {
                     RoomParam::Play => {
-                        let file = format!("recs/{}", m.svalue);
-                        match mixer.open_stream(&file, now, m.ivalue_1.clamp(0, 100) as usize) {
-                            Ok(()) => {}
-                            Err(e) => { warn!("open error {:?}", e); }
-                        }
+                        // the main thread sends the path
+                        mixer.open_stream(&m.svalue, now, m.ivalue_1.clamp(0, 100) as usize)
                     }
                     RoomParam::Stop => {
                         mixer.close_stream();
+                        Ok(())
                     }
                     RoomParam::Seek => {
-                        match mixer.seek_to(now, m.ivalue_1 as usize) {
-                            Ok(()) => {}
-                            Err(e) => { warn!("seek error {:?}", e); }
-                        }
+                        mixer.seek_to(now, m.ivalue_1 as usize)
+                    }
+                    _ => Ok(())
+                };
+                if let Err(e) = &result {
+                    warn!("playback error {:?}", e);
+                }
+                // The main thread answers for Stop
+                if m.param != RoomParam::Stop {
+                    if let Some(reply) = m.reply(result.map(|_| mixer.get_status())) {
+                        to_ws_tx.send(WebsockMessage::Chat(reply))?;
                     }
-                    _ => {}
                 }
                 dbg!(m);
             }
-            Err(_e) => {
-                // ignore error for now
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                info!("playback thread done");
+                return Ok(());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // nothing to do
             }
         }
     }
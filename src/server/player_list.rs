@@ -2,18 +2,57 @@
 //!
 //! The broadcast component will add/remove sound components to the room using
 //! this list.
+//!
+//! Players are keyed by client id.  A player's address can change mid-session (a NAT rebinding
+//! their port), but packets from a new address are only accepted once it proves it is the same
+//! session by sending a JOIN with the session nonce the player joined with.  Until then the new
+//! address is told [`REJECT_UNVERIFIED`] which makes the sound component send that JOIN, and the
+//! player's own address is warned in case it is still there.  A player that never sent a nonce
+//! (older sound components) can only move once its old address has gone quiet.  Two live
+//! addresses claiming the same id are both warned.
+//!
+//! A JOIN can only replace a player's nonce once their session has gone quiet (a restarted sound
+//! component), so a JOIN spoofed with the player's address can't swap in a nonce of its own and
+//! then take the stream somewhere else.
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
 
-use log::debug;
+use log::{info, warn};
+
+use crate::common::{
+    jam_packet::{REJECT_DUPLICATE_ID, REJECT_UNVERIFIED},
+    player::Player,
+    session_report::SessionReport,
+};
+use crate::server::admission::AdmissionPolicy;
+
+/// Don't tell a refused client more than once a second (usec)
+const REJECT_NOTICE_INTERVAL: u128 = 1_000_000;
+/// how long (usec) an address has to be silent before a player without a session can move
+const MIGRATE_QUIET: u128 = 250_000;
+
+/// Why a packet was refused.  warn is the address of a player whose id is being claimed.  They get
+/// a [`REJECT_DUPLICATE_ID`] notice
+#[derive(Debug, PartialEq)]
+pub struct Refusal {
+    pub code: u8,
+    pub warn: Option<SocketAddr>,
+}
 
-use crate::common::player::Player;
+impl From<u8> for Refusal {
+    fn from(code: u8) -> Self {
+        Refusal { code, warn: None }
+    }
+}
 
 /// Structure to hold the list of players
 pub struct PlayerList {
     pub players: Vec<Player>,
-    pub stat_queue: Vec<serde_json::Value>,
+    pub stat_queue: Vec<SessionReport>,
+    pub admission: AdmissionPolicy,
     update_cnt: usize,
+    reject_notices: HashMap<SocketAddr, u128>,
 }
 
 impl PlayerList {
@@ -21,19 +60,38 @@ impl PlayerList {
         PlayerList {
             players: vec![],
             stat_queue: vec![],
+            admission: AdmissionPolicy::new(),
             update_cnt: 0,
+            reject_notices: HashMap::new(),
         }
     }
-    /// tell if the player is allowed in the room
-    ///
-    /// TODO: Needs to be implemented.  Right now everybody is allowed.  Need to have
-    /// a list of allowed players that will be retrieved from rtjam-nation
-    pub fn is_allowed(&self, _id: u32) -> bool {
+    /// tell if the player sending from addr is allowed in the room
+    pub fn is_allowed(&self, id: u32, addr: SocketAddr) -> bool {
+        self.check_admission(id, addr).is_ok()
+    }
+    /// check the player against the [`AdmissionPolicy`].  The error is the rejection code
+    pub fn check_admission(&self, id: u32, addr: SocketAddr) -> Result<(), u8> {
+        let present = self.players.iter().any(|p| p.client_id == id);
+        self.admission.check(id, addr, present, self.players.len())
+    }
+    /// player sent a JOIN packet from addr with their password token
+    pub fn join(&mut self, id: u32, addr: SocketAddr, token: u64, now_time: u128) -> Result<(), u8> {
+        let present = self.players.iter().any(|p| p.client_id == id);
+        self.admission.join(id, addr, token, present, self.players.len(), now_time)
+    }
+    /// should we send a rejection notice to this address.  Limits how often a refused client
+    /// hears from us
+    pub fn should_notify(&mut self, addr: SocketAddr, now_time: u128) -> bool {
+        self.reject_notices.retain(|_, t| now_time < *t + REJECT_NOTICE_INTERVAL);
+        if self.reject_notices.contains_key(&addr) {
+            return false;
+        }
+        self.reject_notices.insert(addr, now_time);
         true
     }
-    /// update the keepalive for this player (found by ip address)
+    /// update the keepalive for this player (found by client id)
     ///
-    /// called when we receive a packet from a player
+    /// called when we receive a packet from a player.  An error means the packet should be dropped.
     pub fn update_player(
         &mut self,
         now_time: u128,
@@ -41,32 +99,88 @@ impl PlayerList {
         id: u32,
         addr: SocketAddr,
         seq: u32,
-    ) -> () {
+    ) -> Result<(), Refusal> {
         // look for this player and update their timestamp if found
-        for player in &mut self.players {
-            if player.address == addr {
+        match self.players.iter_mut().find(|p| p.client_id == id) {
+            Some(player) => {
+                if player.address != addr {
+                    if player.session.is_some() {
+                        // they need to prove it's them.  Let the player know in case it isn't
+                        return Err(Refusal { code: REJECT_UNVERIFIED, warn: Some(player.address) });
+                    }
+                    if now_time < player.get_keep_alive() + MIGRATE_QUIET {
+                        warn!("client {} sending from {} and {}", id, player.address, addr);
+                        return Err(Refusal { code: REJECT_DUPLICATE_ID, warn: Some(player.address) });
+                    }
+                    info!("client {} moved from {} to {}", id, player.address, addr);
+                    player.address = addr;
+                }
                 player.update(now_time, id, loop_time, seq);
-                return ();
+            }
+            None => {
+                // we don't know this guy.  add him
+                self.players.push(Player::new(now_time, id, addr));
             }
         }
-        // If we got here, we don't know this guy.  add him
-        self.players.push(Player::new(now_time, id, addr));
+        Ok(())
     }
-    /// look for any player entries that have timed out
-    pub fn prune(&mut self, now_time: u128) -> () {
+    /// player sent a JOIN with their session nonce.  Binds the nonce to the client id, or moves
+    /// the player to this address if the nonce matches.
+    pub fn identify(&mut self, now_time: u128, id: u32, nonce: u64, addr: SocketAddr) -> Result<(), Refusal> {
+        match self.players.iter_mut().find(|p| p.client_id == id) {
+            Some(player) => {
+                let quiet = now_time >= player.get_keep_alive() + MIGRATE_QUIET;
+                if player.address == addr {
+                    // a new nonce from here is a restarted session, once the old one has stopped
+                    if player.session.unwrap_or(nonce) == nonce || quiet {
+                        player.session = Some(nonce);
+                        return Ok(());
+                    }
+                    warn!("client {} sent a new session from {} while the old one is live", id, addr);
+                    return Err(REJECT_DUPLICATE_ID.into());
+                }
+                let proven = match player.session {
+                    Some(session) => session == nonce,
+                    None => quiet,
+                };
+                if !proven {
+                    warn!("client {} claimed from {} and {}", id, player.address, addr);
+                    return Err(Refusal { code: REJECT_DUPLICATE_ID, warn: Some(player.address) });
+                }
+                info!("client {} moved from {} to {}", id, player.address, addr);
+                player.address = addr;
+                player.session = Some(nonce);
+            }
+            None => {
+                let mut player = Player::new(now_time, id, addr);
+                player.session = Some(nonce);
+                self.players.push(player);
+            }
+        }
+        Ok(())
+    }
+    /// look for any player entries that have timed out.  Returns the client ids that left
+    pub fn prune(&mut self, now_time: u128) -> Vec<u32> {
+        let mut left = vec![];
         for p in &self.players {
             // save stats for values about to be cleared
             if p.is_old(now_time) {
-                match serde_json::to_value(p) {
-                    Ok(v) => {
-                        self.stat_queue.push(v);
-                    },
-                    Err(e) => debug!("Unable to serialize player stats {}", e),
-                }
+                // they have to join again when they come back
+                self.admission.forget(p.client_id);
+                self.stat_queue.push(p.report());
+                left.push(p.client_id);
             }
         }
         // this function will age out any old Players
         self.players.retain(|p| !p.is_old(now_time));
+        left
+    }
+    /// everybody is leaving (the server is shutting down).  Their reports go on the stat queue
+    pub fn close(&mut self) {
+        for p in &self.players {
+            self.stat_queue.push(p.report());
+        }
+        self.players.clear();
     }
     /// Get a list of players to iterate though
     pub fn get_players(&self) -> &Vec<Player> {
@@ -98,7 +212,7 @@ impl fmt::Display for PlayerList {
         }
         write!(f, " ]\nold: [\n")?;
         for stat in &self.stat_queue {
-            write!(f, " {},\n", stat.to_string())?;
+            write!(f, " {},\n", serde_json::to_string(stat).map_err(|_| fmt::Error)?)?;
         }
         write!(f, " ]")
     }
@@ -117,10 +231,25 @@ mod test_playerlist {
     }
     #[test]
     fn is_allowed() {
-        // TODO:  no id verification yet.  Must implement ability to filter data to just those
-        // clients who have joined the room on the server
-        let plist = PlayerList::new();
-        assert_eq!(plist.is_allowed(44455), true);
+        // everybody is allowed until there is a policy
+        let mut plist = PlayerList::new();
+        let addr: SocketAddr = "182.1.1.1:33345".parse().unwrap();
+        assert_eq!(plist.is_allowed(44455, addr), true);
+        plist.admission.ban(44455);
+        assert!(!plist.is_allowed(44455, addr));
+        // room full only counts people who are not already in
+        plist.admission.set_max_players(1);
+        plist.update_player(get_micro_time(), 0, 55533, addr, 0).unwrap();
+        assert!(plist.is_allowed(55533, addr));
+        assert_eq!(plist.check_admission(1, addr), Err(crate::common::jam_packet::REJECT_FULL));
+    }
+    #[test]
+    fn rejection_notices() {
+        let mut plist = PlayerList::new();
+        let addr: SocketAddr = "182.1.1.1:33345".parse().unwrap();
+        assert!(plist.should_notify(addr, 0));
+        assert!(!plist.should_notify(addr, 500_000));
+        assert!(plist.should_notify(addr, 1_000_001));
     }
     #[test]
     fn update_player() {
@@ -133,17 +262,55 @@ mod test_playerlist {
             .parse()
             .expect("Unable to parse socket address");
         // Add a new player to an empty list
-        plist.update_player(now_time, loop_time, id, addr, 0);
+        plist.update_player(now_time, loop_time, id, addr, 0).unwrap();
         assert_eq!(plist.get_players().len(), 1);
         // this will update a player if we have seen them before
-        plist.update_player(now_time + 100, loop_time, id, addr, 0);
+        plist.update_player(now_time + 100, loop_time, id, addr, 0).unwrap();
         assert_eq!(plist.get_players().len(), 1);
         // This will add another player
-        let addr: SocketAddr = "192.1.1.1:33345"
+        let addr2: SocketAddr = "192.1.1.1:33345"
             .parse()
             .expect("Unable to parse socket address");
-        plist.update_player(now_time, loop_time, id, addr, 0);
+        plist.update_player(now_time, loop_time, id + 1, addr2, 0).unwrap();
+        assert_eq!(plist.get_players().len(), 2);
+        // same id from another live address is a duplicate.  The original gets warned
+        let addr3: SocketAddr = "200.1.1.1:33345".parse().unwrap();
+        let refusal = plist.update_player(now_time + 200, loop_time, id, addr3, 1).unwrap_err();
+        assert_eq!(refusal, Refusal { code: REJECT_DUPLICATE_ID, warn: Some(addr) });
+        // once the old address goes quiet a player without a session can move
+        plist.update_player(now_time + 101 + MIGRATE_QUIET, loop_time, id, addr3, 1).unwrap();
         assert_eq!(plist.get_players().len(), 2);
+        assert_eq!(plist.get_players()[0].address, addr3);
+    }
+    #[test]
+    fn address_migration() {
+        let mut plist = PlayerList::new();
+        let now_time = get_micro_time();
+        let id = 55533;
+        let addr: SocketAddr = "182.1.1.1:33345".parse().unwrap();
+        let rebound: SocketAddr = "182.1.1.1:40001".parse().unwrap();
+        plist.identify(now_time, id, 777, addr).unwrap();
+        plist.update_player(now_time, 0, id, addr, 1).unwrap();
+        // a JOIN spoofed from the player's own address can't swap the nonce
+        assert_eq!(plist.identify(now_time + 5, id, 123, addr), Err(REJECT_DUPLICATE_ID.into()));
+        // port changed.  Packets are refused until the new address proves it's the same session,
+        // and the old address hears about it
+        assert_eq!(
+            plist.update_player(now_time + 10, 0, id, rebound, 2),
+            Err(Refusal { code: REJECT_UNVERIFIED, warn: Some(addr) })
+        );
+        // a spoofer doesn't know the nonce
+        let refusal = plist.identify(now_time + 20, id, 123, rebound).unwrap_err();
+        assert_eq!(refusal.code, REJECT_DUPLICATE_ID);
+        assert_eq!(plist.get_players()[0].address, addr);
+        // the real player does
+        plist.identify(now_time + 30, id, 777, rebound).unwrap();
+        plist.update_player(now_time + 40, 0, id, rebound, 3).unwrap();
+        assert_eq!(plist.get_players().len(), 1);
+        assert_eq!(plist.get_players()[0].address, rebound);
+        // a restarted session gets a new nonce once the old one has gone quiet
+        plist.identify(now_time + 41 + MIGRATE_QUIET, id, 888, rebound).unwrap();
+        assert_eq!(plist.get_players()[0].session, Some(888));
     }
     #[test]
     fn prune() {
@@ -155,10 +322,10 @@ mod test_playerlist {
             .parse()
             .expect("Unable to parse socket address");
         // Add a new player to an empty list
-        plist.update_player(now_time, now_time, id, addr, 0);
+        plist.update_player(now_time, now_time, id, addr, 0).unwrap();
         assert_eq!(plist.get_players().len(), 1);
         // Call prune with a now_time that is past
-        plist.prune(now_time + EXPIRATION_IN_MICROSECONDS + 1);
+        assert_eq!(plist.prune(now_time + EXPIRATION_IN_MICROSECONDS + 1), vec![id]);
         assert_eq!(plist.get_players().len(), 0);
     }
     #[test]
@@ -170,7 +337,7 @@ mod test_playerlist {
             .parse()
             .expect("Unable to parse socket address");
         // Add a new player to an empty list
-        plist.update_player(now_time, now_time, id, addr, 0);
+        plist.update_player(now_time, now_time, id, addr, 0).unwrap();
         println!("latency: {:?}", plist.get_latency());
     }
 }
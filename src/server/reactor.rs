@@ -0,0 +1,162 @@
+//! Wakes the audio thread when there is something to do
+//!
+//! The audio thread sleeps in [`Reactor::wait`] (epoll on linux, by way of mio) until one of:
+//! - the room socket has datagrams to read
+//! - somebody sent a room command on a [`WakingSender`] channel
+//! - the timeout (the next frame from the [`FrameClock`](crate::server::frame_clock::FrameClock))
+//!   runs out
+//!
+//! The socket stays a plain non-blocking [`UdpSocket`].  Readiness is edge triggered so the
+//! thread has to read until `WouldBlock` before waiting again.
+//!
+//! The broadcast server's main loop sleeps in one too (no socket) until the websocket, the other
+//! threads or the recording have something for it.
+//!
+//! There is one socket on the room port.  `SO_REUSEPORT` worker sockets would have the kernel
+//! spread the incoming datagrams across all of them, and the player list and room mixer live in
+//! the one audio thread.  Batching the sends ([`fan_out`](crate::server::fan_out)) is where the
+//! forwarding time goes.
+use std::{
+    io::ErrorKind,
+    net::UdpSocket,
+    os::fd::AsRawFd,
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+
+use simple_error::bail;
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
+
+use crate::common::box_error::BoxError;
+
+const SOCKET: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// mpsc sender that wakes the reactor on the other end
+pub struct WakingSender<T> {
+    tx: mpsc::Sender<T>,
+    waker: Arc<Waker>,
+}
+
+impl<T> Clone for WakingSender<T> {
+    fn clone(&self) -> Self {
+        WakingSender {
+            tx: self.tx.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+}
+
+impl<T> WakingSender<T> {
+    pub fn send(&self, msg: T) -> Result<(), BoxError> {
+        if self.tx.send(msg).is_err() {
+            bail!("receiver has hung up");
+        }
+        self.waker.wake()?;
+        Ok(())
+    }
+}
+
+/// What woke us up
+#[derive(Debug, Default, PartialEq)]
+pub struct Wakeup {
+    /// the socket has datagrams
+    pub readable: bool,
+    /// a message came in on a channel
+    pub message: bool,
+}
+
+pub struct Reactor {
+    poll: Poll,
+    events: Events,
+    waker: Arc<Waker>,
+}
+
+impl Reactor {
+    pub fn new() -> Result<Reactor, BoxError> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        Ok(Reactor {
+            poll,
+            events: Events::with_capacity(16),
+            waker,
+        })
+    }
+    /// a channel that wakes this reactor when sent to
+    pub fn channel<T>(&self) -> (WakingSender<T>, mpsc::Receiver<T>) {
+        let (tx, rx) = mpsc::channel();
+        (
+            WakingSender {
+                tx,
+                waker: self.waker.clone(),
+            },
+            rx,
+        )
+    }
+    /// watch the socket.  It gets switched to non-blocking
+    pub fn register(&self, sock: &UdpSocket) -> Result<(), BoxError> {
+        sock.set_nonblocking(true)?;
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&sock.as_raw_fd()), SOCKET, Interest::READABLE)?;
+        Ok(())
+    }
+    /// sleep until the socket is readable, a message is sent, or the timeout
+    pub fn wait(&mut self, timeout: Duration) -> Result<Wakeup, BoxError> {
+        let mut wakeup = Wakeup::default();
+        if let Err(e) = self.poll.poll(&mut self.events, Some(timeout)) {
+            // a signal is not a problem.  Just go around again
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(wakeup);
+            }
+            return Err(e.into());
+        }
+        for event in self.events.iter() {
+            match event.token() {
+                SOCKET => wakeup.readable = true,
+                WAKER => wakeup.message = true,
+                _ => (),
+            }
+        }
+        Ok(wakeup)
+    }
+}
+
+#[cfg(test)]
+mod test_reactor {
+    use super::*;
+    use std::{thread, time::Instant};
+
+    #[test]
+    fn wakes_on_messages() {
+        let mut reactor = Reactor::new().unwrap();
+        let (tx, rx) = reactor.channel::<u32>();
+        // nothing going on
+        assert_eq!(reactor.wait(Duration::from_millis(5)).unwrap(), Wakeup::default());
+        let start = Instant::now();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            tx.send(42).unwrap();
+        });
+        let wakeup = reactor.wait(Duration::from_secs(10)).unwrap();
+        assert!(wakeup.message);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(rx.try_recv().unwrap(), 42);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn wakes_on_packets() {
+        let mut reactor = Reactor::new().unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        reactor.register(&sock).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&[1, 2, 3], sock.local_addr().unwrap()).unwrap();
+        let wakeup = reactor.wait(Duration::from_secs(10)).unwrap();
+        assert!(wakeup.readable);
+        let mut buf = [0u8; 8];
+        assert_eq!(sock.recv_from(&mut buf).unwrap().0, 3);
+        // non-blocking now
+        assert_eq!(sock.recv_from(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -1,12 +1,52 @@
+//! Mixes the room on the broadcast server ("mix" room mode)
+//!
+//! Everybody's audio goes into one [`Mixer`].  Each frame, [`RoomMixer::mix_frame`] pulls the
+//! audio out of every channel once, then [`RoomMixer::get_a_packet`] builds the mix for a
+//! particular listener:
+//! - mix-minus: the listener's own channels are left out (they would hear themselves a full
+//!   round trip late)
+//! - personal mix: the listener can set a gain (dB) and pan for each source in the room.  Sources
+//!   without a setting are at unity gain, center pan.
+use std::collections::HashMap;
+
+use pedal_board::utils::to_lin;
+use serde_json::{json, Value};
+
 use crate::{common::
-    jam_packet::JamMessage, 
-    sound::{mixer::Mixer, channel_map::ChannelMap}
+    jam_packet::JamMessage,
+    sound::{mixer::{Mixer, MIXER_CHANNELS}, channel_map::ChannelMap, fader::Fader}
 };
 
+/// samples per channel in a room packet
+const FRAME_SIZE: usize = 128;
+/// client id the room mix goes out under
+pub const MIX_CLIENT_ID: u32 = 40002;
+
+/// how one listener wants to hear one source
+pub struct SourceMix {
+    gain: f64,
+    fader: Fader,
+}
+
+impl SourceMix {
+    /// gain is in dB, pan is -1.0 (left) to 1.0 (right)
+    pub fn new(gain: f64, pan: f32) -> SourceMix {
+        let mut fader = Fader::new();
+        fader.set(pan);
+        SourceMix {
+            gain: gain.clamp(-60.0, 12.0),
+            fader,
+        }
+    }
+}
+
 pub struct RoomMixer {
     mixer: Mixer,
     chan_map: ChannelMap,
     seq: u32,
+    frames: Vec<Vec<f32>>,
+    // listener id -> source id -> settings
+    mixes: HashMap<u32, HashMap<u32, SourceMix>>,
 }
 
 impl RoomMixer {
@@ -15,6 +55,8 @@ impl RoomMixer {
             mixer: Mixer::new(),
             chan_map: ChannelMap::new(),
             seq: 0,
+            frames: vec![vec![0.0; FRAME_SIZE]; MIXER_CHANNELS],
+            mixes: HashMap::new(),
         }
     }
     pub fn add_a_packet(&mut self, now: u128, msg: &JamMessage) -> () {
@@ -38,18 +80,135 @@ impl RoomMixer {
             }
         }
     }
-
-    pub fn get_a_packet(&mut self, now: u128) -> JamMessage {
-        // Mix out a packet
-        let mut out_a: [f32; 128] = [0.0; 128];
-        let mut out_b: [f32; 128] = [0.0; 128];
-        self.mixer.get_mix(0, &mut out_a, &mut out_b);
+    /// pull the next frame out of all the channels.  Call once per frame before getting the
+    /// listener packets
+    pub fn mix_frame(&mut self, now: u128) {
+        self.chan_map.prune(now);
+        self.mixer.get_channel_frames(&mut self.frames);
+        self.seq += 1;
+    }
+    /// the mix of the current frame for one listener
+    pub fn get_a_packet(&self, listener: u32, now: u128) -> JamMessage {
+        let mut out_a: [f32; FRAME_SIZE] = [0.0; FRAME_SIZE];
+        let mut out_b: [f32; FRAME_SIZE] = [0.0; FRAME_SIZE];
+        let default_mix = SourceMix::new(0.0, 0.0);
+        let personal = self.mixes.get(&listener);
+        for (slot, source) in self.chan_map.get_clients().iter().enumerate() {
+            if source.is_empty() || source.client_id == listener {
+                continue;
+            }
+            let setting = personal
+                .and_then(|m| m.get(&source.client_id))
+                .unwrap_or(&default_mix);
+            let gain = to_lin(setting.gain) as f32;
+            let (left, right) = (gain * setting.fader.left(), gain * setting.fader.right());
+            // same slot numbering as the channel map
+            let idx = (slot + 1) * 2;
+            for frame in self.frames.iter().skip(idx).take(2) {
+                for (i, v) in frame.iter().enumerate().take(FRAME_SIZE) {
+                    out_a[i] += v * left;
+                    out_b[i] += v * right;
+                }
+            }
+        }
         let mut packet = JamMessage::new();
-        packet.set_client_id(40002);
+        packet.set_client_id(MIX_CLIENT_ID);
         packet.set_sequence_num(self.seq);
-        self.seq += 1;
         packet.set_server_time(now as u64);
         packet.encode_audio(&out_a, &out_b);
         packet
     }
-}
\ No newline at end of file
+    /// how much of each source (usec) is sitting in its jitter buffer
+    pub fn get_depths(&self) -> HashMap<u32, u32> {
+        self.chan_map
+            .get_clients()
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| !source.is_empty())
+            .map(|(slot, source)| {
+                let idx = (slot + 1) * 2;
+                let msec = self.mixer.get_depth_in_msec(idx).max(self.mixer.get_depth_in_msec(idx + 1));
+                (source.client_id, (msec * 1000.0) as u32)
+            })
+            .collect()
+    }
+    /// how shallow and deep (samples) the source jitter buffers adapt to
+    pub fn set_jitter_limits(&mut self, min: usize, max: usize) {
+        self.mixer.set_jitter_limits(min, max);
+    }
+    /// set how a listener hears a source.  gain in dB, pan -1.0 to 1.0
+    pub fn set_source_mix(&mut self, listener: u32, source: u32, gain: f64, pan: f32) {
+        self.mixes
+            .entry(listener)
+            .or_default()
+            .insert(source, SourceMix::new(gain, pan));
+    }
+    /// back to the default mix for a listener.  Also called when they leave the room
+    pub fn clear_personal_mix(&mut self, listener: u32) {
+        self.mixes.remove(&listener);
+    }
+    pub fn personal_mix_json(&self, listener: u32) -> Value {
+        let sources: Vec<Value> = self
+            .mixes
+            .get(&listener)
+            .map(|m| {
+                m.iter()
+                    .map(|(id, s)| json!({ "source": id, "gain": s.gain, "pan": s.fader.get() }))
+                    .collect()
+            })
+            .unwrap_or_default();
+        json!({ "personalMix": { "listener": listener, "sources": sources } })
+    }
+}
+
+#[cfg(test)]
+mod test_room_mixer {
+    use super::*;
+
+    fn packet(id: u32, seq: u32, level: f32) -> JamMessage {
+        let mut msg = JamMessage::new();
+        msg.set_client_id(id);
+        msg.set_sequence_num(seq);
+        msg.encode_audio(&[level; FRAME_SIZE], &[level; FRAME_SIZE]);
+        msg
+    }
+
+    // fill the jitter buffers and pull out a frame
+    fn primed(mixer: &mut RoomMixer) {
+        for seq in 0..8 {
+            mixer.add_a_packet(seq as u128, &packet(1, seq, 0.25));
+            mixer.add_a_packet(seq as u128, &packet(2, seq, -0.125));
+        }
+        mixer.mix_frame(10);
+    }
+
+    #[test]
+    fn mix_minus() {
+        let mut mixer = RoomMixer::new();
+        primed(&mut mixer);
+        // player 1 only hears player 2 (both channels, center pan)
+        let (l, r) = mixer.get_a_packet(1, 10).decode_audio();
+        assert!((l[64] - 2.0 * -0.125).abs() < 0.01);
+        assert!((r[64] - l[64]).abs() < 0.001);
+        // somebody not playing hears everybody
+        let (l, _r) = mixer.get_a_packet(3, 10).decode_audio();
+        assert!((l[64] - 2.0 * (0.25 - 0.125)).abs() < 0.01);
+    }
+
+    #[test]
+    fn personal_mix() {
+        let mut mixer = RoomMixer::new();
+        primed(&mut mixer);
+        // player 2 pans player 1 hard left and turns them down 10dB
+        mixer.set_source_mix(2, 1, -10.0, -1.0);
+        let (l, r) = mixer.get_a_packet(2, 10).decode_audio();
+        assert!((l[64] - 2.0 * 0.25 * 0.1 * f32::sqrt(2.0)).abs() < 0.01);
+        assert!(r[64].abs() < 0.001);
+        assert_eq!(mixer.personal_mix_json(2)["personalMix"]["sources"][0]["gain"], -10.0);
+        // nobody else's mix changes
+        let (l, _r) = mixer.get_a_packet(3, 10).decode_audio();
+        assert!((l[64] - 2.0 * (0.25 - 0.125)).abs() < 0.01);
+        mixer.clear_personal_mix(2);
+        assert_eq!(mixer.personal_mix_json(2)["personalMix"]["sources"], json!([]));
+    }
+}
@@ -0,0 +1,79 @@
+//! Stopping the broadcast component cleanly
+//!
+//! SIGTERM and SIGINT are caught ([`catch_signals`]) and the main thread notices on its next trip
+//! around the loop.  It then requests a [`Shutdown`], which the audio thread watches for.  The audio
+//! thread tells the players the room is going away and saves their session reports before it stops.
+//! The main thread then finishes the recording.  The rtjam-nation drops the room once the broadcast
+//! pings stop.
+use std::sync::{
+    atomic::{AtomicBool, AtomicI32, Ordering},
+    Arc,
+};
+
+use simple_error::bail;
+
+use crate::common::box_error::BoxError;
+
+// the last signal caught (0 for none)
+static SIGNALLED: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_signal(sig: libc::c_int) {
+    // storing an atomic is all that's safe to do in here
+    SIGNALLED.store(sig, Ordering::SeqCst);
+}
+
+/// catch SIGTERM and SIGINT instead of dying on the spot
+pub fn catch_signals() -> Result<(), BoxError> {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for sig in [libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(sig, handler) } == libc::SIG_ERR {
+            bail!("can't catch signal {}", sig);
+        }
+    }
+    Ok(())
+}
+
+/// the signal that asked us to stop, if one has
+pub fn signal() -> Option<i32> {
+    match SIGNALLED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Shared flag telling the room threads to wind down.  Clone it to hand it out
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Self::default()
+    }
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+
+    #[test]
+    fn catches_term() {
+        // call the handler directly, raising a real SIGTERM would hit the whole test run
+        assert_eq!(signal(), None);
+        on_signal(libc::SIGTERM);
+        assert_eq!(signal(), Some(libc::SIGTERM));
+        SIGNALLED.store(0, Ordering::SeqCst);
+        let shutdown = Shutdown::new();
+        let other = shutdown.clone();
+        assert!(!other.is_requested());
+        shutdown.request();
+        assert!(other.is_requested());
+    }
+}
@@ -2,23 +2,39 @@
 
 use crate::common::box_error::BoxError;
 
+/// rate (Hz) the audio runs at
+pub const SAMPLE_RATE: u32 = 48_000;
+
 pub trait SoundCallback {
     fn is_running(&self) -> bool;
     fn process(&mut self, in_a: &[f32], in_b: &[f32], out_a: &mut [f32], out_b: &mut [f32]) -> Result<(), BoxError>;
     fn process_inputs(&mut self, in_a: &[f32], in_b: &[f32]);
     fn get_playback_data(&mut self, out_a: &mut [f32], out_b: &mut [f32]);
+    /// the audio device's capture and playback latency (usec), reported to the room
+    fn set_device_latency(&mut self, capture: u32, playback: u32);
 }
 
 
 pub mod channel_map;
 pub mod channel_strip;
 pub mod client;
+pub mod diag_actions;
 pub mod fader;
 pub mod alsa_thread;
 pub mod jack_thread;
+pub mod jam_command;
 pub mod jam_engine;
 pub mod jam_socket;
 pub mod jitter_buffer;
+pub mod limiter;
 pub mod mixer;
+pub mod mixer_scenes;
+pub mod osc_message;
+pub mod osc_thread;
 pub mod param_message;
+pub mod param_tree;
+pub mod ramp;
+pub mod peer_presets;
+pub mod status_topics;
+pub mod unit_state;
 pub mod click_track;
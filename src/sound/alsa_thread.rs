@@ -7,11 +7,10 @@ use crate::common::box_error::BoxError;
 use crate::common::get_micro_time;
 use crate::common::stream_time_stat::{MicroTimer, StreamTimeStat};
 
-use super::SoundCallback;
+use super::{SoundCallback, SAMPLE_RATE};
 
 type SF = i16;
 const FRAME_SIZE: usize = 128;
-const SAMPLE_RATE: u32 = 48_000;
 const CHANNELS: u32 = 2;
 const MAX_SAMPLE: f32 = 32766.0;
 const SMP_FORMAT: Format = Format::s16();
@@ -34,8 +33,9 @@ impl OutputBuffer {
         // interleave and convert floats
         let mut i: usize = 0;
         while i < FRAME_SIZE {
-            self.buf[i*2] = (out_a[i] * MAX_SAMPLE) as i16;
-            self.buf[i*2+1] = (out_b[i] * MAX_SAMPLE) as i16;
+            // never let an overload turn into a blast, full scale is as loud as it gets
+            self.buf[i*2] = (out_a[i].clamp(-1.0, 1.0) * MAX_SAMPLE) as i16;
+            self.buf[i*2+1] = (out_b[i].clamp(-1.0, 1.0) * MAX_SAMPLE) as i16;
             i += 1;
         }
         self.pos = 0;
@@ -160,6 +160,10 @@ fn write_samples_io(p: &alsa::PCM, io: &mut alsa::pcm::IO<SF>, buf: &mut OutputB
     }
 }
 
+fn frames_to_usec(frames: Frames) -> u32 {
+    (frames as f64 * 1_000_000.0 / SAMPLE_RATE as f64) as u32
+}
+
 // Run the loop to read/write alsa
 pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) -> Result<(), BoxError> {
     // stats for callback
@@ -173,6 +177,9 @@ pub fn run(engine: &mut dyn SoundCallback, in_device: &str, out_device: &str) ->
     let mut in_buf = [0; FRAME_SIZE * CHANNELS as usize];
 
     let outdev = open_playback_dev(out_device)?;
+    // a period going in, the whole buffer coming out
+    let out_frames = outdev.hw_params_current()?.get_buffer_size()?;
+    engine.set_device_latency(frames_to_usec(FRAME_SIZE as Frames), frames_to_usec(out_frames));
     // let mut mmap = outdev.direct_mmap_playback::<SF>()?;
     let mut io_out = outdev.io_i16()?;
     let mut out_buf = OutputBuffer::new();
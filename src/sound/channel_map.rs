@@ -14,7 +14,7 @@ use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 // This is how long a player lasts until we boot them (if they go silent)
-const NUM_PLAYERS_IN_ROOM: usize = MIXER_CHANNELS / 2 - 1; // take away one cause the local guy is always in the room
+pub const NUM_PLAYERS_IN_ROOM: usize = MIXER_CHANNELS / 2 - 1; // take away one cause the local guy is always in the room
 
 /// Map of the clients.
 ///
@@ -23,14 +23,18 @@ const NUM_PLAYERS_IN_ROOM: usize = MIXER_CHANNELS / 2 - 1; // take away one caus
 ///
 /// Note that the local user is always assigned slots 0 and 1.  Your channels
 /// are always the first two on the mixer
+///
+/// Clients that just got a slot are kept as arrivals so the engine can put their
+/// [`PeerPreset`](super::peer_presets::PeerPreset) on the strips (see [`ChannelMap::take_arrivals`])
 pub struct ChannelMap {
     players: Vec<Player>,
+    arrivals: Vec<(u32, usize)>,
 }
 
 impl ChannelMap {
     /// Build a map
     pub fn new() -> ChannelMap {
-        let mut map = ChannelMap { players: vec![] };
+        let mut map = ChannelMap { players: vec![], arrivals: vec![] };
         for _ in 0..NUM_PLAYERS_IN_ROOM {
             map.players.push(Player::new(
                 0,
@@ -45,6 +49,7 @@ impl ChannelMap {
         for p in &mut self.players {
             p.clear();
         }
+        self.arrivals.clear();
     }
     /// see if any slots can be freed up (the guy left)
     pub fn prune(&mut self, now: u128) -> () {
@@ -73,6 +78,7 @@ impl ChannelMap {
                 match self.players.iter().position(|p| p.is_empty()) {
                     Some(idx) => {
                         self.players[idx].update(now, id, 0, seq);
+                        self.arrivals.push((id, (idx + 1) * 2));
                         Some((idx + 1) * 2)
                     }
                     None => None,
@@ -80,6 +86,24 @@ impl ChannelMap {
             }
         }
     }
+    /// first mixer channel for a client already in the map (does not add them)
+    pub fn find_channel(&self, id: u32) -> Option<usize> {
+        self.players
+            .iter()
+            .position(|c| !c.is_empty() && c.client_id == id)
+            .map(|idx| (idx + 1) * 2)
+    }
+    /// the client on a mixer channel.  None for the local channels and empty slots
+    pub fn client_at(&self, chan: usize) -> Option<u32> {
+        if chan < 2 {
+            return None;
+        }
+        self.players.get(chan / 2 - 1).filter(|c| !c.is_empty()).map(|c| c.client_id)
+    }
+    /// (client id, first mixer channel) for everyone given a slot since the last call
+    pub fn take_arrivals(&mut self) -> Vec<(u32, usize)> {
+        std::mem::take(&mut self.arrivals)
+    }
 }
 
 impl fmt::Display for ChannelMap {
@@ -110,6 +134,13 @@ mod test_channel_map {
         assert_eq!(val, 2);
         let val_2 = map.get_loc_channel(4444, now, 1).unwrap();
         assert_eq!(val_2, 4);
+        // both are new, seeing them again is not
+        assert_eq!(map.take_arrivals(), vec![(1234, 2), (4444, 4)]);
+        map.get_loc_channel(1234, now, 2);
+        assert!(map.take_arrivals().is_empty());
+        assert_eq!(map.find_channel(4444), Some(4));
+        assert_eq!(map.client_at(5), Some(4444));
+        assert_eq!(map.client_at(1), None);
         map.prune(now + EXPIRATION_IN_MICROSECONDS + 1);
     }
 }
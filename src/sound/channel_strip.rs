@@ -3,6 +3,9 @@
 //! Each strip has a fader and gain control.  It also has a PowerMeter that
 //! tells the power level for data running through the strip.
 //!
+//! Gain, fade and mute changes don't land all at once.  The left and right gains
+//! [`Ramp`] to their new values so a dragged fader doesn't zipper and a mute doesn't click.
+//!
 //! the strip also has a JitterBuffer  to which samples are stored.  When
 //! the strip is pulled for data, the samples come from the JitterBuffer
 //!
@@ -28,7 +31,11 @@
 use pedal_board::dsp::power_meter::PowerMeter;
 use std::fmt;
 
-use super::{fader::Fader, jitter_buffer::JitterBuffer};
+use super::{
+    fader::Fader,
+    jitter_buffer::JitterBuffer,
+    ramp::{ramp_samples, Ramp, DEFAULT_RAMP_MSEC},
+};
 
 /// represents a channel in a mixer
 pub struct ChannelStrip {
@@ -37,6 +44,9 @@ pub struct ChannelStrip {
     buffer: JitterBuffer,
     level: PowerMeter,
     mute: bool,
+    // what actually gets applied to the samples (gain, fade and mute together)
+    left: Ramp,
+    right: Ramp,
 }
 
 impl ChannelStrip {
@@ -47,17 +57,25 @@ impl ChannelStrip {
             buffer: JitterBuffer::new(),
             level: PowerMeter::new(),
             mute: false,
+            left: Ramp::new(1.0, ramp_samples(DEFAULT_RAMP_MSEC)),
+            right: Ramp::new(1.0, ramp_samples(DEFAULT_RAMP_MSEC)),
         }
     }
-    fn calc_values(&self, in_val: f32) -> (f32, f32) {
-        (
-            self.gain as f32 * in_val * self.fader.left(),
-            self.gain as f32 * in_val * self.fader.right(),
-        )
+    // point the ramps at the current settings
+    fn update_ramps(&mut self) {
+        let gain = if self.mute { 0.0 } else { self.gain as f32 };
+        self.left.set(gain * self.fader.left());
+        self.right.set(gain * self.fader.right());
+    }
+    /// how long (msec) gain, fade and mute changes take
+    pub fn set_ramp_time(&mut self, msec: f64) {
+        self.left.set_samples(ramp_samples(msec));
+        self.right.set_samples(ramp_samples(msec));
     }
     /// set the gain on the strip.  note v is linear and not in dB
     pub fn set_gain(&mut self, v: f64) -> () {
         self.gain = f64::clamp(v, 0.0, 8.0);
+        self.update_ramps();
     }
     /// retrieve the current gain setting
     pub fn get_gain(&self) -> f64 {
@@ -66,6 +84,7 @@ impl ChannelStrip {
     /// set the mute on the strip.
     pub fn set_mute(&mut self, enabled: bool) -> () {
         self.mute = enabled;
+        self.update_ramps();
     }
     /// retrieve the current gain setting
     pub fn get_mute(&self) -> bool {
@@ -74,6 +93,7 @@ impl ChannelStrip {
     /// set the fade value on the channels fader.  -1.0 hard left, +1.0 hard right
     pub fn set_fade(&mut self, v: f32) -> () {
         self.fader.set(v);
+        self.update_ramps();
     }
     pub fn get_fade(&self) -> f32 {
         self.fader.get()
@@ -86,17 +106,23 @@ impl ChannelStrip {
         // First get some data from the buff
         let samps = self.buffer.get(out_a.len(), self.level.get_avg());
         self.level.add_frame(&samps, self.gain);
-        // mix in the frame to the output if not muted
-        if !self.mute {
-        let mut i: usize = 0;
+        // mix in the frame to the output unless it's muted (and done fading out)
+        if !self.mute || !self.left.is_settled() || !self.right.is_settled() {
+            let mut i: usize = 0;
             for v in samps {
-                let (l, r) = self.calc_values(v);
-                out_a[i] = out_a[i] + l;
-                out_b[i] = out_b[i] + r;
+                out_a[i] = out_a[i] + v * self.left.tick();
+                out_b[i] = out_b[i] + v * self.right.tick();
                 i += 1;
             }
         }
     }
+    /// Pull a frame of samples out of the JitterBuffer into out without applying gain or fade.
+    ///
+    /// The room mixer uses this to make a different mix for each listener from the same frame
+    pub fn get_frame(&mut self, out: &mut [f32]) {
+        self.buffer.get_into(out);
+        self.level.add_frame(out, self.gain);
+    }
     /// Get the average power from the strips PowerMeter
     pub fn get_power_avg(&self) -> f64 {
         self.level.get_avg()
@@ -109,6 +135,10 @@ impl ChannelStrip {
     pub fn get_depth(&self) -> f64 {
         self.buffer.avg_depth()
     }
+    /// how shallow and deep (samples) the jitter buffer can adapt to
+    pub fn set_depth_limits(&mut self, min: usize, max: usize) {
+        self.buffer.set_depth_limits(min, max);
+    }
 
     /// push audio data into the channels jitter buffer
     pub fn add_data(&mut self, audio: &[f32]) -> () {
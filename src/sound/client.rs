@@ -14,8 +14,20 @@
 //! A final thread will be started to wake up every 10 seconds to ping the rtjam-nation server to
 //! indicate the component is still alive.
 //!
+//! If the local_ws_port config value is not zero, a [`local_websocket_thread`] is also started so a
+//! browser on the same network can talk to the unit without the round trip through rtjam-nation.
+//! Likewise a non zero osc_port starts the [`osc_thread`] for OSC control surfaces.
+//!
 //! Initial thread will then loop relaying mpsc messages between the various threads.
 //!
+//! The engine's user-facing state (mixer, pedal boards, codec gains, room) is saved to the
+//! state_file as it changes and restored at startup.  The rejoin_last_room setting also puts the
+//! unit back in the room it was in.  Named mixer scenes are kept in the scenes_file.
+//!
+//! The jitter buffer depths, level update interval, metronome settings, pan spread, ramp time and
+//! limiter ceiling are sent to the engine as commands at startup and again whenever they change in
+//! the settings file (or on SIGHUP) so they apply without restarting audio.
+//!
 //! All threads and components will return to a reconnect mode in the case that they cannot talk to their
 //! necessary systems.  If rtjam-nation goes down for some reason, the websocket will go into
 //! reconnect loop till it comes back.  Likewise the ping thread will go into a loop, re-register, and then
@@ -26,10 +38,13 @@
 //! have it re-initialize into acquire more if jack falls down in the middle.
 use crate::{
     common::{
+        auth::{rejection, CommandAuth},
         box_error::BoxError,
-        config::Config,
+        command_reply::command_reply,
         get_micro_time,
         jam_nation_api::{JamNationApi,JamNationApiTrait},
+        layered_config::{self, catch_hangup, LayeredConfig, Reloader},
+        local_websocket::{client_tag, start_local_websocket, UxSender},
         stream_time_stat::MicroTimer,
         websock_message::WebsockMessage, 
         websocket::{websocket_thread, WebSocketThreadFn},
@@ -38,14 +53,25 @@ use crate::{
         codec_control::ScanMode, hw_control_thread::hw_control_thread, status_light::{has_lights, HardwareMessage}
     }, 
     sound::{
-        alsa_thread, jack_thread, jam_engine::JamEngine, param_message::{JamParam, ParamMessage}
+        alsa_thread, diag_actions::{DiagRunner, DIAG_AUDIT_FILE}, jack_thread, jam_engine::{JamEngine, IDLE_REFRESH, MAX_IDLE_REFRESH, MIN_UPDATE_INTERVAL},
+        jitter_buffer::{check_depth_limits, MAX_DEPTH, MIN_DEPTH},
+        osc_thread::start_osc_thread,
+        mixer_scenes::{Scene, SceneFile},
+        ramp::{DEFAULT_RAMP_MSEC, MAX_RAMP_MSEC},
+        limiter::{check_ceiling, DEFAULT_CEILING},
+        unit_state::{UnitState, UnitStateFile},
+        param_message::{JamParam, ParamMessage},
+        status_topics::{encode_levels, StatusSnapshot, StatusTopic, StatusTopics},
     }, 
     utils,
 };
 use pedal_board::PedalBoard;
-use serde_json::json;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map};
+use simple_error::bail;
 use thread_priority::{ThreadBuilder, ThreadPriority};
 use std::{
+    collections::BTreeMap,
     io::{ErrorKind, Write},
     process::Command,
     sync::mpsc,
@@ -63,6 +89,7 @@ use log::{trace, debug, info, warn, error};
 /// - `git_hash`: A `String` representing the current version of the code, which will be sent to the rtjam-nation server.
 /// - `in_dev`: A `String` specifying the input device to be used for audio processing.
 /// - `out_dev`: A `String` specifying the output device for audio playback.
+/// - `options`: The settings file and what the command line changes in it ([`ClientOptions`]).
 ///
 /// note the git_hash string allows the software to tell rtjam-nation what version of code it
 /// is currently running.
@@ -70,28 +97,37 @@ pub fn run(
     git_hash: String, 
     use_alsa: bool, 
     in_dev: String, 
-    out_dev: String
+    out_dev: String,
+    options: ClientOptions,
 ) -> Result<(), BoxError> {
     info!("client - starting run function");
     // Initialize config and API connection
-    // TODO: pass in the config file name as an optional parameter
-    let (api_url, ws_url, mac_address, no_loopback) = init_config(None)?;
+    let cli = command_line(&options);
+    let settings = init_config(&options.config_file, &cli)?;
     debug!("client::run - config file init complete");
 
-    info!(
-        "Config values: api_url: {}, ws_url: {}, mac_address: {}, no_loopback: {}",
-        api_url, ws_url, mac_address, no_loopback
-    );
-
-    let mut api = JamNationApi::new(&api_url, &mac_address, &git_hash);
+    let mut api = JamNationApi::new(&settings.api_url, &settings.mac_address, &git_hash);
     let _connected_api = init_api_connection(&mut api)?;
     let token = String::from(api.get_token());
     debug!("client::run - API connection established. Token: {}", token);
 
     // Initialize websocket channels and thread
-    let (to_ws_tx, from_ws_rx, _ws_handle) = init_websocket_thread(&token, &ws_url, None)?;
+    let (to_ws_tx, from_ws_rx, _ws_handle) = init_websocket_thread(&token, &settings.ws_url, None)?;
     debug!("client::run - websocket connection established");
 
+    // Local websocket and OSC clients share a channel to the main loop
+    let (from_local_tx, from_local_rx) = mpsc::channel();
+    let to_local_tx = start_local_websocket(settings.local_ws_port, from_local_tx.clone());
+    let mut to_ux_tx = UxSender::new(to_ws_tx, to_local_tx);
+    if let Some(to_osc_tx) = start_osc_thread(
+        settings.osc_port,
+        settings.osc_meter_rate,
+        &settings.osc_meter_target,
+        from_local_tx,
+    ) {
+        to_ux_tx.add_listener(to_osc_tx);
+    }
+
     // Initialize hardware control channels and thread if needed
     let (light_option, _hw_handle) = init_hardware_control(&in_dev)?;
     // TODO:  This sleep is here so that the codec initialization that happens in the hardware control
@@ -106,20 +142,54 @@ pub fn run(
     let (command_tx, command_rx) = mpsc::channel();
     let (pedal_tx, pedal_rx) = mpsc::channel();
 
-    if no_loopback {
+    if settings.no_loopback {
         info!("client - local loopback disabled");        
     }
     // Create and start audio engine
-    let engine = JamEngine::new(
+    let mut engine = JamEngine::new(
         light_option,
         status_data_tx,
         command_rx,
         pedal_rx,
         api.get_token(),
         git_hash.as_str(),
-        no_loopback,
+        settings.no_loopback,
     )?;
     debug!("client::run - audio engine started");
+    engine.set_safety_ceiling(settings.safety_ceiling)?;
+
+    // put the unit back the way the user left it, and keep track of changes
+    let state_file = UnitStateFile::new(&settings.state_file);
+    let restored = match state_file.load() {
+        Some(state) => {
+            info!("restoring unit state from {}", settings.state_file);
+            engine.restore_state(&state, settings.rejoin_last_room);
+            true
+        }
+        None => false,
+    };
+    let (state_tx, state_rx) = mpsc::channel();
+    engine.set_state_channel(state_tx);
+    let (snapshot_tx, snapshot_rx) = mpsc::channel();
+    engine.set_status_channel(snapshot_tx);
+    start_state_thread(state_file, state_rx);
+    let scene_file = SceneFile::new(&settings.scenes_file);
+    engine.load_scenes(scene_file.load().unwrap_or_default());
+    let (scene_tx, scene_rx) = mpsc::channel();
+    engine.set_scene_channel(scene_tx);
+    start_scene_thread(scene_file, scene_rx);
+
+    // the hot settings go to the engine like any other command, now and when they change.  A
+    // restored metronome is what the user last set so the settings don't override it
+    let startup_keys: Vec<String> = ClientConfig::HOT_KEYS
+        .iter()
+        .filter(|k| !(restored && k.starts_with("metronome_")))
+        .map(|k| k.to_string())
+        .collect();
+    for msg in hot_commands(&settings, &startup_keys) {
+        command_tx.send(msg)?;
+    }
+    start_reload_thread(&options.config_file, cli, &settings, command_tx.clone())?;
 
     // Start appropriate hardware level sound thread
     if use_alsa {
@@ -138,54 +208,361 @@ pub fn run(
     });
     debug!("client::run - ping handle started");
 
+    let diag = start_diag_thread(init_diag_runner(&settings)?, to_ux_tx.clone());
+    let mut gate = CommandGate {
+        diag,
+        auth: CommandAuth::new(&settings.command_secret),
+    };
+
     debug!("client::run - setup complete, beginning main event loop");
-    run_main_loop(from_ws_rx, to_ws_tx, command_tx, pedal_tx, status_data_rx)?;
+    let status = StatusRelay {
+        status_data_rx,
+        snapshot_rx,
+        topics: StatusTopics::new(&git_hash),
+        interval: None,
+    };
+    run_main_loop(from_ws_rx, from_local_rx, to_ux_tx, command_tx, pedal_tx, status, &mut gate)?;
 
     Ok(())
 }
 
+/// the settings file the client reads (and watches) unless told otherwise
+pub const SETTINGS_FILE: &str = "settings.json";
+
+/// What the command line can change.  None leaves it to the settings file (or the default)
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub config_file: String,
+    pub api_url: Option<String>,
+    pub local_ws_port: Option<u32>,
+    pub osc_port: Option<u32>,
+    /// turn off the local loopback
+    pub no_loopback: bool,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            config_file: SETTINGS_FILE.to_string(),
+            api_url: None,
+            local_ws_port: None,
+            osc_port: None,
+            no_loopback: false,
+        }
+    }
+}
+
+/// the command line values that go on top of the settings file
+fn command_line(options: &ClientOptions) -> Map<String, serde_json::Value> {
+    let mut cli = Map::new();
+    if let Some(url) = &options.api_url {
+        cli.insert("api_url".to_string(), url.as_str().into());
+    }
+    if let Some(port) = options.local_ws_port {
+        cli.insert("local_ws_port".to_string(), port.into());
+    }
+    if let Some(port) = options.osc_port {
+        cli.insert("osc_port".to_string(), port.into());
+    }
+    if options.no_loopback {
+        cli.insert("no_loopback".to_string(), true.into());
+    }
+    cli
+}
+
+/// Client settings, layered from the defaults, the settings file, RTJAM_ environment variables and
+/// the command line by [`init_config`] (see [`layered_config`](crate::common::layered_config))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct ClientConfig {
+    /// The URL for the API endpoint.
+    api_url: String,
+    /// The WebSocket URL for real-time communication.
+    ws_url: String,
+    /// The MAC address of the device (not a setting).
+    #[serde(skip)]
+    mac_address: String,
+    /// Local loopback is disabled.
+    no_loopback: bool,
+    /// The port for the local websocket server (0, the default, disables it).
+    local_ws_port: u32,
+    /// The UDP port for OSC control surfaces (0 disables it).
+    osc_port: u32,
+    /// OSC meter bundles per second.
+    osc_meter_rate: u32,
+    /// Optional "ip:port" that always gets OSC meters.
+    osc_meter_target: String,
+    /// Let the u/x run any command line, not just the diag actions.
+    allow_raw_commands: bool,
+    /// Extra diag actions (json object of name: [program, args...]).
+    diag_actions: Option<serde_json::Value>,
+    /// Secret used to check command signatures (see [`CommandAuth`]).  When set every command has to be signed.
+    command_secret: String,
+    /// How shallow the jitter buffers can get (samples).
+    jitter_min_depth: u32,
+    /// How deep the jitter buffers can get (samples).
+    jitter_max_depth: u32,
+    /// How often (msec) level updates go to the u/x when it hasn't asked for faster.
+    idle_update_msec: u32,
+    /// Metronome click gain (dB).
+    metronome_gain: f64,
+    /// Metronome click starts out muted.
+    metronome_mute: bool,
+    /// How far (0.0 to 1.0) peers the unit hasn't mixed before are panned from center.
+    pan_spread: f64,
+    /// How long (msec) gain, pan and mute changes take to ramp in.
+    ramp_msec: f64,
+    /// Most (dBFS) the master bus can put out.
+    limiter_ceiling: f64,
+    /// Hearing-safety cap (dBFS) on the unit's output, whatever the mixer is set to.
+    safety_ceiling: f64,
+    /// Where the unit's state is saved between restarts (see [`UnitStateFile`]).
+    state_file: String,
+    /// Go back into the room the unit was in before it restarted.
+    rejoin_last_room: bool,
+    /// Where the mixer scenes are saved (see [`SceneFile`]).
+    scenes_file: String,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            api_url: "http://rtjam-nation.com/api/1/".to_string(),
+            ws_url: "ws://rtjam-nation.com/primus".to_string(),
+            mac_address: String::new(),
+            no_loopback: false,
+            local_ws_port: 0,
+            osc_port: 0,
+            osc_meter_rate: 10,
+            osc_meter_target: String::new(),
+            allow_raw_commands: false,
+            diag_actions: None,
+            command_secret: String::new(),
+            jitter_min_depth: MIN_DEPTH as u32,
+            jitter_max_depth: MAX_DEPTH as u32,
+            idle_update_msec: (IDLE_REFRESH / 1000) as u32,
+            metronome_gain: 0.0,
+            metronome_mute: true,
+            pan_spread: 0.0,
+            ramp_msec: DEFAULT_RAMP_MSEC,
+            limiter_ceiling: DEFAULT_CEILING,
+            safety_ceiling: DEFAULT_CEILING,
+            state_file: "unit_state.json".to_string(),
+            rejoin_last_room: false,
+            scenes_file: "scenes.json".to_string(),
+        }
+    }
+}
+
+impl LayeredConfig for ClientConfig {
+    const ENV_PREFIX: &'static str = "RTJAM_";
+    const HOT_KEYS: &'static [&'static str] = &["jitter_min_depth", "jitter_max_depth", "idle_update_msec", "metronome_gain", "metronome_mute", "pan_spread", "ramp_msec", "limiter_ceiling"];
+    fn validate(&self) -> Result<(), BoxError> {
+        check_depth_limits(self.jitter_min_depth as usize, self.jitter_max_depth as usize)?;
+        let (min, max) = (MIN_UPDATE_INTERVAL / 1000, MAX_IDLE_REFRESH / 1000);
+        if !(min..=max).contains(&(self.idle_update_msec as u128)) {
+            bail!("idle_update_msec {} should be {} to {}", self.idle_update_msec, min, max);
+        }
+        if self.osc_meter_rate == 0 {
+            bail!("osc_meter_rate has to be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.pan_spread) {
+            bail!("pan_spread should be between 0.0 and 1.0");
+        }
+        if !(0.0..=MAX_RAMP_MSEC).contains(&self.ramp_msec) {
+            bail!("ramp_msec should be between 0 and {}", MAX_RAMP_MSEC);
+        }
+        check_ceiling(self.limiter_ceiling)?;
+        check_ceiling(self.safety_ceiling)?;
+        Ok(())
+    }
+}
+
 /// Wraps client specific config value extraction into a convenience function.
 /// 
-/// This function attempts to load configuration values from a specified file. 
-/// If no file is provided, it defaults to "settings.json". It returns a 
-/// tuple containing the following values:
-/// 
-/// - `api_url`: The URL for the API endpoint.
-/// - `ws_url`: The WebSocket URL for real-time communication.
-/// - `mac_address`: The MAC address of the device.
-/// - `no_loopback`: A boolean indicating whether local loopback is disabled.
+/// This function loads configuration values from a specified file (usually
+/// [`SETTINGS_FILE`]) with the command line values (by key) on top. It returns a
+/// [`ClientConfig`] with the values.
 /// 
 /// # Errors
 /// This function will return an error if the configuration file cannot be 
-/// read or if any of the expected values are missing or invalid. 
-fn init_config(config_file: Option<&str>) -> Result<(String, String, String, bool), BoxError> {
-    let default_params = json::object! {
-        "api_url": "http://rtjam-nation.com/api/1/",
-        "ws_url": "ws://rtjam-nation.com/primus",
-        "no_loopback": false
-    };
-
-    // Default to settings.json if no file is provided
-    let filename = config_file.unwrap_or("settings.json");
+/// read or if any of the values are the wrong type or don't make sense.
+fn init_config(filename: &str, cli: &Map<String, serde_json::Value>) -> Result<ClientConfig, BoxError> {
     info!("Using config file: {}", filename);
 
-    let config = Config::build(String::from(filename), default_params)
+    let mut settings: ClientConfig = layered_config::load(filename, cli)
         .map_err(|e| {
             error!("Issue with config file or parameter: {}", e);
             e
         })?;
+    settings.mac_address = utils::get_my_mac_address()?;
+
+    // don't put the secret in the logs
+    let shown = ClientConfig { command_secret: String::from("<hidden>"), ..settings.clone() };
+    info!("Config values: {:?}", shown);
+
+    Ok(settings)
+}
+
+/// The engine commands that put the hot settings (keys) into effect
+fn hot_commands(settings: &ClientConfig, keys: &[String]) -> Vec<ParamMessage> {
+    let mut commands = vec![];
+    if keys.iter().any(|k| k.starts_with("jitter_")) {
+        commands.push(ParamMessage::new(
+            JamParam::SetJitterDepth,
+            settings.jitter_min_depth as i64,
+            settings.jitter_max_depth as i64,
+            0.0,
+            "",
+        ));
+    }
+    if keys.iter().any(|k| k == "idle_update_msec") {
+        commands.push(ParamMessage::new(JamParam::SetIdleRefresh, settings.idle_update_msec as i64, 0, 0.0, ""));
+    }
+    if keys.iter().any(|k| k == "metronome_gain") {
+        commands.push(ParamMessage::new(JamParam::MetronomeGain, 0, 0, settings.metronome_gain, ""));
+    }
+    if keys.iter().any(|k| k == "metronome_mute") {
+        commands.push(ParamMessage::new(JamParam::MetronomeMute, settings.metronome_mute as i64, 0, 0.0, ""));
+    }
+    if keys.iter().any(|k| k == "pan_spread") {
+        commands.push(ParamMessage::new(JamParam::SetPanSpread, 0, 0, settings.pan_spread, ""));
+    }
+    if keys.iter().any(|k| k == "ramp_msec") {
+        commands.push(ParamMessage::new(JamParam::SetRampTime, 0, 0, settings.ramp_msec, ""));
+    }
+    if keys.iter().any(|k| k == "limiter_ceiling") {
+        commands.push(ParamMessage::new(JamParam::SetLimiterCeiling, 0, 0, settings.limiter_ceiling, ""));
+    }
+    commands
+}
 
-    let api_url = String::from(config.get_str_value("api_url", None)?);
-    let ws_url = String::from(config.get_str_value("ws_url", None)?);
-    let mac_address = utils::get_my_mac_address()?;
-    let no_loopback = config.get_bool_value("no_loopback", None)?;
+/// Save each [`UnitState`] the engine sends.  Saving is off the audio thread
+fn start_state_thread(file: UnitStateFile, state_rx: mpsc::Receiver<UnitState>) {
+    thread::spawn(move || {
+        for state in state_rx {
+            if let Err(e) = file.save(&state) {
+                warn!("can't save unit state: {}", e);
+            }
+        }
+    });
+}
+
+/// Save the scenes each time the engine sends them
+fn start_scene_thread(file: SceneFile, scene_rx: mpsc::Receiver<BTreeMap<String, Scene>>) {
+    thread::spawn(move || {
+        for scenes in scene_rx {
+            if let Err(e) = file.save(&scenes) {
+                warn!("can't save mixer scenes: {}", e);
+            }
+        }
+    });
+}
 
-    info!(
-        "Config values: api_url: {}, ws_url: {}, mac_address: {}, no_loopback: {}",
-        api_url, ws_url, mac_address, no_loopback
-    );
+/// Watch the settings file (and SIGHUP) and send the engine any hot settings that change.  The
+/// command line values (cli) stay on top
+fn start_reload_thread(
+    filename: &str,
+    cli: Map<String, serde_json::Value>,
+    settings: &ClientConfig,
+    command_tx: mpsc::Sender<ParamMessage>,
+) -> Result<(), BoxError> {
+    catch_hangup()?;
+    let mut reloader = Reloader::new(filename, cli, settings.clone(), get_micro_time());
+    thread::spawn(move || loop {
+        sleep(Duration::from_millis(250));
+        if let Some((settings, keys)) = reloader.poll(get_micro_time()) {
+            for msg in hot_commands(&settings, &keys) {
+                let _res = command_tx.send(msg);
+            }
+        }
+    });
+    Ok(())
+}
 
-    Ok((api_url, ws_url, mac_address, no_loopback))
+/// What u/x commands have to get through before they are carried out
+struct CommandGate {
+    /// RandomCommand requests go to the diag thread (see [`start_diag_thread`])
+    diag: mpsc::Sender<ParamMessage>,
+    /// signature checks
+    auth: CommandAuth,
+}
+
+/// Status from the engine on its way to the u/x
+struct StatusRelay {
+    /// events the engine builds itself (levelEvent, command replies, ...)
+    status_data_rx: mpsc::Receiver<serde_json::Value>,
+    /// what the status topics are built from
+    snapshot_rx: mpsc::Receiver<StatusSnapshot>,
+    /// the topic subscriptions (see [`status_topics`](crate::sound::status_topics))
+    topics: StatusTopics,
+    /// snapshot interval (usec) the engine was last told
+    interval: Option<u128>,
+}
+
+impl StatusRelay {
+    /// subscribe (or unsubscribe) the sender of a subscribeStatus
+    fn subscribe(&mut self, msg: &ParamMessage) -> Result<serde_json::Value, BoxError> {
+        let topic = StatusTopic::from_name(&msg.svalue)?;
+        if msg.ivalue_1 > 0 {
+            let delta = msg.ivalue_2 & 1 != 0;
+            let binary = msg.ivalue_2 & 2 != 0;
+            self.topics.subscribe(msg.client, topic, msg.ivalue_1, delta, binary, get_micro_time());
+        } else {
+            self.topics.unsubscribe(msg.client, topic);
+        }
+        Ok(self.topics.as_json(msg.client))
+    }
+    /// let the engine know how often to send snapshots when the subscriptions change
+    fn sync_engine(&mut self, command_tx: &mpsc::Sender<ParamMessage>) {
+        let fastest = self.topics.fastest();
+        if fastest != self.interval {
+            self.interval = fastest;
+            let msec = fastest.map(|i| (i / 1000) as i64).unwrap_or(0);
+            let _res = command_tx.send(ParamMessage::new(JamParam::SetStatusInterval, msec, 0, 0.0, ""));
+        }
+    }
+}
+
+/// Build the allow-list of diagnostic actions the u/x can run (see [`diag_actions`])
+fn init_diag_runner(settings: &ClientConfig) -> Result<DiagRunner, BoxError> {
+    if settings.allow_raw_commands {
+        warn!("raw commands from the u/x are enabled");
+    }
+    let mut diag = DiagRunner::new(settings.allow_raw_commands, Some(DIAG_AUDIT_FILE));
+    if let Some(actions) = &settings.diag_actions {
+        diag.add_actions(actions)?;
+    }
+    Ok(diag)
+}
+
+/// Run the diag actions on their own thread.  An action can take up to the diag timeout and the
+/// main loop has to keep relaying in the meantime, so the output and the reply are sent straight to
+/// the u/x from here.
+fn start_diag_thread(diag: DiagRunner, to_ux_tx: UxSender) -> mpsc::Sender<ParamMessage> {
+    let (diag_tx, diag_rx) = mpsc::channel::<ParamMessage>();
+    thread::spawn(move || {
+        for msg in diag_rx {
+            let result = match diag.run(&msg.svalue) {
+                Ok(output) => {
+                    let _res = to_ux_tx.send(WebsockMessage::Chat(msg.addressed(output.clone())));
+                    Ok(output)
+                }
+                Err(e) => {
+                    warn!("diag action {} failed: {}", msg.svalue, e);
+                    let _res = to_ux_tx.send(WebsockMessage::Chat(msg.addressed(json!({
+                        "speaker": "UnitChatRobot",
+                        "cmdOutput": format!("Error: {}", e),
+                    }))));
+                    Err(e)
+                }
+            };
+            if let Some(reply) = msg.reply(result) {
+                let _res = to_ux_tx.send(WebsockMessage::Chat(reply));
+            }
+        }
+    });
+    diag_tx
 }
 
 /// Initializes the API connection by registering the jam unit and retrying if necessary.
@@ -311,16 +688,20 @@ fn start_jack_thread(engine: JamEngine) -> Result<thread::JoinHandle<()>, BoxErr
 
 fn run_main_loop(
     from_ws_rx: mpsc::Receiver<serde_json::Value>,
-    to_ws_tx: mpsc::Sender<WebsockMessage>,
+    from_local_rx: mpsc::Receiver<serde_json::Value>,
+    to_ws_tx: UxSender,
     command_tx: mpsc::Sender<ParamMessage>,
     pedal_tx: mpsc::Sender<PedalBoard>,
-    status_data_rx: mpsc::Receiver<serde_json::Value>,
+    mut status: StatusRelay,
+    gate: &mut CommandGate,
 ) -> Result<(), BoxError> {
     let mut websock_room_ping = MicroTimer::new(get_micro_time(), 2_000_000);
 
     loop {
-        handle_websocket_messages(&from_ws_rx, &to_ws_tx, &command_tx, &pedal_tx)?;
-        handle_status_messages(&status_data_rx, &to_ws_tx)?;
+        handle_websocket_messages(&from_ws_rx, &to_ws_tx, &command_tx, &pedal_tx, gate, &mut status, false)?;
+        // local clients speak the same language as the room
+        handle_websocket_messages(&from_local_rx, &to_ws_tx, &command_tx, &pedal_tx, gate, &mut status, true)?;
+        handle_status_messages(&mut status, &to_ws_tx, &command_tx)?;
         handle_room_ping(&mut websock_room_ping, &to_ws_tx)?;
         
         sleep(Duration::new(0, 200_000));
@@ -329,53 +710,95 @@ fn run_main_loop(
 
 fn handle_websocket_messages(
     from_ws_rx: &mpsc::Receiver<serde_json::Value>,
-    to_ws_tx: &mpsc::Sender<WebsockMessage>,
+    to_ws_tx: &UxSender,
     command_tx: &mpsc::Sender<ParamMessage>,
     pedal_tx: &mpsc::Sender<PedalBoard>,
+    gate: &mut CommandGate,
+    status: &mut StatusRelay,
+    local: bool,
 ) -> Result<(), BoxError> {
     match from_ws_rx.try_recv() {
         Ok(m) => {
             info!("websocket message: {}", m);
-            if let Ok(msg) = ParamMessage::from_json(&m) {
-                match msg.param {
-                    JamParam::SetAudioInput => {
-                        info!("Set audio input: {}", msg);
-                        write_string_to_file("soundin.cfg", &msg.svalue);
-                    }
-                    JamParam::SetAudioOutput => {
-                        info!("Set audio output: {}", msg);
-                        write_string_to_file("soundout.cfg", &msg.svalue);
-                    }
-                    JamParam::ListAudioConfig => {
-                        let _res = to_ws_tx.send(WebsockMessage::Chat(make_audio_config()));
-                    }
-                    JamParam::CheckForUpdate => {
-                        info!("Check for update requested. Restarting.");
-                        std::process::exit(-1);
-                    }
-                    JamParam::RandomCommand => {
-                        info!("Rando: {}", msg);
-                        info!("Output: {}", run_a_command(&msg.svalue));
-                        let _res = to_ws_tx.send(WebsockMessage::Chat(run_a_command(&msg.svalue)));
-                    }
-                    JamParam::ShutdownDevice => {
-                        info!("Exiting app");
-                        std::process::exit(-1);
+            if local && m["wsClosed"] == true {
+                // a local connection went away (only the local websocket says so).  The engine drops anything it kept for it
+                let client = client_tag(&m);
+                status.topics.drop_client(client);
+                status.sync_engine(command_tx);
+                let _res = command_tx.send(ParamMessage::new(JamParam::ClientClosed, client as i64, 0, 0.0, ""));
+                return Ok(());
+            }
+            if let Err(e) = gate.auth.verify(&m, (get_micro_time() / 1_000_000) as u64) {
+                warn!("rejected command: {}", e);
+                to_ws_tx.send(WebsockMessage::Chat(rejection("UnitChatRobot", &m, e)))?;
+                return Ok(());
+            }
+            match ParamMessage::from_json(&m) {
+                Ok(msg) => {
+                    let mut result = Ok(serde_json::Value::Null);
+                    if matches!(msg.param, JamParam::ConnectionKeepAlive) {
+                        // the engine gets it too (below)
+                        status.topics.keep_alive(msg.client, get_micro_time());
                     }
-                    JamParam::LoadBoard => {
-                        let idx = msg.ivalue_1 as usize;
-                        if idx < 2 {
-                            let mut board = PedalBoard::new(idx);
-                            board.load_from_json(&msg.svalue);
-                            let _res = pedal_tx.send(board);
+                    match msg.param {
+                        JamParam::SetAudioInput => {
+                            info!("Set audio input: {}", msg);
+                            write_string_to_file("soundin.cfg", &msg.svalue);
+                        }
+                        JamParam::SetAudioOutput => {
+                            info!("Set audio output: {}", msg);
+                            write_string_to_file("soundout.cfg", &msg.svalue);
+                        }
+                        JamParam::ListAudioConfig => {
+                            let config = make_audio_config();
+                            result = Ok(config["audioHardware"].clone());
+                            let _res = to_ws_tx.send(WebsockMessage::Chat(config));
+                        }
+                        JamParam::CheckForUpdate => {
+                            info!("Check for update requested. Restarting.");
+                            std::process::exit(-1);
+                        }
+                        JamParam::RandomCommand => {
+                            info!("Diag action: {}", msg);
+                            // The diag thread will reply to this one
+                            let _res = gate.diag.send(msg);
+                            return Ok(());
+                        }
+                        JamParam::SubscribeStatus => {
+                            result = status.subscribe(&msg);
+                            status.sync_engine(command_tx);
+                        }
+                        JamParam::ShutdownDevice => {
+                            info!("Exiting app");
+                            std::process::exit(-1);
+                        }
+                        JamParam::LoadBoard => {
+                            let idx = msg.ivalue_1 as usize;
+                            if idx < 2 {
+                                let mut board = PedalBoard::new(idx);
+                                board.load_from_json(&msg.svalue);
+                                let _res = pedal_tx.send(board);
+                            } else {
+                                result = Err(format!("board {} out of range", idx).into());
+                            }
+                        }
+                        _ => {
+                            // The engine will reply to this one
+                            let _res = command_tx.send(msg);
+                            return Ok(());
                         }
                     }
-                    _ => {
-                        let _res = command_tx.send(msg);
+                    if let Some(reply) = msg.reply(result) {
+                        to_ws_tx.send(WebsockMessage::Chat(reply))?;
+                    }
+                }
+                Err(e) => {
+                    warn!("JSON parse Error: {}", e);
+                    // Let a u/x waiting for a reply know it's not coming
+                    if let Some(id) = m["requestId"].as_str() {
+                        to_ws_tx.send(WebsockMessage::Chat(command_reply("UnitChatRobot", id, Err(e))))?;
                     }
                 }
-            } else {
-                warn!("JSON parse Error");
             }
         }
         Err(mpsc::TryRecvError::Empty) => {}
@@ -385,10 +808,24 @@ fn handle_websocket_messages(
 }
 
 fn handle_status_messages(
-    status_data_rx: &mpsc::Receiver<serde_json::Value>,
-    to_ws_tx: &mpsc::Sender<WebsockMessage>,
+    status: &mut StatusRelay,
+    to_ws_tx: &UxSender,
+    command_tx: &mpsc::Sender<ParamMessage>,
 ) -> Result<(), BoxError> {
-    match status_data_rx.try_recv() {
+    // status topics are built here from the latest snapshot so the audio thread doesn't have to
+    if let Some(snapshot) = status.snapshot_rx.try_iter().last() {
+        for event in status.topics.publish(&snapshot, get_micro_time()) {
+            if event["statusEvent"]["binary"] == true {
+                let levels = encode_levels(&event["statusEvent"]["data"]);
+                to_ws_tx.send(WebsockMessage::Binary(client_tag(&event), levels))?;
+            } else {
+                to_ws_tx.send(WebsockMessage::Chat(event))?;
+            }
+        }
+        // idle subscriptions may have been dropped
+        status.sync_engine(command_tx);
+    }
+    match status.status_data_rx.try_recv() {
         Ok(m) => {
             trace!("audio thread message: {}", m.to_string());
             to_ws_tx.send(WebsockMessage::Chat(m))?;
@@ -406,7 +843,7 @@ fn handle_status_messages(
 
 fn handle_room_ping(
     websock_room_ping: &mut MicroTimer,
-    to_ws_tx: &mpsc::Sender<WebsockMessage>,
+    to_ws_tx: &UxSender,
 ) -> Result<(), BoxError> {
     let now = get_micro_time();
     if websock_room_ping.expired(now) {
@@ -477,28 +914,6 @@ fn make_audio_config() -> serde_json::Value {
     })
 }
 
-fn run_a_command(cmd_line: &str) -> serde_json::Value {
-    let vals = cmd_line.split(" ").collect::<Vec<&str>>();
-    let mut rval = String::from("Error");
-    let mut cmd = Command::new(vals[0]);
-    for arg in &vals[1..] {
-        cmd.arg(arg);
-    }
-    match cmd.output() {
-        Ok(output) => {
-            rval = String::from_utf8_lossy(&output.stdout).to_string();
-            debug!("run command: {}", rval);
-        }
-        Err(err) => {
-            debug!("run command Error: {}, command: {}", err, cmd_line);
-        }
-    }
-    json!({
-        "cmdOutput": rval,
-        "speaker": "UnitChatRobot"
-    })
-}
-
 fn write_string_to_file(fname: &str, contents: &str) -> () {
     match std::fs::OpenOptions::new()
         .write(true)
@@ -546,26 +961,67 @@ mod init_config {
             let default_params = json::object! {
                 "api_url": "http://rtjam-nation.com/api/1/",
                 "ws_url": "ws://rtjam-nation.com/primus",
-                "no_loopback": false
+                "no_loopback": false,
+                "local_ws_port": 0,
+                "osc_port": 0,
+                "osc_meter_rate": 10,
+                "osc_meter_target": "",
+                "allow_raw_commands": false
             };
         */
         let expected_api_url = "http://rtjam-nation.com/api/1/";
         let expected_ws_url = "ws://rtjam-nation.com/primus";
         let expected_no_loopback = false;
+        let expected_local_ws_port = 0;
 
-        let result = init_config(Some("custom_settings.json"));
+        let result = init_config("custom_settings.json", &Map::new());
         assert!(result.is_ok());
-        let (api_url, ws_url, mac_address, no_loopback) = result.unwrap();
-        assert_eq!(api_url, expected_api_url);
-        assert_eq!(ws_url, expected_ws_url);
-        assert!(!mac_address.is_empty());
-        assert_eq!(no_loopback, expected_no_loopback);
+        let settings = result.unwrap();
+        assert_eq!(settings.api_url, expected_api_url);
+        assert_eq!(settings.ws_url, expected_ws_url);
+        assert!(!settings.mac_address.is_empty());
+        assert_eq!(settings.no_loopback, expected_no_loopback);
+        assert_eq!(settings.local_ws_port, expected_local_ws_port);
+        assert_eq!(settings.osc_port, 0);
+        assert_eq!(settings.osc_meter_rate, 10);
+        assert_eq!(settings.osc_meter_target, "");
+        assert!(!settings.allow_raw_commands);
+        assert!(settings.diag_actions.is_none());
+        assert_eq!(settings.jitter_min_depth, MIN_DEPTH as u32);
+        assert!(settings.metronome_mute);
+        // the command line goes on top
+        let options = ClientOptions { local_ws_port: Some(8080), no_loopback: true, ..Default::default() };
+        let settings = init_config("custom_settings.json", &command_line(&options)).unwrap();
+        assert_eq!(settings.local_ws_port, 8080);
+        assert!(settings.no_loopback);
+        assert_eq!(settings.osc_port, 0);
+    }
+
+    #[test]
+    fn test_hot_commands() {
+        let settings = ClientConfig { jitter_min_depth: 256, metronome_gain: -6.0, ..Default::default() };
+        let keys = vec!["jitter_min_depth".to_string(), "metronome_gain".to_string()];
+        let commands = hot_commands(&settings, &keys);
+        assert_eq!(commands.len(), 2);
+        assert!(matches!(commands[0].param, JamParam::SetJitterDepth));
+        assert_eq!(commands[0].ivalue_1, 256);
+        assert_eq!(commands[0].ivalue_2, MAX_DEPTH as i64);
+        assert!(matches!(commands[1].param, JamParam::MetronomeGain));
+        assert_eq!(commands[1].fvalue, -6.0);
+        assert!(settings.validate().is_ok());
+        let shallow = ClientConfig { jitter_max_depth: 600, ..settings.clone() };
+        assert!(shallow.validate().is_err());
+        let idle = hot_commands(&settings, &["idle_update_msec".to_string()]);
+        assert!(matches!(idle[0].param, JamParam::SetIdleRefresh));
+        assert_eq!(idle[0].ivalue_1, 2000);
+        let hasty = ClientConfig { idle_update_msec: 10, ..settings };
+        assert!(hasty.validate().is_err());
     }
 
     #[test]
     fn test_bad_file_name() {
         // Test with custom config file
-        let result = init_config(Some("Illegal*File$Name"));
+        let result = init_config("Illegal*File$Name", &Map::new());
         assert!(result.is_err());
         assert!(result.err().unwrap().to_string().starts_with("Invalid filename 'Illegal*File$Name'"));
     }
@@ -721,4 +1177,33 @@ mod init_api_connection {
 //             true => assert!(light_option.is_some()),
 //             false => assert!(light_option.is_none())
 //         }
-//     }
\ No newline at end of file
+//     }
+
+#[cfg(test)]
+mod diag_thread {
+    use super::*;
+
+    #[test]
+    fn runs_off_the_main_loop() {
+        let (room_tx, room_rx) = mpsc::channel();
+        let (local_tx, local_rx) = mpsc::channel();
+        let mut runner = DiagRunner::new(false, None);
+        runner.add_actions(&json!({ "slow": ["sleep", "0.2"] })).unwrap();
+        let diag_tx = start_diag_thread(runner, UxSender::new(room_tx, Some(local_tx)));
+        let mut msg = ParamMessage::new(JamParam::RandomCommand, 0, 0, 0.0, "slow");
+        msg.request_id = Some(String::from("d1"));
+        msg.client = 3;
+        let start = std::time::Instant::now();
+        diag_tx.send(msg).unwrap();
+        // handing it off doesn't wait for the action
+        assert!(start.elapsed() < Duration::from_millis(100));
+        // the output then the reply, only to the local connection that asked
+        for _ in 0..2 {
+            match local_rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+                WebsockMessage::Chat(v) => assert_eq!(v["wsClient"], 3),
+                other => panic!("unexpected message {}", other),
+            }
+        }
+        assert!(room_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,286 @@
+//! Named diagnostic actions the u/x can run on the unit
+//!
+//! `JamParam::RandomCommand` used to hand its svalue straight to [`Command`], so anyone who could
+//! post in the unit's chat room could run anything on the Pi.  Now the svalue names an action from
+//! an allow-list followed by its arguments:
+//!
+//! ```text
+//! ping 10.0.0.1
+//! audio_devices
+//! ```
+//!
+//! Each action has a fixed program and argument template.  `{0}`, `{1}`... in the template are
+//! replaced by the caller's arguments, which must be short and only contain characters that can't
+//! be used to sneak in options or paths (letters, digits, `.`, `:`, `_` and `-` but not leading).
+//! Extra actions can be added in settings.json:
+//!
+//! ```json
+//! { "diag_actions": { "wifi": ["iwconfig", "wlan0"] } }
+//! ```
+//!
+//! Every run is time limited, the output is capped, and the request and result are written to
+//! the audit log.  Raw command lines (the old behavior) only run if `allow_raw_commands` is set.
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use simple_error::bail;
+
+use crate::common::{box_error::BoxError, get_micro_time};
+
+/// how long an action can run before we kill it
+pub const DIAG_TIMEOUT: Duration = Duration::from_secs(10);
+/// most output (bytes) we keep from an action
+pub const DIAG_MAX_OUTPUT: usize = 16 * 1024;
+/// where every request gets recorded
+pub const DIAG_AUDIT_FILE: &str = "diag_audit.log";
+/// longest argument a caller can pass
+const MAX_ARG_LEN: usize = 64;
+
+/// The allow-list of actions and the limits to run them with
+pub struct DiagRunner {
+    actions: BTreeMap<String, Vec<String>>,
+    allow_raw: bool,
+    timeout: Duration,
+    audit_file: Option<String>,
+}
+
+impl DiagRunner {
+    /// build a runner with the default actions
+    pub fn new(allow_raw: bool, audit_file: Option<&str>) -> DiagRunner {
+        let mut runner = DiagRunner {
+            actions: BTreeMap::new(),
+            allow_raw,
+            timeout: DIAG_TIMEOUT,
+            audit_file: audit_file.map(String::from),
+        };
+        for (name, template) in [
+            ("uptime", vec!["uptime"]),
+            ("disk", vec!["df", "-h"]),
+            ("memory", vec!["free", "-m"]),
+            ("network", vec!["ip", "addr"]),
+            ("ping", vec!["ping", "-c", "3", "-W", "2", "{0}"]),
+            ("audio_devices", vec!["aplay", "-l"]),
+            ("capture_devices", vec!["arecord", "-l"]),
+            ("temperature", vec!["vcgencmd", "measure_temp"]),
+            ("jack_ports", vec!["jack_lsp"]),
+            ("sound_log", vec!["journalctl", "-u", "rtjam-jack", "-n", "50", "--no-pager"]),
+        ] {
+            runner.actions.insert(name.to_string(), template.iter().map(|s| s.to_string()).collect());
+        }
+        runner
+    }
+    /// add (or replace) actions from a json object of name: [program, args...]
+    pub fn add_actions(&mut self, actions: &Value) -> Result<(), BoxError> {
+        let actions = match actions.as_object() {
+            Some(a) => a,
+            None => bail!("diag actions must be an object"),
+        };
+        for (name, template) in actions {
+            let template: Vec<String> = serde_json::from_value(template.clone())?;
+            if template.is_empty() {
+                bail!("diag action {} has no program", name);
+            }
+            self.actions.insert(name.clone(), template);
+        }
+        Ok(())
+    }
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+    /// names of the actions that can be run
+    pub fn action_names(&self) -> Vec<String> {
+        self.actions.keys().cloned().collect()
+    }
+    /// Run a request.  The request is an action name followed by its arguments.  An empty request
+    /// lists the actions.
+    pub fn run(&self, request: &str) -> Result<Value, BoxError> {
+        let words: Vec<&str> = request.split_whitespace().collect();
+        if words.is_empty() {
+            return Ok(json!({ "actions": self.action_names() }));
+        }
+        let result = match self.actions.get(words[0]) {
+            Some(template) => expand(template, &words[1..])
+                .and_then(|argv| run_with_limits(&argv, self.timeout, DIAG_MAX_OUTPUT)),
+            None if self.allow_raw => {
+                warn!("running raw command: {}", request);
+                let argv: Vec<String> = words.iter().map(|s| s.to_string()).collect();
+                run_with_limits(&argv, self.timeout, DIAG_MAX_OUTPUT)
+            }
+            None => Err(format!("unknown diag action {}", words[0]).into()),
+        };
+        self.audit(request, &result);
+        let mut result = result?;
+        result["action"] = json!(words[0]);
+        Ok(result)
+    }
+    fn audit(&self, request: &str, result: &Result<Value, BoxError>) {
+        let entry = match result {
+            Ok(r) => json!({
+                "time": get_micro_time() as u64 / 1_000_000,
+                "request": request,
+                "exitCode": r["exitCode"],
+                "timedOut": r["timedOut"],
+            }),
+            Err(e) => json!({
+                "time": get_micro_time() as u64 / 1_000_000,
+                "request": request,
+                "error": e.to_string(),
+            }),
+        };
+        info!("diag audit: {}", entry);
+        if let Some(fname) = &self.audit_file {
+            let res = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(fname)
+                .and_then(|mut f| writeln!(f, "{}", entry));
+            if let Err(e) = res {
+                warn!("can't write diag audit log {}: {}", fname, e);
+            }
+        }
+    }
+}
+
+/// make sure an argument from the u/x can't be anything but a simple value
+fn validate_arg(arg: &str) -> Result<(), BoxError> {
+    if arg.is_empty() || arg.len() > MAX_ARG_LEN {
+        bail!("bad argument length");
+    }
+    if arg.starts_with('-') {
+        bail!("argument {} can't be an option", arg);
+    }
+    if !arg.chars().all(|c| c.is_ascii_alphanumeric() || ".:_-".contains(c)) {
+        bail!("argument {} has illegal characters", arg);
+    }
+    Ok(())
+}
+
+/// fill in the template with the arguments.  The argument count must match the template
+fn expand(template: &[String], args: &[&str]) -> Result<Vec<String>, BoxError> {
+    let wanted = template.iter().filter(|t| t.starts_with('{') && t.ends_with('}')).count();
+    if args.len() != wanted {
+        bail!("expected {} arguments, got {}", wanted, args.len());
+    }
+    let mut argv = vec![];
+    for t in template {
+        if t.starts_with('{') && t.ends_with('}') {
+            let idx: usize = t[1..t.len() - 1].parse()?;
+            match args.get(idx) {
+                Some(arg) => {
+                    validate_arg(arg)?;
+                    argv.push(arg.to_string());
+                }
+                None => bail!("template argument {} missing", t),
+            }
+        } else {
+            argv.push(t.clone());
+        }
+    }
+    Ok(argv)
+}
+
+/// read up to max bytes and throw away the rest so the child doesn't block on a full pipe
+fn read_capped(mut src: impl Read + Send + 'static, max: usize) -> thread::JoinHandle<(Vec<u8>, bool)> {
+    thread::spawn(move || {
+        let mut buf = vec![];
+        let _res = (&mut src).take(max as u64).read_to_end(&mut buf);
+        let extra = io::copy(&mut src, &mut io::sink()).unwrap_or(0);
+        (buf, extra > 0)
+    })
+}
+
+/// run a program with a timeout and output cap
+fn run_with_limits(argv: &[String], timeout: Duration, max_output: usize) -> Result<Value, BoxError> {
+    let mut child = Command::new(&argv[0])
+        .args(&argv[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = read_capped(child.stdout.take().unwrap(), max_output);
+    let stderr = read_capped(child.stderr.take().unwrap(), max_output);
+    let start = Instant::now();
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if start.elapsed() > timeout {
+            warn!("diag command {:?} timed out", argv);
+            let _res = child.kill();
+            let _res = child.wait();
+            timed_out = true;
+            break None;
+        }
+        sleep(Duration::from_millis(10));
+    };
+    let (out, out_cut) = stdout.join().unwrap_or_default();
+    let (err, err_cut) = stderr.join().unwrap_or_default();
+    Ok(json!({
+        "speaker": "UnitChatRobot",
+        "cmdOutput": String::from_utf8_lossy(&out),
+        "cmdError": String::from_utf8_lossy(&err),
+        "exitCode": status.and_then(|s| s.code()),
+        "timedOut": timed_out,
+        "truncated": out_cut || err_cut,
+    }))
+}
+
+#[cfg(test)]
+mod test_diag_actions {
+    use super::*;
+
+    #[test]
+    fn validates_args() {
+        assert!(validate_arg("10.0.0.1").is_ok());
+        assert!(validate_arg("rtjam-nation.com").is_ok());
+        assert!(validate_arg("-f").is_err());
+        assert!(validate_arg("a;rm").is_err());
+        assert!(validate_arg("../etc").is_err());
+        assert!(validate_arg(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn expands_templates() {
+        let template: Vec<String> = ["ping", "-c", "3", "{0}"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(expand(&template, &["host"]).unwrap(), vec!["ping", "-c", "3", "host"]);
+        assert!(expand(&template, &[]).is_err());
+        assert!(expand(&template, &["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn only_allowed_actions() {
+        let mut runner = DiagRunner::new(false, None);
+        runner.add_actions(&json!({"echo": ["echo", "hi", "{0}"]})).unwrap();
+        let out = runner.run("echo there").unwrap();
+        assert_eq!(out["cmdOutput"], "hi there\n");
+        assert_eq!(out["exitCode"], 0);
+        assert_eq!(out["action"], "echo");
+        assert!(runner.run("rm -rf /").is_err());
+        assert!(runner.run("echo $(reboot)").is_err());
+        assert!(runner.run("").unwrap()["actions"].as_array().unwrap().contains(&json!("echo")));
+        // raw commands only when enabled
+        assert!(DiagRunner::new(true, None).run("echo raw").unwrap()["cmdOutput"] == "raw\n");
+    }
+
+    #[test]
+    fn limits_time_and_output() {
+        let mut runner = DiagRunner::new(false, None);
+        runner
+            .add_actions(&json!({"nap": ["sleep", "5"], "big": ["head", "-c", "100000", "/dev/zero"]}))
+            .unwrap();
+        runner.set_timeout(Duration::from_millis(200));
+        let out = runner.run("nap").unwrap();
+        assert_eq!(out["timedOut"], true);
+        let out = runner.run("big").unwrap();
+        assert_eq!(out["truncated"], true);
+        assert_eq!(out["cmdOutput"].as_str().unwrap().len(), DIAG_MAX_OUTPUT);
+    }
+}
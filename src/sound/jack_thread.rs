@@ -30,6 +30,9 @@ pub fn run(mut engine: JamEngine) -> Result<(), BoxError> {
                 let mut out_a = client.register_port("rtjam_out_l", jack::AudioOut::default())?;
                 let mut out_b = client.register_port("rtjam_out_r", jack::AudioOut::default())?;
                 let midi_in = client.register_port("rtjam_midi_input", jack::MidiIn::default())?;
+                // a period going in, two coming out
+                let period = client.buffer_size() as u64 * 1_000_000 / client.sample_rate() as u64;
+                engine.set_device_latency(period as u32, 2 * period as u32);
         
                 // The callback gets called by jack whenever we have a frame
                 let process_callback =
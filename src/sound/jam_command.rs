@@ -0,0 +1,328 @@
+//! Typed commands for the sound engine
+//!
+//! The legacy [`ParamMessage`] packs every command into the same four values (ivalue_1, ivalue_2,
+//! fvalue, svalue) and each [`JamParam`] interprets them differently.  A [`JamCommand`] names its
+//! fields instead.  The u/x sends them tagged by "cmd" with an optional "requestId":
+//!
+//! ```json
+//! { "cmd": "setChannelGain", "requestId": "abc123", "channel": 4, "gain": -6.0 }
+//! { "cmd": "movePedal", "board": 0, "from": 2, "to": 0 }
+//! ```
+//!
+//! [`ParamMessage::from_json`] recognizes this form and converts it into the legacy encoding so
+//! the engine only has one dispatcher.  When a request id is given the engine answers with a
+//! [`command_reply`](crate::common::command_reply::command_reply).
+use serde::{Deserialize, Serialize};
+
+use crate::sound::param_message::{JamParam, ParamMessage};
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "cmd", rename_all = "camelCase")]
+pub enum JamCommand {
+    /// join a room on a broadcast server
+    JoinRoom { host: String, port: i64, identity: i64 },
+    /// leave the room
+    Disconnect,
+    /// gain (dB) on the master output
+    SetMasterVolume { gain: f64 },
+    /// gain (dB) on a mixer channel
+    SetChannelGain { channel: i64, gain: f64 },
+    /// mute a mixer channel
+    SetChannelMute { channel: i64, mute: bool },
+    /// pan a mixer channel (-1.0 to 1.0)
+    SetChannelFade { channel: i64, fade: f64 },
+    /// send silence to the room for an input channel
+    MuteToRoom { channel: i64, mute: bool },
+    /// gain (dB) on the metronome click
+    SetMetronomeGain { gain: f64 },
+    /// mute the metronome click
+    SetMetronomeMute { mute: bool },
+    /// hardware input gain (units with a codec)
+    SetInputGain { channel: i64, gain: f64 },
+    /// hardware headphone gain (units with a codec)
+    SetHeadphoneGain { gain: f64 },
+    /// turn the tuner on or off for an input channel
+    TuneChannel { channel: i64, on: bool },
+    /// ask for the pedalInfo event
+    GetPedalInfo,
+    /// ask for the list of pedal types
+    GetPedalTypes,
+    /// add a pedal to a board at index
+    InsertPedal {
+        board: i64,
+        #[serde(rename = "pedalType")]
+        pedal_type: String,
+        index: i64,
+    },
+    /// remove a pedal from a board
+    DeletePedal { board: i64, index: i64 },
+    /// move a pedal on a board
+    MovePedal { board: i64, from: i64, to: i64 },
+    /// change a setting on a pedal.  setting is the setting json (name, value, etc)
+    SetPedalSetting {
+        board: i64,
+        pedal: i64,
+        setting: serde_json::Value,
+    },
+    /// replace a board with a saved config (json string)
+    LoadBoard { board: i64, config: String },
+    /// how often (msec) the u/x wants level updates
+    SetUpdateInterval { msec: i64 },
+    /// let the engine know a u/x is still there
+    KeepAlive,
+    /// get the parameter tree under a path prefix (everything if empty)
+    GetParams {
+        #[serde(default)]
+        prefix: String,
+    },
+    /// set a parameter by path.  bools can be sent as true/false
+    SetParam { path: String, value: serde_json::Value },
+    /// subscribe (or unsubscribe) to changes under a path prefix
+    SubscribeParams {
+        #[serde(default)]
+        prefix: String,
+        on: bool,
+    },
+    /// get a status topic every interval msec.  See [`crate::sound::status_topics`]
+    SubscribeStatus {
+        topic: String,
+        interval: i64,
+        #[serde(default)]
+        delta: bool,
+        #[serde(default)]
+        binary: bool,
+    },
+    /// stop getting a status topic
+    UnsubscribeStatus { topic: String },
+    /// password for rooms that need one.  Send before (or after) roomChange
+    SetRoomPassword {
+        #[serde(default)]
+        password: String,
+    },
+    /// how shallow and deep (samples) the jitter buffers adapt
+    SetJitterDepth { min: i64, max: i64 },
+    /// gain (dB) on one of a peer's channels (0 or 1).  Kept for the next time they join too
+    SetPeerGain {
+        #[serde(rename = "clientId")]
+        client_id: i64,
+        channel: i64,
+        gain: f64,
+    },
+    /// mute one of a peer's channels
+    SetPeerMute {
+        #[serde(rename = "clientId")]
+        client_id: i64,
+        channel: i64,
+        mute: bool,
+    },
+    /// pan one of a peer's channels (-1.0 to 1.0)
+    SetPeerFade {
+        #[serde(rename = "clientId")]
+        client_id: i64,
+        channel: i64,
+        fade: f64,
+    },
+    /// go back to the default mix for a peer
+    ForgetPeer {
+        #[serde(rename = "clientId")]
+        client_id: i64,
+    },
+    /// how far (0.0 to 1.0) newcomers are panned from center
+    SetPanSpread { spread: f64 },
+    /// get the names of the saved mixer scenes
+    ListScenes,
+    /// save the mixer as a named scene, with the room tempo (bpm) if the u/x knows it
+    SaveScene {
+        name: String,
+        #[serde(default)]
+        tempo: u32,
+    },
+    /// put the mixer to a saved scene, crossfading over fadeMsec
+    RecallScene {
+        name: String,
+        #[serde(rename = "fadeMsec", default)]
+        fade_msec: i64,
+    },
+    /// delete a saved scene
+    DeleteScene { name: String },
+    /// how long (msec) gain, pan and mute changes take to ramp in
+    SetRampTime { msec: f64 },
+    /// most (dBFS) the master bus can put out
+    SetLimiterCeiling { ceiling: f64 },
+}
+
+/// A typed command plus the optional id used to match the reply
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CommandRequest {
+    #[serde(rename = "requestId")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub command: JamCommand,
+}
+
+impl JamCommand {
+    /// convert to the legacy encoding understood by the engine
+    pub fn to_param_message(&self) -> ParamMessage {
+        match self {
+            JamCommand::JoinRoom { host, port, identity } => {
+                ParamMessage::new(JamParam::RoomChange, *port, *identity, 0.0, host)
+            }
+            JamCommand::Disconnect => ParamMessage::new(JamParam::Disconnect, 0, 0, 0.0, ""),
+            JamCommand::SetMasterVolume { gain } => {
+                ParamMessage::new(JamParam::MasterVol, 0, 0, *gain, "")
+            }
+            JamCommand::SetChannelGain { channel, gain } => {
+                ParamMessage::new(JamParam::ChannelGain, *channel, 0, *gain, "")
+            }
+            JamCommand::SetChannelMute { channel, mute } => {
+                ParamMessage::new(JamParam::ChannelMute, *channel, *mute as i64, 0.0, "")
+            }
+            JamCommand::SetChannelFade { channel, fade } => {
+                ParamMessage::new(JamParam::SetFader, *channel, 0, *fade, "")
+            }
+            JamCommand::MuteToRoom { channel, mute } => {
+                ParamMessage::new(JamParam::MuteToRoom, *channel, *mute as i64, 0.0, "")
+            }
+            JamCommand::SetMetronomeGain { gain } => {
+                ParamMessage::new(JamParam::MetronomeGain, 0, 0, *gain, "")
+            }
+            JamCommand::SetMetronomeMute { mute } => {
+                ParamMessage::new(JamParam::MetronomeMute, *mute as i64, 0, 0.0, "")
+            }
+            JamCommand::SetInputGain { channel, gain } => {
+                ParamMessage::new(JamParam::InputGain, *channel, 0, *gain, "")
+            }
+            JamCommand::SetHeadphoneGain { gain } => {
+                ParamMessage::new(JamParam::HeadphoneGain, 0, 0, *gain, "")
+            }
+            JamCommand::TuneChannel { channel, on } => {
+                ParamMessage::new(JamParam::TuneChannel, *channel, *on as i64, 0.0, "")
+            }
+            JamCommand::GetPedalInfo => ParamMessage::new(JamParam::GetConfigJson, 0, 0, 0.0, ""),
+            JamCommand::GetPedalTypes => ParamMessage::new(JamParam::GetPedalTypes, 0, 0, 0.0, ""),
+            JamCommand::InsertPedal { board, pedal_type, index } => {
+                ParamMessage::new(JamParam::InsertPedal, *board, *index, 0.0, pedal_type)
+            }
+            JamCommand::DeletePedal { board, index } => {
+                ParamMessage::new(JamParam::DeletePedal, *board, *index, 0.0, "")
+            }
+            JamCommand::MovePedal { board, from, to } => {
+                // The legacy encoding hides the destination in fvalue
+                ParamMessage::new(JamParam::MovePedal, *board, *from, *to as f64, "")
+            }
+            JamCommand::SetPedalSetting { board, pedal, setting } => ParamMessage::new(
+                JamParam::SetEffectConfig,
+                *board,
+                *pedal,
+                0.0,
+                &setting.to_string(),
+            ),
+            JamCommand::LoadBoard { board, config } => {
+                ParamMessage::new(JamParam::LoadBoard, *board, 0, 0.0, config)
+            }
+            JamCommand::SetUpdateInterval { msec } => {
+                ParamMessage::new(JamParam::SetUpdateInterval, *msec, 0, 0.0, "")
+            }
+            JamCommand::KeepAlive => {
+                ParamMessage::new(JamParam::ConnectionKeepAlive, 0, 0, 0.0, "")
+            }
+            JamCommand::GetParams { prefix } => {
+                ParamMessage::new(JamParam::GetParams, 0, 0, 0.0, prefix)
+            }
+            JamCommand::SetParam { path, value } => {
+                let fvalue = match value {
+                    serde_json::Value::Bool(b) => *b as i64 as f64,
+                    // anything not a number fails the range check in the engine
+                    v => v.as_f64().unwrap_or(f64::NAN),
+                };
+                ParamMessage::new(JamParam::SetParam, 0, 0, fvalue, path)
+            }
+            JamCommand::SubscribeParams { prefix, on } => {
+                ParamMessage::new(JamParam::SubscribeParams, *on as i64, 0, 0.0, prefix)
+            }
+            JamCommand::SubscribeStatus { topic, interval, delta, binary } => {
+                let flags = *delta as i64 | (*binary as i64) << 1;
+                // an interval of 0 would unsubscribe so keep it at the fastest rate
+                ParamMessage::new(JamParam::SubscribeStatus, (*interval).max(1), flags, 0.0, topic)
+            }
+            JamCommand::UnsubscribeStatus { topic } => {
+                ParamMessage::new(JamParam::SubscribeStatus, 0, 0, 0.0, topic)
+            }
+            JamCommand::SetRoomPassword { password } => {
+                ParamMessage::new(JamParam::SetRoomPassword, 0, 0, 0.0, password)
+            }
+            JamCommand::SetJitterDepth { min, max } => {
+                ParamMessage::new(JamParam::SetJitterDepth, *min, *max, 0.0, "")
+            }
+            JamCommand::SetPeerGain { client_id, channel, gain } => {
+                ParamMessage::new(JamParam::PeerGain, *client_id, *channel, *gain, "")
+            }
+            JamCommand::SetPeerMute { client_id, channel, mute } => {
+                ParamMessage::new(JamParam::PeerMute, *client_id, *channel, *mute as i64 as f64, "")
+            }
+            JamCommand::SetPeerFade { client_id, channel, fade } => {
+                ParamMessage::new(JamParam::PeerFade, *client_id, *channel, *fade, "")
+            }
+            JamCommand::ForgetPeer { client_id } => {
+                ParamMessage::new(JamParam::ForgetPeer, *client_id, 0, 0.0, "")
+            }
+            JamCommand::SetPanSpread { spread } => {
+                ParamMessage::new(JamParam::SetPanSpread, 0, 0, *spread, "")
+            }
+            JamCommand::ListScenes => ParamMessage::new(JamParam::ListScenes, 0, 0, 0.0, ""),
+            JamCommand::SaveScene { name, tempo } => ParamMessage::new(JamParam::SaveScene, *tempo as i64, 0, 0.0, name),
+            JamCommand::RecallScene { name, fade_msec } => {
+                ParamMessage::new(JamParam::RecallScene, *fade_msec, 0, 0.0, name)
+            }
+            JamCommand::DeleteScene { name } => ParamMessage::new(JamParam::DeleteScene, 0, 0, 0.0, name),
+            JamCommand::SetRampTime { msec } => ParamMessage::new(JamParam::SetRampTime, 0, 0, *msec, ""),
+            JamCommand::SetLimiterCeiling { ceiling } => {
+                ParamMessage::new(JamParam::SetLimiterCeiling, 0, 0, *ceiling, "")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_jam_command {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_tagged() {
+        let req: CommandRequest = serde_json::from_value(json!({
+            "cmd": "setChannelGain",
+            "requestId": "abc",
+            "channel": 4,
+            "gain": -6.0
+        }))
+        .unwrap();
+        assert_eq!(req.request_id, Some("abc".to_string()));
+        assert_eq!(req.command, JamCommand::SetChannelGain { channel: 4, gain: -6.0 });
+    }
+
+    #[test]
+    fn request_id_optional() {
+        let req: CommandRequest = serde_json::from_value(json!({"cmd": "disconnect"})).unwrap();
+        assert!(req.request_id.is_none());
+        assert_eq!(req.command, JamCommand::Disconnect);
+    }
+
+    #[test]
+    fn move_pedal_legacy_encoding() {
+        let msg = JamCommand::MovePedal { board: 1, from: 2, to: 0 }.to_param_message();
+        assert!(matches!(msg.param, JamParam::MovePedal));
+        assert_eq!(msg.ivalue_1, 1);
+        assert_eq!(msg.ivalue_2, 2);
+        assert_eq!(msg.fvalue, 0.0);
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        let res: Result<CommandRequest, _> =
+            serde_json::from_value(json!({"cmd": "setChannelGain", "gain": 1.0}));
+        assert!(res.is_err());
+        let res: Result<CommandRequest, _> = serde_json::from_value(json!({"cmd": "noSuchThing"}));
+        assert!(res.is_err());
+    }
+}
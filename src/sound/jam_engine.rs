@@ -1,36 +1,56 @@
 //! the JamEngine aggregates all the sound components into a single structure.  
 //!
 //! The engine drives off the [`JamEngine::process`] function
-use std::{str::FromStr, sync::mpsc};
+use std::{collections::BTreeMap, str::FromStr, sync::{mpsc, Arc}};
 
 use jack::RawMidi;
 use serde_json::json;
+use simple_error::bail;
 use pedal_board::dsp::{power_meter::PowerMeter, tuner::Tuner};
+use pedal_board::utils::to_db;
 use pedal_board::pedals::pedal_board::PedalBoard;
 use crate::{
     common::{
         box_error::BoxError,
         get_micro_time,
-        jam_packet::JamMessage,
+        jam_packet::{
+            reject_reason, JamMessage, PACKET_ADMIT, PACKET_AUDIO, PACKET_CHALLENGE, PACKET_LATENCY, PACKET_NOTICE,
+            PACKET_REJECT,
+        },
+        latency_matrix::{DeviceReport, LatencyMatrix},
         stream_time_stat::{MicroTimer, StreamTimeStat},
     },  hw_control::status_light::HardwareMessage, 
 };
 
 use super::SoundCallback;
 use super::{
-    channel_map::ChannelMap,
+    channel_map::{ChannelMap, NUM_PLAYERS_IN_ROOM},
     jam_socket::JamSocket,
+    jitter_buffer::{check_depth_limits, MAX_DEPTH, MIN_DEPTH},
+    limiter::{check_ceiling, SafetyClip, DEFAULT_CEILING},
+    unit_state::{ChannelState, RoomState, UnitState, STATE_INTERVAL},
     mixer::{Mixer, MIXER_CHANNELS},
+    mixer_scenes::{Crossfade, Scene},
     param_message::{JamParam, ParamMessage},
+    param_tree::{self, ParamInfo, ParamWatcher},
+    peer_presets::{PeerPreset, PeerPresets},
+    ramp::{ramp_samples, Ramp, DEFAULT_RAMP_MSEC, MAX_RAMP_MSEC},
+    status_topics::{ChannelLevel, PeerStatus, StatusSnapshot, MIN_TOPIC_INTERVAL},
 };
 
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 
 
 // Set a timer for how long a connect will hold up without a keepalive from the web client
 pub const IDLE_DISCONNECT: u128 = 90 * 60 * 1000 * 1000; // 90 minutes
 pub const IDLE_REFRESH: u128 = 2 * 1000 * 1000; // 2 seconds
 pub const  LIGHT_REFRESH: u128 = 50 * 1000; // 50 msec
+pub const MIN_UPDATE_INTERVAL: u128 = 150 * 1000; // 150 msec
+pub const MAX_IDLE_REFRESH: u128 = 10 * 1000 * 1000; // 10 seconds
+pub const JOIN_RETRY: u128 = 2 * 1000 * 1000; // 2 seconds
+pub const REPORT_INTERVAL: u128 = 1000 * 1000; // 1 second
+// UDP port the unit talks to rooms from.  Tests take any free one so they can run side by side
+const LOCAL_PORT: i64 = if cfg!(test) { 0 } else { 9991 };
 
 /// Aggregates all the sound components into a single structure
 ///
@@ -41,9 +61,15 @@ pub const  LIGHT_REFRESH: u128 = 50 * 1000; // 50 msec
 /// - UDP Socket and Connection state to rooms hosted by broadcast components [`JamSocket`]
 /// - Audio Mixer for room members (optionally, including the unit itself) [`Mixer`]
 /// - ChannelMap to map room members to mixer channels [`ChannelMap`]
+/// - Mixer settings for each room member by client id, applied wherever they land [`PeerPresets`]
+/// - Named mixer scenes the user can save and recall (with a crossfade) [`Scene`]
+/// - A hearing-safety [`SafetyClip`] on the local output that the mixer settings can't get around
 /// - PedalBoards for the two local channesl [`PedalBoard`]
 /// - Tuners for both incoming channels (to tune your instruments) [`Tuner`]
 ///
+/// What the user has set (mixer, pedal boards, codec gains, the room) can be saved and restored
+/// across restarts as a [`UnitState`] (see [`JamEngine::set_state_channel`] and [`JamEngine::restore_state`]).
+///
 ///
 /// To avoid having a mutex around the objects in the process loop, the JamEngine is created
 /// with a mpsc::Sender and mpsc::Receiver.  The Engine will send json formatted status messages
@@ -64,6 +90,8 @@ pub struct JamEngine {
     update_timer: MicroTimer,
     light_timer: MicroTimer,
     update_fallback_timer: MicroTimer,
+    // level update interval to fall back to
+    idle_refresh: u128,
     disconnect_timer: MicroTimer,
     debug_timer: MicroTimer,
     token: String,
@@ -79,6 +107,81 @@ pub struct JamEngine {
     no_loopback: bool,
     room_mutes: [bool; 2],
     beat: u8,
+    param_watcher: ParamWatcher,
+    status_tx: Option<mpsc::Sender<StatusSnapshot>>,
+    // how often a status snapshot is sent (None when nobody is subscribed)
+    status_timer: Option<MicroTimer>,
+    pedal_json: Arc<serde_json::Value>,
+    pedals_changed: bool,
+    room_password: String,
+    admitted: bool,
+    reject_code: u8,
+    join_timer: MicroTimer,
+    device_latency: (u32, u32),
+    room_takes_reports: bool,
+    report_timer: MicroTimer,
+    jitter_limits: (usize, usize),
+    input_gains: [Option<f64>; 2],
+    headphone_gain: Option<f64>,
+    last_room: Option<RoomState>,
+    state_tx: Option<mpsc::Sender<UnitState>>,
+    state_timer: MicroTimer,
+    // something that may be in the unit state changed since it was last looked at
+    state_dirty: bool,
+    saved_state: Option<UnitState>,
+    peer_presets: PeerPresets,
+    // by slot, the client whose strips were changed since their preset was last kept
+    peers_touched: [Option<u32>; NUM_PLAYERS_IN_ROOM],
+    scenes: BTreeMap<String, Scene>,
+    scene_tx: Option<mpsc::Sender<BTreeMap<String, Scene>>>,
+    crossfade: Option<Crossfade>,
+    // fades what goes to the room in and out on a room mute
+    room_mute_ramps: [Ramp; 2],
+    ramp_msec: f64,
+    safety: SafetyClip,
+}
+
+// does the command change anything kept in the UnitState
+fn changes_saved_state(param: &JamParam) -> bool {
+    matches!(
+        param,
+        JamParam::ChanGain1
+            | JamParam::ChanGain2
+            | JamParam::ChanGain3
+            | JamParam::ChanGain4
+            | JamParam::ChanGain5
+            | JamParam::ChanGain6
+            | JamParam::ChanGain7
+            | JamParam::ChanGain8
+            | JamParam::ChanGain9
+            | JamParam::ChanGain10
+            | JamParam::ChanGain11
+            | JamParam::ChanGain12
+            | JamParam::ChanGain13
+            | JamParam::ChanGain14
+            | JamParam::MasterVol
+            | JamParam::SetFader
+            | JamParam::ChannelGain
+            | JamParam::ChannelMute
+            | JamParam::MuteToRoom
+            | JamParam::MetronomeGain
+            | JamParam::MetronomeMute
+            | JamParam::InputGain
+            | JamParam::HeadphoneGain
+            | JamParam::PeerGain
+            | JamParam::PeerMute
+            | JamParam::PeerFade
+            | JamParam::ForgetPeer
+            | JamParam::RecallScene
+            | JamParam::RoomChange
+            | JamParam::Disconnect
+            | JamParam::InsertPedal
+            | JamParam::DeletePedal
+            | JamParam::MovePedal
+            | JamParam::SetEffectConfig
+            | JamParam::TuneChannel
+            | JamParam::SetParam
+    )
 }
 
 impl SoundCallback for JamEngine {
@@ -104,18 +207,27 @@ impl SoundCallback for JamEngine {
         self.check_disconnect();
         self.check_command();
         self.check_pedal_board();
+        self.check_join();
+        self.send_report();
+        self.save_state();
+        self.step_crossfade();
         self.read_network();
         self.send_my_audio(in_a, in_b);
         self.debug_output();
     }
     fn get_playback_data(&mut self, out_a: &mut [f32], out_b: &mut [f32]) -> () {
         self.mixer.get_mix(self.beat, out_a, out_b);
+        // whatever the mix does, the headphones don't get more than this
+        self.safety.process(out_a, out_b);
     }
 
     /// This will let you know if the engine is still running
     fn is_running(&self) -> bool {
         self.is_running
     }
+    fn set_device_latency(&mut self, capture: u32, playback: u32) {
+        self.device_latency = (capture, playback);
+    }
 }
 
 impl JamEngine {
@@ -142,7 +254,7 @@ impl JamEngine {
         let now = get_micro_time();
         let mut engine = JamEngine {
             is_running: true,
-            sock: JamSocket::new(9991)?,
+            sock: JamSocket::new(LOCAL_PORT)?,
             recv_message: JamMessage::new(),
             xmit_message: JamMessage::new(),
             lights_option: lights_option,
@@ -152,6 +264,7 @@ impl JamEngine {
             update_timer: MicroTimer::new(now, IDLE_REFRESH),
             light_timer: MicroTimer::new(now, LIGHT_REFRESH),
             update_fallback_timer: MicroTimer::new(now, IDLE_REFRESH * 5),
+            idle_refresh: IDLE_REFRESH,
             disconnect_timer: MicroTimer::new(now, IDLE_DISCONNECT), // 15 minutes in uSeconds
             debug_timer: MicroTimer::new(now, 500_000),
             token: String::from(tok),
@@ -168,11 +281,226 @@ impl JamEngine {
             no_loopback: no_loopback,
             room_mutes: [false, false],
             beat: 0,
+            param_watcher: ParamWatcher::new(),
+            status_tx: None,
+            status_timer: None,
+            pedal_json: Arc::new(serde_json::Value::Null),
+            pedals_changed: true,
+            room_password: String::new(),
+            admitted: false,
+            reject_code: 0,
+            join_timer: MicroTimer::new(now, JOIN_RETRY),
+            device_latency: (0, 0),
+            jitter_limits: (MIN_DEPTH, MAX_DEPTH),
+            room_takes_reports: false,
+            report_timer: MicroTimer::new(now, REPORT_INTERVAL),
+            input_gains: [None, None],
+            headphone_gain: None,
+            last_room: None,
+            state_tx: None,
+            state_timer: MicroTimer::new(now, STATE_INTERVAL),
+            state_dirty: false,
+            saved_state: None,
+            peer_presets: PeerPresets::new(),
+            peers_touched: [None; NUM_PLAYERS_IN_ROOM],
+            scenes: BTreeMap::new(),
+            scene_tx: None,
+            crossfade: None,
+            room_mute_ramps: [
+                Ramp::new(1.0, ramp_samples(DEFAULT_RAMP_MSEC)),
+                Ramp::new(1.0, ramp_samples(DEFAULT_RAMP_MSEC)),
+            ],
+            ramp_msec: DEFAULT_RAMP_MSEC,
+            safety: SafetyClip::new(DEFAULT_CEILING),
         };
         // Set out client id to some rando number when not connected
         engine.xmit_message.set_client_id(4321);
         Ok(engine)
     }
+    /// the hearing-safety cap (dBFS) on what the unit plays.  Set from the settings, not the u/x
+    pub fn set_safety_ceiling(&mut self, ceiling: f64) -> Result<(), BoxError> {
+        check_ceiling(ceiling)?;
+        self.safety.set_ceiling(ceiling);
+        Ok(())
+    }
+    /// send a [`UnitState`] here whenever the user-facing settings change
+    pub fn set_state_channel(&mut self, tx: mpsc::Sender<UnitState>) {
+        self.state_tx = Some(tx);
+    }
+    /// what the user has set the unit to
+    pub fn snapshot(&self) -> UnitState {
+        UnitState {
+            master_vol: self.mixer.get_master(),
+            channels: (0..MIXER_CHANNELS)
+                .map(|idx| ChannelState {
+                    // the mixer hands back linear gain
+                    gain: to_db(self.mixer.get_channel_gain(idx)),
+                    mute: self.mixer.get_channel_mute(idx),
+                    fade: self.mixer.get_channel_fade(idx),
+                })
+                .collect(),
+            metronome_gain: self.mixer.get_metronome_gain(),
+            metronome_mute: self.mixer.get_metronome_mute(),
+            room_mutes: self.room_mutes,
+            tuners: [self.tuners[0].enable, self.tuners[1].enable],
+            pedal_boards: vec![self.pedal_boards[0].as_json(0), self.pedal_boards[1].as_json(1)],
+            input_gains: self.input_gains,
+            headphone_gain: self.headphone_gain,
+            peer_presets: self.peer_mixes(),
+            room: self.last_room.clone(),
+        }
+    }
+    /// put the unit back the way it was.  rejoin goes back into the last room (if it was in one)
+    pub fn restore_state(&mut self, state: &UnitState, rejoin: bool) {
+        self.mixer.set_master(state.master_vol);
+        for (idx, chan) in state.channels.iter().enumerate().take(MIXER_CHANNELS) {
+            self.mixer.set_channel_gain(idx, chan.gain);
+            self.mixer.set_channel_mute(idx, chan.mute);
+            self.mixer.set_channel_fade(idx, chan.fade);
+        }
+        self.mixer.set_metronome_gain(state.metronome_gain);
+        self.mixer.set_metronome_mute(state.metronome_mute);
+        self.room_mutes = state.room_mutes;
+        for (tuner, enable) in self.tuners.iter_mut().zip(state.tuners) {
+            tuner.enable = enable;
+        }
+        for (idx, board) in state.pedal_boards.iter().enumerate().take(2) {
+            // a board loads from its effects (what a saved board calls its config)
+            let mut pedals = PedalBoard::new(idx);
+            pedals.load_from_json(&board["effects"].to_string());
+            self.pedal_boards[idx] = pedals;
+        }
+        self.peer_presets.load(&state.peer_presets);
+        self.peers_touched = [None; NUM_PLAYERS_IN_ROOM];
+        self.input_gains = state.input_gains;
+        self.headphone_gain = state.headphone_gain;
+        if let Some(tx) = &self.lights_option {
+            if state.input_gains.iter().any(Option::is_some) || state.headphone_gain.is_some() {
+                let _res = tx.send(HardwareMessage::GainMessage {
+                    input_1_gain: state.input_gains[0].unwrap_or(-1.0),
+                    input_2_gain: state.input_gains[1].unwrap_or(-1.0),
+                    headphone_gain: state.headphone_gain.unwrap_or(-1.0),
+                });
+            }
+        }
+        self.last_room = state.room.clone();
+        if let Some(room) = &state.room {
+            if rejoin {
+                info!("rejoining room {}:{}", room.host, room.port);
+                self.connect(&room.host, room.port, room.identity);
+            }
+        }
+        self.saved_state = Some(self.snapshot());
+    }
+    // send out the state when it changed.  Only looked at when something marked it dirty
+    fn save_state(&mut self) {
+        let tx = match &self.state_tx {
+            Some(tx) if self.state_dirty && self.state_timer.expired(self.now) => tx,
+            _ => return,
+        };
+        self.state_timer.reset(self.now);
+        self.state_dirty = false;
+        let state = self.snapshot();
+        if self.saved_state.as_ref() != Some(&state) {
+            let _res = tx.send(state.clone());
+            self.saved_state = Some(state);
+        }
+    }
+    /// send a [`StatusSnapshot`] here as often as the status topic subscriptions need
+    pub fn set_status_channel(&mut self, tx: mpsc::Sender<StatusSnapshot>) {
+        self.status_tx = Some(tx);
+    }
+    /// the saved scenes, by name
+    pub fn load_scenes(&mut self, scenes: BTreeMap<String, Scene>) {
+        self.scenes = scenes;
+    }
+    /// send all the scenes here whenever one is saved or deleted
+    pub fn set_scene_channel(&mut self, tx: mpsc::Sender<BTreeMap<String, Scene>>) {
+        self.scene_tx = Some(tx);
+    }
+    fn send_scenes(&self) {
+        if let Some(tx) = &self.scene_tx {
+            let _res = tx.send(self.scenes.clone());
+        }
+    }
+    // the mixer as it is now.  Only the peers in the room are included (by client id)
+    fn capture_scene(&self) -> Scene {
+        let local = |idx| ChannelState {
+            gain: to_db(self.mixer.get_channel_gain(idx)),
+            mute: self.mixer.get_channel_mute(idx),
+            fade: self.mixer.get_channel_fade(idx),
+        };
+        let mut peers = BTreeMap::new();
+        for c in self.chan_map.get_clients().iter().filter(|c| !c.is_empty()) {
+            if let Some(idx) = self.chan_map.find_channel(c.client_id) {
+                peers.insert(c.client_id, self.mixer.get_preset(idx));
+            }
+        }
+        Scene {
+            master_vol: self.mixer.get_master(),
+            local: [local(0), local(1)],
+            peers,
+            metronome_gain: self.mixer.get_metronome_gain(),
+            metronome_mute: self.mixer.get_metronome_mute(),
+            // the room has the tempo, the u/x adds it when it saves the scene
+            tempo: None,
+            room_mutes: self.room_mutes,
+        }
+    }
+    // put a scene on the mixer.  Peers not in the room pick theirs up from the presets when they join
+    fn apply_scene(&mut self, scene: &Scene) {
+        self.mixer.set_master(scene.master_vol);
+        for (idx, chan) in scene.local.iter().enumerate() {
+            self.mixer.set_channel_gain(idx, chan.gain);
+            self.mixer.set_channel_mute(idx, chan.mute);
+            self.mixer.set_channel_fade(idx, chan.fade);
+        }
+        for (id, preset) in &scene.peers {
+            if let Some(idx) = self.chan_map.find_channel(*id) {
+                self.mixer.apply_preset(idx, preset);
+            }
+        }
+        self.mixer.set_metronome_gain(scene.metronome_gain);
+        self.mixer.set_metronome_mute(scene.metronome_mute);
+        self.room_mutes = scene.room_mutes;
+    }
+    fn recall_scene(&mut self, name: &str, fade_msec: i64) -> Result<(), BoxError> {
+        let scene = match self.scenes.get(name) {
+            Some(scene) => scene.clone(),
+            None => bail!("no scene named {}", name),
+        };
+        for (id, preset) in &scene.peers {
+            self.peer_presets.remember(*id, preset.clone());
+        }
+        if fade_msec > 0 {
+            let duration = fade_msec as u128 * 1000;
+            let strips: Vec<(u32, usize)> =
+                scene.peers.keys().filter_map(|id| self.chan_map.find_channel(*id).map(|idx| (*id, idx))).collect();
+            self.crossfade = Some(Crossfade::new(self.capture_scene(), scene, &strips, self.now, duration));
+        } else {
+            self.crossfade = None;
+            self.apply_scene(&scene);
+        }
+        Ok(())
+    }
+    // move the mix along if a scene is fading in
+    fn step_crossfade(&mut self) {
+        if let Some(fade) = self.crossfade.take() {
+            let (t, done) = fade.progress(self.now);
+            self.apply_scene(&fade.mix_at(t));
+            for (id, idx, preset) in fade.strips_at(t) {
+                // somebody else may have the strips by now
+                if self.chan_map.client_at(idx) == Some(id) {
+                    self.mixer.apply_preset(idx, &preset);
+                }
+            }
+            if done {
+                self.state_dirty = true;
+            } else {
+                self.crossfade = Some(fade);
+            }
+        }
+    }
     fn debug_output(&mut self) {
         if self.debug_timer.expired(self.now) {
             self.debug_timer.reset(self.now);
@@ -193,11 +521,126 @@ impl JamEngine {
         self.sock.disconnect();
         // self.xmit_message.set_client_id(0);
         self.chan_map.clear();
+        self.keep_departed_mixes();
+        self.admitted = false;
+        self.reject_code = 0;
+        self.room_takes_reports = false;
     }
     fn connect(&mut self, server: &str, port: i64, id: i64) -> () {
         let _res = self.sock.connect(server, port, id);
+        self.last_room = Some(RoomState {
+            host: server.to_string(),
+            port,
+            identity: id,
+        });
         self.xmit_message.set_client_id(id as u32);
         self.disconnect_timer.reset(self.now);
+        self.admitted = false;
+        self.reject_code = 0;
+        self.room_takes_reports = false;
+        // join right away
+        self.join_timer.reset(0);
+    }
+    // JOIN the room until we are admitted.  This proves our session (and password) to the broadcast
+    fn check_join(&mut self) -> () {
+        if self.admitted || !self.sock.is_connected() {
+            return;
+        }
+        if self.join_timer.expired(self.now) {
+            self.join_timer.reset(self.now);
+            if let Err(e) = self.sock.send_join(&self.room_password) {
+                warn!("can't send join: {}", e);
+            }
+        }
+    }
+    // Tell the room our device latency and how deep each peer's jitter buffer is.  Only rooms that
+    // ADMIT us know what to do with it (an older one would send it around as audio)
+    fn send_report(&mut self) {
+        if !self.room_takes_reports || !self.report_timer.expired(self.now) {
+            return;
+        }
+        self.report_timer.reset(self.now);
+        let depths = self
+            .chan_map
+            .get_clients()
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| !c.is_empty())
+            .map(|(slot, c)| {
+                // same slot numbering as the channel map
+                let idx = (slot + 1) * 2;
+                let msec = self.mixer.get_depth_in_msec(idx).max(self.mixer.get_depth_in_msec(idx + 1));
+                (c.client_id, (msec * 1000.0) as u32)
+            })
+            .collect();
+        let report = DeviceReport {
+            capture: self.device_latency.0,
+            playback: self.device_latency.1,
+            depths,
+        };
+        if let Err(e) = self.sock.send_report(&report) {
+            warn!("can't send latency report: {}", e);
+        }
+    }
+    // ADMIT, REJECT, NOTICE, CHALLENGE and LATENCY packets from the broadcast.  Returns true if this was one of them
+    fn check_admission(&mut self) -> bool {
+        let (admitted, code) = match self.recv_message.get_packet_type() {
+            PACKET_ADMIT => {
+                self.room_takes_reports = true;
+                (true, 0)
+            }
+            PACKET_REJECT => (false, self.recv_message.get_num_audio_chunks()),
+            // getting room audio means we are in
+            PACKET_AUDIO => (true, 0),
+            PACKET_NOTICE => {
+                let code = self.recv_message.get_num_audio_chunks();
+                warn!("room notice: {}", reject_reason(code));
+                let _res = self.status_data_tx.send(json!({
+                    "speaker": "UnitChatRobot",
+                    "roomNotice": { "reason": reject_reason(code), "code": code }
+                }));
+                return true;
+            }
+            PACKET_CHALLENGE => {
+                // the room wants the password proven.  Answer right away
+                self.sock.set_challenge(self.recv_message.get_server_time());
+                if let Err(e) = self.sock.send_join(&self.room_password) {
+                    warn!("can't answer join challenge: {}", e);
+                }
+                return true;
+            }
+            PACKET_LATENCY => {
+                match LatencyMatrix::from_words(&self.recv_message.get_words()) {
+                    Ok(matrix) => {
+                        let _res = self.status_data_tx.send(json!({
+                            "speaker": "UnitChatRobot",
+                            "latencyMatrix": matrix.as_json()
+                        }));
+                    }
+                    Err(e) => debug!("{}", e),
+                }
+                return true;
+            }
+            _ => return true,
+        };
+        if admitted != self.admitted || code != self.reject_code {
+            self.admitted = admitted;
+            self.reject_code = code;
+            if !admitted {
+                warn!("not admitted to room: {}", reject_reason(code));
+                // try again now, it could just be our address changed
+                self.join_timer.reset(0);
+            }
+            let _res = self.status_data_tx.send(json!({
+                "speaker": "UnitChatRobot",
+                "roomAdmission": {
+                    "admitted": admitted,
+                    "reason": if admitted { "" } else { reject_reason(code) },
+                    "code": code,
+                }
+            }));
+        }
+        self.recv_message.get_packet_type() != PACKET_AUDIO
     }
     fn send_status(&mut self) -> () {
         // give any clients on the websocket an update
@@ -205,12 +648,26 @@ impl JamEngine {
             self.update_timer.reset(self.now);
             if self.update_fallback_timer.expired(self.now) {
                 // throttle back to default refresh interval
-                self.update_timer.set_interval(IDLE_REFRESH);
+                self.update_timer.set_interval(self.idle_refresh);
             }
             // send level updates
             let event = self.build_level_event();
             let _res = self.status_data_tx.send(event);
         }
+        // and the numbers the main thread builds the status topics from
+        let snapshot_due = match &mut self.status_timer {
+            Some(timer) if timer.expired(self.now) => {
+                timer.reset(self.now);
+                true
+            }
+            _ => false,
+        };
+        if snapshot_due {
+            let snapshot = self.status_snapshot();
+            if let Some(tx) = &self.status_tx {
+                let _res = tx.send(snapshot);
+            }
+        }
         if self.light_timer.expired(self.now) {
             self.light_timer.reset(self.now);
             // send level update for lights
@@ -240,7 +697,10 @@ impl JamEngine {
                 if idx < 2 {
                     self.pedal_boards[idx] = board;
                 }
+                self.pedals_changed = true;
+                self.state_dirty = true;
                 self.send_pedal_info();
+                self.send_param_changes(Some(format!("pedal.{}", idx)));
             }
             Err(_) => (),
         }        
@@ -249,7 +709,20 @@ impl JamEngine {
     fn check_command(&mut self) -> () {
         match self.command_rx.try_recv() {
             Ok(msg) => {
-                self.process_param_command(msg);
+                let result = self
+                    .process_param_command(&msg)
+                    .map(|_| self.command_state(&msg));
+                // keepalives and queries come all the time.  Only snapshot for what gets saved
+                if result.is_ok() && changes_saved_state(&msg.param) {
+                    self.state_dirty = true;
+                }
+                if let Err(e) = &result {
+                    warn!("command {} failed: {}", msg, e);
+                }
+                if let Some(reply) = msg.reply(result) {
+                    let _res = self.status_data_tx.send(reply);
+                }
+                self.send_param_changes(param_tree::touched_by(&msg));
             }
             Err(_) => (),
         }
@@ -257,12 +730,17 @@ impl JamEngine {
     // This is where we read packets off of the network
     fn read_network(&mut self) -> () {
         self.chan_map.prune(self.now);
+        self.keep_departed_mixes();
         let mut reading = true;
         while reading {
             let _res = self.sock.recv(&mut self.recv_message);
             match _res {
                 Ok(_v) => {
                     // got a network packet
+                    if self.check_admission() {
+                        // control packet, no audio in it
+                        continue;
+                    }
                     // we got the beat
                     self.beat = self.recv_message.get_beat();
                     // Set the server timestamp on xmit packets to loop it back to broadcast server
@@ -294,6 +772,74 @@ impl JamEngine {
                 }
             }
         }
+        self.apply_arrivals();
+    }
+    // anybody new in a slot gets their mix (or the newcomer default) on the strips
+    fn apply_arrivals(&mut self) {
+        for (id, idx) in self.chan_map.take_arrivals() {
+            let preset = self.peer_presets.preset_for(id, idx / 2 - 1);
+            self.mixer.apply_preset(idx, &preset);
+        }
+    }
+    // a peer strip changed by index.  Their preset is kept when they leave (see keep_departed_mixes)
+    fn remember_peer(&mut self, idx: usize) {
+        if let Some(id) = self.chan_map.client_at(idx) {
+            self.peers_touched[idx / 2 - 1] = Some(id);
+        }
+    }
+    // the strips of a peer who left (or was pruned) still hold their mix.  Keep it for their client id
+    fn keep_departed_mixes(&mut self) {
+        for slot in 0..NUM_PLAYERS_IN_ROOM {
+            let chan = (slot + 1) * 2;
+            if let Some(id) = self.peers_touched[slot] {
+                if self.chan_map.client_at(chan) != Some(id) {
+                    self.peer_presets.remember(id, self.mixer.get_preset(chan));
+                    self.peers_touched[slot] = None;
+                }
+            }
+        }
+    }
+    // the presets with the changes still on the strips of peers in the room
+    fn peer_mixes(&self) -> BTreeMap<u32, PeerPreset> {
+        let mut peers = self.peer_presets.to_map();
+        for (slot, touched) in self.peers_touched.iter().enumerate() {
+            if let Some(id) = touched {
+                peers.insert(*id, self.mixer.get_preset((slot + 1) * 2));
+            }
+        }
+        peers
+    }
+    // change a peer's mix by client id.  They don't have to be in the room
+    fn peer_command(&mut self, msg: &ParamMessage) -> Result<(), BoxError> {
+        let id = u32::try_from(msg.ivalue_1)?;
+        let chan = Self::check_input(msg.ivalue_2)?;
+        let idx = self.chan_map.find_channel(id);
+        let mut preset = match idx {
+            Some(idx) => self.mixer.get_preset(idx),
+            None => self.peer_presets.get(id).cloned().unwrap_or_default(),
+        };
+        match msg.param {
+            JamParam::PeerGain => preset.gain[chan] = msg.fvalue,
+            JamParam::PeerMute => preset.mute[chan] = msg.fvalue != 0.0,
+            _ => preset.fade[chan] = msg.fvalue as f32,
+        }
+        match idx {
+            Some(idx) => {
+                self.mixer.apply_preset(idx, &preset);
+                self.remember_peer(idx);
+            }
+            None => self.peer_presets.remember(id, preset),
+        }
+        Ok(())
+    }
+    /// what a peer gets: where they are (null when not in the room) and their mix
+    fn peer_mix(&self, id: u32) -> serde_json::Value {
+        let idx = self.chan_map.find_channel(id);
+        let preset = match idx {
+            Some(idx) => self.mixer.get_preset(idx),
+            None => self.peer_presets.get(id).cloned().unwrap_or_default(),
+        };
+        json!({ "clientId": id, "chanIdx": idx, "preset": preset })
     }
     // This is where we forward our data to the network (if connected)
     fn send_my_audio(&mut self, in_a: &[f32], in_b: &[f32]) -> () {
@@ -315,12 +861,15 @@ impl JamEngine {
             self.mixer.add_to_channel(0, &a_temp);
             self.mixer.add_to_channel(1, &b_temp);
         }
-        // room mutes
-        if self.room_mutes[0] {
-            a_temp = vec![0.0; in_a.len()];
-        }
-        if self.room_mutes[1] {
-            b_temp = vec![0.0; in_b.len()];
+        // room mutes fade rather than cut
+        for (idx, buf) in [&mut a_temp, &mut b_temp].into_iter().enumerate() {
+            let ramp = &mut self.room_mute_ramps[idx];
+            ramp.set(if self.room_mutes[idx] { 0.0 } else { 1.0 });
+            if !ramp.is_settled() || self.room_mutes[idx] {
+                for v in buf.iter_mut() {
+                    *v *= ramp.tick();
+                }
+            }
         }
         self.xmit_message.encode_audio(&a_temp, &b_temp);
         let _res = self.sock.send(&mut self.xmit_message);
@@ -330,6 +879,7 @@ impl JamEngine {
         players.push(json!(
             {
                 "clientId": self.xmit_message.get_client_id(),
+                "chanIdx": 0,
                 "depth": self.mixer.get_depth_in_msec(0),  // convert to msec
                 "level0": self.mixer.get_channel_power_avg(0),
                 "mute0": self.mixer.get_channel_mute(0),
@@ -350,6 +900,7 @@ impl JamEngine {
                 players.push(json!(
                     {
                         "clientId": c.client_id,
+                        "chanIdx": idx,
                         "depth": self.mixer.get_depth_in_msec(idx),
                         "level0": self.mixer.get_channel_power_avg(idx),
                         "mute0": self.mixer.get_channel_mute(idx),
@@ -362,6 +913,7 @@ impl JamEngine {
                         "gain1": self.mixer.get_channel_gain(idx+1),
                         "peak1": self.mixer.get_channel_power_peak(idx+1),
                         "drops": c.get_drops(),
+                        "seq": c.get_seq_stats(),
                     }
                 ));
             }
@@ -375,6 +927,10 @@ impl JamEngine {
                   "git_hash": self.git_hash,
                   "masterLevel": self.mixer.get_master_level_avg(),
                   "peakMaster": self.mixer.get_master_level_peak(),
+                  // how hard the master limiter and the hearing-safety cap are working
+                  "limiterReduction": self.mixer.get_limiter_reduction(),
+                  "softClips": self.mixer.get_soft_clips(),
+                  "safetyReduction": self.safety.get_reduction(),
                   // This is the input levels before any gain or processing
                   "inputLeft": self.input_meters[0].get_avg(),
                   "inputRight": self.input_meters[1].get_avg(),
@@ -403,6 +959,68 @@ impl JamEngine {
 
         data
     }
+    /// what the status topics are built from.  Only numbers here, the json is built by the main
+    /// thread (see [`StatusSnapshot`])
+    fn status_snapshot(&mut self) -> StatusSnapshot {
+        if self.pedals_changed {
+            self.pedals_changed = false;
+            self.pedal_json = Arc::new(json!([
+                self.pedal_boards[0].as_json(0),
+                self.pedal_boards[1].as_json(1)
+            ]));
+        }
+        let mut snapshot = StatusSnapshot {
+            master: [self.mixer.get_master_level_avg(), self.mixer.get_master_level_peak()],
+            limiter_reduction: self.mixer.get_limiter_reduction(),
+            soft_clips: self.mixer.get_soft_clips(),
+            safety_reduction: self.safety.get_reduction(),
+            inputs: [
+                [self.input_meters[0].get_avg(), self.input_meters[0].get_peak()],
+                [self.input_meters[1].get_avg(), self.input_meters[1].get_peak()],
+            ],
+            room_inputs: [
+                [self.room_meters[0].get_avg(), self.room_meters[0].get_peak()],
+                [self.room_meters[1].get_avg(), self.room_meters[1].get_peak()],
+            ],
+            tuner_freqs: [self.tuners[0].get_note(), self.tuners[1].get_note()],
+            tuners_on: [self.tuners[0].enable, self.tuners[1].enable],
+            connected: self.sock.is_connected(),
+            client_id: self.xmit_message.get_client_id(),
+            pedals: self.pedal_json.clone(),
+            beat: self.beat,
+            admitted: self.admitted,
+            jack_jitter_mean: self.jack_jitter.get_mean(),
+            jack_jitter_sigma: self.jack_jitter.get_sigma(),
+            update_interval: self.update_timer.get_interval() / 1000,
+            ..Default::default()
+        };
+        let clients = self.chan_map.get_clients();
+        for (idx, level) in snapshot.channels.iter_mut().enumerate() {
+            // local channels are always there.  Peers only if the slot is in use
+            let active = idx < 2 || clients.get(idx / 2 - 1).is_some_and(|c| !c.is_empty());
+            if active {
+                *level = ChannelLevel {
+                    active,
+                    level: self.mixer.get_channel_power_avg(idx),
+                    peak: self.mixer.get_channel_power_peak(idx),
+                };
+            }
+        }
+        for (slot, (c, peer)) in clients.iter().zip(snapshot.peers.iter_mut()).enumerate() {
+            if !c.is_empty() {
+                let idx = 2 + slot * 2;
+                *peer = Some(PeerStatus {
+                    client_id: c.client_id,
+                    chan_idx: idx,
+                    depth: [self.mixer.get_depth_in_msec(idx), self.mixer.get_depth_in_msec(idx + 1)],
+                    drops: c.get_drops(),
+                    seq: c.get_seq_stats().clone(),
+                    loop_time: c.get_last_loop(),
+                });
+            }
+        }
+        snapshot
+    }
     pub fn send_midi_event (&mut self, _e: RawMidi) -> () {
         // TODO: Port back the midi
         // let mevent = MidiEvent::new(e);
@@ -412,7 +1030,23 @@ impl JamEngine {
         // });
         // let _res = self.status_data_tx.send(data);
     }
-    fn process_param_command(&mut self, msg: ParamMessage) -> () {
+    fn process_param_command(&mut self, msg: &ParamMessage) -> Result<(), BoxError> {
+        if matches!(
+            msg.param,
+            JamParam::MasterVol
+                | JamParam::SetFader
+                | JamParam::ChannelGain
+                | JamParam::ChannelMute
+                | JamParam::MuteToRoom
+                | JamParam::MetronomeGain
+                | JamParam::MetronomeMute
+                | JamParam::PeerGain
+                | JamParam::PeerMute
+                | JamParam::PeerFade
+        ) {
+            // the user took over the mix, stop any scene fading in
+            self.crossfade = None;
+        }
         match msg.param {
             JamParam::ChanGain1 => {
                 self.mixer.set_channel_gain(0, msg.fvalue);
@@ -460,34 +1094,94 @@ impl JamEngine {
                 self.mixer.set_master(msg.fvalue);
             }
             JamParam::SetFader => {
-                if Self::check_index(msg.ivalue_1 as usize) {
-                    self.mixer
-                        .set_channel_fade(msg.ivalue_1 as usize, msg.fvalue as f32);
-                }
+                let idx = Self::check_index(msg.ivalue_1)?;
+                self.mixer.set_channel_fade(idx, msg.fvalue as f32);
+                self.remember_peer(idx);
             }
             JamParam::ChannelGain => {
-                if Self::check_index(msg.ivalue_1 as usize) {
-                    self.mixer
-                        .set_channel_gain(msg.ivalue_1 as usize, msg.fvalue);
-                }
+                let idx = Self::check_index(msg.ivalue_1)?;
+                self.mixer.set_channel_gain(idx, msg.fvalue);
+                self.remember_peer(idx);
             }
             JamParam::ChannelMute => {
-                if Self::check_index(msg.ivalue_1 as usize) {
-                    self.mixer
-                        .set_channel_mute(msg.ivalue_1 as usize, msg.ivalue_2 == 1);
+                let idx = Self::check_index(msg.ivalue_1)?;
+                self.mixer.set_channel_mute(idx, msg.ivalue_2 == 1);
+                self.remember_peer(idx);
+            }
+            JamParam::PeerGain | JamParam::PeerMute | JamParam::PeerFade => {
+                self.peer_command(msg)?;
+            }
+            JamParam::ForgetPeer => {
+                let id = u32::try_from(msg.ivalue_1)?;
+                self.peer_presets.forget(id);
+                // back to what a newcomer on that slot gets
+                if let Some(idx) = self.chan_map.find_channel(id) {
+                    self.peers_touched[idx / 2 - 1] = None;
+                    let preset = self.peer_presets.default_for(idx / 2 - 1);
+                    self.mixer.apply_preset(idx, &preset);
                 }
             }
-            JamParam::MuteToRoom => {
-                if Self::check_index(msg.ivalue_1 as usize) {
-                    self.room_mutes[msg.ivalue_1 as usize] = msg.ivalue_2 == 1;
+            JamParam::SetPanSpread => {
+                self.peer_presets.set_pan_spread(msg.fvalue)?;
+            }
+            JamParam::SetRampTime => {
+                if !(0.0..=MAX_RAMP_MSEC).contains(&msg.fvalue) {
+                    bail!("ramp time {} should be between 0 and {} msec", msg.fvalue, MAX_RAMP_MSEC);
+                }
+                self.ramp_msec = msg.fvalue;
+                self.mixer.set_ramp_time(msg.fvalue);
+                for ramp in &mut self.room_mute_ramps {
+                    ramp.set_samples(ramp_samples(msg.fvalue));
                 }
             }
+            JamParam::SetLimiterCeiling => {
+                check_ceiling(msg.fvalue)?;
+                self.mixer.set_limiter_ceiling(msg.fvalue);
+            }
+            JamParam::ListScenes => {
+                // with a request id the names come back in the reply
+                if msg.request_id.is_none() {
+                    let _res = self.status_data_tx.send(json!({
+                        "speaker": "UnitChatRobot",
+                        "scenes": self.scenes.keys().collect::<Vec<_>>()
+                    }));
+                }
+            }
+            JamParam::SaveScene => {
+                if msg.svalue.trim().is_empty() {
+                    bail!("a scene needs a name");
+                }
+                let tempo = u32::try_from(msg.ivalue_1)?;
+                let scene = Scene { tempo: (tempo > 0).then_some(tempo), ..self.capture_scene() };
+                self.scenes.insert(msg.svalue.clone(), scene);
+                self.send_scenes();
+            }
+            JamParam::RecallScene => {
+                self.recall_scene(&msg.svalue, msg.ivalue_1)?;
+            }
+            JamParam::DeleteScene => {
+                if self.scenes.remove(&msg.svalue).is_none() {
+                    bail!("no scene named {}", msg.svalue);
+                }
+                self.send_scenes();
+            }
+            JamParam::MuteToRoom => {
+                let idx = Self::check_input(msg.ivalue_1)?;
+                self.room_mutes[idx] = msg.ivalue_2 == 1;
+            }
             JamParam::RoomChange => {
                 // connect message
                 self.connect(&msg.svalue, msg.ivalue_1, msg.ivalue_2);
             }
             JamParam::Disconnect => {
                 self.disconnect();
+                // left on purpose, don't go back there after a restart
+                self.last_room = None;
+            }
+            JamParam::SetRoomPassword => {
+                self.room_password = msg.svalue.clone();
+                self.admitted = false;
+                self.join_timer.reset(0);
             }
             JamParam::MetronomeGain => {
                 // set the volume on the metronome
@@ -514,9 +1208,13 @@ impl JamEngine {
                             },
                             headphone_gain: -1.0,
                         });
+                        if let Some(gain) = self.input_gains.get_mut(msg.ivalue_1 as usize) {
+                            *gain = Some(msg.fvalue);
+                        }
                     }
                     None => {
                         // Not on a system that has lights
+                        bail!("no hardware gain control on this unit");
                     }
                 
                 }
@@ -532,55 +1230,44 @@ impl JamEngine {
                                 headphone_gain: msg.fvalue,
                             }
                         );
+                        self.headphone_gain = Some(msg.fvalue);
                     }
                     None => {
                         // Not on a system that has lights
+                        bail!("no hardware gain control on this unit");
                     }
                 }
             }
             JamParam::InsertPedal => {
-                let idx = msg.ivalue_1 as usize;
-                if idx < self.pedal_boards.len() {
-                    self.pedal_boards[idx].insert_pedal(&msg.svalue, msg.ivalue_2 as usize)
-                }
+                let idx = Self::check_input(msg.ivalue_1)?;
+                self.pedal_boards[idx].insert_pedal(&msg.svalue, msg.ivalue_2 as usize);
+                self.pedals_changed = true;
                 self.send_pedal_info();
             }
             JamParam::DeletePedal => {
-                let idx = msg.ivalue_1 as usize;
-                if idx < self.pedal_boards.len() {
-                    self.pedal_boards[idx].delete_pedal(msg.ivalue_2 as usize);
-                }
+                let idx = Self::check_input(msg.ivalue_1)?;
+                self.pedal_boards[idx].delete_pedal(msg.ivalue_2 as usize);
+                self.pedals_changed = true;
                 self.send_pedal_info();
             }
             JamParam::MovePedal => {
-                let idx = msg.ivalue_1 as usize;
+                let idx = Self::check_input(msg.ivalue_1)?;
                 let from_idx: usize = msg.ivalue_2 as usize;
                 let to_idx: usize = msg.fvalue.round() as usize;
-                if idx < self.pedal_boards.len() {
-                    self.pedal_boards[idx].move_pedal(from_idx, to_idx);
-                }
+                self.pedal_boards[idx].move_pedal(from_idx, to_idx);
+                self.pedals_changed = true;
                 self.send_pedal_info();
             }
             JamParam::TuneChannel => {
-                let idx = msg.ivalue_1 as usize;
-                if idx < 2 {
-                    self.tuners[idx].enable = msg.ivalue_2 == 1;
-                }
+                let idx = Self::check_input(msg.ivalue_1)?;
+                self.tuners[idx].enable = msg.ivalue_2 == 1;
             }
             JamParam::SetEffectConfig => {
                 // Change a parameter on a pedal
-                let idx = msg.ivalue_1 as usize;
-                if idx < self.pedal_boards.len() {
-                    match serde_json::Value::from_str(&msg.svalue) {
-                        Ok(setting) => {
-                            self.pedal_boards[idx].change_value(msg.ivalue_2 as usize, &setting);
-                        }
-                        Err(e) => {
-                            // error parsing json to modify a setting
-                            dbg!(e);
-                        }
-                    }
-                }
+                let idx = Self::check_input(msg.ivalue_1)?;
+                let setting = serde_json::Value::from_str(&msg.svalue)?;
+                self.pedal_boards[idx].change_value(msg.ivalue_2 as usize, &setting);
+                self.pedals_changed = true;
             }
             JamParam::ConnectionKeepAlive => {
                 // Sent by web client to let us know they are still there.
@@ -589,15 +1276,29 @@ impl JamEngine {
             JamParam::SetUpdateInterval => {
                 // Update the refresh rate
                 let mut interval = (msg.ivalue_1 * 1000) as u128; // convert to msec
-                if interval < 150_000 {
-                    interval = 150_000;
+                if interval < MIN_UPDATE_INTERVAL {
+                    interval = MIN_UPDATE_INTERVAL;
                 }
-                if interval > IDLE_REFRESH {
-                    interval = IDLE_REFRESH;
+                if interval > self.idle_refresh {
+                    interval = self.idle_refresh;
                 }
                 self.update_timer.set_interval(interval);
                 self.update_fallback_timer.reset(self.now);
             }
+            JamParam::SetIdleRefresh => {
+                let msec = u128::try_from(msg.ivalue_1)?;
+                self.idle_refresh = (msec * 1000).clamp(MIN_UPDATE_INTERVAL, MAX_IDLE_REFRESH);
+                if self.update_fallback_timer.expired(self.now) {
+                    // nobody asked for faster so it takes effect now
+                    self.update_timer.set_interval(self.idle_refresh);
+                }
+            }
+            JamParam::SetJitterDepth => {
+                let (min, max) = (usize::try_from(msg.ivalue_1)?, usize::try_from(msg.ivalue_2)?);
+                check_depth_limits(min, max)?;
+                self.mixer.set_jitter_limits(min, max);
+                self.jitter_limits = (min, max);
+            }
             JamParam::GetConfigJson => {
                 self.send_pedal_info();
             }
@@ -607,16 +1308,162 @@ impl JamEngine {
                     "pedalTypes": PedalBoard::get_pedal_types()
                 }));
             }
+            JamParam::GetParams => {
+                // with a request id the tree comes back in the reply
+                if msg.request_id.is_none() {
+                    let _res = self.status_data_tx.send(msg.addressed(json!({
+                        "speaker": "UnitChatRobot",
+                        "paramTree": self.param_tree(&msg.svalue)
+                    })));
+                }
+            }
+            JamParam::SetParam => {
+                let params = self.params(&msg.svalue);
+                let info = match params.iter().find(|p| p.path == msg.svalue) {
+                    Some(info) => info,
+                    None => bail!("no such parameter {}", msg.svalue),
+                };
+                let set_msg = param_tree::param_to_message(info, msg.fvalue)?;
+                self.process_param_command(&set_msg)?;
+            }
+            JamParam::SubscribeParams => {
+                let on = msg.ivalue_1 == 1;
+                let params = if on { self.params(&msg.svalue) } else { vec![] };
+                self.param_watcher.subscribe(msg.client, &msg.svalue, on, &params);
+            }
+            JamParam::ClientClosed => {
+                self.param_watcher.drop_client(msg.ivalue_1 as u32);
+            }
+            JamParam::SetStatusInterval => {
+                self.status_timer = if msg.ivalue_1 > 0 {
+                    let interval = (msg.ivalue_1 as u128 * 1000).max(MIN_TOPIC_INTERVAL);
+                    // send the first one right away
+                    Some(MicroTimer::new(0, interval))
+                } else {
+                    None
+                };
+            }
             JamParam::StopAudio => {
                 self.is_running = false;
             }
             _ => {
                 info!("unknown command: {}", msg);
+                bail!("unknown command");
+            }
+        }
+        Ok(())
+    }
+    /// The state that a command changed.  This goes in the reply so the u/x can update itself.
+    fn command_state(&self, msg: &ParamMessage) -> serde_json::Value {
+        match msg.param {
+            JamParam::SetFader | JamParam::ChannelGain | JamParam::ChannelMute => {
+                let idx = msg.ivalue_1 as usize;
+                json!({
+                    "channel": idx,
+                    "gain": to_db(self.mixer.get_channel_gain(idx)),
+                    "mute": self.mixer.get_channel_mute(idx),
+                    "fade": self.mixer.get_channel_fade(idx),
+                })
+            }
+            JamParam::MasterVol => json!({ "masterVol": self.mixer.get_master() }),
+            JamParam::MuteToRoom => json!({
+                "leftRoomMute": self.room_mutes[0],
+                "rightRoomMute": self.room_mutes[1],
+            }),
+            JamParam::MetronomeGain | JamParam::MetronomeMute => json!({
+                "metronomeGain": self.mixer.get_metronome_gain(),
+                "metronomeMute": self.mixer.get_metronome_mute(),
+            }),
+            JamParam::RoomChange | JamParam::Disconnect | JamParam::SetRoomPassword => json!({
+                "connected": self.sock.is_connected(),
+                "clientId": self.xmit_message.get_client_id(),
+                "admitted": self.admitted,
+                "hasPassword": !self.room_password.is_empty(),
+            }),
+            JamParam::TuneChannel => json!({
+                "leftTunerOn": self.tuners[0].enable,
+                "rightTunerOn": self.tuners[1].enable,
+            }),
+            JamParam::InsertPedal
+            | JamParam::DeletePedal
+            | JamParam::MovePedal
+            | JamParam::SetEffectConfig
+            | JamParam::GetConfigJson => json!({
+                "pedalInfo": [
+                    self.pedal_boards[0].as_json(0),
+                    self.pedal_boards[1].as_json(1)
+                ]
+            }),
+            JamParam::SetUpdateInterval => json!({
+                "updateInterval": self.update_timer.get_interval() / 1000,
+            }),
+            JamParam::SetJitterDepth => json!({
+                "jitterDepth": { "min": self.jitter_limits.0, "max": self.jitter_limits.1 },
+            }),
+            JamParam::PeerGain | JamParam::PeerMute | JamParam::PeerFade | JamParam::ForgetPeer => {
+                self.peer_mix(msg.ivalue_1 as u32)
             }
+            JamParam::SetPanSpread => json!({ "panSpread": self.peer_presets.get_pan_spread() }),
+            JamParam::SetRampTime => json!({ "rampTime": self.ramp_msec }),
+            JamParam::SetLimiterCeiling => json!({ "limiterCeiling": self.mixer.get_limiter_ceiling() }),
+            JamParam::ListScenes | JamParam::SaveScene | JamParam::RecallScene | JamParam::DeleteScene => json!({
+                "scenes": self.scenes.keys().collect::<Vec<_>>(),
+                "fading": self.crossfade.is_some(),
+                // for the u/x to set on the room
+                "tempo": self.scenes.get(&msg.svalue).and_then(|s| s.tempo),
+            }),
+            JamParam::GetParams => self.param_tree(&msg.svalue),
+            JamParam::SetParam => match self.params(&msg.svalue).iter().find(|p| p.path == msg.svalue) {
+                Some(info) => json!({ "path": info.path, "value": info.value }),
+                None => serde_json::Value::Null,
+            },
+            _ => serde_json::Value::Null,
+        }
+    }
+    fn check_index(idx: i64) -> Result<usize, BoxError> {
+        if idx < 0 || idx as usize >= MIXER_CHANNELS {
+            bail!("channel {} out of range", idx);
         }
+        Ok(idx as usize)
+    }
+    /// the local inputs (room mutes, tuners, pedal boards) only go to 2
+    fn check_input(idx: i64) -> Result<usize, BoxError> {
+        if !(0..2).contains(&idx) {
+            bail!("input {} out of range", idx);
+        }
+        Ok(idx as usize)
+    }
+    /// What a u/x can control under prefix ("" for everything), see [`param_tree`]
+    fn params(&self, prefix: &str) -> Vec<ParamInfo> {
+        param_tree::build_params(
+            prefix,
+            &self.mixer,
+            &self.room_mutes,
+            &[self.tuners[0].enable, self.tuners[1].enable],
+            &self.pedal_boards,
+        )
     }
-    fn check_index(idx: usize) -> bool {
-        idx < MIXER_CHANNELS
+    fn param_tree(&self, prefix: &str) -> serde_json::Value {
+        param_tree::as_tree(&self.params(prefix))
+    }
+    /// push any changes to subscribed parameters under the part of the tree that was touched.
+    /// Covers changes from any source (websocket, OSC, pedal loads)
+    fn send_param_changes(&mut self, touched: Option<String>) {
+        let prefix = match touched {
+            Some(prefix) if self.param_watcher.is_watching(&prefix) => prefix,
+            _ => return,
+        };
+        let params = self.params(&prefix);
+        for (client, changes) in self.param_watcher.changes(&params) {
+            let mut event = json!({
+                "speaker": "UnitChatRobot",
+                "paramChange": changes
+            });
+            if client != 0 {
+                event["wsClient"] = json!(client);
+            }
+            let _res = self.status_data_tx.send(event);
+        }
     }
     fn send_pedal_info(&self) -> () {
         let _res = self.status_data_tx.send(json!({
@@ -633,30 +1480,304 @@ impl JamEngine {
 
 mod test_jam_engine {
     use super::*;
+    use crate::common::jam_packet::{REJECT_DUPLICATE_ID, REJECT_PASSWORD};
 
-    fn build_one() -> JamEngine {
+    // an engine with the sending end of its command channel and the receiving end of its status
+    fn build_one() -> (JamEngine, mpsc::Sender<ParamMessage>, mpsc::Receiver<serde_json::Value>) {
         // This is the channel the audio engine will use to send us status data
-        let (status_data_tx, _status_data_rx): (
+        let (status_data_tx, status_data_rx): (
             mpsc::Sender<serde_json::Value>,
             mpsc::Receiver<serde_json::Value>,
         ) = mpsc::channel();
 
         // This is the channel we will use to send commands to the jack engine
-        let (_command_tx, command_rx): (mpsc::Sender<ParamMessage>, mpsc::Receiver<ParamMessage>) =
+        let (command_tx, command_rx): (mpsc::Sender<ParamMessage>, mpsc::Receiver<ParamMessage>) =
             mpsc::channel();
         let (_pedal_tx, pedal_rx): (mpsc::Sender<PedalBoard>, mpsc::Receiver<PedalBoard>) =
             mpsc::channel();
     
 
-        JamEngine::new(None, status_data_tx, command_rx, pedal_rx, "someToken", "some_git_hash", false).unwrap()
+        let engine = JamEngine::new(None, status_data_tx, command_rx, pedal_rx, "someToken", "some_git_hash", false).unwrap();
+        (engine, command_tx, status_data_rx)
     }
 
     #[test]
     fn disconnect_timer() {
         // It should have a disconnect timer
-        let mut engine = build_one();
+        let (mut engine, _, _) = build_one();
         assert_eq!(engine.disconnect_timer.expired(engine.now), false);
         engine.now = engine.now + IDLE_DISCONNECT + 1;
         assert_eq!(engine.disconnect_timer.expired(engine.now), true);
     }
+    #[test]
+    fn command_replies() {
+        let (mut engine, command_tx, status_data_rx) = build_one();
+        // typed command with a request id gets the resulting state
+        let msg = ParamMessage::from_json(&json!({"cmd": "setChannelGain", "requestId": "a", "channel": 3, "gain": -6.0})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        let reply = status_data_rx.try_recv().unwrap();
+        assert_eq!(reply["commandReply"]["requestId"], "a");
+        assert_eq!(reply["commandReply"]["success"], true);
+        assert_eq!(reply["commandReply"]["state"]["channel"], 3);
+        // gain comes back in dB, the same as it went in
+        assert!((reply["commandReply"]["state"]["gain"].as_f64().unwrap() + 6.0).abs() < 0.01);
+        // bad channel is an error
+        let msg = ParamMessage::from_json(&json!({"cmd": "muteToRoom", "requestId": "b", "channel": 7, "mute": true})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        let reply = status_data_rx.try_recv().unwrap();
+        assert_eq!(reply["commandReply"]["success"], false);
+        assert_eq!(reply["commandReply"]["error"], "input 7 out of range");
+        // legacy message without an id gets no reply
+        command_tx.send(ParamMessage::new(JamParam::MasterVol, 0, 0, -3.0, "")).unwrap();
+        engine.check_command();
+        assert!(status_data_rx.try_recv().is_err());
+    }
+    #[test]
+    fn state_round_trip() {
+        let (state_tx, state_rx) = mpsc::channel();
+        let (mut engine, command_tx, _) = build_one();
+        engine.set_state_channel(state_tx);
+        for msg in [
+            ParamMessage::new(JamParam::ChannelGain, 3, 0, -6.0, ""),
+            ParamMessage::new(JamParam::MasterVol, 0, 0, -3.0, ""),
+            ParamMessage::new(JamParam::TuneChannel, 1, 1, 0.0, ""),
+            ParamMessage::new(JamParam::SetRoomPassword, 0, 0, 0.0, "secret"),
+            ParamMessage::new(JamParam::RoomChange, 7891, 42, 0.0, "localhost"),
+        ] {
+            command_tx.send(msg).unwrap();
+            engine.check_command();
+        }
+        engine.now += STATE_INTERVAL + 1;
+        engine.save_state();
+        let state = state_rx.try_recv().unwrap();
+        assert!((state.channels[3].gain + 6.0).abs() < 0.01);
+        assert_eq!(state.room.as_ref().unwrap().identity, 42);
+        // the password stays in memory
+        assert!(!serde_json::to_string(&state).unwrap().contains("secret"));
+        // nothing changed, nothing sent.  Keepalives and queries don't even take a snapshot
+        for msg in [
+            ParamMessage::new(JamParam::ConnectionKeepAlive, 0, 0, 0.0, ""),
+            ParamMessage::new(JamParam::SetUpdateInterval, 500, 0, 0.0, ""),
+            ParamMessage::new(JamParam::GetParams, 0, 0, 0.0, ""),
+            ParamMessage::new(JamParam::ListScenes, 0, 0, 0.0, ""),
+        ] {
+            command_tx.send(msg).unwrap();
+            engine.check_command();
+        }
+        assert!(!engine.state_dirty);
+        engine.now += STATE_INTERVAL + 1;
+        engine.save_state();
+        assert!(state_rx.try_recv().is_err());
+        command_tx.send(ParamMessage::new(JamParam::MasterVol, 0, 0, -3.0, "")).unwrap();
+        engine.check_command();
+        engine.now += STATE_INTERVAL + 1;
+        engine.save_state();
+        assert!(state_rx.try_recv().is_err());
+        assert!(!engine.state_dirty);
+        // leaving the room forgets it
+        command_tx.send(ParamMessage::new(JamParam::Disconnect, 0, 0, 0.0, "")).unwrap();
+        engine.check_command();
+        assert!(engine.snapshot().room.is_none());
+        // a new engine comes back the same, in the room only if asked to
+        let (mut restored, _, _) = build_one();
+        restored.restore_state(&state, false);
+        assert!(!restored.sock.is_connected());
+        let again = restored.snapshot();
+        assert!((again.channels[3].gain - state.channels[3].gain).abs() < 0.01);
+        assert_eq!(again.tuners, [false, true]);
+        assert_eq!(again.room, state.room);
+        assert_eq!(again.pedal_boards, state.pedal_boards);
+        restored.restore_state(&state, true);
+        assert!(restored.sock.is_connected());
+    }
+    #[test]
+    fn peer_mix_follows_client() {
+        let (mut engine, command_tx, _) = build_one();
+        let now = engine.now;
+        // the drummer takes the first slot, the bassist the second
+        engine.chan_map.get_loc_channel(1111, now, 1);
+        engine.chan_map.get_loc_channel(2222, now, 1);
+        engine.apply_arrivals();
+        for msg in [
+            ParamMessage::new(JamParam::SetPanSpread, 0, 0, 0.5, ""),
+            ParamMessage::new(JamParam::ChannelGain, 4, 0, -6.0, ""),
+            ParamMessage::from_json(&json!({"cmd": "setPeerMute", "clientId": 2222, "channel": 1, "mute": true})).unwrap(),
+            // not in the room yet
+            ParamMessage::from_json(&json!({"cmd": "setPeerFade", "clientId": 3333, "channel": 0, "fade": 0.25})).unwrap(),
+        ] {
+            command_tx.send(msg).unwrap();
+            engine.check_command();
+        }
+        assert!(engine.mixer.get_channel_mute(5));
+        // the bassist drops and comes back on the other side of the board
+        engine.disconnect();
+        engine.chan_map.get_loc_channel(3333, now, 1);
+        engine.chan_map.get_loc_channel(2222, now, 1);
+        engine.chan_map.get_loc_channel(4444, now, 1);
+        engine.apply_arrivals();
+        assert_eq!(engine.mixer.get_channel_fade(2), 0.25);
+        assert!((to_db(engine.mixer.get_channel_gain(4)) + 6.0).abs() < 0.01);
+        assert!(!engine.mixer.get_channel_mute(4));
+        assert!(engine.mixer.get_channel_mute(5));
+        // a newcomer gets a default mix, panned out of the middle
+        assert_eq!(engine.mixer.get_preset(6), engine.peer_presets.default_for(2));
+        assert!(engine.mixer.get_channel_fade(6) < 0.0);
+        // the presets are part of the saved state
+        assert!(engine.snapshot().peer_presets.contains_key(&2222));
+        command_tx.send(ParamMessage::new(JamParam::ForgetPeer, 2222, 0, 0.0, "")).unwrap();
+        engine.check_command();
+        assert!(!engine.mixer.get_channel_mute(5));
+        assert!(!engine.snapshot().peer_presets.contains_key(&2222));
+    }
+    #[test]
+    fn scenes() {
+        let (scene_tx, scene_rx) = mpsc::channel();
+        let (mut engine, command_tx, _) = build_one();
+        engine.set_scene_channel(scene_tx);
+        engine.chan_map.get_loc_channel(1111, engine.now, 1);
+        engine.apply_arrivals();
+        let send = |engine: &mut JamEngine, msg: ParamMessage| {
+            command_tx.send(msg).unwrap();
+            engine.check_command();
+        };
+        send(&mut engine, ParamMessage::new(JamParam::MasterVol, 0, 0, -10.0, ""));
+        send(&mut engine, ParamMessage::new(JamParam::PeerGain, 1111, 0, -6.0, ""));
+        // somebody mixed but not in the room isn't part of the scene
+        send(&mut engine, ParamMessage::new(JamParam::PeerGain, 5555, 0, -3.0, ""));
+        send(&mut engine, ParamMessage::from_json(&json!({"cmd": "saveScene", "name": "show", "tempo": 96})).unwrap());
+        let saved = scene_rx.try_recv().unwrap();
+        assert_eq!(saved.keys().collect::<Vec<_>>(), vec!["show"]);
+        assert_eq!(saved["show"].peers.keys().collect::<Vec<_>>(), vec![&1111]);
+        assert_eq!(saved["show"].tempo, Some(96));
+        send(&mut engine, ParamMessage::new(JamParam::MasterVol, 0, 0, 0.0, ""));
+        send(&mut engine, ParamMessage::new(JamParam::MuteToRoom, 0, 1, 0.0, ""));
+        send(&mut engine, ParamMessage::new(JamParam::PeerGain, 1111, 0, 0.0, ""));
+        // jump straight back.  The tempo comes back for the u/x to put on the room
+        let recall = ParamMessage::new(JamParam::RecallScene, 0, 0, 0.0, "show");
+        assert_eq!(engine.command_state(&recall)["tempo"], 96);
+        send(&mut engine, recall);
+        assert!((engine.mixer.get_master() + 10.0).abs() < 0.01);
+        assert!(!engine.room_mutes[0]);
+        assert!((engine.mixer.get_preset(2).gain[0] + 6.0).abs() < 0.01);
+        // or fade there over a second
+        send(&mut engine, ParamMessage::new(JamParam::MasterVol, 0, 0, 0.0, ""));
+        send(&mut engine, ParamMessage::new(JamParam::PeerGain, 1111, 0, 0.0, ""));
+        send(&mut engine, ParamMessage::from_json(&json!({"cmd": "recallScene", "name": "show", "fadeMsec": 1000})).unwrap());
+        engine.now += 500_000;
+        engine.step_crossfade();
+        assert!((engine.mixer.get_master() + 5.0).abs() < 0.01);
+        assert!((engine.mixer.get_preset(2).gain[0] + 3.0).abs() < 0.01);
+        engine.now += 500_000;
+        engine.step_crossfade();
+        assert!((engine.mixer.get_master() + 10.0).abs() < 0.01);
+        assert!(engine.crossfade.is_none());
+        // touching a fader stops a fade
+        send(&mut engine, ParamMessage::new(JamParam::MasterVol, 0, 0, 0.0, ""));
+        send(&mut engine, ParamMessage::new(JamParam::RecallScene, 1000, 0, 0.0, "show"));
+        send(&mut engine, ParamMessage::new(JamParam::MasterVol, 0, 0, -3.0, ""));
+        assert!(engine.crossfade.is_none());
+        assert!(engine.process_param_command(&ParamMessage::new(JamParam::RecallScene, 0, 0, 0.0, "nope")).is_err());
+        send(&mut engine, ParamMessage::new(JamParam::DeleteScene, 0, 0, 0.0, "show"));
+        assert!(scene_rx.try_recv().unwrap().is_empty());
+    }
+    #[test]
+    fn params_by_path() {
+        let (mut engine, command_tx, status_data_rx) = build_one();
+        // subscribed from local connection 5
+        let mut subscribe = ParamMessage::new(JamParam::SubscribeParams, 1, 0, 0.0, "mixer.strip.2");
+        subscribe.client = 5;
+        command_tx.send(subscribe).unwrap();
+        engine.check_command();
+        // set by path
+        let msg = ParamMessage::from_json(&json!({"cmd": "setParam", "requestId": "a", "path": "mixer.strip.2.mute", "value": true})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        let reply = status_data_rx.try_recv().unwrap();
+        assert_eq!(reply["commandReply"]["state"]["value"], true);
+        assert!(engine.mixer.get_channel_mute(2));
+        // the subscriber hears about it
+        let change = status_data_rx.try_recv().unwrap();
+        assert_eq!(change["paramChange"][0]["path"], "mixer.strip.2.mute");
+        assert_eq!(change["wsClient"], 5);
+        // out of range and unknown paths fail
+        let msg = ParamMessage::from_json(&json!({"cmd": "setParam", "requestId": "b", "path": "master.volume", "value": 100.0})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        assert_eq!(status_data_rx.try_recv().unwrap()["commandReply"]["success"], false);
+        let msg = ParamMessage::from_json(&json!({"cmd": "setParam", "requestId": "c", "path": "nope", "value": 1.0})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        assert_eq!(status_data_rx.try_recv().unwrap()["commandReply"]["error"], "no such parameter nope");
+        // tree comes back in the reply
+        let msg = ParamMessage::from_json(&json!({"cmd": "getParams", "requestId": "d", "prefix": "tuner"})).unwrap();
+        command_tx.send(msg).unwrap();
+        engine.check_command();
+        let reply = status_data_rx.try_recv().unwrap();
+        assert_eq!(reply["commandReply"]["state"]["tuner"]["1"]["type"], "bool");
+        assert!(reply["commandReply"]["state"]["master"].is_null());
+        // once the connection is gone nothing more is sent for it
+        command_tx.send(ParamMessage::new(JamParam::ClientClosed, 5, 0, 0.0, "")).unwrap();
+        engine.check_command();
+        command_tx.send(ParamMessage::new(JamParam::ChannelMute, 2, 0, 0.0, "")).unwrap();
+        engine.check_command();
+        assert!(status_data_rx.try_recv().is_err());
+    }
+    #[test]
+    fn status_snapshots() {
+        let (mut engine, _, _) = build_one();
+        let (snapshot_tx, snapshot_rx) = mpsc::channel();
+        engine.set_status_channel(snapshot_tx);
+        // nothing until the main thread asks for them
+        engine.send_status();
+        assert!(snapshot_rx.try_recv().is_err());
+        engine.process_param_command(&ParamMessage::new(JamParam::SetStatusInterval, 100, 0, 0.0, "")).unwrap();
+        engine.process_param_command(&ParamMessage::new(JamParam::TuneChannel, 1, 1, 0.0, "")).unwrap();
+        // first one goes right away, then at the interval
+        engine.send_status();
+        let snapshot = snapshot_rx.try_recv().unwrap();
+        assert_eq!(snapshot.tuners_on, [false, true]);
+        assert!(snapshot.channels[0].active && !snapshot.channels[2].active);
+        assert_eq!(snapshot.pedals[1]["boardId"], 1);
+        engine.now += 50_000;
+        engine.send_status();
+        assert!(snapshot_rx.try_recv().is_err());
+        engine.now += 60_000;
+        engine.send_status();
+        assert!(snapshot_rx.try_recv().is_ok());
+        engine.process_param_command(&ParamMessage::new(JamParam::SetStatusInterval, 0, 0, 0.0, "")).unwrap();
+        engine.now += 200_000;
+        engine.send_status();
+        assert!(snapshot_rx.try_recv().is_err());
+    }
+    #[test]
+    fn room_admission() {
+        let (mut engine, _, status_data_rx) = build_one();
+        // a rejection gets surfaced once
+        engine.recv_message.set_control(PACKET_REJECT, REJECT_PASSWORD, 0);
+        assert!(engine.check_admission());
+        let event = status_data_rx.try_recv().unwrap();
+        assert_eq!(event["roomAdmission"]["admitted"], false);
+        assert_eq!(event["roomAdmission"]["reason"], "room password required");
+        assert!(engine.check_admission());
+        assert!(status_data_rx.try_recv().is_err());
+        // then admitted
+        engine.recv_message.set_control(PACKET_ADMIT, 0, 0);
+        assert!(engine.check_admission());
+        assert_eq!(status_data_rx.try_recv().unwrap()["roomAdmission"]["admitted"], true);
+        // audio packets are not control packets
+        engine.recv_message = JamMessage::new();
+        assert!(!engine.check_admission());
+        assert!(engine.admitted);
+        // somebody else claiming our id
+        engine.recv_message.set_control(PACKET_NOTICE, REJECT_DUPLICATE_ID, 0);
+        assert!(engine.check_admission());
+        assert_eq!(status_data_rx.try_recv().unwrap()["roomNotice"]["code"], REJECT_DUPLICATE_ID);
+        assert!(engine.admitted);
+        // a password challenge is answered, not shown
+        engine.recv_message.set_control(PACKET_CHALLENGE, 0, 42);
+        assert!(engine.check_admission());
+        assert!(status_data_rx.try_recv().is_err());
+    }
 }
@@ -13,7 +13,14 @@
 //! This prevents the jitter buffer from having to have any mutexes. (one writer, one reader)
 use simple_error::bail;
 
-use crate::common::{box_error::BoxError, get_micro_time, jam_packet::JamMessage, sock_with_tos};
+use crate::common::{
+    auth::password_token,
+    box_error::BoxError,
+    get_micro_time,
+    jam_packet::{JamMessage, PACKET_JOIN, PACKET_REPORT},
+    latency_matrix::DeviceReport,
+    sock_with_tos,
+};
 use std::fmt;
 use std::net::UdpSocket;
 
@@ -23,6 +30,8 @@ pub struct JamSocket {
     client_id: Option<i64>,
     server: String,
     seq_no: u32,
+    session: u64,
+    challenge: u64,
 }
 
 impl JamSocket {
@@ -36,12 +45,17 @@ impl JamSocket {
             client_id: None,
             server: String::new(),
             seq_no: 0,
+            session: 0,
+            challenge: 0,
         })
     }
     /// Connect the socket to a specific broadcast unit
     pub fn connect(&mut self, host: &str, port: i64, id: i64) -> Result<(), BoxError> {
         self.server = format!("{}:{}", host, port);
         self.client_id = Some(id);
+        // new session nonce so the room can tell us apart from anybody else using this id
+        self.session = rand::random::<u64>().max(1);
+        self.challenge = 0;
         Ok(())
     }
     /// clear out server state data.
@@ -72,6 +86,44 @@ impl JamSocket {
             }
         }
     }
+    /// The nonce the room sent in a CHALLENGE.  The next JOIN answers it
+    pub fn set_challenge(&mut self, challenge: u64) {
+        self.challenge = challenge;
+    }
+    /// Ask to join the room.  Sends the password token for the last challenge (not the password)
+    /// and our session nonce which lets the room follow us if our address changes.
+    pub fn send_join(&mut self, password: &str) -> Result<usize, BoxError> {
+        match self.client_id {
+            Some(id) => {
+                let mut packet = JamMessage::new();
+                packet.set_client_id(id as u32);
+                packet.set_control(PACKET_JOIN, 0, password_token(password, id as u32, self.challenge));
+                packet.set_client_timestamp(self.session);
+                Ok(self
+                    .sock
+                    .send_to(packet.get_send_buffer(), self.server.as_str())?)
+            }
+            None => {
+                bail!("socket not connected");
+            }
+        }
+    }
+    /// Tell the room our device latency and jitter buffer depths.  No sequence number, it is not audio
+    pub fn send_report(&mut self, report: &DeviceReport) -> Result<usize, BoxError> {
+        match self.client_id {
+            Some(id) => {
+                let mut packet = JamMessage::new();
+                packet.set_client_id(id as u32);
+                packet.set_words(PACKET_REPORT, &report.to_words())?;
+                Ok(self
+                    .sock
+                    .send_to(packet.get_send_buffer(), self.server.as_str())?)
+            }
+            None => {
+                bail!("socket not connected");
+            }
+        }
+    }
     /// Read a packet into a JamMessage,  returns an Err result if there is nothing there to read.
     pub fn recv(&self, packet: &mut JamMessage) -> Result<(), BoxError> {
         let (nbytes, _addr) = self.sock.recv_from(packet.get_buffer())?;
@@ -104,6 +156,11 @@ mod test_jam_socket {
         assert!(!sock.is_connected());
         sock.connect("10.0.0.9", 48481, 3949384).unwrap();
         assert!(sock.is_connected());
+        // every connect is a new session
+        let session = sock.session;
+        assert_ne!(session, 0);
+        sock.connect("10.0.0.9", 48481, 3949384).unwrap();
+        assert_ne!(sock.session, session);
         sock.disconnect();
         assert!(!sock.is_connected());
     }
@@ -114,5 +171,11 @@ mod test_jam_socket {
         let mut packet = JamMessage::new();
         sock.connect("10.0.0.9", 48481, 3949384).unwrap();
         assert_eq!(sock.send(&mut packet).unwrap(), JAM_HEADER_SIZE);
+        assert_eq!(sock.send_join("letmein").unwrap(), JAM_HEADER_SIZE);
+        // reports don't use up sequence numbers
+        assert_eq!(sock.send_report(&DeviceReport::default()).unwrap(), JAM_HEADER_SIZE + 8);
+        assert_eq!(sock.seq_no, 1);
+        sock.disconnect();
+        assert!(sock.send_join("letmein").is_err());
     }
 }
@@ -16,14 +16,32 @@
 //! buffer depth from driving to the largest inter packet delay.  Net effect is this
 //! allows for some gaps in playback in order to drive buffer latency down.
 
-use crate::common::stream_time_stat::StreamTimeStat;
+use crate::common::{box_error::BoxError, stream_time_stat::StreamTimeStat};
 use std::fmt;
 use pedal_board::dsp::attack_hold_release::AttackHoldRelease;
+use simple_error::bail;
 
-const MIN_DEPTH: usize = 128 * 4;
-const MAX_DEPTH: usize = 8192;
+/// default smallest depth (samples) the buffer fills to before playing
+pub const MIN_DEPTH: usize = 128 * 4;
+/// default deepest the buffer is allowed to get (samples)
+pub const MAX_DEPTH: usize = 8192;
 // const MIN_SIGMA: f64 = 5.0;
 
+/// check depth limits (samples) before handing them to [`JitterBuffer::set_depth_limits`].  The min
+/// is at least a frame, the max no more than a second and room for the high water to adapt
+pub fn check_depth_limits(min: usize, max: usize) -> Result<(), BoxError> {
+    if min < 128 {
+        bail!("jitter buffer min depth {} is less than a frame (128)", min);
+    }
+    if max > 48_000 {
+        bail!("jitter buffer max depth {} is more than a second (48000)", max);
+    }
+    if max < min * 3 {
+        bail!("jitter buffer max depth {} should be at least 3 times the min depth {}", max, min);
+    }
+    Ok(())
+}
+
 /// Adaptive buffer for smoothing network audio data
 ///
 /// Note that all adaptation functions are performed on buffer read.  
@@ -38,6 +56,8 @@ pub struct JitterBuffer {
     depth_filter: AttackHoldRelease<f64>,
     puts: usize,
     gets: usize,
+    min_depth: usize,
+    max_depth: usize,
 }
 
 impl fmt::Display for JitterBuffer {
@@ -71,8 +91,16 @@ impl JitterBuffer {
             depth_filter: AttackHoldRelease::new(0.4, 1.0, 2.0, 48000.0 / 128.0),
             puts: 0,
             gets: 0,
+            min_depth: MIN_DEPTH,
+            max_depth: MAX_DEPTH,
         }
     }
+    /// change how shallow (min) and deep (max) the buffer can adapt to, in samples
+    pub fn set_depth_limits(&mut self, min: usize, max: usize) {
+        self.min_depth = min;
+        // high water can adapt up to 3 * min on its own
+        self.max_depth = max.max(min * 3);
+    }
     /// retrieves the current number of samples in the buffer  (just for testing)
     pub fn length(&self) -> usize {
         self.buffer.len()
@@ -101,6 +129,13 @@ impl JitterBuffer {
     /// get will retrieve data from the jitter buffer.  It will always give you a full vector but
     /// it might have zeros if there is no data or the buffer is still filling
     pub fn get(&mut self, count: usize, _power: f64) -> Vec<f32> {
+        let mut rval = vec![0.0; count];
+        self.get_into(&mut rval);
+        rval
+    }
+    /// same as [`JitterBuffer::get`] but fills out (all of it) instead of allocating
+    pub fn get_into(&mut self, out: &mut [f32]) {
+        let count = out.len();
         // It should get some data off the buffer
         self.gets += 1;
 
@@ -108,9 +143,10 @@ impl JitterBuffer {
         self.depth_stats.add_sample(self.buffer.len() as f64); // Gather depth stats
 
         // Adjust low water depth based on near or current starve (attach hold release filter)
-        self.low_water = MIN_DEPTH + (self.depth_filter.get(self.buffer.len() < self.low_water / 4) * MIN_DEPTH as f64) as usize;
-        // Adjust high-water based on jitter sigma
-        self.high_water = MIN_DEPTH + self.low_water + (self.depth_stats.get_sigma() * 8.0) as usize;
+        let min = self.min_depth;
+        self.low_water = min + (self.depth_filter.get(self.buffer.len() < self.low_water / 4) * min as f64) as usize;
+        // Adjust high-water based on jitter sigma (but never past the max depth)
+        self.high_water = (min + self.low_water + (self.depth_stats.get_sigma() * 8.0) as usize).min(self.max_depth);
 
         // check if we are done filling
         if self.filling {
@@ -122,7 +158,8 @@ impl JitterBuffer {
         // First case, we are filling so don't give them anything
         if self.filling {
             // just give silence
-            return vec![0.0; count];
+            out.fill(0.0);
+            return;
         }
 
         // Second case see if we have too much data and need to throw some out
@@ -133,26 +170,20 @@ impl JitterBuffer {
 
         // Third case, we have enough data to satisfy
         if self.buffer.len() >= count {
-            return self.buffer.drain(..count).collect();
+            out.copy_from_slice(&self.buffer[..count]);
+            self.buffer.drain(..count);
+            return;
         }
 
         // This is the onset of an underrun
         self.underruns += 1;
         self.filling = true;
 
-        // The buffer is empty
-        if self.buffer.len() == 0 {
-            // No data in the buffer
-            return vec![0.0; count];
-        }
-
-        // consuming the last bits of a partial read
-        let remainder = count - self.buffer.len();
-        // get the partial data
-        let mut rval: Vec<f32> = self.buffer.drain(..).collect();
-        // fill zeros on the end
-        rval.append(&mut vec![0.0; remainder]);
-        return rval;
+        // consuming the last bits of a partial read (if any) and fill zeros on the end
+        let have = self.buffer.len();
+        out[..have].copy_from_slice(&self.buffer);
+        out[have..].fill(0.0);
+        self.buffer.clear();
     }
 }
 
@@ -200,4 +231,18 @@ mod test_jitter_buffer {
         // assert_eq!(res, vec![0.0; 4]);
         assert!(buf.is_filling());
     }
+
+    #[test]
+    fn depth_limits() {
+        // a shallower buffer starts playing sooner
+        let mut buf = JitterBuffer::new();
+        buf.set_depth_limits(256, 4096);
+        buf.append(&vec![0.2; 256]);
+        buf.get(128, -60.0);
+        assert!(!buf.is_filling());
+        assert_eq!(buf.length(), 128);
+        assert!(check_depth_limits(MIN_DEPTH, MAX_DEPTH).is_ok());
+        assert!(check_depth_limits(64, 4096).is_err());
+        assert!(check_depth_limits(512, 1024).is_err());
+    }
 }
@@ -0,0 +1,267 @@
+//! Lookahead brickwall limiter with a soft clipper
+//!
+//! Summing a full room plus the click can go well past full scale.  The [`Limiter`] keeps a stereo
+//! signal under a ceiling without audible steps:
+//!
+//! - the audio is delayed by [`LOOKAHEAD`] samples so the gain can come down before a peak gets out
+//! - the gain needed by every sample in the lookahead window is held, released slowly
+//!   ([`RELEASE_MSEC`]) and smoothed over the window, so it is already low enough when the peak
+//!   comes out of the delay
+//! - a soft clipper rounds off the last part below the ceiling so nothing ever passes it
+//!
+//! The mixer runs one on the master bus ([`crate::sound::mixer::Mixer`]).  The engine puts a
+//! [`SafetyClip`] on the local output as a hearing-safety cap.  That is a plain clamp, so the output
+//! only goes through one lookahead delay and audio the master limiter already brought under the cap
+//! isn't touched again.
+use std::collections::VecDeque;
+
+use simple_error::bail;
+
+use crate::common::box_error::BoxError;
+
+use super::SAMPLE_RATE;
+
+/// samples of delay (about 1.3 msec at 48kHz)
+pub const LOOKAHEAD: usize = 64;
+/// how long (msec) the gain takes to come back up after a peak
+pub const RELEASE_MSEC: f32 = 50.0;
+/// ceiling (dBFS) unless told otherwise
+pub const DEFAULT_CEILING: f64 = -1.0;
+/// lowest ceiling (dBFS) allowed
+pub const MIN_CEILING: f64 = -40.0;
+// the soft clipper starts at this fraction of the ceiling
+const KNEE: f32 = 0.9;
+// how fast (dB per frame) the reduction shown to the u/x falls back
+const REDUCTION_DECAY: f64 = 0.1;
+
+// dBFS is an amplitude ratio
+fn from_dbfs(db: f64) -> f32 {
+    10f64.powf(db / 20.0) as f32
+}
+
+fn to_dbfs(v: f32) -> f64 {
+    20.0 * (v as f64).log10()
+}
+
+/// ceiling (dBFS) has to be between [`MIN_CEILING`] and 0.0
+pub fn check_ceiling(ceiling: f64) -> Result<(), BoxError> {
+    if !(MIN_CEILING..=0.0).contains(&ceiling) {
+        bail!("ceiling {} should be between {} and 0.0 dBFS", ceiling, MIN_CEILING);
+    }
+    Ok(())
+}
+
+// round off everything above the knee so it never gets past the ceiling
+fn soft_clip(v: f32, ceiling: f32) -> (f32, bool) {
+    let knee = ceiling * KNEE;
+    let mag = v.abs();
+    if mag <= knee {
+        return (v, false);
+    }
+    let room = ceiling - knee;
+    (v.signum() * (knee + room * f32::tanh((mag - knee) / room)), true)
+}
+
+pub struct Limiter {
+    // linear
+    ceiling: f32,
+    delay: VecDeque<(f32, f32)>,
+    // gain each sample in the window needs, and how many of them need less than 1.0
+    required: VecDeque<f32>,
+    loud: usize,
+    envelope: f32,
+    release: f32,
+    // smoothing window over the envelope
+    smooth: VecDeque<f32>,
+    smooth_sum: f64,
+    smooth_low: usize,
+    // what the u/x sees
+    reduction: f64,
+    clips: u64,
+}
+
+impl Limiter {
+    /// build one with a ceiling in dBFS
+    pub fn new(ceiling: f64) -> Limiter {
+        Limiter {
+            ceiling: from_dbfs(ceiling),
+            delay: VecDeque::from(vec![(0.0, 0.0); LOOKAHEAD - 1]),
+            required: VecDeque::from(vec![1.0; LOOKAHEAD]),
+            loud: 0,
+            envelope: 1.0,
+            release: 1.0 - f32::exp(-1.0 / (RELEASE_MSEC * SAMPLE_RATE as f32 / 1000.0)),
+            smooth: VecDeque::from(vec![1.0; LOOKAHEAD]),
+            smooth_sum: LOOKAHEAD as f64,
+            smooth_low: 0,
+            reduction: 0.0,
+            clips: 0,
+        }
+    }
+    pub fn set_ceiling(&mut self, ceiling: f64) {
+        self.ceiling = from_dbfs(ceiling);
+    }
+    /// ceiling in dBFS
+    pub fn get_ceiling(&self) -> f64 {
+        to_dbfs(self.ceiling)
+    }
+    /// recent gain reduction (dB, 0.0 or less).  Falls back slowly so a short peak still shows
+    pub fn get_reduction(&self) -> f64 {
+        self.reduction
+    }
+    /// frames the soft clipper has had to touch since this was built
+    pub fn get_clips(&self) -> u64 {
+        self.clips
+    }
+    // gain for the sample coming out of the delay now
+    fn next_gain(&mut self, peak: f32) -> f32 {
+        let needed = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+        if needed < 1.0 {
+            self.loud += 1;
+        }
+        self.required.push_back(needed);
+        if self.required.pop_front().is_some_and(|v| v < 1.0) {
+            self.loud -= 1;
+        }
+        let hold = if self.loud == 0 {
+            1.0
+        } else {
+            self.required.iter().fold(1.0, |m: f32, v| m.min(*v))
+        };
+        // down right away, back up slowly
+        if hold < self.envelope {
+            self.envelope = hold;
+        } else {
+            self.envelope += (hold - self.envelope) * self.release;
+            if hold - self.envelope < 1e-4 {
+                self.envelope = hold;
+            }
+        }
+        if self.envelope < 1.0 {
+            self.smooth_low += 1;
+        }
+        self.smooth.push_back(self.envelope);
+        self.smooth_sum += self.envelope as f64;
+        if let Some(old) = self.smooth.pop_front() {
+            self.smooth_sum -= old as f64;
+            if old < 1.0 {
+                self.smooth_low -= 1;
+            }
+        }
+        if self.smooth_low == 0 {
+            // back to idle, clear any rounding
+            self.smooth_sum = LOOKAHEAD as f64;
+        }
+        (self.smooth_sum / LOOKAHEAD as f64) as f32
+    }
+    /// limit a stereo frame in place.  The sides go through in pairs, so only as many samples as the
+    /// shorter side has are limited
+    pub fn process(&mut self, out_a: &mut [f32], out_b: &mut [f32]) {
+        let mut min_gain: f32 = 1.0;
+        let mut clipped = false;
+        for (va, vb) in out_a.iter_mut().zip(out_b.iter_mut()) {
+            let gain = self.next_gain(va.abs().max(vb.abs()));
+            min_gain = min_gain.min(gain);
+            self.delay.push_back((*va, *vb));
+            let (a, b) = self.delay.pop_front().unwrap_or((0.0, 0.0));
+            let (a, clip_a) = soft_clip(a * gain, self.ceiling);
+            let (b, clip_b) = soft_clip(b * gain, self.ceiling);
+            *va = a;
+            *vb = b;
+            clipped |= clip_a || clip_b;
+        }
+        if clipped {
+            self.clips += 1;
+        }
+        self.reduction = to_dbfs(min_gain).min(self.reduction + REDUCTION_DECAY).min(0.0);
+    }
+}
+
+/// Clamps a stereo signal to a ceiling right away: no delay, no gain riding and nothing under the ceiling changes
+pub struct SafetyClip {
+    // linear
+    ceiling: f32,
+    // what the u/x sees
+    reduction: f64,
+}
+
+impl SafetyClip {
+    /// build one with a ceiling in dBFS
+    pub fn new(ceiling: f64) -> SafetyClip {
+        SafetyClip {
+            ceiling: from_dbfs(ceiling),
+            reduction: 0.0,
+        }
+    }
+    pub fn set_ceiling(&mut self, ceiling: f64) {
+        self.ceiling = from_dbfs(ceiling);
+    }
+    /// recent gain reduction (dB, 0.0 or less).  Falls back slowly so a short peak still shows
+    pub fn get_reduction(&self) -> f64 {
+        self.reduction
+    }
+    /// clamp a stereo frame in place
+    pub fn process(&mut self, out_a: &mut [f32], out_b: &mut [f32]) {
+        let mut min_gain: f32 = 1.0;
+        for v in out_a.iter_mut().chain(out_b.iter_mut()) {
+            let mag = v.abs();
+            if mag > self.ceiling {
+                min_gain = min_gain.min(self.ceiling / mag);
+                *v = v.signum() * self.ceiling;
+            }
+        }
+        self.reduction = to_dbfs(min_gain).min(self.reduction + REDUCTION_DECAY).min(0.0);
+    }
+}
+
+#[cfg(test)]
+mod test_limiter {
+    use super::*;
+
+    #[test]
+    fn holds_the_ceiling() {
+        let mut limiter = Limiter::new(-6.0);
+        let ceiling = from_dbfs(-6.0);
+        // quiet audio goes through untouched, just late
+        let mut out_a: Vec<f32> = (0..128).map(|i| (i as f32 * 0.1).sin() * 0.25).collect();
+        let mut out_b = out_a.clone();
+        let input = out_a.clone();
+        limiter.process(&mut out_a, &mut out_b);
+        assert_eq!(out_a[LOOKAHEAD - 1..], input[..128 - LOOKAHEAD + 1]);
+        assert_eq!(limiter.get_reduction(), 0.0);
+        // a room's worth of overload never gets past the ceiling
+        for frame in 0..20 {
+            let mut out_a: Vec<f32> = (0..128).map(|i| ((frame * 128 + i) as f32 * 0.05).sin() * 8.0).collect();
+            let mut out_b: Vec<f32> = out_a.iter().map(|v| -v).collect();
+            limiter.process(&mut out_a, &mut out_b);
+            assert!(out_a.iter().chain(out_b.iter()).all(|v| v.abs() <= ceiling));
+        }
+        assert!(limiter.get_reduction() < -12.0);
+        // a single spike is caught before it gets out
+        let mut limiter = Limiter::new(0.0);
+        let mut out_a = vec![0.5; 128];
+        out_a[100] = 4.0;
+        let mut out_b = vec![0.0; 128];
+        limiter.process(&mut out_a, &mut out_b);
+        assert!(out_a.iter().all(|v| v.abs() <= 1.0));
+        // it comes out of the delay in the next frame
+        let (mut next_a, mut next_b) = (vec![0.0; 128], vec![0.0; 128]);
+        limiter.process(&mut next_a, &mut next_b);
+        assert!(next_a.iter().all(|v| v.abs() <= 1.0));
+        assert!(next_a[100 + LOOKAHEAD - 1 - 128].abs() > 0.9);
+        assert!(limiter.get_reduction() < -6.0);
+        // sides that don't match don't take it down
+        limiter.process(&mut vec![0.0; 128], &mut vec![0.0; 64]);
+        // the safety cap clamps in place, nothing is held back
+        let mut safety = SafetyClip::new(-6.0);
+        let mut out_a = vec![0.45; 128];
+        out_a[10] = 4.0;
+        let mut out_b = vec![-4.0; 128];
+        safety.process(&mut out_a, &mut out_b);
+        assert_eq!(out_a[0], 0.45);
+        assert_eq!(out_a[10], ceiling);
+        assert!(out_b.iter().all(|v| *v == -ceiling));
+        assert!(safety.get_reduction() < -12.0);
+        assert!(check_ceiling(-3.0).is_ok());
+        assert!(check_ceiling(3.0).is_err());
+    }
+}
@@ -6,15 +6,28 @@
 //!
 //! the [`crate::sound::jam_engine::JamEngine`] has a mixer that it uses to mix audio from
 //! room members into a stereo feed for the audio output device.
+//!
+//! Master, channel gain, pan and mute changes ramp over the ramp time (see [`Mixer::set_ramp_time`])
+//! so moving them doesn't make zipper noise or clicks.  The master bus ends in a [`Limiter`] so the
+//! mix never goes past the ceiling (see [`Mixer::set_limiter_ceiling`]).
 use pedal_board::{dsp::power_meter::PowerMeter, utils::{to_lin, to_db}};
 
-use super::{channel_strip::ChannelStrip, click_track::ClickTrack};
+use super::{
+    channel_strip::ChannelStrip,
+    click_track::ClickTrack,
+    limiter::{Limiter, DEFAULT_CEILING},
+    peer_presets::PeerPreset,
+    ramp::{ramp_samples, Ramp, DEFAULT_RAMP_MSEC},
+    SAMPLE_RATE,
+};
 use std::fmt;
 
 pub const MIXER_CHANNELS: usize = 24;
 
 pub struct Mixer {
     master_vol: f64,
+    master_ramp: Ramp,
+    limiter: Limiter,
     master_level: PowerMeter,
     strips: Vec<ChannelStrip>,
     click: ClickTrack,
@@ -25,6 +38,8 @@ impl Mixer {
     pub fn new() -> Mixer {
         let mut mixer = Mixer {
             master_vol: 1.0,
+            master_ramp: Ramp::new(1.0, ramp_samples(DEFAULT_RAMP_MSEC)),
+            limiter: Limiter::new(DEFAULT_CEILING),
             strips: vec![],
             master_level: PowerMeter::new(),
             click: ClickTrack::new(),
@@ -41,6 +56,29 @@ impl Mixer {
     /// set master volume for the overall mix
     pub fn set_master(&mut self, v: f64) -> () {
         self.master_vol = to_lin(v);
+        self.master_ramp.set(self.master_vol as f32);
+    }
+    /// most (dBFS) the master bus can put out
+    pub fn set_limiter_ceiling(&mut self, ceiling: f64) {
+        self.limiter.set_ceiling(ceiling);
+    }
+    pub fn get_limiter_ceiling(&self) -> f64 {
+        self.limiter.get_ceiling()
+    }
+    /// how hard (dB) the master limiter has been working lately
+    pub fn get_limiter_reduction(&self) -> f64 {
+        self.limiter.get_reduction()
+    }
+    /// frames the master soft clipper has touched
+    pub fn get_soft_clips(&self) -> u64 {
+        self.limiter.get_clips()
+    }
+    /// how long (msec) master and channel changes take to ramp in.  0 changes them on the spot
+    pub fn set_ramp_time(&mut self, msec: f64) {
+        self.master_ramp.set_samples(ramp_samples(msec));
+        for strip in &mut self.strips {
+            strip.set_ramp_time(msec);
+        }
     }
     /// retrieve avg power of the total mix
     pub fn get_master_level_avg(&self) -> f64 {
@@ -68,7 +106,7 @@ impl Mixer {
     }
     /// get the jitter buffer avg depth for a channel
     pub fn get_depth_in_msec(&self, idx: usize) -> f64 {
-        self.strips[idx].get_depth() / 48.0 // Convert to msec
+        self.strips[idx].get_depth() * 1000.0 / SAMPLE_RATE as f64 // Convert to msec
     }
     /// set gain on a particular channel
     pub fn set_channel_gain(&mut self, idx: usize, val: f64) -> () {
@@ -94,6 +132,22 @@ impl Mixer {
     pub fn get_channel_fade(&self, idx: usize) -> f32 {
         self.strips[idx].get_fade()
     }
+    /// put a peer's preset on the pair of channels starting at idx
+    pub fn apply_preset(&mut self, idx: usize, preset: &PeerPreset) {
+        for chan in 0..2 {
+            self.set_channel_gain(idx + chan, preset.gain[chan]);
+            self.set_channel_mute(idx + chan, preset.mute[chan]);
+            self.set_channel_fade(idx + chan, preset.fade[chan]);
+        }
+    }
+    /// the settings on the pair of channels starting at idx
+    pub fn get_preset(&self, idx: usize) -> PeerPreset {
+        PeerPreset {
+            gain: [to_db(self.get_channel_gain(idx)), to_db(self.get_channel_gain(idx + 1))],
+            mute: [self.get_channel_mute(idx), self.get_channel_mute(idx + 1)],
+            fade: [self.get_channel_fade(idx), self.get_channel_fade(idx + 1)],
+        }
+    }
     pub fn get_metronome_gain(&self) -> f64 {
         to_db(self.click.get_gain())
     }
@@ -106,10 +160,17 @@ impl Mixer {
     pub fn set_metronome_mute(&mut self, mute: bool) -> () {
         self.click.set_mute(mute);
     }
+    /// how shallow and deep (samples) every channel's jitter buffer can adapt to
+    pub fn set_jitter_limits(&mut self, min: usize, max: usize) {
+        for strip in &mut self.strips {
+            strip.set_depth_limits(min, max);
+        }
+    }
     /// get a frame of audio from the mixer.  this will
     /// - pull audio from all jitter buffers for all channels
     /// - apply channel strip fade and gain
     /// - apply master gain to final mix
+    /// - limit the mix to the ceiling
     pub fn get_mix(&mut self, beat: u8, out_a: &mut [f32], out_b: &mut [f32]) -> () {
         // Zero out the out buffer
         for i in 0..out_a.len() {
@@ -122,12 +183,15 @@ impl Mixer {
         }
         self.click.mix_into(beat, out_a, out_b);
         // Apply Master Volume
+        let mut master = 0.0;
         for i in 0..out_a.len() {
-            out_a[i] = out_a[i] * self.master_vol as f32;
-            out_b[i] = out_b[i] * self.master_vol as f32;
+            master = self.master_ramp.tick();
+            out_a[i] = out_a[i] * master;
+            out_b[i] = out_b[i] * master;
         }
-        // get the output volume
-        self.master_level.add_frame(out_a, self.master_vol);
+        self.limiter.process(out_a, out_b);
+        // get the output volume at the gain actually applied, not where it's ramping to
+        self.master_level.add_frame(out_a, master as f64);
     }
 
     pub fn get_chan_mix(&mut self, chan: usize, out_a: &mut [f32], out_b: &mut [f32]) -> () {
@@ -138,6 +202,13 @@ impl Mixer {
         self.strips[chan].mix_into(out_a, out_b);
     }
 
+    /// pull a frame from every channel into frames (one per channel) without mixing them
+    pub fn get_channel_frames(&mut self, frames: &mut [Vec<f32>]) {
+        for (strip, frame) in self.strips.iter_mut().zip(frames.iter_mut()) {
+            strip.get_frame(frame);
+        }
+    }
+
     /// call this to stuff data into one of the channels jitter buffer
     pub fn add_to_channel(&mut self, chan_no: usize, audio: &[f32]) -> () {
         if chan_no > MIXER_CHANNELS {
@@ -163,6 +234,7 @@ impl fmt::Display for Mixer {
 #[cfg(test)]
 mod test_mixer {
     use super::*;
+    use crate::sound::limiter::LOOKAHEAD;
 
     #[test]
     fn build_mixer() {
@@ -171,4 +243,28 @@ mod test_mixer {
         mixer.set_master(-32.0);
         assert_eq!(mixer.get_master().round(), -32.0);  // round out so tiny fractions to blow test
     }
+    #[test]
+    fn mute_fades_out() {
+        let mut mixer = Mixer::new();
+        mixer.set_ramp_time(1.0);
+        let (mut out_a, mut out_b) = ([0.0; 128], [0.0; 128]);
+        // keep the jitter buffer fed so the channel plays
+        let play = |mixer: &mut Mixer, out_a: &mut [f32], out_b: &mut [f32]| {
+            for _ in 0..4 {
+                mixer.add_to_channel(2, &[0.5; 128]);
+            }
+            mixer.get_mix(0, out_a, out_b);
+        };
+        // the master limiter holds everything back by its lookahead
+        let start = LOOKAHEAD - 1;
+        play(&mut mixer, &mut out_a, &mut out_b);
+        assert!(out_a[start..].iter().all(|v| *v > 0.0));
+        mixer.set_channel_mute(2, true);
+        play(&mut mixer, &mut out_a, &mut out_b);
+        // down over 48 samples instead of all at once
+        assert!(out_a[start] > out_a[start + 24] && out_a[start + 24] > 0.0);
+        assert_eq!(out_a[start + 48], 0.0);
+        play(&mut mixer, &mut out_a, &mut out_b);
+        assert!(out_a.iter().all(|v| *v == 0.0));
+    }
 }
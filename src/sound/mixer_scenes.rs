@@ -0,0 +1,197 @@
+//! Named mixer balances ("rehearsal", "recording", "show") the user can switch between
+//!
+//! A [`Scene`] holds the master volume, the local channels, the mix for each peer in the room by
+//! client id (see [`PeerPreset`]), the metronome and the room mutes.  The engine keeps them by name and sends
+//! the whole set out when it changes so the client can save it to a [`SceneFile`].
+//!
+//! The room runs the metronome tempo, not the unit, so a scene only holds the tempo the u/x gives
+//! it on save.  Recalling the scene hands it back for the u/x to set on the room.
+//!
+//! Recalling a scene can [`Crossfade`] to it: gains move in dB and pans move linearly over the fade
+//! time.  Mutes can't fade so they switch halfway through.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{peer_presets::PeerPreset, unit_state::ChannelState};
+use crate::common::json_file::JsonFile;
+
+/// gains below this (dB) are treated as silence while fading
+const FADE_FLOOR: f64 = -60.0;
+
+/// A mixer balance
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Scene {
+    /// dB
+    pub master_vol: f64,
+    /// the unit's own two channels
+    pub local: [ChannelState; 2],
+    /// mix for each peer in the room when it was saved, by client id
+    pub peers: BTreeMap<u32, PeerPreset>,
+    /// dB
+    pub metronome_gain: f64,
+    pub metronome_mute: bool,
+    /// bpm the room was at, if the u/x said
+    pub tempo: Option<u32>,
+    pub room_mutes: [bool; 2],
+}
+
+fn lerp(from: f64, to: f64, t: f64) -> f64 {
+    from + (to - from) * t
+}
+
+// gains fade in dB so the move sounds even.  Silence fades from the floor instead of -inf
+fn lerp_db(from: f64, to: f64, t: f64) -> f64 {
+    lerp(from.max(FADE_FLOOR), to.max(FADE_FLOOR), t)
+}
+
+fn lerp_channel(from: &ChannelState, to: &ChannelState, t: f64) -> ChannelState {
+    ChannelState {
+        gain: lerp_db(from.gain, to.gain, t),
+        mute: if t < 0.5 { from.mute } else { to.mute },
+        fade: lerp(from.fade as f64, to.fade as f64, t) as f32,
+    }
+}
+
+fn lerp_preset(from: &PeerPreset, to: &PeerPreset, t: f64) -> PeerPreset {
+    if t >= 1.0 {
+        return to.clone();
+    }
+    let chan = |p: &PeerPreset, idx: usize| ChannelState { gain: p.gain[idx], mute: p.mute[idx], fade: p.fade[idx] };
+    let a = lerp_channel(&chan(from, 0), &chan(to, 0), t);
+    let b = lerp_channel(&chan(from, 1), &chan(to, 1), t);
+    PeerPreset { gain: [a.gain, b.gain], mute: [a.mute, b.mute], fade: [a.fade, b.fade] }
+}
+
+impl Scene {
+    /// t of the way (0.0 to 1.0) from one scene to another.  Peers only in `to` are already there
+    pub fn lerp(from: &Scene, to: &Scene, t: f64) -> Scene {
+        if t >= 1.0 {
+            return to.clone();
+        }
+        Scene {
+            master_vol: lerp_db(from.master_vol, to.master_vol, t),
+            local: [
+                lerp_channel(&from.local[0], &to.local[0], t),
+                lerp_channel(&from.local[1], &to.local[1], t),
+            ],
+            peers: to
+                .peers
+                .iter()
+                .map(|(id, p)| match from.peers.get(id) {
+                    Some(f) => (*id, lerp_preset(f, p, t)),
+                    None => (*id, p.clone()),
+                })
+                .collect(),
+            metronome_gain: lerp_db(from.metronome_gain, to.metronome_gain, t),
+            metronome_mute: if t < 0.5 { from.metronome_mute } else { to.metronome_mute },
+            tempo: to.tempo,
+            room_mutes: if t < 0.5 { from.room_mutes } else { to.room_mutes },
+        }
+    }
+}
+
+/// A recall in progress.  The peers are worked out per mixer strip when it starts so stepping it
+/// along doesn't build anything
+pub struct Crossfade {
+    // without their peers
+    from: Scene,
+    to: Scene,
+    // client id, first mixer strip and the mix it fades from and to
+    strips: Vec<(u32, usize, PeerPreset, PeerPreset)>,
+    start: u128,
+    // usec
+    duration: u128,
+}
+
+impl Crossfade {
+    /// strips has the first mixer strip of each peer in the room (by client id).  Peers in the scene
+    /// that aren't in the room are left out
+    pub fn new(mut from: Scene, mut to: Scene, strips: &[(u32, usize)], start: u128, duration: u128) -> Crossfade {
+        let strips = strips
+            .iter()
+            .filter_map(|(id, idx)| {
+                let target = to.peers.get(id)?.clone();
+                let current = from.peers.get(id).cloned().unwrap_or_else(|| target.clone());
+                Some((*id, *idx, current, target))
+            })
+            .collect();
+        from.peers.clear();
+        to.peers.clear();
+        Crossfade { from, to, strips, start, duration }
+    }
+    /// how far along (0.0 to 1.0) it is, and true when the fade is over
+    pub fn progress(&self, now: u128) -> (f64, bool) {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed >= self.duration {
+            return (1.0, true);
+        }
+        (elapsed as f64 / self.duration as f64, false)
+    }
+    /// everything but the peers t of the way along
+    pub fn mix_at(&self, t: f64) -> Scene {
+        Scene::lerp(&self.from, &self.to, t)
+    }
+    /// (client id, first mixer strip, mix) for each peer t of the way along
+    pub fn strips_at(&self, t: f64) -> impl Iterator<Item = (u32, usize, PeerPreset)> + '_ {
+        self.strips.iter().map(move |(id, idx, from, to)| (*id, *idx, lerp_preset(from, to, t)))
+    }
+}
+
+/// Where the scenes are kept, by name
+pub type SceneFile = JsonFile<BTreeMap<String, Scene>>;
+
+#[cfg(test)]
+mod test_mixer_scenes {
+    use super::*;
+
+    #[test]
+    fn crossfades_between_scenes() {
+        let from = Scene {
+            master_vol: -12.0,
+            local: [ChannelState { gain: 0.0, mute: false, fade: -1.0 }, ChannelState::default()],
+            peers: BTreeMap::from([(1234, PeerPreset::default())]),
+            ..Default::default()
+        };
+        let to = Scene {
+            master_vol: 0.0,
+            local: [ChannelState { gain: -100.0, mute: true, fade: 1.0 }, ChannelState::default()],
+            peers: BTreeMap::from([(1234, PeerPreset { gain: [-6.0, -6.0], ..Default::default() }), (4444, PeerPreset::default())]),
+            metronome_mute: true,
+            tempo: Some(96),
+            ..Default::default()
+        };
+        let fade = Crossfade::new(from.clone(), to.clone(), &[(1234, 2), (4444, 4)], 1_000_000, 2_000_000);
+        // a quarter of the way
+        let (t, done) = fade.progress(1_500_000);
+        assert!(!done);
+        let quarter = fade.mix_at(t);
+        assert!((quarter.master_vol + 9.0).abs() < 0.001);
+        assert!((quarter.local[0].fade + 0.5).abs() < 0.001);
+        // fades from the floor, not -inf
+        assert!((quarter.local[0].gain + 15.0).abs() < 0.001);
+        assert!(!quarter.local[0].mute && !quarter.metronome_mute);
+        let strips: Vec<_> = fade.strips_at(t).collect();
+        assert_eq!((strips[0].0, strips[0].1), (1234, 2));
+        assert!((strips[0].2.gain[0] + 1.5).abs() < 0.001);
+        // a peer that wasn't in the from scene is already there
+        assert_eq!(strips[1], (4444, 4, PeerPreset::default()));
+        // mutes switch halfway
+        let (t, _) = fade.progress(2_000_000);
+        let half = fade.mix_at(t);
+        assert!(half.local[0].mute && half.metronome_mute);
+        let (t, done) = fade.progress(5_000_000);
+        assert!(done);
+        assert_eq!(fade.mix_at(t), Scene { peers: BTreeMap::new(), ..to.clone() });
+        assert_eq!(fade.strips_at(t).map(|s| s.2).collect::<Vec<_>>(), to.peers.values().cloned().collect::<Vec<_>>());
+        // saved and loaded by name
+        let path = std::env::temp_dir().join(format!("scenes_{}.json", std::process::id()));
+        let file = SceneFile::new(path.to_str().unwrap());
+        assert!(file.load().is_none());
+        let scenes = BTreeMap::from([("show".to_string(), to)]);
+        file.save(&scenes).unwrap();
+        assert_eq!(file.load(), Some(scenes));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Minimal Open Sound Control (OSC 1.0) encoding and decoding
+//!
+//! Only the argument types that control surfaces actually send are supported: int32, float32,
+//! string, and the True/False tags.  Incoming bundles are flattened into their messages since we
+//! act on everything immediately.  Outgoing bundles always carry the "immediately" time tag.
+use simple_error::bail;
+use std::fmt;
+
+use crate::common::box_error::BoxError;
+
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+const TIME_TAG_IMMEDIATE: u64 = 1;
+
+/// A single OSC argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    /// numeric value of the argument (strings parse if they can)
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArg::Int(v) => Some(*v as f64),
+            OscArg::Float(v) => Some(*v as f64),
+            OscArg::Str(s) => s.parse().ok(),
+            OscArg::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        }
+    }
+    /// truthiness of the argument.  Surfaces send mutes as 0/1 ints, 0.0/1.0 floats, or T/F
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            OscArg::Bool(b) => Some(*b),
+            _ => self.as_f64().map(|v| v >= 0.5),
+        }
+    }
+}
+
+/// An OSC message is an address pattern and a list of arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub addr: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(addr: &str, args: Vec<OscArg>) -> OscMessage {
+        OscMessage {
+            addr: String::from(addr),
+            args,
+        }
+    }
+    /// encode the message into OSC wire format
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        write_str(&mut buf, &self.addr);
+        let mut tags = String::from(",");
+        for arg in &self.args {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Str(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            });
+        }
+        write_str(&mut buf, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                OscArg::Float(v) => buf.extend_from_slice(&v.to_be_bytes()),
+                OscArg::Str(s) => write_str(&mut buf, s),
+                OscArg::Bool(_) => {}
+            }
+        }
+        buf
+    }
+    /// split the address into its parts.  "/rtjam/channel/3/gain" is ["rtjam", "channel", "3", "gain"]
+    pub fn addr_parts(&self) -> Vec<&str> {
+        self.addr.split('/').filter(|p| !p.is_empty()).collect()
+    }
+}
+
+impl fmt::Display for OscMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}", self.addr, self.args)
+    }
+}
+
+/// encode a list of messages as a bundle to be delivered immediately
+pub fn encode_bundle(msgs: &[OscMessage]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend_from_slice(BUNDLE_TAG);
+    buf.extend_from_slice(&TIME_TAG_IMMEDIATE.to_be_bytes());
+    for msg in msgs {
+        let data = msg.encode();
+        buf.extend_from_slice(&(data.len() as i32).to_be_bytes());
+        buf.extend_from_slice(&data);
+    }
+    buf
+}
+
+/// decode a datagram into the messages it contains (bundles are flattened)
+pub fn decode_packet(data: &[u8]) -> Result<Vec<OscMessage>, BoxError> {
+    let mut msgs = vec![];
+    decode_into(data, &mut msgs)?;
+    Ok(msgs)
+}
+
+fn decode_into(data: &[u8], msgs: &mut Vec<OscMessage>) -> Result<(), BoxError> {
+    if data.starts_with(BUNDLE_TAG) {
+        // skip the tag and the time tag
+        let mut pos = BUNDLE_TAG.len() + 8;
+        while pos < data.len() {
+            let size = read_i32(data, &mut pos)?;
+            if size < 0 || pos + size as usize > data.len() {
+                bail!("bad bundle element size");
+            }
+            decode_into(&data[pos..pos + size as usize], msgs)?;
+            pos += size as usize;
+        }
+        return Ok(());
+    }
+    let mut pos = 0;
+    let addr = read_str(data, &mut pos)?;
+    if !addr.starts_with('/') {
+        bail!("osc address must start with /");
+    }
+    let mut args = vec![];
+    if pos < data.len() {
+        let tags = read_str(data, &mut pos)?;
+        if !tags.starts_with(',') {
+            bail!("missing osc type tags");
+        }
+        for tag in tags.chars().skip(1) {
+            match tag {
+                'i' => args.push(OscArg::Int(read_i32(data, &mut pos)?)),
+                'f' => args.push(OscArg::Float(f32::from_bits(read_i32(data, &mut pos)? as u32))),
+                's' => args.push(OscArg::Str(read_str(data, &mut pos)?)),
+                'T' => args.push(OscArg::Bool(true)),
+                'F' => args.push(OscArg::Bool(false)),
+                _ => bail!("unsupported osc type tag {}", tag),
+            }
+        }
+    }
+    msgs.push(OscMessage { addr, args });
+    Ok(())
+}
+
+/// OSC strings are null terminated and padded to a multiple of 4 bytes
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    let pad = 4 - (s.len() % 4);
+    buf.resize(buf.len() + pad, 0);
+}
+
+fn read_str(data: &[u8], pos: &mut usize) -> Result<String, BoxError> {
+    let start = *pos;
+    let end = match data[start..].iter().position(|&b| b == 0) {
+        Some(n) => start + n,
+        None => bail!("unterminated osc string"),
+    };
+    let s = String::from_utf8(data[start..end].to_vec())?;
+    // advance past the null and the padding
+    *pos = start + (end - start) + (4 - ((end - start) % 4));
+    Ok(s)
+}
+
+fn read_i32(data: &[u8], pos: &mut usize) -> Result<i32, BoxError> {
+    if *pos + 4 > data.len() {
+        bail!("osc packet too short");
+    }
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[*pos..*pos + 4]);
+    *pos += 4;
+    Ok(i32::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod test_osc_message {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let msg = OscMessage::new(
+            "/rtjam/channel/3/gain",
+            vec![
+                OscArg::Float(-6.0),
+                OscArg::Int(2),
+                OscArg::Str("abc".to_string()),
+                OscArg::Bool(true),
+            ],
+        );
+        let data = msg.encode();
+        assert_eq!(data.len() % 4, 0);
+        let msgs = decode_packet(&data).unwrap();
+        assert_eq!(msgs, vec![msg]);
+    }
+
+    #[test]
+    fn known_bytes() {
+        // "/a" with one int arg of 1
+        let data = [
+            b'/', b'a', 0, 0, b',', b'i', 0, 0, 0, 0, 0, 1,
+        ];
+        let msgs = decode_packet(&data).unwrap();
+        assert_eq!(msgs[0].addr, "/a");
+        assert_eq!(msgs[0].args, vec![OscArg::Int(1)]);
+        assert_eq!(OscMessage::new("/a", vec![OscArg::Int(1)]).encode(), data.to_vec());
+    }
+
+    #[test]
+    fn bundles_flatten() {
+        let a = OscMessage::new("/rtjam/master", vec![OscArg::Float(-3.0)]);
+        let b = OscMessage::new("/rtjam/beat", vec![OscArg::Int(2)]);
+        let msgs = decode_packet(&encode_bundle(&[a.clone(), b.clone()])).unwrap();
+        assert_eq!(msgs, vec![a, b]);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(decode_packet(b"nope").is_err());
+        assert!(decode_packet(b"/a\0\0,i\0\0\0").is_err());
+    }
+
+    #[test]
+    fn arg_conversions() {
+        assert_eq!(OscArg::Int(1).as_bool(), Some(true));
+        assert_eq!(OscArg::Float(0.0).as_bool(), Some(false));
+        assert_eq!(OscArg::Str("2.5".to_string()).as_f64(), Some(2.5));
+        assert_eq!(OscArg::Str("x".to_string()).as_f64(), None);
+    }
+}
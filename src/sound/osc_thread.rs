@@ -0,0 +1,364 @@
+//! OSC over UDP control and metering for the sound component
+//!
+//! Control surfaces (TouchOSC, console software, etc) send OSC messages to the unit.  The
+//! addresses are translated into [`ParamMessage`] json and handed to the main thread exactly as if
+//! they came from the websocket.  The supported addresses are:
+//!
+//! | address                          | argument   | JamParam       |
+//! |----------------------------------|------------|----------------|
+//! | `/rtjam/master`                  | gain (dB)  | MasterVol      |
+//! | `/rtjam/channel/N/gain`          | gain (dB)  | ChannelGain    |
+//! | `/rtjam/channel/N/mute`          | 0/1        | ChannelMute    |
+//! | `/rtjam/channel/N/fade`          | -1.0..1.0  | SetFader       |
+//! | `/rtjam/room/N/mute`             | 0/1        | MuteToRoom     |
+//! | `/rtjam/metronome/gain`          | gain (dB)  | MetronomeGain  |
+//! | `/rtjam/metronome/mute`          | 0/1        | MetronomeMute  |
+//! | `/rtjam/input/N/gain`            | gain       | InputGain      |
+//! | `/rtjam/headphone/gain`          | gain       | HeadphoneGain  |
+//! | `/rtjam/tuner/N`                 | 0/1        | TuneChannel    |
+//!
+//! Meters are taken from the levelEvent the [`JamEngine`](crate::sound::jam_engine::JamEngine)
+//! sends and published as a bundle at the configured rate to every surface that has talked to
+//! us recently (plus an optional fixed target).  The rate is kept here: the latest level event is
+//! republished on our own timer and the engine's update interval (shared with every websocket
+//! listener) is left alone.  The bundle contains:
+//!
+//! - `/rtjam/meter/input/0` and `/1` - input levels before processing
+//! - `/rtjam/meter/room/0` and `/1` - levels being sent to the room
+//! - `/rtjam/meter/master` - master output level
+//! - `/rtjam/meter/channel/N` - level for each mixer channel (peer channels included)
+//! - `/rtjam/beat` - current beat from the room
+use log::{debug, info, trace, warn};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    common::{
+        box_error::BoxError, get_micro_time, stream_time_stat::MicroTimer,
+        websock_message::WebsockMessage,
+    },
+    sound::{
+        osc_message::{decode_packet, encode_bundle, OscArg, OscMessage},
+        param_message::{JamParam, ParamMessage},
+    },
+};
+
+/// surfaces that have not sent anything in this long stop getting meters
+const PEER_EXPIRATION: u128 = 60_000_000;
+/// timer interval used when meters are turned off
+const IDLE_METERS: u128 = 1_000_000;
+
+/// Translate an OSC message into the ParamMessage the engine understands.
+///
+/// Returns None if the address is not one we know or the argument is missing.
+pub fn to_param_message(msg: &OscMessage) -> Option<ParamMessage> {
+    let parts = msg.addr_parts();
+    let arg = msg.args.first()?;
+    if parts.first() != Some(&"rtjam") {
+        return None;
+    }
+    let index = |s: &str| s.parse::<i64>().ok();
+    match parts[1..] {
+        ["master"] => Some(ParamMessage::new(JamParam::MasterVol, 0, 0, arg.as_f64()?, "")),
+        ["channel", n, "gain"] => Some(ParamMessage::new(
+            JamParam::ChannelGain,
+            index(n)?,
+            0,
+            arg.as_f64()?,
+            "",
+        )),
+        ["channel", n, "mute"] => Some(ParamMessage::new(
+            JamParam::ChannelMute,
+            index(n)?,
+            arg.as_bool()? as i64,
+            0.0,
+            "",
+        )),
+        ["channel", n, "fade"] => Some(ParamMessage::new(
+            JamParam::SetFader,
+            index(n)?,
+            0,
+            arg.as_f64()?,
+            "",
+        )),
+        ["room", n, "mute"] => Some(ParamMessage::new(
+            JamParam::MuteToRoom,
+            index(n)?,
+            arg.as_bool()? as i64,
+            0.0,
+            "",
+        )),
+        ["metronome", "gain"] => Some(ParamMessage::new(
+            JamParam::MetronomeGain,
+            0,
+            0,
+            arg.as_f64()?,
+            "",
+        )),
+        ["metronome", "mute"] => Some(ParamMessage::new(
+            JamParam::MetronomeMute,
+            arg.as_bool()? as i64,
+            0,
+            0.0,
+            "",
+        )),
+        ["input", n, "gain"] => Some(ParamMessage::new(
+            JamParam::InputGain,
+            index(n)?,
+            0,
+            arg.as_f64()?,
+            "",
+        )),
+        ["headphone", "gain"] => Some(ParamMessage::new(
+            JamParam::HeadphoneGain,
+            0,
+            0,
+            arg.as_f64()?,
+            "",
+        )),
+        ["tuner", n] => Some(ParamMessage::new(
+            JamParam::TuneChannel,
+            index(n)?,
+            arg.as_bool()? as i64,
+            0.0,
+            "",
+        )),
+        _ => None,
+    }
+}
+
+/// Build the meter bundle contents from a levelEvent
+pub fn meter_messages(level_event: &Value) -> Vec<OscMessage> {
+    let level = |v: &Value| OscArg::Float(v.as_f64().unwrap_or(-100.0) as f32);
+    let mut msgs = vec![
+        OscMessage::new("/rtjam/meter/input/0", vec![level(&level_event["inputLeft"])]),
+        OscMessage::new("/rtjam/meter/input/1", vec![level(&level_event["inputRight"])]),
+        OscMessage::new("/rtjam/meter/room/0", vec![level(&level_event["roomInputLeft"])]),
+        OscMessage::new("/rtjam/meter/room/1", vec![level(&level_event["roomInputRight"])]),
+        OscMessage::new("/rtjam/meter/master", vec![level(&level_event["masterLevel"])]),
+        OscMessage::new(
+            "/rtjam/beat",
+            vec![OscArg::Int(level_event["beat"].as_i64().unwrap_or(0) as i32)],
+        ),
+    ];
+    if let Some(players) = level_event["players"].as_array() {
+        for p in players {
+            if let Some(chan) = p["chanIdx"].as_u64() {
+                msgs.push(OscMessage::new(
+                    &format!("/rtjam/meter/channel/{}", chan),
+                    vec![level(&p["level0"])],
+                ));
+                msgs.push(OscMessage::new(
+                    &format!("/rtjam/meter/channel/{}", chan + 1),
+                    vec![level(&p["level1"])],
+                ));
+            }
+        }
+    }
+    msgs
+}
+
+/// Open the OSC socket and spawn the thread.
+///
+/// Translated commands are sent as json on ws_tx.  Returns the channel the main thread uses to
+/// pass along u/x messages (the levelEvents are used for meters) or None if the port is 0 or can't
+/// be opened.
+pub fn start_osc_thread(
+    port: u32,
+    meter_rate: u32,
+    meter_target: &str,
+    ws_tx: mpsc::Sender<Value>,
+) -> Option<mpsc::Sender<WebsockMessage>> {
+    if port == 0 {
+        info!("osc disabled");
+        return None;
+    }
+    let sock = match UdpSocket::bind(format!("0.0.0.0:{}", port)) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("osc can't listen on port {}: {}", port, e);
+            return None;
+        }
+    };
+    let target = if meter_target.is_empty() {
+        None
+    } else {
+        match meter_target.parse::<SocketAddr>() {
+            Ok(a) => Some(a),
+            Err(e) => {
+                warn!("bad osc_meter_target {}: {}", meter_target, e);
+                None
+            }
+        }
+    };
+    info!("osc listening on port {}", port);
+    let (to_osc_tx, to_osc_rx) = mpsc::channel();
+    let _osc_handle = thread::spawn(move || {
+        if let Err(e) = osc_thread(sock, meter_rate, target, ws_tx, to_osc_rx) {
+            warn!("osc thread exited with error: {}", e);
+        }
+    });
+    Some(to_osc_tx)
+}
+
+/// The OSC thread.  Reads commands from the socket and writes meter bundles.
+pub fn osc_thread(
+    sock: UdpSocket,                       // socket to read commands from
+    meter_rate: u32,                       // meter bundles per second (0 turns meters off)
+    meter_target: Option<SocketAddr>,      // always send meters here
+    ws_tx: mpsc::Sender<Value>,            // channel to main thread
+    ws_rx: mpsc::Receiver<WebsockMessage>, // channel from main thread
+) -> Result<(), BoxError> {
+    sock.set_read_timeout(Some(Duration::new(0, 5_000_000)))?;
+    let now = get_micro_time();
+    let meter_interval = if meter_rate > 0 {
+        1_000_000 / meter_rate as u128
+    } else {
+        IDLE_METERS
+    };
+    let mut meter_timer = MicroTimer::new(now, meter_interval);
+    let mut peers: HashMap<SocketAddr, u128> = HashMap::new();
+    let mut level_event = Value::Null;
+    let mut buf = [0; 2048];
+    loop {
+        match sock.recv_from(&mut buf) {
+            Ok((amt, src)) => {
+                peers.insert(src, get_micro_time());
+                match decode_packet(&buf[..amt]) {
+                    Ok(msgs) => {
+                        for msg in msgs {
+                            match to_param_message(&msg) {
+                                Some(param) => {
+                                    debug!("osc {} -> {}", msg, param);
+                                    let _res = ws_tx.send(param.as_json());
+                                }
+                                None => {
+                                    debug!("osc unknown address: {}", msg);
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        debug!("osc decode error from {}: {}", src, e);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => {
+                warn!("osc socket error: {}", e);
+            }
+        }
+        // keep the latest level event
+        for msg in ws_rx.try_iter() {
+            if let WebsockMessage::Chat(mut v) = msg {
+                if !v["levelEvent"].is_null() {
+                    level_event = v["levelEvent"].take();
+                }
+            }
+        }
+        let now = get_micro_time();
+        peers.retain(|_addr, heard| now - *heard < PEER_EXPIRATION);
+        if meter_rate > 0 && meter_timer.expired(now) {
+            meter_timer.reset(now);
+            if !level_event.is_null() {
+                let bundle = encode_bundle(&meter_messages(&level_event));
+                for addr in peers.keys().chain(meter_target.iter()) {
+                    trace!("osc meters to {}", addr);
+                    let _res = sock.send_to(&bundle, addr);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_osc_thread {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn maps_addresses() {
+        let msg = OscMessage::new("/rtjam/channel/3/gain", vec![OscArg::Float(-6.0)]);
+        let param = to_param_message(&msg).unwrap();
+        assert!(matches!(param.param, JamParam::ChannelGain));
+        assert_eq!(param.ivalue_1, 3);
+        assert_eq!(param.fvalue, -6.0);
+
+        let msg = OscMessage::new("/rtjam/master", vec![OscArg::Int(-3)]);
+        let param = to_param_message(&msg).unwrap();
+        assert!(matches!(param.param, JamParam::MasterVol));
+        assert_eq!(param.fvalue, -3.0);
+
+        let msg = OscMessage::new("/rtjam/channel/2/mute", vec![OscArg::Bool(true)]);
+        let param = to_param_message(&msg).unwrap();
+        assert!(matches!(param.param, JamParam::ChannelMute));
+        assert_eq!(param.ivalue_2, 1);
+
+        let msg = OscMessage::new("/rtjam/metronome/mute", vec![OscArg::Float(1.0)]);
+        let param = to_param_message(&msg).unwrap();
+        assert!(matches!(param.param, JamParam::MetronomeMute));
+        assert_eq!(param.ivalue_1, 1);
+    }
+
+    #[test]
+    fn ignores_unknown() {
+        assert!(to_param_message(&OscMessage::new("/rtjam/bogus", vec![OscArg::Int(1)])).is_none());
+        assert!(to_param_message(&OscMessage::new("/other/master", vec![OscArg::Int(1)])).is_none());
+        assert!(to_param_message(&OscMessage::new("/rtjam/channel/x/gain", vec![OscArg::Int(1)])).is_none());
+        // no argument
+        assert!(to_param_message(&OscMessage::new("/rtjam/master", vec![])).is_none());
+    }
+
+    #[test]
+    fn builds_meters() {
+        let event = json!({
+            "inputLeft": -20.0,
+            "inputRight": -30.0,
+            "roomInputLeft": -21.0,
+            "roomInputRight": -31.0,
+            "masterLevel": -10.0,
+            "beat": 3,
+            "players": [
+                { "chanIdx": 0, "level0": -20.0, "level1": -30.0 },
+                { "chanIdx": 4, "level0": -40.0, "level1": -50.0 },
+            ]
+        });
+        let msgs = meter_messages(&event);
+        assert_eq!(msgs.len(), 10);
+        assert!(msgs.contains(&OscMessage::new("/rtjam/beat", vec![OscArg::Int(3)])));
+        assert!(msgs.contains(&OscMessage::new(
+            "/rtjam/meter/channel/5",
+            vec![OscArg::Float(-50.0)]
+        )));
+    }
+
+    #[test]
+    fn commands_and_meters_over_udp() {
+        let (from_osc_tx, from_osc_rx) = mpsc::channel();
+        let to_osc_tx = start_osc_thread(18932, 50, "", from_osc_tx).unwrap();
+        let surface = UdpSocket::bind("127.0.0.1:0").unwrap();
+        surface.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+        let msg = OscMessage::new("/rtjam/channel/4/gain", vec![OscArg::Float(-12.0)]);
+        surface.send_to(&msg.encode(), "127.0.0.1:18932").unwrap();
+        // The command comes through and nothing touches the engine's update interval
+        let cmd = ParamMessage::from_json(&from_osc_rx.recv_timeout(Duration::new(1, 0)).unwrap()).unwrap();
+        assert!(matches!(cmd.param, JamParam::ChannelGain));
+        assert_eq!(cmd.ivalue_1, 4);
+        assert!(from_osc_rx.recv_timeout(Duration::from_millis(100)).is_err());
+        // Now feed a level event and we should get meters back
+        to_osc_tx
+            .send(WebsockMessage::Chat(json!({"speaker": "UnitChatRobot", "levelEvent": {"beat": 2}})))
+            .unwrap();
+        let mut buf = [0; 2048];
+        let (amt, _src) = surface.recv_from(&mut buf).unwrap();
+        let meters = decode_packet(&buf[..amt]).unwrap();
+        assert!(meters.contains(&OscMessage::new("/rtjam/beat", vec![OscArg::Int(2)])));
+    }
+}
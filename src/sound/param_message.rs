@@ -5,7 +5,10 @@ use serde_json::json;
 use simple_error::bail;
 use std::fmt;
 
-use crate::common::box_error::BoxError;
+use crate::{
+    common::{box_error::BoxError, command_reply::command_reply, local_websocket::client_tag},
+    sound::jam_command::CommandRequest,
+};
 
 #[derive(FromPrimitive, ToPrimitive)]
 pub enum JamParam {
@@ -59,14 +62,41 @@ pub enum JamParam {
     SetAudioOutput,
     ListAudioConfig,
     CheckForUpdate,
-    RandomCommand,
+    RandomCommand,  // Run a diag action (svalue is the action name and its arguments)
     GetPedalTypes,
     SetUpdateInterval,  // Sets the frequency the unit will update the ux in the browser
+    GetParams,  // Get the parameter tree (svalue is an optional path prefix)
+    SetParam,  // Set a parameter by path (svalue is the path, fvalue the value)
+    SubscribeParams,  // Subscribe to changes under a path prefix (svalue) ivalue_1 1 to subscribe, 0 to stop
+    SubscribeStatus,  // Subscribe to a status topic (svalue) every ivalue_1 msec (0 to stop) ivalue_2 bit 0 delta, bit 1 binary.  Handled by the main thread
+    SetRoomPassword,  // Password (svalue) to send when joining a room.  Empty for none
+    SetJitterDepth,  // How shallow (ivalue_1) and deep (ivalue_2) the jitter buffers adapt, in samples
+    PeerGain,  // Gain (fvalue dB) for channel ivalue_2 (0 or 1) of the peer with client id ivalue_1
+    PeerMute,  // Mute (fvalue 1.0) channel ivalue_2 of the peer with client id ivalue_1
+    PeerFade,  // Pan (fvalue) channel ivalue_2 of the peer with client id ivalue_1
+    ForgetPeer,  // Drop the mixer preset for client id ivalue_1
+    SetPanSpread,  // How far (fvalue 0.0 to 1.0) newcomers are panned from center
+    ListScenes,  // Get the names of the saved mixer scenes
+    SaveScene,  // Save the mixer as scene svalue (replaces one with the same name) with room tempo ivalue_1 bpm (0 for none)
+    RecallScene,  // Put the mixer to scene svalue, crossfading over ivalue_1 msec (0 to jump)
+    DeleteScene,  // Delete scene svalue
+    SetRampTime,  // How long (fvalue msec) gain, pan and mute changes take to ramp in
+    SetLimiterCeiling,  // Most (fvalue dBFS) the master bus can put out
+    ClientClosed,  // Local websocket connection ivalue_1 went away.  Drops its subscriptions
+    SetStatusInterval,  // How often (ivalue_1 msec, 0 to stop) the engine sends a status snapshot for the topic subscriptions
+    SetIdleRefresh,  // How often (ivalue_1 msec) the engine sends level updates when the u/x hasn't asked for faster
     RebootDevice = 9998,  // deprecated
     ShutdownDevice = 9999,
     StopAudio,    // Stop the jamEngine audio component
 }
 
+impl JamParam {
+    /// params the unit sends itself.  They are refused from the room and the u/x
+    pub fn is_internal(&self) -> bool {
+        matches!(self, JamParam::ClientClosed | JamParam::SetStatusInterval | JamParam::SetIdleRefresh)
+    }
+}
+
 /// The ParamMessage is used to define the API to the sound engine from the outside
 /// world.  Interpretation of this message is by the
 /// [`JamEngine`](crate::sound::jam_engine::JamEngine).
@@ -76,15 +106,18 @@ pub enum JamParam {
 ///
 /// other values are ivalue_1: integer, ivalue_2: integer, fvalue: float, and svalue: string.
 ///
-/// ### TODO
-/// This encoding needs to get normalized.  But it will require coordination between the u/x and the
-/// sound unit.
+/// The u/x can also send a typed [`JamCommand`](crate::sound::jam_command::JamCommand) which is
+/// converted into this encoding by [`ParamMessage::from_json`].  Either form can carry a request_id
+/// and the engine will answer with a commandReply when it is present.
 pub struct ParamMessage {
     pub param: JamParam,
     pub ivalue_1: i64,
     pub ivalue_2: i64,
     pub fvalue: f64,
     pub svalue: String,
+    pub request_id: Option<String>,
+    /// the local websocket connection that sent it (0 for the room and everything else)
+    pub client: u32,
 }
 
 impl ParamMessage {
@@ -95,22 +128,53 @@ impl ParamMessage {
             ivalue_2: ival2,
             fvalue: fval,
             svalue: String::from(sval),
+            request_id: None,
+            client: 0,
         }
     }
     pub fn as_json(&self) -> serde_json::Value {
-        json!({
+        let mut rval = json!({
           "param": num::ToPrimitive::to_usize(&self.param),
           "iValue1": self.ivalue_1,
           "iValue2": self.ivalue_2,
           "fValue": self.fvalue,
           "sValue": self.svalue,
-        })
+        });
+        if let Some(id) = &self.request_id {
+            rval["requestId"] = json!(id);
+        }
+        if self.client != 0 {
+            rval["wsClient"] = json!(self.client);
+        }
+        rval
+    }
+    /// build the commandReply for this message.  None if the sender did not ask for one
+    pub fn reply(&self, result: Result<serde_json::Value, BoxError>) -> Option<serde_json::Value> {
+        self.request_id
+            .as_ref()
+            .map(|id| self.addressed(command_reply("UnitChatRobot", id, result)))
+    }
+    /// address a message to whoever sent this one.  Messages for a local websocket connection only
+    /// go to that connection
+    pub fn addressed(&self, mut event: serde_json::Value) -> serde_json::Value {
+        if self.client != 0 {
+            event["wsClient"] = json!(self.client);
+        }
+        event
     }
     pub fn from_string(data: &str) -> Result<ParamMessage, BoxError> {
         let raw = serde_json::from_str(data)?;
         Self::from_json(&raw)
     }
     pub fn from_json(raw: &serde_json::Value) -> Result<ParamMessage, BoxError> {
+        if raw["cmd"].is_string() {
+            // This is a typed command
+            let req: CommandRequest = serde_json::from_value(raw.clone())?;
+            let mut msg = req.command.to_param_message();
+            msg.request_id = req.request_id;
+            msg.client = client_tag(raw);
+            return Ok(msg);
+        }
         if !(raw["param"].is_i64() || raw["param"].is_string()) {
             bail!("no param in message");
         }
@@ -122,6 +186,9 @@ impl ParamMessage {
             param = FromPrimitive::from_i64(str::parse(raw["param"].as_str().unwrap())?);
         }
         match param {
+            Some(p) if p.is_internal() => {
+                bail!("param {} can't be sent to the unit", raw["param"]);
+            }
             Some(p) => {
                 let mut msg = ParamMessage::new(p, 0, 0, 0.0, "");
                 if raw["iValue1"].is_i64() {
@@ -148,6 +215,10 @@ impl ParamMessage {
                 if raw["sValue"].is_string() {
                     msg.svalue = String::from(raw["sValue"].as_str().unwrap());
                 }
+                if raw["requestId"].is_string() {
+                    msg.request_id = Some(String::from(raw["requestId"].as_str().unwrap()));
+                }
+                msg.client = client_tag(raw);
                 Ok(msg)
             }
             None => {
@@ -220,6 +291,45 @@ mod test_param_message {
         let raw: serde_json::Value = serde_json::from_str(data).unwrap();
         let msg = ParamMessage::from_json(&raw).unwrap();
         assert_eq!(msg.fvalue, 2.0);
+        assert!(msg.request_id.is_none());
+    }
+    #[test]
+    fn from_typed_command() {
+        let raw = json!({"cmd": "setChannelMute", "requestId": "r1", "channel": 3, "mute": true});
+        let msg = ParamMessage::from_json(&raw).unwrap();
+        assert!(matches!(msg.param, JamParam::ChannelMute));
+        assert_eq!(msg.ivalue_1, 3);
+        assert_eq!(msg.ivalue_2, 1);
+        assert_eq!(msg.request_id, Some("r1".to_string()));
+        let reply = msg.reply(Ok(json!({"mute": true}))).unwrap();
+        assert_eq!(reply["commandReply"]["requestId"], "r1");
+    }
+    #[test]
+    fn legacy_with_request_id() {
+        let raw = json!({"param": 14, "fValue": -3.0, "requestId": "r2"});
+        let msg = ParamMessage::from_json(&raw).unwrap();
+        assert!(matches!(msg.param, JamParam::MasterVol));
+        assert_eq!(msg.as_json()["requestId"], "r2");
+        // no id means no reply
+        let msg = ParamMessage::new(JamParam::MasterVol, 0, 0, 0.0, "");
+        assert!(msg.reply(Ok(json!({}))).is_none());
+    }
+    #[test]
+    fn replies_to_local_client() {
+        let raw = json!({"cmd": "setMasterVolume", "requestId": "r3", "gain": -3.0, "wsClient": 2});
+        let msg = ParamMessage::from_json(&raw).unwrap();
+        assert_eq!(msg.client, 2);
+        assert_eq!(msg.reply(Ok(json!({}))).unwrap()["wsClient"], 2);
+        // the room (and OSC) are client 0 and get untagged replies
+        let msg = ParamMessage::from_json(&json!({"param": 14, "requestId": "r4"})).unwrap();
+        assert!(msg.reply(Ok(json!({}))).unwrap()["wsClient"].is_null());
+    }
+    #[test]
+    fn refuses_internal_params() {
+        for param in [JamParam::ClientClosed, JamParam::SetStatusInterval, JamParam::SetIdleRefresh] {
+            let raw = json!({"param": param.to_i64(), "iValue1": 0});
+            assert!(ParamMessage::from_json(&raw).is_err());
+        }
     }
 }
 
@@ -0,0 +1,455 @@
+//! Hierarchical registry of the engine parameters a u/x can control
+//!
+//! Every control the [`JamEngine`](crate::sound::jam_engine::JamEngine) exposes is described by a
+//! [`ParamInfo`] with a dotted path, type, range, unit, default and current value.  A front end can
+//! ask for the tree and build its controls from it instead of hard coding [`JamParam`] indexes.
+//!
+//! The paths are:
+//! - `master.volume`
+//! - `mixer.strip.N.gain`, `mixer.strip.N.mute`, `mixer.strip.N.fade` for each mixer channel
+//! - `metronome.gain`, `metronome.mute`
+//! - `room.mute.N` for the two inputs
+//! - `tuner.N` for the two inputs
+//! - `pedal.B.P.setting` for each setting on each pedal (B is the board, P the pedal index)
+//!
+//! Setting a value by path is translated into the legacy [`ParamMessage`] so there is still only
+//! one place commands are carried out.
+//!
+//! This all runs on the audio thread, so only the part of the tree asked about is built.  After a
+//! command [`touched_by`] says which part it could have changed and only that part is compared
+//! against the subscriptions.  The pedal json is only looked at when a pedal changed.
+use std::collections::HashMap;
+
+use pedal_board::{pedals::pedal_board::PedalBoard, utils::to_db};
+use serde::Serialize;
+use serde_json::{json, Value};
+use simple_error::bail;
+
+use crate::{
+    common::box_error::BoxError,
+    sound::{
+        mixer::{Mixer, MIXER_CHANNELS},
+        param_message::{JamParam, ParamMessage},
+    },
+};
+
+/// gain range (dB) for all the gain controls
+const MIN_GAIN: f64 = -60.0;
+const MAX_GAIN: f64 = 18.0;
+
+#[derive(Debug, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    Float,
+    Bool,
+}
+
+/// Description and current value of a single parameter
+#[derive(Debug, Serialize, Clone)]
+pub struct ParamInfo {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: ParamType,
+    pub min: f64,
+    pub max: f64,
+    pub unit: String,
+    pub default: Value,
+    pub value: Value,
+}
+
+impl ParamInfo {
+    pub fn float(path: &str, min: f64, max: f64, unit: &str, default: f64, value: f64) -> ParamInfo {
+        ParamInfo {
+            path: String::from(path),
+            kind: ParamType::Float,
+            min,
+            max,
+            unit: String::from(unit),
+            default: json!(default),
+            value: json!(value),
+        }
+    }
+    pub fn bool(path: &str, default: bool, value: bool) -> ParamInfo {
+        ParamInfo {
+            path: String::from(path),
+            kind: ParamType::Bool,
+            min: 0.0,
+            max: 1.0,
+            unit: String::from(""),
+            default: json!(default),
+            value: json!(value),
+        }
+    }
+}
+
+/// Build the list of parameters under prefix ("" for all of them) from the engine components
+pub fn build_params(
+    prefix: &str,
+    mixer: &Mixer,
+    room_mutes: &[bool; 2],
+    tuners_on: &[bool; 2],
+    boards: &[PedalBoard],
+) -> Vec<ParamInfo> {
+    let wanted = |section: &str| path_matches(prefix, section) || path_matches(section, prefix);
+    let mut params = vec![];
+    if wanted("master.volume") {
+        params.push(ParamInfo::float(
+            "master.volume",
+            MIN_GAIN,
+            MAX_GAIN,
+            "dB",
+            0.0,
+            mixer.get_master(),
+        ));
+    }
+    for i in 0..MIXER_CHANNELS {
+        if !wanted(&format!("mixer.strip.{}", i)) {
+            continue;
+        }
+        params.push(ParamInfo::float(
+            &format!("mixer.strip.{}.gain", i),
+            MIN_GAIN,
+            MAX_GAIN,
+            "dB",
+            0.0,
+            // the strip keeps gain linear
+            to_db(mixer.get_channel_gain(i)),
+        ));
+        params.push(ParamInfo::bool(
+            &format!("mixer.strip.{}.mute", i),
+            false,
+            mixer.get_channel_mute(i),
+        ));
+        params.push(ParamInfo::float(
+            &format!("mixer.strip.{}.fade", i),
+            -1.0,
+            1.0,
+            "pan",
+            0.0,
+            mixer.get_channel_fade(i) as f64,
+        ));
+    }
+    if wanted("metronome") {
+        params.push(ParamInfo::float(
+            "metronome.gain",
+            MIN_GAIN,
+            MAX_GAIN,
+            "dB",
+            0.0,
+            mixer.get_metronome_gain(),
+        ));
+        params.push(ParamInfo::bool("metronome.mute", true, mixer.get_metronome_mute()));
+    }
+    for (i, mute) in room_mutes.iter().enumerate() {
+        let path = format!("room.mute.{}", i);
+        if wanted(&path) {
+            params.push(ParamInfo::bool(&path, false, *mute));
+        }
+    }
+    for (i, on) in tuners_on.iter().enumerate() {
+        let path = format!("tuner.{}", i);
+        if wanted(&path) {
+            params.push(ParamInfo::bool(&path, false, *on));
+        }
+    }
+    for (b, board) in boards.iter().enumerate() {
+        if wanted(&format!("pedal.{}", b)) {
+            params.append(&mut pedal_params(b, &board.as_json(b)));
+        }
+    }
+    params.retain(|p| path_matches(prefix, &p.path));
+    params
+}
+
+/// The part of the tree (a path prefix) a command could change.  None if it doesn't change any
+/// parameters.  "" means it could have changed anything
+pub fn touched_by(msg: &ParamMessage) -> Option<String> {
+    let prefix = match msg.param {
+        JamParam::MasterVol => String::from("master"),
+        JamParam::ChannelGain | JamParam::ChannelMute | JamParam::SetFader => {
+            format!("mixer.strip.{}", msg.ivalue_1)
+        }
+        // peers are found by client id so it could be any strip
+        JamParam::PeerGain | JamParam::PeerMute | JamParam::PeerFade => String::from("mixer"),
+        JamParam::MetronomeGain | JamParam::MetronomeMute => String::from("metronome"),
+        JamParam::MuteToRoom => format!("room.mute.{}", msg.ivalue_1),
+        JamParam::TuneChannel => format!("tuner.{}", msg.ivalue_1),
+        JamParam::SetEffectConfig | JamParam::InsertPedal | JamParam::DeletePedal | JamParam::MovePedal => {
+            format!("pedal.{}", msg.ivalue_1)
+        }
+        JamParam::SetParam => msg.svalue.clone(),
+        JamParam::RecallScene => String::new(),
+        _ => return None,
+    };
+    Some(prefix)
+}
+
+/// Pull the settings out of the pedal board json
+fn pedal_params(board: usize, board_json: &Value) -> Vec<ParamInfo> {
+    let mut params = vec![];
+    if let Some(effects) = board_json["effects"].as_array() {
+        for (p, effect) in effects.iter().enumerate() {
+            if let Some(settings) = effect["settings"].as_array() {
+                for setting in settings {
+                    if let Some(name) = setting["name"].as_str() {
+                        let path = format!("pedal.{}.{}.{}", board, p, name);
+                        // setting types and units are the codes used by the pedal board
+                        let mut info = if setting["type"] == 2 {
+                            ParamInfo::bool(&path, false, setting["value"].as_bool().unwrap_or(false))
+                        } else {
+                            ParamInfo::float(
+                                &path,
+                                setting["min"].as_f64().unwrap_or(0.0),
+                                setting["max"].as_f64().unwrap_or(1.0),
+                                "",
+                                0.0,
+                                setting["value"].as_f64().unwrap_or(0.0),
+                            )
+                        };
+                        info.unit = String::from(match setting["units"].as_u64() {
+                            Some(1) => "dB",
+                            Some(2) => "ms",
+                            _ => "",
+                        });
+                        // pedals don't tell us their defaults
+                        info.default = Value::Null;
+                        params.push(info);
+                    }
+                }
+            }
+        }
+    }
+    params
+}
+
+/// turn the flat list into nested json keyed by the path parts
+pub fn as_tree(params: &[ParamInfo]) -> Value {
+    let mut tree = json!({});
+    for param in params {
+        let mut node = &mut tree;
+        for part in param.path.split('.') {
+            node = node
+                .as_object_mut()
+                .unwrap()
+                .entry(part)
+                .or_insert_with(|| json!({}));
+        }
+        *node = json!(param);
+    }
+    tree
+}
+
+/// check if a path is under a prefix.  Matches whole parts so "mixer.strip.2" is not "mixer.strip.20"
+pub fn path_matches(prefix: &str, path: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with('.'))
+}
+
+/// Make the ParamMessage that sets a parameter to value.
+pub fn param_to_message(info: &ParamInfo, value: f64) -> Result<ParamMessage, BoxError> {
+    if !(info.min..=info.max).contains(&value) {
+        bail!("{} out of range for {}", value, info.path);
+    }
+    let on = value >= 0.5;
+    let parts: Vec<&str> = info.path.split('.').collect();
+    let index = |s: &str| -> Result<i64, BoxError> { Ok(s.parse::<i64>()?) };
+    let msg = match parts[..] {
+        ["master", "volume"] => ParamMessage::new(JamParam::MasterVol, 0, 0, value, ""),
+        ["mixer", "strip", n, "gain"] => ParamMessage::new(JamParam::ChannelGain, index(n)?, 0, value, ""),
+        ["mixer", "strip", n, "mute"] => ParamMessage::new(JamParam::ChannelMute, index(n)?, on as i64, 0.0, ""),
+        ["mixer", "strip", n, "fade"] => ParamMessage::new(JamParam::SetFader, index(n)?, 0, value, ""),
+        ["metronome", "gain"] => ParamMessage::new(JamParam::MetronomeGain, 0, 0, value, ""),
+        ["metronome", "mute"] => ParamMessage::new(JamParam::MetronomeMute, on as i64, 0, 0.0, ""),
+        ["room", "mute", n] => ParamMessage::new(JamParam::MuteToRoom, index(n)?, on as i64, 0.0, ""),
+        ["tuner", n] => ParamMessage::new(JamParam::TuneChannel, index(n)?, on as i64, 0.0, ""),
+        ["pedal", b, p, name] => {
+            let setting = match info.kind {
+                ParamType::Bool => json!({ "name": name, "value": on }),
+                ParamType::Float => json!({ "name": name, "value": value }),
+            };
+            ParamMessage::new(JamParam::SetEffectConfig, index(b)?, index(p)?, 0.0, &setting.to_string())
+        }
+        _ => bail!("no such parameter {}", info.path),
+    };
+    Ok(msg)
+}
+
+/// Tracks the parameters front ends have subscribed to so changes can be pushed to them.
+///
+/// Subscriptions are by path prefix ("mixer.strip.4" gets gain, mute and fade for channel 4) and
+/// are kept for each client (see [`ParamMessage::client`]) so one browser's subscriptions don't
+/// change what another one gets.
+#[derive(Default)]
+pub struct ParamWatcher {
+    watches: HashMap<u32, Watch>,
+}
+
+#[derive(Default)]
+struct Watch {
+    prefixes: Vec<String>,
+    last: HashMap<String, Value>,
+}
+
+impl Watch {
+    fn wants(&self, path: &str) -> bool {
+        self.prefixes.iter().any(|p| path_matches(p, path))
+    }
+}
+
+impl ParamWatcher {
+    pub fn new() -> ParamWatcher {
+        ParamWatcher {
+            watches: HashMap::new(),
+        }
+    }
+    pub fn has_subscriptions(&self) -> bool {
+        !self.watches.is_empty()
+    }
+    /// true if anybody is subscribed to something under (or over) prefix
+    pub fn is_watching(&self, prefix: &str) -> bool {
+        self.watches.values().any(|w| {
+            w.prefixes.iter().any(|p| path_matches(p, prefix) || path_matches(prefix, p))
+        })
+    }
+    /// add or remove a subscription for a client.  An empty prefix is everything.  params are the
+    /// current values under prefix
+    pub fn subscribe(&mut self, client: u32, prefix: &str, on: bool, params: &[ParamInfo]) {
+        let watch = self.watches.entry(client).or_default();
+        watch.prefixes.retain(|p| p != prefix);
+        if on {
+            watch.prefixes.push(String::from(prefix));
+            // take a snapshot so only real changes get reported
+            for param in params {
+                watch.last.insert(param.path.clone(), param.value.clone());
+            }
+        } else {
+            let prefixes = &watch.prefixes;
+            watch.last.retain(|path, _| prefixes.iter().any(|p| path_matches(p, path)));
+        }
+        if watch.prefixes.is_empty() {
+            self.watches.remove(&client);
+        }
+    }
+    /// forget everything a client subscribed to (it went away)
+    pub fn drop_client(&mut self, client: u32) {
+        self.watches.remove(&client);
+    }
+    /// get the subscribed parameters in params that changed since the last call, by client
+    pub fn changes(&mut self, params: &[ParamInfo]) -> Vec<(u32, Vec<Value>)> {
+        let mut rval = vec![];
+        for (client, watch) in self.watches.iter_mut() {
+            let mut changes = vec![];
+            for param in params {
+                if !watch.wants(&param.path) {
+                    continue;
+                }
+                if watch.last.get(&param.path) != Some(&param.value) {
+                    if watch.last.contains_key(&param.path) {
+                        changes.push(json!({ "path": param.path, "value": param.value }));
+                    }
+                    watch.last.insert(param.path.clone(), param.value.clone());
+                }
+            }
+            if !changes.is_empty() {
+                rval.push((*client, changes));
+            }
+        }
+        rval
+    }
+}
+
+#[cfg(test)]
+mod test_param_tree {
+    use super::*;
+
+    fn params() -> Vec<ParamInfo> {
+        build_params("", &Mixer::new(), &[false, true], &[false, false], &[])
+    }
+
+    #[test]
+    fn builds_paths() {
+        let params = params();
+        // master + 3 per strip + metronome 2 + room 2 + tuner 2
+        assert_eq!(params.len(), 1 + MIXER_CHANNELS * 3 + 6);
+        let mute = params.iter().find(|p| p.path == "room.mute.1").unwrap();
+        assert_eq!(mute.kind, ParamType::Bool);
+        assert_eq!(mute.value, json!(true));
+        let gain = params.iter().find(|p| p.path == "mixer.strip.4.gain").unwrap();
+        assert_eq!(gain.unit, "dB");
+        assert_eq!(gain.value, json!(0.0));
+        // just the part asked for
+        let strip = build_params("mixer.strip.4", &Mixer::new(), &[false, true], &[false, false], &[]);
+        assert_eq!(strip.len(), 3);
+        assert!(build_params("mixer.strip.4.mute", &Mixer::new(), &[false, true], &[false, false], &[]).len() == 1);
+        let msg = ParamMessage::new(JamParam::ChannelMute, 4, 1, 0.0, "");
+        assert_eq!(touched_by(&msg).unwrap(), "mixer.strip.4");
+        assert!(touched_by(&ParamMessage::new(JamParam::SetUpdateInterval, 100, 0, 0.0, "")).is_none());
+    }
+
+    #[test]
+    fn nests_tree() {
+        let tree = as_tree(&params());
+        assert_eq!(tree["mixer"]["strip"]["4"]["fade"]["path"], "mixer.strip.4.fade");
+        assert_eq!(tree["master"]["volume"]["type"], "float");
+    }
+
+    #[test]
+    fn reads_pedal_settings() {
+        let board = json!({"boardId": 0, "effects": [
+            {"index": 0, "name": "Overdrive", "settings": [
+                {"name": "drive", "type": 0, "min": 0.0, "max": 10.0, "units": 0, "value": 4.5},
+                {"name": "bypass", "type": 2, "min": 0, "max": 1, "units": 4, "value": false},
+            ]},
+        ]});
+        let params = pedal_params(0, &board);
+        assert_eq!(params[0].path, "pedal.0.0.drive");
+        assert_eq!(params[0].max, 10.0);
+        assert_eq!(params[1].kind, ParamType::Bool);
+        let msg = param_to_message(&params[1], 1.0).unwrap();
+        assert!(matches!(msg.param, JamParam::SetEffectConfig));
+        assert_eq!(msg.svalue, r#"{"name":"bypass","value":true}"#);
+    }
+
+    #[test]
+    fn sets_by_path() {
+        let params = params();
+        let gain = params.iter().find(|p| p.path == "mixer.strip.4.gain").unwrap();
+        let msg = param_to_message(gain, -6.0).unwrap();
+        assert!(matches!(msg.param, JamParam::ChannelGain));
+        assert_eq!(msg.ivalue_1, 4);
+        assert!(param_to_message(gain, 100.0).is_err());
+    }
+
+    #[test]
+    fn matches_whole_parts() {
+        assert!(path_matches("", "master.volume"));
+        assert!(path_matches("mixer.strip.2", "mixer.strip.2.gain"));
+        assert!(!path_matches("mixer.strip.2", "mixer.strip.20.gain"));
+        assert!(path_matches("tuner.0", "tuner.0"));
+    }
+
+    #[test]
+    fn watches_changes() {
+        let mut mixer = Mixer::new();
+        let mut watcher = ParamWatcher::new();
+        let build = |m: &Mixer, prefix: &str| build_params(prefix, m, &[false, false], &[false, false], &[]);
+        watcher.subscribe(0, "mixer.strip.2", true, &build(&mixer, "mixer.strip.2"));
+        watcher.subscribe(3, "metronome", true, &build(&mixer, "metronome"));
+        assert!(watcher.changes(&build(&mixer, "")).is_empty());
+        assert!(watcher.is_watching("mixer"));
+        assert!(!watcher.is_watching("tuner.0"));
+        mixer.set_channel_mute(2, true);
+        mixer.set_channel_mute(20, true);
+        let click_mute = !mixer.get_metronome_mute();
+        mixer.set_metronome_mute(click_mute);
+        // each client only hears about what it subscribed to
+        let mut changes = watcher.changes(&build(&mixer, ""));
+        changes.sort_by_key(|(client, _)| *client);
+        assert_eq!(changes[0], (0, vec![json!({"path": "mixer.strip.2.mute", "value": true})]));
+        assert_eq!(changes[1], (3, vec![json!({"path": "metronome.mute", "value": click_mute})]));
+        watcher.subscribe(0, "mixer.strip.2", false, &[]);
+        watcher.drop_client(3);
+        assert!(!watcher.has_subscriptions());
+    }
+}
@@ -0,0 +1,138 @@
+//! Mixer settings that follow a peer from slot to slot
+//!
+//! The [`ChannelMap`](super::channel_map::ChannelMap) puts a peer on whatever slot is free when
+//! they show up, so somebody whose wifi hiccups can come back on a different pair of strips.  The
+//! engine keeps a [`PeerPreset`] for the client ids the user has mixed (the last
+//! [`MAX_PEER_PRESETS`] of them) and puts it on whichever strips that id lands on.  Somebody we
+//! have never mixed gets unity gain, unmuted, and a pan from [`PeerPresets::default_for`] that
+//! spreads newcomers across the stereo field.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+
+use super::channel_map::NUM_PLAYERS_IN_ROOM;
+use crate::common::box_error::BoxError;
+
+/// most client ids a preset is kept for.  The one used longest ago makes room for a new one
+pub const MAX_PEER_PRESETS: usize = 64;
+
+/// gain, mute and pan for both of a peer's channels
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerPreset {
+    /// dB
+    pub gain: [f64; 2],
+    pub mute: [bool; 2],
+    /// pan -1.0 to 1.0
+    pub fade: [f32; 2],
+}
+
+/// Presets by client id
+#[derive(Debug, Default)]
+pub struct PeerPresets {
+    // with the use count when each was last used
+    presets: BTreeMap<u32, (PeerPreset, u64)>,
+    uses: u64,
+    pan_spread: f32,
+}
+
+impl PeerPresets {
+    pub fn new() -> PeerPresets {
+        Self::default()
+    }
+    /// how far (0.0 to 1.0) newcomers get panned away from center.  0.0 leaves them all in the middle
+    pub fn set_pan_spread(&mut self, spread: f64) -> Result<(), BoxError> {
+        if !(0.0..=1.0).contains(&spread) {
+            bail!("pan spread {} should be between 0.0 and 1.0", spread);
+        }
+        self.pan_spread = spread as f32;
+        Ok(())
+    }
+    pub fn get_pan_spread(&self) -> f32 {
+        self.pan_spread
+    }
+    /// what somebody we have never mixed gets on a slot.  Slots alternate left and right, moving
+    /// further out as the room fills
+    pub fn default_for(&self, slot: usize) -> PeerPreset {
+        let step = self.pan_spread / NUM_PLAYERS_IN_ROOM.div_ceil(2) as f32;
+        let side = if slot.is_multiple_of(2) { -1.0 } else { 1.0 };
+        let fade = side * step * (slot / 2 + 1) as f32;
+        PeerPreset { fade: [fade, fade], ..Default::default() }
+    }
+    /// the preset for a client id landing on a slot
+    pub fn preset_for(&mut self, id: u32, slot: usize) -> PeerPreset {
+        self.uses += 1;
+        match self.presets.get_mut(&id) {
+            Some((preset, used)) => {
+                *used = self.uses;
+                preset.clone()
+            }
+            None => self.default_for(slot),
+        }
+    }
+    pub fn get(&self, id: u32) -> Option<&PeerPreset> {
+        self.presets.get(&id).map(|(preset, _)| preset)
+    }
+    /// keep the preset for an id.  When there are too many the one used longest ago is dropped
+    pub fn remember(&mut self, id: u32, preset: PeerPreset) {
+        if self.presets.len() >= MAX_PEER_PRESETS && !self.presets.contains_key(&id) {
+            let oldest = self.presets.iter().min_by_key(|(_, (_, used))| *used).map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.presets.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        self.presets.insert(id, (preset, self.uses));
+    }
+    /// drop the preset for an id.  false if there wasn't one
+    pub fn forget(&mut self, id: u32) -> bool {
+        self.presets.remove(&id).is_some()
+    }
+    pub fn to_map(&self) -> BTreeMap<u32, PeerPreset> {
+        self.presets.iter().map(|(id, (preset, _))| (*id, preset.clone())).collect()
+    }
+    /// replace all the presets (when the unit state is restored)
+    pub fn load(&mut self, presets: &BTreeMap<u32, PeerPreset>) {
+        self.presets.clear();
+        for (id, preset) in presets {
+            self.remember(*id, preset.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_peer_presets {
+    use super::*;
+
+    #[test]
+    fn spreads_newcomers() {
+        let mut presets = PeerPresets::new();
+        // no spread keeps everybody centered
+        assert_eq!(presets.preset_for(1234, 3), PeerPreset::default());
+        presets.set_pan_spread(0.6).unwrap();
+        assert!(presets.set_pan_spread(1.5).is_err());
+        let first = presets.default_for(0);
+        let second = presets.default_for(1);
+        assert!(first.fade[0] < 0.0 && second.fade[0] > 0.0);
+        assert_eq!(first.fade[0], -second.fade[0]);
+        // the last slot is as far out as it goes
+        let last = presets.default_for(NUM_PLAYERS_IN_ROOM - 1);
+        assert!((last.fade[0].abs() - 0.6).abs() < 0.001);
+        // somebody we mixed gets their preset wherever they land
+        let mixed = PeerPreset { gain: [-6.0, 0.0], mute: [false, true], fade: [0.5, 0.5] };
+        presets.remember(1234, mixed.clone());
+        assert_eq!(presets.preset_for(1234, 7), mixed);
+        assert!(presets.forget(1234));
+        assert!(!presets.forget(1234));
+        assert_eq!(presets.preset_for(1234, 0), first);
+        // only so many are kept, the one not seen for longest goes
+        for id in 0..MAX_PEER_PRESETS as u32 {
+            presets.remember(id, mixed.clone());
+        }
+        presets.preset_for(0, 0);
+        presets.remember(9999, mixed.clone());
+        assert_eq!(presets.to_map().len(), MAX_PEER_PRESETS);
+        assert!(presets.get(0).is_some() && presets.get(1).is_none());
+    }
+}
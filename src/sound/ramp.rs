@@ -0,0 +1,117 @@
+//! Linear ramp used to move a gain to a new setting without a click
+//!
+//! Changing a gain between two samples makes a step in the waveform, which is heard as zipper
+//! noise when a fader is dragged and a click on a mute.  A [`Ramp`] moves to its new target a
+//! little every sample over the ramp time instead.
+//!
+//! # Example
+//! ```
+//! use rtjam_rust::sound::ramp::Ramp;
+//!
+//! let mut gain = Ramp::new(1.0, 4);
+//! gain.set(0.0);
+//! let steps: Vec<f32> = (0..5).map(|_| gain.tick()).collect();
+//! assert_eq!(steps, vec![0.75, 0.5, 0.25, 0.0, 0.0]);
+//! ```
+use super::SAMPLE_RATE;
+
+/// how long (msec) settings take to move unless told otherwise
+pub const DEFAULT_RAMP_MSEC: f64 = 10.0;
+/// longest ramp (msec) allowed
+pub const MAX_RAMP_MSEC: f64 = 500.0;
+
+/// number of samples (at [`SAMPLE_RATE`]) in a ramp of msec
+pub fn ramp_samples(msec: f64) -> usize {
+    (msec.clamp(0.0, MAX_RAMP_MSEC) * SAMPLE_RATE as f64 / 1000.0).round() as usize
+}
+
+pub struct Ramp {
+    current: f32,
+    target: f32,
+    step: f32,
+    // samples to go
+    remaining: usize,
+    samples: usize,
+}
+
+impl Ramp {
+    /// a ramp sitting at value that takes samples to move
+    pub fn new(value: f32, samples: usize) -> Ramp {
+        Ramp {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+            samples,
+        }
+    }
+    /// start moving to target
+    pub fn set(&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if self.samples == 0 {
+            self.current = target;
+            self.remaining = 0;
+        } else {
+            self.step = (target - self.current) / self.samples as f32;
+            self.remaining = self.samples;
+        }
+    }
+    /// how many samples later ramps take.  A ramp already moving finishes at its old rate
+    pub fn set_samples(&mut self, samples: usize) {
+        self.samples = samples;
+    }
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+    /// true when it has reached the target
+    pub fn is_settled(&self) -> bool {
+        self.remaining == 0
+    }
+    /// the value for the next sample
+    pub fn tick(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod test_ramp {
+    use super::*;
+
+    #[test]
+    fn ramps_to_target() {
+        let mut ramp = Ramp::new(0.0, ramp_samples(1.0));
+        assert_eq!(ramp_samples(1.0), 48);
+        ramp.set(1.0);
+        assert!(!ramp.is_settled());
+        let first = ramp.tick();
+        assert!(first > 0.0 && first < 0.05);
+        for _ in 0..47 {
+            ramp.tick();
+        }
+        assert!(ramp.is_settled());
+        assert_eq!(ramp.tick(), 1.0);
+        // changing direction part way starts from where it is
+        ramp.set(0.0);
+        for _ in 0..24 {
+            ramp.tick();
+        }
+        ramp.set(1.0);
+        assert!((ramp.tick() - 0.5).abs() < 0.05);
+        // no ramp time jumps
+        ramp.set_samples(0);
+        ramp.set(0.25);
+        assert!(ramp.is_settled());
+        assert_eq!(ramp.tick(), 0.25);
+    }
+}
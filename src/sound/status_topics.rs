@@ -0,0 +1,534 @@
+//! Subscribable status topics for the u/x
+//!
+//! The [`JamEngine`](crate::sound::jam_engine::JamEngine) sends one big `levelEvent` on a single
+//! timer shared by every listener.  A front end that only shows a tuner still gets all the meters,
+//! and a meter bridge can't go faster than 150 msec.  Instead a front end can subscribe to the
+//! topics it shows, each with its own interval:
+//!
+//! ```json
+//! { "cmd": "subscribeStatus", "topic": "levels", "interval": 50, "binary": true }
+//! { "cmd": "subscribeStatus", "topic": "network", "interval": 1000, "delta": true }
+//! { "cmd": "unsubscribeStatus", "topic": "levels" }
+//! ```
+//!
+//! Each update is sent as
+//!
+//! ```json
+//! { "speaker": "UnitChatRobot", "statusEvent": { "topic": "network", "full": false, "data": {...} } }
+//! ```
+//!
+//! With `delta` set only the top level fields that changed since the last update are sent and
+//! nothing is sent if nothing changed.  The first update after subscribing is always full.
+//!
+//! With `binary` set (levels only) the update is packed by [`encode_levels`] and delivered as a
+//! binary frame on the local websocket.  The nation chat room only carries text, so binary levels are local only.
+//!
+//! Subscriptions are kept for each client (the room, or a local websocket connection, see
+//! [`ParamMessage::client`](crate::sound::param_message::ParamMessage::client)) and updates only go
+//! to the client that asked for them.  They live in the main thread, not the engine: the engine just
+//! fills in a [`StatusSnapshot`] (plain numbers) as often as the fastest subscription needs and the
+//! main thread builds the json.
+//!
+//! The legacy `levelEvent` is still sent.  A client's subscriptions are dropped if it stops sending
+//! keepalives so a closed browser doesn't leave the engine sending topics to no one.
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use simple_error::bail;
+
+use crate::{
+    common::{box_error::BoxError, seq_tracker::SeqStats, stream_time_stat::MicroTimer},
+    sound::{channel_map::NUM_PLAYERS_IN_ROOM, mixer::MIXER_CHANNELS},
+};
+
+/// fastest and slowest rates (usec) a topic can be sent at
+pub const MIN_TOPIC_INTERVAL: u128 = 20 * 1000;
+pub const MAX_TOPIC_INTERVAL: u128 = 10 * 1000 * 1000;
+/// drop a client's subscriptions if we don't hear a keepalive from it for this long (usec)
+pub const TOPIC_IDLE: u128 = 60 * 1000 * 1000;
+
+/// first bytes of a binary levels frame
+const LEVELS_MAGIC: u8 = b'L';
+const LEVELS_VERSION: u8 = 1;
+/// fields packed after the header, in order
+const LEVEL_FIELDS: [&str; 10] = [
+    "masterLevel",
+    "peakMaster",
+    "inputLeft",
+    "inputRight",
+    "peakLeft",
+    "peakRight",
+    "roomInputLeft",
+    "roomInputRight",
+    "roomPeakLeft",
+    "roomPeakRight",
+];
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StatusTopic {
+    Levels,
+    Tuner,
+    Network,
+    Pedals,
+    Beat,
+    Health,
+}
+
+impl StatusTopic {
+    pub const ALL: [StatusTopic; 6] = [
+        StatusTopic::Levels,
+        StatusTopic::Tuner,
+        StatusTopic::Network,
+        StatusTopic::Pedals,
+        StatusTopic::Beat,
+        StatusTopic::Health,
+    ];
+    pub fn from_name(name: &str) -> Result<StatusTopic, BoxError> {
+        match serde_json::from_value(json!(name)) {
+            Ok(topic) => Ok(topic),
+            Err(_) => bail!("unknown status topic {}", name),
+        }
+    }
+    pub fn name(&self) -> String {
+        json!(self).as_str().unwrap_or_default().to_string()
+    }
+}
+
+/// Meter for one mixer channel
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChannelLevel {
+    /// the slot is in use (local channels always are)
+    pub active: bool,
+    pub level: f64,
+    pub peak: f64,
+}
+
+/// How the stream from one peer is doing
+#[derive(Debug, Default, Clone)]
+pub struct PeerStatus {
+    pub client_id: u32,
+    pub chan_idx: usize,
+    /// jitter buffer depth (msec) for both channels
+    pub depth: [f64; 2],
+    pub drops: usize,
+    pub seq: SeqStats,
+    pub loop_time: f64,
+}
+
+/// Everything the topics are built from.
+///
+/// The engine fills this in on the audio thread.  It is all numbers (the pedal json is only rebuilt
+/// when a pedal changes) so nothing gets serialized there.
+#[derive(Debug, Default, Clone)]
+pub struct StatusSnapshot {
+    /// level and peak (the same for the pairs below)
+    pub master: [f64; 2],
+    pub limiter_reduction: f64,
+    pub soft_clips: u64,
+    pub safety_reduction: f64,
+    pub inputs: [[f64; 2]; 2],
+    pub room_inputs: [[f64; 2]; 2],
+    pub channels: [ChannelLevel; MIXER_CHANNELS],
+    pub tuner_freqs: [f64; 2],
+    pub tuners_on: [bool; 2],
+    pub connected: bool,
+    pub client_id: u32,
+    pub peers: [Option<PeerStatus>; NUM_PLAYERS_IN_ROOM],
+    pub pedals: Arc<Value>,
+    pub beat: u8,
+    pub admitted: bool,
+    pub jack_jitter_mean: f64,
+    pub jack_jitter_sigma: f64,
+    /// msec
+    pub update_interval: u128,
+}
+
+impl StatusSnapshot {
+    /// the data for a topic
+    pub fn topic_data(&self, topic: StatusTopic, git_hash: &str) -> Value {
+        match topic {
+            StatusTopic::Levels => {
+                let channels: Vec<Value> = self
+                    .channels
+                    .iter()
+                    .enumerate()
+                    .filter(|(_idx, c)| c.active)
+                    .map(|(idx, c)| json!({ "chanIdx": idx, "level": c.level, "peak": c.peak }))
+                    .collect();
+                json!({
+                    "masterLevel": self.master[0],
+                    "peakMaster": self.master[1],
+                    "limiterReduction": self.limiter_reduction,
+                    "softClips": self.soft_clips,
+                    "safetyReduction": self.safety_reduction,
+                    "inputLeft": self.inputs[0][0],
+                    "inputRight": self.inputs[1][0],
+                    "peakLeft": self.inputs[0][1],
+                    "peakRight": self.inputs[1][1],
+                    "roomInputLeft": self.room_inputs[0][0],
+                    "roomInputRight": self.room_inputs[1][0],
+                    "roomPeakLeft": self.room_inputs[0][1],
+                    "roomPeakRight": self.room_inputs[1][1],
+                    "channels": channels,
+                })
+            }
+            StatusTopic::Tuner => json!({
+                "inputLeftFreq": self.tuner_freqs[0],
+                "inputRightFreq": self.tuner_freqs[1],
+                "leftTunerOn": self.tuners_on[0],
+                "rightTunerOn": self.tuners_on[1],
+            }),
+            StatusTopic::Network => {
+                let peers: Vec<Value> = self
+                    .peers
+                    .iter()
+                    .flatten()
+                    .map(|p| {
+                        json!({
+                            "clientId": p.client_id,
+                            "chanIdx": p.chan_idx,
+                            "depth0": p.depth[0],
+                            "depth1": p.depth[1],
+                            "drops": p.drops,
+                            "seq": p.seq,
+                            "loopTime": p.loop_time,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "connected": self.connected,
+                    "clientId": self.client_id,
+                    "peers": peers,
+                })
+            }
+            StatusTopic::Pedals => json!({ "pedalInfo": *self.pedals }),
+            StatusTopic::Beat => json!({ "beat": self.beat }),
+            StatusTopic::Health => json!({
+                "connected": self.connected,
+                "admitted": self.admitted,
+                "git_hash": git_hash,
+                "jackJitterMean": self.jack_jitter_mean,
+                "jackJitterSigma": self.jack_jitter_sigma,
+                "updateInterval": self.update_interval,
+            }),
+        }
+    }
+}
+
+struct Subscription {
+    timer: MicroTimer,
+    fresh: bool,
+    delta: bool,
+    binary: bool,
+    last: Value,
+}
+
+// what one client subscribed to
+struct ClientTopics {
+    subs: [Option<Subscription>; 6],
+    idle_timer: MicroTimer,
+}
+
+/// The topic subscriptions, by client
+pub struct StatusTopics {
+    clients: HashMap<u32, ClientTopics>,
+    git_hash: String,
+}
+
+impl StatusTopics {
+    pub fn new(git_hash: &str) -> StatusTopics {
+        StatusTopics {
+            clients: HashMap::new(),
+            git_hash: String::from(git_hash),
+        }
+    }
+    /// subscribe a client to a topic (or change the subscription).  interval is in msec
+    pub fn subscribe(
+        &mut self,
+        client: u32,
+        topic: StatusTopic,
+        interval: i64,
+        delta: bool,
+        binary: bool,
+        now: u128,
+    ) {
+        let interval = ((interval.max(0) as u128) * 1000).clamp(MIN_TOPIC_INTERVAL, MAX_TOPIC_INTERVAL);
+        // a binary frame is always the full set of meters
+        let binary = binary && topic == StatusTopic::Levels;
+        let topics = self.clients.entry(client).or_insert_with(|| ClientTopics {
+            subs: Default::default(),
+            idle_timer: MicroTimer::new(now, TOPIC_IDLE),
+        });
+        topics.subs[topic as usize] = Some(Subscription {
+            timer: MicroTimer::new(now, interval),
+            // send the first update right away
+            fresh: true,
+            delta: delta && !binary,
+            binary,
+            last: Value::Null,
+        });
+        topics.idle_timer.reset(now);
+    }
+    pub fn unsubscribe(&mut self, client: u32, topic: StatusTopic) {
+        if let Some(topics) = self.clients.get_mut(&client) {
+            topics.subs[topic as usize] = None;
+            if topics.subs.iter().all(|s| s.is_none()) {
+                self.clients.remove(&client);
+            }
+        }
+    }
+    pub fn keep_alive(&mut self, client: u32, now: u128) {
+        if let Some(topics) = self.clients.get_mut(&client) {
+            topics.idle_timer.reset(now);
+        }
+    }
+    /// forget everything a client subscribed to (it went away)
+    pub fn drop_client(&mut self, client: u32) {
+        self.clients.remove(&client);
+    }
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+    /// how often (usec) the engine needs to send a [`StatusSnapshot`].  None when nobody is subscribed
+    pub fn fastest(&self) -> Option<u128> {
+        self.clients
+            .values()
+            .flat_map(|t| t.subs.iter().flatten())
+            .map(|s| s.timer.get_interval())
+            .min()
+    }
+    /// get the topics that need to be sent now, by client
+    pub fn due(&mut self, now: u128) -> Vec<(u32, StatusTopic)> {
+        self.clients.retain(|_client, topics| !topics.idle_timer.expired(now));
+        let mut rval = vec![];
+        for (client, topics) in self.clients.iter_mut() {
+            for topic in StatusTopic::ALL {
+                if let Some(sub) = &mut topics.subs[topic as usize] {
+                    if sub.fresh || sub.timer.expired(now) {
+                        sub.fresh = false;
+                        sub.timer.reset(now);
+                        rval.push((*client, topic));
+                    }
+                }
+            }
+        }
+        rval
+    }
+    /// build the event for a client's topic.  None if this is a delta subscription and nothing changed
+    pub fn event(&mut self, client: u32, topic: StatusTopic, data: Value) -> Option<Value> {
+        let sub = self.clients.get_mut(&client)?.subs[topic as usize].as_mut()?;
+        let mut event = json!({ "topic": topic, "full": true });
+        if sub.binary {
+            event["binary"] = json!(true);
+        } else if sub.delta {
+            if !sub.last.is_null() {
+                event["full"] = json!(false);
+                event["data"] = delta(&sub.last, &data)?;
+            }
+            sub.last = data.clone();
+        }
+        if event["data"].is_null() {
+            event["data"] = data;
+        }
+        let mut rval = json!({
+            "speaker": "UnitChatRobot",
+            "statusEvent": event
+        });
+        if client != 0 {
+            rval["wsClient"] = json!(client);
+        }
+        Some(rval)
+    }
+    /// the events due now, built from the latest snapshot.  Each topic's data is only built once
+    pub fn publish(&mut self, snapshot: &StatusSnapshot, now: u128) -> Vec<Value> {
+        let mut data: [Option<Value>; 6] = Default::default();
+        let mut events = vec![];
+        for (client, topic) in self.due(now) {
+            let topic_data = data[topic as usize]
+                .get_or_insert_with(|| snapshot.topic_data(topic, &self.git_hash))
+                .clone();
+            if let Some(event) = self.event(client, topic, topic_data) {
+                events.push(event);
+            }
+        }
+        events
+    }
+    /// a client's subscriptions.  Goes in the command reply
+    pub fn as_json(&self, client: u32) -> Value {
+        let mut subs = Map::new();
+        if let Some(topics) = self.clients.get(&client) {
+            for topic in StatusTopic::ALL {
+                if let Some(sub) = &topics.subs[topic as usize] {
+                    subs.insert(
+                        topic.name(),
+                        json!({
+                            "interval": sub.timer.get_interval() / 1000,
+                            "delta": sub.delta,
+                            "binary": sub.binary,
+                        }),
+                    );
+                }
+            }
+        }
+        Value::Object(subs)
+    }
+}
+
+/// the top level fields that changed between last and data.  None if nothing changed
+fn delta(last: &Value, data: &Value) -> Option<Value> {
+    match (last.as_object(), data.as_object()) {
+        (Some(last), Some(data)) => {
+            let mut changes = Map::new();
+            for (k, v) in data {
+                if last.get(k) != Some(v) {
+                    changes.insert(k.clone(), v.clone());
+                }
+            }
+            for k in last.keys() {
+                if !data.contains_key(k) {
+                    // let the u/x know the field went away
+                    changes.insert(k.clone(), Value::Null);
+                }
+            }
+            if changes.is_empty() {
+                None
+            } else {
+                Some(Value::Object(changes))
+            }
+        }
+        _ if last == data => None,
+        _ => Some(data.clone()),
+    }
+}
+
+/// Pack a levels topic into a binary websocket frame.  All values are little endian.
+///
+/// | bytes | contents |
+/// |-------|----------|
+/// | 0     | 'L' |
+/// | 1     | version (1) |
+/// | 2     | number of channels N |
+/// | 3     | unused |
+/// | 4..44 | f32 master level, master peak, input L/R level, input L/R peak, room L/R level, room L/R peak |
+/// | 44..  | N times: u8 mixer channel, f32 level, f32 peak |
+pub fn encode_levels(data: &Value) -> Vec<u8> {
+    let empty = vec![];
+    let channels = data["channels"].as_array().unwrap_or(&empty);
+    let count = channels.len().min(u8::MAX as usize);
+    let mut buf = vec![LEVELS_MAGIC, LEVELS_VERSION, count as u8, 0];
+    for field in LEVEL_FIELDS {
+        buf.extend_from_slice(&(data[field].as_f64().unwrap_or(0.0) as f32).to_le_bytes());
+    }
+    for chan in &channels[..count] {
+        buf.push(chan["chanIdx"].as_u64().unwrap_or(0) as u8);
+        buf.extend_from_slice(&(chan["level"].as_f64().unwrap_or(0.0) as f32).to_le_bytes());
+        buf.extend_from_slice(&(chan["peak"].as_f64().unwrap_or(0.0) as f32).to_le_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test_status_topics {
+    use super::*;
+
+    #[test]
+    fn topic_names() {
+        assert_eq!(StatusTopic::from_name("tuner").unwrap(), StatusTopic::Tuner);
+        assert_eq!(StatusTopic::Health.name(), "health");
+        assert!(StatusTopic::from_name("bogus").is_err());
+    }
+
+    #[test]
+    fn rates_per_topic() {
+        let mut topics = StatusTopics::new("hash");
+        assert!(topics.due(0).is_empty());
+        assert!(topics.fastest().is_none());
+        topics.subscribe(0, StatusTopic::Levels, 50, false, false, 0);
+        topics.subscribe(0, StatusTopic::Health, 1000, false, false, 0);
+        assert_eq!(topics.fastest(), Some(50_000));
+        // first update goes right away
+        assert_eq!(topics.due(0), vec![(0, StatusTopic::Levels), (0, StatusTopic::Health)]);
+        assert!(topics.due(50_000).is_empty());
+        assert_eq!(topics.due(50_001), vec![(0, StatusTopic::Levels)]);
+        assert_eq!(topics.due(1_000_001), vec![(0, StatusTopic::Levels), (0, StatusTopic::Health)]);
+        topics.unsubscribe(0, StatusTopic::Levels);
+        assert!(topics.due(1_100_000).is_empty());
+        // intervals get clamped
+        topics.subscribe(0, StatusTopic::Beat, 1, false, false, 0);
+        assert_eq!(topics.as_json(0)["beat"]["interval"], 20);
+    }
+
+    #[test]
+    fn delta_updates() {
+        let mut topics = StatusTopics::new("hash");
+        topics.subscribe(0, StatusTopic::Tuner, 100, true, false, 0);
+        let first = topics.event(0, StatusTopic::Tuner, json!({"leftTunerOn": false, "inputLeftFreq": 0.0})).unwrap();
+        assert_eq!(first["statusEvent"]["full"], true);
+        assert_eq!(first["statusEvent"]["data"]["leftTunerOn"], false);
+        assert!(topics.event(0, StatusTopic::Tuner, json!({"leftTunerOn": false, "inputLeftFreq": 0.0})).is_none());
+        let next = topics.event(0, StatusTopic::Tuner, json!({"leftTunerOn": true, "inputLeftFreq": 0.0})).unwrap();
+        assert_eq!(next["statusEvent"]["full"], false);
+        assert_eq!(next["statusEvent"]["data"], json!({"leftTunerOn": true}));
+        // not subscribed, no event
+        assert!(topics.event(0, StatusTopic::Beat, json!({"beat": 1})).is_none());
+    }
+
+    #[test]
+    fn idle_subscriptions_expire() {
+        let mut topics = StatusTopics::new("hash");
+        topics.subscribe(0, StatusTopic::Beat, 100, false, false, 0);
+        topics.keep_alive(0, TOPIC_IDLE / 2);
+        assert_eq!(topics.due(TOPIC_IDLE), vec![(0, StatusTopic::Beat)]);
+        assert!(topics.due(TOPIC_IDLE * 2).is_empty());
+        assert!(topics.is_empty());
+    }
+
+    #[test]
+    fn separate_clients() {
+        let mut topics = StatusTopics::new("hash");
+        topics.subscribe(0, StatusTopic::Beat, 100, false, false, 0);
+        topics.subscribe(7, StatusTopic::Health, 1000, false, false, 0);
+        let snapshot = StatusSnapshot { beat: 3, ..Default::default() };
+        let mut events = topics.publish(&snapshot, 0);
+        events.sort_by_key(|e| e["wsClient"].as_u64());
+        // the room gets the beat, local connection 7 gets health and nothing else
+        assert_eq!(events.len(), 2);
+        assert!(events[0]["wsClient"].is_null());
+        assert_eq!(events[0]["statusEvent"]["data"]["beat"], 3);
+        assert_eq!(events[1]["wsClient"], 7);
+        assert_eq!(events[1]["statusEvent"]["data"]["git_hash"], "hash");
+        assert!(topics.as_json(7)["beat"].is_null());
+        topics.drop_client(7);
+        assert_eq!(topics.fastest(), Some(100_000));
+    }
+
+    #[test]
+    fn binary_levels() {
+        let mut topics = StatusTopics::new("hash");
+        topics.subscribe(0, StatusTopic::Levels, 50, true, true, 0);
+        let data = json!({
+            "masterLevel": -12.0,
+            "roomPeakRight": -3.0,
+            "channels": [{"chanIdx": 0, "level": -20.0, "peak": -6.0}, {"chanIdx": 3, "level": -30.0, "peak": -9.0}]
+        });
+        let event = topics.event(0, StatusTopic::Levels, data.clone()).unwrap();
+        assert_eq!(event["statusEvent"]["binary"], true);
+        assert_eq!(event["statusEvent"]["full"], true);
+        let buf = encode_levels(&data);
+        assert_eq!(buf.len(), 4 + 40 + 2 * 9);
+        assert_eq!(&buf[0..3], &[b'L', 1, 2]);
+        assert_eq!(f32::from_le_bytes(buf[4..8].try_into().unwrap()), -12.0);
+        assert_eq!(f32::from_le_bytes(buf[40..44].try_into().unwrap()), -3.0);
+        assert_eq!(buf[53], 3);
+        assert_eq!(f32::from_le_bytes(buf[54..58].try_into().unwrap()), -30.0);
+        // levels built from a snapshot only list the channels in use
+        let mut snapshot = StatusSnapshot::default();
+        snapshot.channels[0].active = true;
+        snapshot.channels[5] = ChannelLevel { active: true, level: -20.0, peak: -10.0 };
+        let levels = snapshot.topic_data(StatusTopic::Levels, "");
+        assert_eq!(levels["channels"].as_array().unwrap().len(), 2);
+        assert_eq!(levels["channels"][1]["chanIdx"], 5);
+    }
+}
@@ -0,0 +1,92 @@
+//! What the unit was set to, kept across restarts
+//!
+//! When a command or a new pedal board may have changed its user-facing settings, the
+//! [`JamEngine`](crate::sound::jam_engine::JamEngine) takes a [`UnitState`] snapshot (at most every
+//! [`STATE_INTERVAL`]) and sends it out if something did change: the mixer, metronome, room mutes,
+//! tuners, pedal boards, codec gains, peer presets and the room it is in.  The client saves it to a
+//! [`UnitStateFile`] and gives it back to the engine when it starts up again.  Going back into the
+//! last room is up to the `rejoin_last_room` setting.  The room password is never saved, a room
+//! that needs one waits for the u/x to send it again.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::peer_presets::PeerPreset;
+use crate::common::json_file::JsonFile;
+
+/// how often (usec) at most the engine looks for changes to save
+pub const STATE_INTERVAL: u128 = 5_000_000;
+
+/// One mixer channel
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelState {
+    /// dB
+    pub gain: f64,
+    pub mute: bool,
+    /// pan -1.0 to 1.0
+    pub fade: f32,
+}
+
+/// The room the unit was in
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomState {
+    pub host: String,
+    pub port: i64,
+    pub identity: i64,
+}
+
+/// The user-facing settings of the unit
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct UnitState {
+    /// dB
+    pub master_vol: f64,
+    pub channels: Vec<ChannelState>,
+    /// dB
+    pub metronome_gain: f64,
+    pub metronome_mute: bool,
+    pub room_mutes: [bool; 2],
+    pub tuners: [bool; 2],
+    /// what `PedalBoard::as_json` says for each board
+    pub pedal_boards: Vec<Value>,
+    /// codec input gains.  None until the u/x sets one
+    pub input_gains: [Option<f64>; 2],
+    pub headphone_gain: Option<f64>,
+    /// mixer settings by client id
+    pub peer_presets: BTreeMap<u32, PeerPreset>,
+    pub room: Option<RoomState>,
+}
+
+/// Where the state is kept
+pub type UnitStateFile = JsonFile<UnitState>;
+
+#[cfg(test)]
+mod test_unit_state {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("unit_state_{}.json", std::process::id()));
+        let file = UnitStateFile::new(path.to_str().unwrap());
+        assert!(file.load().is_none());
+        let state = UnitState {
+            master_vol: -3.0,
+            channels: vec![ChannelState { gain: -6.0, mute: true, fade: 0.5 }],
+            pedal_boards: vec![json!({"boardId": 0, "effects": []})],
+            input_gains: [Some(12.0), None],
+            room: Some(RoomState { host: "jam.example.com".to_string(), port: 7891, identity: 42 }),
+            ..Default::default()
+        };
+        file.save(&state).unwrap();
+        assert_eq!(file.load(), Some(state));
+        // a damaged file is ignored
+        fs::write(&path, "{ not json").unwrap();
+        assert!(file.load().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}